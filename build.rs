@@ -0,0 +1,50 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Build Script
+// Copies the vendored SDL_GameControllerDB mappings into the build, so they
+// can be bundled into the binary. If the submodule hasn't been checked out,
+// an empty set of mappings is used instead, leaving only SDL's own.
+
+const MAPPINGS_PATH: &str = "vendor/SDL_GameControllerDB/gamecontrollerdb.txt";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", MAPPINGS_PATH);
+
+    let mappings = match fs::read_to_string(MAPPINGS_PATH) {
+        Ok(mappings) => mappings,
+        Err(error) => {
+            println!(
+                "cargo:warning=couldn't read {} ({}), so no controller mappings will be built in; \
+                 try `git submodule update --init`",
+                MAPPINGS_PATH, error
+            );
+            String::new()
+        }
+    };
+
+    let out_dir = env::var("OUT_DIR").expect("cargo didn't set OUT_DIR");
+    fs::write(Path::new(&out_dir).join("gamecontrollerdb.txt"), mappings)
+        .expect("failed to write controller mappings");
+}
@@ -16,6 +16,11 @@ main() {
         -- \
         -D warnings
 
+    # Each optional feature has to build on its own, too
+    for feature in metrics fd-device touchpad shm-input uinput; do
+        cargo check --all-targets --features $feature
+    done
+
     cargo fmt \
         --all \
         -- \
@@ -0,0 +1,72 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use omnishock::faux_controller::FauxController;
+use omnishock::protocol::{
+    build_packet, ControllerEmulatorPacketType, MappingOptions, TriggerMode, PACKET_LAYOUT,
+};
+use omnishock::sdl_manager::GameController;
+use sdl2::controller::{Axis, Button};
+
+// Synthetic Controller
+// Builds packets from a controller which only exists in software, and prints
+// them, showing how Omnishock's packets can be made without SDL or hardware.
+// Run it with `cargo run --example synthetic`.
+
+fn print_packet(label: &str, controller: &FauxController, options: &MappingOptions) {
+    let packet = build_packet(
+        controller,
+        &ControllerEmulatorPacketType::TwentyByte,
+        options,
+    );
+
+    println!("{} (“{}”):", label, controller.name());
+    for (byte, description) in packet.iter().zip(PACKET_LAYOUT.iter()) {
+        println!("  {:02X}  {}", byte, description);
+    }
+    println!();
+}
+
+fn main() {
+    let options = MappingOptions::new(TriggerMode::Normal, true);
+    let mut controller = FauxController::create_with_name(String::from("Synthetic Controller"));
+
+    print_packet("At rest", &controller, &options);
+
+    controller.set_button(Button::A, true);
+    controller.set_button(Button::DPadUp, true);
+    print_packet("Pressing Cross and Up", &controller, &options);
+
+    controller.set_button(Button::A, false);
+    controller.set_button(Button::DPadUp, false);
+    controller.set_axis(Axis::LeftX, i16::max_value());
+    controller.set_axis(Axis::TriggerRight, i16::max_value() / 2);
+    print_packet(
+        "Left stick right, right trigger half way",
+        &controller,
+        &options,
+    );
+
+    print_packet(
+        "The same, with the triggers on the right stick",
+        &controller,
+        &MappingOptions::new(TriggerMode::RightStick, true),
+    );
+}
@@ -0,0 +1,106 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use hex_view::HexView;
+use std::io::Write;
+use std::time::Instant;
+
+// Anomaly Log
+// Keeps a timestamped record of anything odd which happens during a
+// session (short reads, unexpected responses, reconnects, slow frames),
+// separate from normal output, for looking over after the fact
+
+pub struct AnomalyLog {
+    output: Option<Box<dyn Write>>,
+    started: Instant,
+}
+
+impl AnomalyLog {
+    pub fn new(output: Option<Box<dyn Write>>) -> AnomalyLog {
+        AnomalyLog {
+            output,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn create(path: Option<&str>) -> std::io::Result<AnomalyLog> {
+        let output = match path {
+            Some(path) => Some(Box::new(std::fs::File::create(path)?) as Box<dyn Write>),
+            None => None,
+        };
+
+        Ok(AnomalyLog::new(output))
+    }
+
+    pub fn record(&mut self, kind: &str, message: &str, context: &[u8]) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("AnomalyLog#record()");
+
+        let output = match self.output {
+            Some(ref mut output) => output,
+            None => return,
+        };
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        let result = if context.is_empty() {
+            writeln!(output, "[{:>10.3}s] {}: {}", elapsed, kind, message)
+        } else {
+            writeln!(
+                output,
+                "[{:>10.3}s] {}: {} ({:x})",
+                elapsed,
+                kind,
+                message,
+                HexView::from(context)
+            )
+        };
+
+        // Logging is best-effort, so give up on it rather than the session
+        if let Err(error) = result.and_then(|_| output.flush()) {
+            println!("Warning: stopped writing anomaly log: {}", error);
+            self.output = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mockstream;
+
+    #[test]
+    fn record_writes_timestamped_lines() {
+        use self::mockstream::SharedMockStream;
+        use super::AnomalyLog;
+
+        let mut stream = SharedMockStream::new();
+        let mut log = AnomalyLog::new(Some(Box::new(stream.clone())));
+
+        log.record("reconnect", "controller #1 connected", &[]);
+
+        let written = String::from_utf8(stream.pop_bytes_written()).unwrap();
+        assert!(written.starts_with('['));
+        assert!(written.ends_with("s] reconnect: controller #1 connected\n"));
+
+        // Turned off, nothing happens at all
+        let mut log = AnomalyLog::new(None);
+        log.record("reconnect", "controller #1 connected", &[]);
+    }
+}
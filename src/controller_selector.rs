@@ -0,0 +1,563 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use omnishock::sdl_manager::GameController;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Controller Selector
+// Ways of picking which connected controller drives the virtual pad,
+// re-evaluated every frame so reconnected controllers are picked up again
+
+// Where the selector comes from when --controller isn't given, for setups
+// where the command line is hard to change
+pub const CONTROLLER_ENV_VAR: &str = "OMNISHOCK_CONTROLLER";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControllerSelector {
+    /// SDL's instance id for the controller
+    Id(u32),
+    /// Part of the controller's name, ignoring case
+    Name(String),
+    /// The controller's USB vendor & product ids
+    VidPid(u16, u16),
+    /// SDL's GUID for the controller, in lowercase
+    Guid(String),
+}
+
+impl Default for ControllerSelector {
+    fn default() -> Self {
+        ControllerSelector::Id(0)
+    }
+}
+
+impl FromStr for ControllerSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_hex = |id: &str| {
+            u16::from_str_radix(id.trim(), 16)
+                .map_err(|error| format!("Unexpected USB id '{}': {}", id, error))
+        };
+
+        match s.split_once('=') {
+            Some(("id", id)) => id
+                .trim()
+                .parse()
+                .map(ControllerSelector::Id)
+                .map_err(|error| format!("Unexpected controller id: {}", error)),
+            Some(("name", name)) => Ok(ControllerSelector::Name(name.to_lowercase())),
+            Some(("guid", guid)) if !guid.trim().is_empty() => {
+                Ok(ControllerSelector::Guid(guid.trim().to_lowercase()))
+            }
+            Some(("vidpid", ids)) => match ids.split_once(':') {
+                Some((vendor_id, product_id)) => Ok(ControllerSelector::VidPid(
+                    parse_hex(vendor_id)?,
+                    parse_hex(product_id)?,
+                )),
+                None => Err("Expected USB ids in the form <vendor>:<product>".to_string()),
+            },
+            _ => Err(concat!(
+                "Expected a controller selector like \"id=0\", \"name=xbox\", ",
+                "\"vidpid=054C:09CC\" or \"guid=<guid>\""
+            )
+            .to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ControllerSelector {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControllerSelector::Id(id) => write!(formatter, "id={}", id),
+            ControllerSelector::Name(name) => write!(formatter, "name={}", name),
+            ControllerSelector::VidPid(vendor_id, product_id) => {
+                write!(formatter, "vidpid={:04X}:{:04X}", vendor_id, product_id)
+            }
+            ControllerSelector::Guid(guid) => write!(formatter, "guid={}", guid),
+        }
+    }
+}
+
+impl ControllerSelector {
+    // The selector to use: the one given as a flag, if there was one,
+    // otherwise the one from the environment, otherwise the default.
+    // An empty environment variable counts as not being set.
+    pub fn with_fallback(
+        flag: Option<&ControllerSelector>,
+        environment: Option<&str>,
+    ) -> Result<ControllerSelector, String> {
+        match (flag, environment.map(str::trim)) {
+            (Some(selector), _) => Ok(selector.clone()),
+            (None, Some(environment)) if !environment.is_empty() => environment
+                .parse()
+                .map_err(|error| format!("Unexpected {}: {}", CONTROLLER_ENV_VAR, error)),
+            _ => Ok(ControllerSelector::default()),
+        }
+    }
+
+    pub fn matches<T: GameController>(&self, id: u32, controller: &T) -> bool {
+        match self {
+            ControllerSelector::Id(wanted) => id == *wanted,
+            ControllerSelector::Name(name) => controller.name().to_lowercase().contains(name),
+            ControllerSelector::VidPid(vendor_id, product_id) => {
+                controller.usb_id() == Some((*vendor_id, *product_id))
+            }
+            ControllerSelector::Guid(guid) => controller.guid().as_ref() == Some(guid),
+        }
+    }
+
+    // For --sticky: a selector matching only controllers with the same
+    // GUID as the one this chooses, if it chooses one with a GUID
+    pub fn pinned<T: GameController>(
+        &self,
+        controllers: &HashMap<u32, T>,
+    ) -> Option<ControllerSelector> {
+        match self {
+            ControllerSelector::Guid(_) => None,
+            _ => self
+                .choose(controllers)
+                .and_then(|(_, controller)| controller.guid())
+                .map(ControllerSelector::Guid),
+        }
+    }
+
+    // Finds the matching, still connected controller
+    // with the lowest id, if there is one
+    pub fn choose<'a, T: GameController>(
+        &self,
+        controllers: &'a HashMap<u32, T>,
+    ) -> Option<(u32, &'a T)> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ControllerSelector#choose()");
+
+        controllers
+            .iter()
+            .filter(|(id, controller)| controller.attached() && self.matches(**id, *controller))
+            .min_by_key(|(id, _)| **id)
+            .map(|(id, controller)| (*id, controller))
+    }
+}
+
+// Remembers which controller a selector last chose, so the session can
+// start before any controller is connected, and say when one turns up
+#[derive(Default)]
+pub struct ControllerBinding {
+    // The ids of the controllers driving the adapter
+    bound: Vec<u32>,
+    announced: bool,
+}
+
+impl ControllerBinding {
+    // Chooses again, describing the change if it's
+    // a different controller to last time
+    pub fn update<T: GameController>(
+        &mut self,
+        selector: &ControllerSelector,
+        controllers: &HashMap<u32, T>,
+    ) -> Option<String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ControllerBinding#update()");
+        let chosen = selector.choose(controllers);
+
+        if !self.rebind(chosen.map(|(id, _)| id).into_iter().collect()) {
+            return None;
+        }
+
+        Some(match chosen {
+            Some((id, controller)) => format!(
+                "Driving adapter from “{}” (#{}), which matches {}",
+                controller.name(),
+                id,
+                selector
+            ),
+            None => format!("Waiting for a controller matching {}", selector),
+        })
+    }
+
+    // The same, for a --merge of two controllers by id
+    pub fn update_merged<T: GameController>(
+        &mut self,
+        first_id: u32,
+        second_id: u32,
+        controllers: &HashMap<u32, T>,
+    ) -> Option<String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ControllerBinding#update_merged()");
+        let chosen = match (controllers.get(&first_id), controllers.get(&second_id)) {
+            (Some(first), Some(second)) => Some((first, second)),
+            _ => None,
+        };
+
+        if !self.rebind(match chosen {
+            Some(_) => vec![first_id, second_id],
+            None => Vec::new(),
+        }) {
+            return None;
+        }
+
+        Some(match chosen {
+            Some((first, second)) => format!(
+                "Driving adapter from “{}” (#{}) merged with “{}” (#{})",
+                first.name(),
+                first_id,
+                second.name(),
+                second_id
+            ),
+            None => format!(
+                "Waiting for controllers #{} and #{} to merge",
+                first_id, second_id
+            ),
+        })
+    }
+
+    // Records which controllers are driving the adapter,
+    // returning whether that's worth announcing
+    fn rebind(&mut self, bound: Vec<u32>) -> bool {
+        if self.announced && bound == self.bound {
+            return false;
+        }
+
+        self.announced = true;
+        self.bound = bound;
+        true
+    }
+}
+
+// The connected controller after `current` in id order, going back round
+// to the first after the last. `current` needn't still be connected, and
+// with no current controller at all, that's the first one.
+fn next_controller_id<T: GameController>(
+    current: Option<u32>,
+    controllers: &HashMap<u32, T>,
+) -> Option<u32> {
+    let mut ids: Vec<u32> = controllers
+        .iter()
+        .filter(|(_, controller)| controller.attached())
+        .map(|(id, _)| *id)
+        .collect();
+    ids.sort_unstable();
+
+    current
+        .and_then(|current| ids.iter().find(|id| **id > current))
+        .or_else(|| ids.first())
+        .copied()
+}
+
+// Hands the adapter to each connected controller in turn, for taking
+// turns at a shared setup
+pub struct ControllerRotation {
+    period: f64,
+    // When the next controller gets its turn, in seconds of session time
+    next_turn: Option<f64>,
+    // Whose turn it was last, so the turn can be handed on
+    // from where it left off after a disconnection
+    last_id: Option<u32>,
+}
+
+impl ControllerRotation {
+    pub fn new(period: f64) -> ControllerRotation {
+        ControllerRotation {
+            period,
+            next_turn: None,
+            last_id: None,
+        }
+    }
+
+    // Gives a selector for the next controller once the current one's
+    // turn is up, or straight away if it's no longer connected. The
+    // controllers are looked at afresh each time, so any connected since
+    // the last turn are included.
+    pub fn update<T: GameController>(
+        &mut self,
+        selector: &ControllerSelector,
+        controllers: &HashMap<u32, T>,
+        now: f64,
+    ) -> Option<ControllerSelector> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ControllerRotation#update()");
+        let current = selector.choose(controllers).map(|(id, _)| id);
+        if current.is_some() {
+            self.last_id = current;
+        }
+        let next_turn = *self.next_turn.get_or_insert(now + self.period);
+
+        if current.is_some() && now < next_turn {
+            return None;
+        }
+
+        self.next_turn = Some(now + self.period);
+        let next =
+            next_controller_id(self.last_id, controllers).filter(|next| Some(*next) != current);
+        if next.is_some() {
+            self.last_id = next;
+        }
+        next.map(ControllerSelector::Id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn controller_selector_parses() {
+        use super::ControllerSelector;
+
+        assert_eq!("id=3".parse(), Ok(ControllerSelector::Id(3)));
+        assert_eq!(
+            "name=DualShock 4".parse(),
+            Ok(ControllerSelector::Name("dualshock 4".to_string()))
+        );
+        assert_eq!(
+            "vidpid=054C:09cc".parse(),
+            Ok(ControllerSelector::VidPid(0x054C, 0x09CC))
+        );
+
+        assert!("vidpid=054C".parse::<ControllerSelector>().is_err());
+        assert!("vidpid=054C:XYZ".parse::<ControllerSelector>().is_err());
+        assert!("id=first".parse::<ControllerSelector>().is_err());
+        assert!("3".parse::<ControllerSelector>().is_err());
+    }
+
+    #[test]
+    fn controller_selector_falls_back_to_the_environment() {
+        use super::ControllerSelector;
+
+        let flag = ControllerSelector::Id(2);
+        assert_eq!(
+            ControllerSelector::with_fallback(Some(&flag), Some("name=xbox")),
+            Ok(flag)
+        );
+        assert_eq!(
+            ControllerSelector::with_fallback(None, Some("name=Xbox")),
+            Ok(ControllerSelector::Name("xbox".to_string()))
+        );
+        assert_eq!(
+            ControllerSelector::with_fallback(None, Some(" ")),
+            Ok(ControllerSelector::Id(0))
+        );
+        assert_eq!(
+            ControllerSelector::with_fallback(None, None),
+            Ok(ControllerSelector::Id(0))
+        );
+        assert!(ControllerSelector::with_fallback(None, Some("xbox")).is_err());
+    }
+
+    #[test]
+    fn controller_selector_chooses_controllers() {
+        use super::ControllerSelector;
+        use omnishock::faux_controller::FauxController;
+        use std::collections::HashMap;
+
+        let mut controllers = HashMap::new();
+
+        let mut xbox = FauxController::create_with_name(String::from("Xbox One Controller"));
+        xbox.set_usb_id(0x045E, 0x02EA);
+        controllers.insert(4, xbox);
+
+        let mut dualshock = FauxController::create_with_name(String::from("PS4 Controller"));
+        dualshock.set_usb_id(0x054C, 0x09CC);
+        controllers.insert(2, dualshock.clone());
+        controllers.insert(7, dualshock);
+
+        let chosen = |selector: &str| {
+            selector
+                .parse::<ControllerSelector>()
+                .unwrap()
+                .choose(&controllers)
+                .map(|(id, _)| id)
+        };
+
+        assert_eq!(chosen("id=4"), Some(4));
+        assert_eq!(chosen("id=0"), None);
+        assert_eq!(chosen("name=xbox"), Some(4));
+        // With more than one match, the lowest id wins
+        assert_eq!(chosen("name=ps4"), Some(2));
+        assert_eq!(chosen("vidpid=054C:09CC"), Some(2));
+        assert_eq!(chosen("vidpid=057E:2009"), None);
+        assert_eq!(chosen("guid=030000004c050000cc09000000000000"), None);
+
+        // Controllers which have disconnected are passed over
+        controllers.get_mut(&2).unwrap().set_attached(false);
+        let chosen = |selector: &str| {
+            selector
+                .parse::<ControllerSelector>()
+                .unwrap()
+                .choose(&controllers)
+                .map(|(id, _)| id)
+        };
+        assert_eq!(chosen("name=ps4"), Some(7));
+    }
+
+    #[test]
+    fn controller_selector_pins_the_chosen_guid() {
+        use super::ControllerSelector;
+        use omnishock::faux_controller::FauxController;
+        use std::collections::HashMap;
+
+        let mut controllers = HashMap::new();
+        let selector = ControllerSelector::Id(1);
+        assert_eq!(selector.pinned(&controllers), None);
+
+        // Without a GUID, there's nothing to stick to
+        controllers.insert(1, FauxController::create_with_name(String::from("Fifo")));
+        assert_eq!(selector.pinned(&controllers), None);
+
+        let mut dualshock = FauxController::create_with_name(String::from("PS4 Controller"));
+        dualshock.set_guid("030000004C050000CC09000000000000");
+        controllers.insert(1, dualshock.clone());
+        let pinned = selector.pinned(&controllers).unwrap();
+        assert_eq!(
+            pinned,
+            ControllerSelector::Guid("030000004c050000cc09000000000000".to_string())
+        );
+        assert_eq!(pinned.pinned(&controllers), None);
+
+        // Once it's gone, a different controller doesn't take its place
+        let mut xbox = FauxController::create_with_name(String::from("Xbox One Controller"));
+        xbox.set_guid("030000005e040000ea02000000000000");
+        controllers.insert(1, xbox);
+        assert_eq!(pinned.choose(&controllers).map(|(id, _)| id), None);
+
+        // Until it's back, under whatever id
+        controllers.insert(3, dualshock);
+        assert_eq!(pinned.choose(&controllers).map(|(id, _)| id), Some(3));
+    }
+
+    #[test]
+    fn controller_binding_announces_changes() {
+        use super::{ControllerBinding, ControllerSelector};
+        use omnishock::faux_controller::FauxController;
+        use std::collections::HashMap;
+
+        let selector = ControllerSelector::VidPid(0x054C, 0x05C4);
+        let mut binding = ControllerBinding::default();
+        let mut controllers = HashMap::new();
+
+        // Nothing's connected yet
+        assert_eq!(
+            binding.update(&selector, &controllers),
+            Some("Waiting for a controller matching vidpid=054C:05C4".to_string())
+        );
+        assert_eq!(binding.update(&selector, &controllers), None);
+
+        // Something else turns up
+        controllers.insert(0, FauxController::create_with_name(String::from("Joy-Con")));
+        assert_eq!(binding.update(&selector, &controllers), None);
+
+        // Then the one we wanted
+        let mut dualshock = FauxController::create_with_name(String::from("PS4 Controller"));
+        dualshock.set_usb_id(0x054C, 0x05C4);
+        controllers.insert(1, dualshock);
+        assert_eq!(
+            binding.update(&selector, &controllers),
+            Some(
+                "Driving adapter from “PS4 Controller” (#1), which matches vidpid=054C:05C4"
+                    .to_string()
+            )
+        );
+        assert_eq!(binding.update(&selector, &controllers), None);
+
+        controllers.remove(&1);
+        assert!(binding.update(&selector, &controllers).is_some());
+
+        // Merged controllers are announced once both are there
+        let mut binding = ControllerBinding::default();
+        assert_eq!(
+            binding.update_merged(0, 2, &controllers),
+            Some("Waiting for controllers #0 and #2 to merge".to_string())
+        );
+        controllers.insert(2, FauxController::create_with_name(String::from("Pedals")));
+        assert_eq!(
+            binding.update_merged(0, 2, &controllers),
+            Some("Driving adapter from “Joy-Con” (#0) merged with “Pedals” (#2)".to_string())
+        );
+        assert_eq!(binding.update_merged(0, 2, &controllers), None);
+    }
+
+    #[test]
+    fn controller_rotation_takes_turns() {
+        use super::{ControllerRotation, ControllerSelector};
+        use omnishock::faux_controller::FauxController;
+        use std::collections::HashMap;
+
+        let mut rotation = ControllerRotation::new(30.0);
+        let mut selector = ControllerSelector::default();
+        let mut controllers = HashMap::new();
+        for id in [0, 3, 5] {
+            controllers.insert(
+                id,
+                FauxController::create_with_name(format!("Player {}", id)),
+            );
+        }
+
+        let mut update = |selector: &mut ControllerSelector, controllers: &HashMap<_, _>, now| {
+            let next = rotation.update(selector, controllers, now);
+            if let Some(ref next) = next {
+                *selector = next.clone();
+            }
+            next
+        };
+
+        assert_eq!(update(&mut selector, &controllers, 0.0), None);
+        assert_eq!(update(&mut selector, &controllers, 29.0), None);
+        assert_eq!(
+            update(&mut selector, &controllers, 30.0),
+            Some(ControllerSelector::Id(3))
+        );
+
+        // A controller connected part way round gets its turn
+        controllers.insert(4, FauxController::create_with_name(String::from("Late")));
+        assert_eq!(
+            update(&mut selector, &controllers, 60.0),
+            Some(ControllerSelector::Id(4))
+        );
+
+        // One disconnected mid-turn hands over to the next straight
+        // away, starting the next turn afresh
+        controllers.get_mut(&4).unwrap().set_attached(false);
+        assert_eq!(
+            update(&mut selector, &controllers, 61.0),
+            Some(ControllerSelector::Id(5))
+        );
+        assert_eq!(update(&mut selector, &controllers, 90.0), None);
+
+        // After the last comes the first again
+        assert_eq!(
+            update(&mut selector, &controllers, 91.0),
+            Some(ControllerSelector::Id(0))
+        );
+        assert_eq!(
+            update(&mut selector, &controllers, 121.0),
+            Some(ControllerSelector::Id(3))
+        );
+
+        // Even when the last has gone
+        controllers.remove(&5);
+        assert_eq!(
+            update(&mut selector, &controllers, 151.0),
+            Some(ControllerSelector::Id(0))
+        );
+
+        // Alone, a controller keeps its turn
+        controllers.retain(|id, _| *id == 0);
+        assert_eq!(update(&mut selector, &controllers, 181.0), None);
+    }
+}
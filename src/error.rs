@@ -0,0 +1,205 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+
+// Omnishock Error
+// The ways a session can fail, each of which
+// maps to a distinct, documented exit code
+
+#[derive(Debug)]
+pub enum OmnishockError {
+    /// The options given don't make sense together (exit code 2)
+    InvalidArguments(String),
+    /// The serial device couldn't be opened (exit code 3)
+    DeviceOpen(String),
+    /// No game controllers were connected when one was needed (exit code 4)
+    NoControllers(String),
+    /// The device's firmware couldn't be identified (exit code 5)
+    DetectionFailed(String),
+    /// Writing to the device failed part way through a session (exit code 6)
+    Serial(std::io::Error),
+    /// The device stopped answering part way through a session (exit code 8)
+    Timeout(std::io::Error),
+    /// SDL couldn't start, or failed once it was up and running (exit code 7)
+    Sdl(String),
+    /// The session was interrupted with Ctrl-C (exit code 130)
+    UserAbort,
+    /// Anything else which went wrong (exit code 1)
+    Other(Box<dyn std::error::Error>),
+}
+
+impl OmnishockError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            OmnishockError::Other(_) => 1,
+            // The same code clap uses when it rejects arguments
+            OmnishockError::InvalidArguments(_) => 2,
+            OmnishockError::DeviceOpen(_) => 3,
+            OmnishockError::NoControllers(_) => 4,
+            OmnishockError::DetectionFailed(_) => 5,
+            OmnishockError::Serial(_) => 6,
+            OmnishockError::Sdl(_) => 7,
+            OmnishockError::Timeout(_) => 8,
+            // The conventional code for a process ended by SIGINT
+            OmnishockError::UserAbort => 130,
+        }
+    }
+
+    // Whether the device stopped responding in time, rather than going away
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, OmnishockError::Timeout(_))
+    }
+
+    // For errors talking to the device, where anything but a
+    // timeout means it can't be talked to any more
+    pub fn from_serial(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::TimedOut => OmnishockError::Timeout(error),
+            _ => OmnishockError::Serial(error),
+        }
+    }
+}
+
+impl fmt::Display for OmnishockError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OmnishockError::InvalidArguments(error) => write!(formatter, "{}", error),
+            OmnishockError::DeviceOpen(error) => {
+                write!(formatter, "failed to open serial device: {}", error)
+            }
+            OmnishockError::NoControllers(error) => {
+                write!(formatter, "no game controllers available: {}", error)
+            }
+            OmnishockError::DetectionFailed(error) => {
+                write!(formatter, "failed to detect device type: {}", error)
+            }
+            OmnishockError::Serial(error) => {
+                write!(formatter, "failed communicating with device: {}", error)
+            }
+            OmnishockError::Sdl(error) => write!(formatter, "SDL error: {}", error),
+            OmnishockError::Timeout(error) => {
+                write!(formatter, "timed out waiting for the device: {}", error)
+            }
+            OmnishockError::UserAbort => write!(formatter, "aborted by user"),
+            OmnishockError::Other(error) => write!(formatter, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for OmnishockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OmnishockError::Serial(error) | OmnishockError::Timeout(error) => Some(error),
+            OmnishockError::Other(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for OmnishockError {
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        OmnishockError::Other(error)
+    }
+}
+
+// Only errors which mean a device has gone away count as serial errors;
+// anything else, like a file which couldn't be read, is just an error
+impl From<std::io::Error> for OmnishockError {
+    fn from(error: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match error.kind() {
+            ErrorKind::TimedOut => OmnishockError::Timeout(error),
+            ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::WriteZero => OmnishockError::Serial(error),
+            _ => OmnishockError::Other(Box::new(error)),
+        }
+    }
+}
+
+impl From<String> for OmnishockError {
+    fn from(error: String) -> Self {
+        OmnishockError::Other(error.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OmnishockError;
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let errors = [
+            OmnishockError::Other("other".into()),
+            OmnishockError::InvalidArguments("arguments".to_string()),
+            OmnishockError::DeviceOpen("device".to_string()),
+            OmnishockError::NoControllers("controllers".to_string()),
+            OmnishockError::DetectionFailed("detection".to_string()),
+            OmnishockError::Serial(std::io::ErrorKind::BrokenPipe.into()),
+            OmnishockError::Sdl("sdl".to_string()),
+            OmnishockError::Timeout(std::io::ErrorKind::TimedOut.into()),
+            OmnishockError::UserAbort,
+        ];
+
+        let mut codes: Vec<i32> = errors.iter().map(|error| error.exit_code()).collect();
+
+        assert!(codes.iter().all(|code| *code != 0));
+
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn serial_errors_tell_timeouts_apart() {
+        use super::OmnishockError;
+        use std::io::ErrorKind;
+
+        assert!(OmnishockError::from_serial(ErrorKind::TimedOut.into()).is_timeout());
+        assert!(!OmnishockError::from_serial(ErrorKind::BrokenPipe.into()).is_timeout());
+        assert!(!OmnishockError::Other("timed out".into()).is_timeout());
+
+        // Any other error talking to the device is a serial error
+        assert!(matches!(
+            OmnishockError::from_serial(ErrorKind::PermissionDenied.into()),
+            OmnishockError::Serial(_)
+        ));
+    }
+
+    #[test]
+    fn io_errors_are_sorted_by_kind() {
+        use super::OmnishockError;
+        use std::io::{Error, ErrorKind};
+
+        let from = |kind: ErrorKind| OmnishockError::from(Error::from(kind));
+
+        assert!(from(ErrorKind::TimedOut).is_timeout());
+        assert_eq!(from(ErrorKind::TimedOut).exit_code(), 8);
+        assert_eq!(from(ErrorKind::BrokenPipe).exit_code(), 6);
+        assert_eq!(from(ErrorKind::UnexpectedEof).exit_code(), 6);
+        // Like a log file which isn't there
+        assert_eq!(from(ErrorKind::NotFound).exit_code(), 1);
+    }
+}
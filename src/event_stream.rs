@@ -0,0 +1,153 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::PACKET_BUTTONS;
+use std::io::Write;
+
+// Event Stream
+// Describes the packets sent to the device as a stream of events, one
+// per line, for other programs to follow along without having to know
+// the DualShock protocol. Only what's changed since the last packet is
+// written; the first packet has everything written, as a starting point.
+//
+// Each line is one of:
+//   "press <button>" / "release <button>", with buttons named as for --at
+//   "axis <axis> <value>", for left-x, left-y, right-x and right-y, from
+//     0 (left or up) to 255 (right or down), with 128 at the centre
+//   "pressure <button> <value>", from 0 to 255, for twenty-byte packets
+
+// Each stick axis' name, and which packet byte holds it
+const STICK_AXES: [(&str, usize); 4] =
+    [("right-x", 3), ("right-y", 4), ("left-x", 5), ("left-y", 6)];
+
+pub struct EventStream {
+    output: Box<dyn Write>,
+    last_packet: Option<Vec<u8>>,
+}
+
+// The events for going from `previous` (if there was one) to `packet`
+pub fn packet_events(previous: Option<&[u8]>, packet: &[u8]) -> Vec<String> {
+    let changed = |byte: usize| match (
+        previous.and_then(|previous| previous.get(byte)),
+        packet.get(byte),
+    ) {
+        (_, None) => None,
+        (Some(before), Some(after)) if before == after => None,
+        (_, Some(after)) => Some(*after),
+    };
+    let mut events = Vec::new();
+
+    for (name, byte, bits, _) in PACKET_BUTTONS.iter() {
+        // The DualShock protocol considers 0 to mean pressed
+        let pressed = |packet: &[u8]| packet.get(*byte).map(|value| value & bits == 0);
+        let now = pressed(packet);
+        if now.is_some() && previous.and_then(pressed) != now {
+            let action = if now == Some(true) {
+                "press"
+            } else {
+                "release"
+            };
+            events.push(format!("{} {}", action, name));
+        }
+    }
+
+    for (name, byte) in STICK_AXES.iter() {
+        if let Some(value) = changed(*byte) {
+            events.push(format!("axis {} {}", name, value));
+        }
+    }
+
+    for (name, _, _, pressure) in PACKET_BUTTONS.iter() {
+        if let Some(value) = pressure.and_then(changed) {
+            events.push(format!("pressure {} {}", name, value));
+        }
+    }
+
+    events
+}
+
+impl EventStream {
+    pub fn new(output: Box<dyn Write>) -> EventStream {
+        EventStream {
+            output,
+            last_packet: None,
+        }
+    }
+
+    // Writes whatever's changed in `packet` since the last one
+    pub fn update(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("EventStream#update()");
+        let events = packet_events(self.last_packet.as_deref(), packet);
+        self.last_packet = Some(packet.to_vec());
+
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        for event in events {
+            writeln!(self.output, "{}", event)?;
+        }
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn packet_events_describe_changes() {
+        use super::packet_events;
+        use omnishock::protocol::{Buttons1, Buttons2, NEUTRAL_PACKET};
+
+        // The first packet has everything
+        let seven_byte = &NEUTRAL_PACKET[..7];
+        let events = packet_events(None, seven_byte);
+        assert_eq!(events.len(), 16 + 4);
+        assert!(events.contains(&"release cross".to_string()));
+        assert!(events.contains(&"axis left-y 128".to_string()));
+
+        // Then only what's changed
+        assert!(packet_events(Some(&NEUTRAL_PACKET), &NEUTRAL_PACKET).is_empty());
+
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        packet[1] = !Buttons1::START.bits();
+        packet[2] = !Buttons2::CROSS.bits();
+        packet[5] = 0xFF;
+        packet[13] = 0xFF;
+        assert_eq!(
+            packet_events(Some(&NEUTRAL_PACKET), &packet),
+            vec![
+                "press start",
+                "press cross",
+                "axis left-x 255",
+                "pressure cross 255",
+            ]
+        );
+        assert_eq!(
+            packet_events(Some(&packet), &NEUTRAL_PACKET),
+            vec![
+                "release start",
+                "release cross",
+                "axis left-x 128",
+                "pressure cross 0",
+            ]
+        );
+    }
+}
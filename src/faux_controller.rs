@@ -0,0 +1,141 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::sdl_manager::GameController;
+use std::collections::HashMap;
+
+// Faux Controller
+// A controller which isn't backed by any hardware, whose state is set
+// programmatically. Used for test patterns and for testing the mappings.
+
+#[derive(Clone, Debug)]
+pub struct FauxController {
+    name: String,
+    buttons: HashMap<sdl2::controller::Button, bool>,
+    axes: HashMap<sdl2::controller::Axis, i16>,
+    has_rumble: bool,
+    attached: bool,
+    rumble: Option<(u16, u16, u32)>,
+    usb_id: Option<(u16, u16)>,
+    guid: Option<String>,
+    touchpad_fingers: HashMap<u8, (f32, f32)>,
+}
+
+impl FauxController {
+    pub fn create_with_name(name: String) -> FauxController {
+        let buttons = HashMap::new();
+        let axes = HashMap::new();
+        FauxController {
+            name,
+            buttons,
+            axes,
+            has_rumble: true,
+            attached: true,
+            rumble: None,
+            usb_id: None,
+            guid: None,
+            touchpad_fingers: HashMap::new(),
+        }
+    }
+
+    pub fn set_button(&mut self, button: sdl2::controller::Button, value: bool) {
+        self.buttons.insert(button, value);
+    }
+
+    pub fn set_axis(&mut self, axis: sdl2::controller::Axis, value: i16) {
+        self.axes.insert(axis, value);
+    }
+
+    pub fn set_usb_id(&mut self, vendor_id: u16, product_id: u16) {
+        self.usb_id = Some((vendor_id, product_id));
+    }
+
+    pub fn set_guid(&mut self, guid: &str) {
+        self.guid = Some(guid.to_lowercase());
+    }
+
+    pub fn set_has_rumble(&mut self, has_rumble: bool) {
+        self.has_rumble = has_rumble;
+    }
+
+    pub fn set_touchpad_finger(&mut self, index: u8, position: Option<(f32, f32)>) {
+        match position {
+            Some(position) => self.touchpad_fingers.insert(index, position),
+            None => self.touchpad_fingers.remove(&index),
+        };
+    }
+
+    pub fn set_attached(&mut self, attached: bool) {
+        self.attached = attached;
+    }
+
+    // The last rumble set, as (low frequency, high frequency, duration)
+    pub fn rumble(&self) -> Option<(u16, u16, u32)> {
+        self.rumble
+    }
+}
+
+impl GameController for FauxController {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn button(&self, button: sdl2::controller::Button) -> bool {
+        *self.buttons.get(&button).unwrap_or(&false)
+    }
+
+    fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
+        *self.axes.get(&axis).unwrap_or(&0)
+    }
+
+    fn has_rumble(&self) -> bool {
+        self.has_rumble
+    }
+
+    fn attached(&self) -> bool {
+        self.attached
+    }
+
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        self.usb_id
+    }
+
+    fn guid(&self) -> Option<String> {
+        self.guid.clone()
+    }
+
+    fn touchpad_finger(&self, index: u8) -> Option<(f32, f32)> {
+        self.touchpad_fingers.get(&index).copied()
+    }
+
+    fn set_rumble(
+        &mut self,
+        low_frequency_rumble: u16,
+        high_frequency_rumble: u16,
+        duration_ms: u32,
+    ) -> Result<(), String> {
+        if !self.has_rumble {
+            return Err("this controller has no rumble".to_string());
+        }
+
+        self.rumble = Some((low_frequency_rumble, high_frequency_rumble, duration_ms));
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use omnishock::faux_controller::FauxController;
+use omnishock::protocol::{parse_axis, parse_button};
+use omnishock::sdl_manager::GameController;
+use std::io::BufRead;
+use std::sync::mpsc::Receiver;
+
+// FIFO Controller
+// A controller whose state is written, a line at a time, to a named pipe
+// by some other program. Each line is a whole frame: the buttons held,
+// using the same names as --shift-button, and any axes which aren't at
+// rest as "<axis>=<value>", e.g. "a dpad-up left-x=-32768". The most
+// recent line wins, and the state holds until the next one arrives.
+
+pub struct FifoController {
+    name: String,
+    lines: Receiver<String>,
+    state: Option<FauxController>,
+}
+
+// Reads one frame, starting from nothing pressed and everything at rest
+fn parse_frame(name: &str, line: &str) -> Result<FauxController, String> {
+    let mut state = FauxController::create_with_name(name.to_string());
+    state.set_has_rumble(false);
+
+    for token in line
+        .split(|character: char| character.is_whitespace() || character == ',')
+        .filter(|token| !token.is_empty())
+    {
+        match token.split_once('=') {
+            Some((axis, value)) => {
+                let value = value
+                    .parse::<i16>()
+                    .map_err(|error| format!("Unexpected axis value '{}': {}", value, error))?;
+                state.set_axis(parse_axis(axis)?, value);
+            }
+            None => state.set_button(parse_button(token)?, true),
+        }
+    }
+
+    Ok(state)
+}
+
+impl FifoController {
+    // Starts reading from the pipe at `path`. Opening a pipe waits until
+    // something opens the other end, so that (and any reopening once the
+    // writer goes away) happens on a thread of its own.
+    pub fn open(path: &str) -> Result<FifoController, String> {
+        std::fs::metadata(path).map_err(|error| format!("Couldn't find '{}': {}", path, error))?;
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let thread_path = path.to_string();
+
+        std::thread::spawn(move || loop {
+            let file = match std::fs::File::open(&thread_path) {
+                Ok(file) => file,
+                Err(error) => {
+                    println!("Couldn't open '{}': {}", thread_path, error);
+                    break;
+                }
+            };
+
+            for line in std::io::BufReader::new(file).lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            // The writer has closed its end, so wait for the next one
+        });
+
+        Ok(FifoController::with_lines(
+            format!("FIFO {}", path),
+            receiver,
+        ))
+    }
+
+    fn with_lines(name: String, lines: Receiver<String>) -> FifoController {
+        FifoController {
+            name,
+            lines,
+            state: None,
+        }
+    }
+
+    // Takes up the latest frame written, if there's been one since last time.
+    // Returns whether there's any state at all yet.
+    pub fn poll(&mut self) -> bool {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("FifoController#poll()");
+        if let Some(line) = self.lines.try_iter().last() {
+            match parse_frame(&self.name, &line) {
+                Ok(state) => self.state = Some(state),
+                Err(error) => println!("Ignoring frame from {}: {}", self.name, error),
+            }
+        }
+
+        self.state.is_some()
+    }
+
+    fn state(&self) -> Option<&FauxController> {
+        self.state.as_ref()
+    }
+}
+
+impl GameController for FifoController {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn button(&self, button: sdl2::controller::Button) -> bool {
+        self.state()
+            .map(|state| state.button(button))
+            .unwrap_or(false)
+    }
+
+    fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
+        self.state().map(|state| state.axis(axis)).unwrap_or(0)
+    }
+
+    fn has_rumble(&self) -> bool {
+        false
+    }
+
+    fn attached(&self) -> bool {
+        true
+    }
+
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    fn guid(&self) -> Option<String> {
+        None
+    }
+
+    fn touchpad_finger(&self, _index: u8) -> Option<(f32, f32)> {
+        None
+    }
+
+    fn set_rumble(
+        &mut self,
+        _low_frequency_rumble: u16,
+        _high_frequency_rumble: u16,
+        _duration_ms: u32,
+    ) -> Result<(), String> {
+        Err("a FIFO has no rumble".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fifo_controller_holds_the_latest_frame() {
+        use super::FifoController;
+        use omnishock::sdl_manager::GameController;
+        use sdl2::controller::{Axis, Button};
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut controller = FifoController::with_lines("FIFO test".to_string(), receiver);
+
+        // Nothing's been written yet
+        assert!(!controller.poll());
+
+        sender.send("a, left-x=-32768".to_string()).unwrap();
+        sender
+            .send("b dpad-up trigger-right=32767".to_string())
+            .unwrap();
+        assert!(controller.poll());
+        assert!(controller.button(Button::B));
+        assert!(controller.button(Button::DPadUp));
+        assert!(!controller.button(Button::A));
+        assert_eq!(controller.axis(Axis::TriggerRight), 32767);
+        assert_eq!(controller.axis(Axis::LeftX), 0);
+
+        // With nothing new, the last frame holds
+        assert!(controller.poll());
+        assert!(controller.button(Button::B));
+
+        // As it does when a frame can't be read
+        sender.send("b left-x=nowhere".to_string()).unwrap();
+        assert!(controller.poll());
+        assert!(controller.button(Button::DPadUp));
+
+        // A blank line lets go of everything
+        sender.send(String::new()).unwrap();
+        assert!(controller.poll());
+        assert!(!controller.button(Button::B));
+        assert_eq!(controller.axis(Axis::TriggerRight), 0);
+    }
+}
@@ -0,0 +1,293 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use game_time::framerate::RunningAverageSampler;
+use game_time::step::{FixedStep, VariableStep};
+use game_time::{FloatDuration, FrameCount, FrameCounter, GameClock};
+use std::str::FromStr;
+
+// Frame Clock
+// Where the frame loops get their sense of time from. Normally that's
+// the wall clock, paced with `game_time`, but tests can swap in a
+// virtual clock which steps through a fixed schedule instead, so
+// anything timing-dependent happens the same way on every run.
+
+// How many frames the running frame rate average covers
+const FRAME_RATE_SAMPLES: u32 = 60;
+
+// How the wall clock steps from one frame to the next
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FrameStep {
+    // Every frame is the same length, like a real pad being polled at a
+    // steady rate, waiting out whatever's left of each one
+    #[default]
+    Fixed,
+    // Each frame lasts as long as it takes, starting the next as soon as
+    // the last is done, for the least latency but uneven timing
+    Variable,
+}
+
+impl FrameStep {
+    pub fn variants() -> [&'static str; 2] {
+        ["fixed", "variable"]
+    }
+}
+
+impl FromStr for FrameStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(FrameStep::Fixed),
+            "variable" => Ok(FrameStep::Variable),
+            _ => Err("Unexpected frame step".to_string()),
+        }
+    }
+}
+
+// The timing of a single frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameTime {
+    total: FloatDuration,
+    elapsed: FloatDuration,
+    average_frame_rate: f64,
+    running_slow: bool,
+}
+
+impl FrameTime {
+    // How long the session has been running
+    pub fn total_wall_time(&self) -> FloatDuration {
+        self.total
+    }
+
+    // How long the last frame took
+    pub fn elapsed_wall_time(&self) -> FloatDuration {
+        self.elapsed
+    }
+
+    pub fn instantaneous_frame_rate(&self) -> f64 {
+        1.0 / self.elapsed.as_seconds()
+    }
+
+    pub fn average_frame_rate(&self) -> f64 {
+        self.average_frame_rate
+    }
+
+    pub fn is_running_slow(&self) -> bool {
+        self.running_slow
+    }
+}
+
+pub trait FrameClock {
+    // Starts the next frame
+    fn tick(&mut self) -> FrameTime;
+    // Waits out whatever's left of the current frame
+    fn sleep_remaining(&mut self);
+    // Forgets the frame rate average so far
+    fn reset_average(&mut self);
+}
+
+// Paces frames against the wall clock
+pub struct WallClock {
+    frame_rate: f64,
+    step: FrameStep,
+    clock: GameClock,
+    counter: FrameCounter<RunningAverageSampler>,
+    spin_sleeper: spin_sleep::SpinSleeper,
+}
+
+impl WallClock {
+    pub fn new(frame_rate: f64) -> WallClock {
+        WallClock::with_step(frame_rate, FrameStep::Fixed)
+    }
+
+    pub fn with_step(frame_rate: f64, step: FrameStep) -> WallClock {
+        WallClock {
+            frame_rate,
+            step,
+            clock: GameClock::new(),
+            counter: FrameCounter::new(
+                frame_rate,
+                RunningAverageSampler::with_max_samples(FRAME_RATE_SAMPLES),
+            ),
+            // `spin_sleeper` gives us a more accurate sleep timer.
+            // With it we will trust `thread::Sleep` for all but the last 1ms
+            // (1,000,000ns) of the sleep timer, then it will spin for the remainder.
+            // With this in place we only dip below 95% of our speed target a handful
+            // of times in a 4-minute period, rather than nearly every iteration.
+            spin_sleeper: spin_sleep::SpinSleeper::new(1_000_000),
+        }
+    }
+}
+
+impl FrameClock for WallClock {
+    fn tick(&mut self) -> FrameTime {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("WallClock#tick()");
+        let sim_time = match self.step {
+            FrameStep::Fixed => self.clock.tick(&FixedStep::new(&self.counter)),
+            FrameStep::Variable => self.clock.tick(&VariableStep::new()),
+        };
+        self.counter.tick(&sim_time);
+
+        FrameTime {
+            total: sim_time.total_wall_time(),
+            elapsed: sim_time.elapsed_wall_time(),
+            average_frame_rate: self.counter.average_frame_rate(),
+            running_slow: self.counter.is_running_slow(&sim_time),
+        }
+    }
+
+    fn sleep_remaining(&mut self) {
+        // Variable frames don't wait for anything
+        if self.step == FrameStep::Variable {
+            return;
+        }
+
+        let spin_sleeper = &self.spin_sleeper;
+        self.clock.sleep_remaining_via(&self.counter, |rem| {
+            spin_sleeper.sleep(rem.to_std().unwrap())
+        });
+    }
+
+    fn reset_average(&mut self) {
+        self.counter = FrameCounter::new(
+            self.frame_rate,
+            RunningAverageSampler::with_max_samples(FRAME_RATE_SAMPLES),
+        );
+    }
+}
+
+// Steps through a fixed schedule of frame lengths without ever sleeping,
+// carrying on with the last one once the schedule runs out
+#[cfg(test)]
+pub struct VirtualClock {
+    frame_length: FloatDuration,
+    schedule: Vec<FloatDuration>,
+    frames: usize,
+    total: FloatDuration,
+    // Frames and time since the average was last reset
+    averaged_frames: usize,
+    averaged_time: FloatDuration,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new(frame_rate: f64, schedule: Vec<FloatDuration>) -> VirtualClock {
+        VirtualClock {
+            frame_length: FloatDuration::seconds(1.0 / frame_rate),
+            schedule,
+            frames: 0,
+            total: FloatDuration::zero(),
+            averaged_frames: 0,
+            averaged_time: FloatDuration::zero(),
+        }
+    }
+
+    // A clock where every frame takes exactly as long as it should
+    pub fn steady(frame_rate: f64) -> VirtualClock {
+        VirtualClock::new(frame_rate, Vec::new())
+    }
+}
+
+#[cfg(test)]
+impl FrameClock for VirtualClock {
+    fn tick(&mut self) -> FrameTime {
+        let elapsed = match self.schedule.get(self.frames) {
+            Some(elapsed) => *elapsed,
+            None => *self.schedule.last().unwrap_or(&self.frame_length),
+        };
+
+        self.frames += 1;
+        self.total += elapsed;
+        self.averaged_frames += 1;
+        self.averaged_time += elapsed;
+
+        FrameTime {
+            total: self.total,
+            elapsed,
+            average_frame_rate: self.averaged_frames as f64 / self.averaged_time.as_seconds(),
+            running_slow: elapsed > self.frame_length,
+        }
+    }
+
+    fn sleep_remaining(&mut self) {}
+
+    fn reset_average(&mut self) {
+        self.averaged_frames = 0;
+        self.averaged_time = FloatDuration::zero();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn virtual_clock_follows_its_schedule() {
+        use super::{FrameClock, VirtualClock};
+        use game_time::FloatDuration;
+
+        let mut clock = VirtualClock::new(
+            50.0,
+            vec![
+                FloatDuration::milliseconds(20.0),
+                FloatDuration::milliseconds(40.0),
+            ],
+        );
+
+        let frame = clock.tick();
+        assert_eq!(frame.total_wall_time(), FloatDuration::milliseconds(20.0));
+        assert!(!frame.is_running_slow());
+
+        let frame = clock.tick();
+        assert_eq!(frame.elapsed_wall_time(), FloatDuration::milliseconds(40.0));
+        assert!(frame.is_running_slow());
+        assert!((frame.instantaneous_frame_rate() - 25.0).abs() < 1e-9);
+
+        // The last step carries on once the schedule's done
+        clock.reset_average();
+        let frame = clock.tick();
+        assert_eq!(frame.elapsed_wall_time(), FloatDuration::milliseconds(40.0));
+        assert!((frame.average_frame_rate() - 25.0).abs() < 1e-9);
+
+        let mut clock = VirtualClock::steady(50.0);
+        for _ in 0..50 {
+            clock.tick();
+        }
+        assert!((clock.tick().total_wall_time().as_seconds() - 1.02).abs() < 1e-9);
+    }
+
+    #[test]
+    fn variable_steps_never_wait() {
+        use super::{FrameClock, FrameStep, WallClock};
+        use std::str::FromStr;
+        use std::time::{Duration, Instant};
+
+        assert_eq!(FrameStep::from_str("variable"), Ok(FrameStep::Variable));
+        assert!(FrameStep::from_str("wobbly").is_err());
+
+        // A fixed step at one frame a second would wait most of a second
+        let mut clock = WallClock::with_step(1.0, FrameStep::Variable);
+        let started = Instant::now();
+        clock.tick();
+        clock.sleep_remaining();
+        clock.tick();
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
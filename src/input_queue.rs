@@ -0,0 +1,206 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{parse_packet_button, press_packet_button};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Input Queue
+// A file of inputs, one line per frame, for playing back exactly.
+// Each line lists the buttons held during that frame, and any stick
+// positions as "<stick>=<byte>" (using "lx", "ly", "rx" or "ry");
+// a blank line is a frame with nothing pressed, and lines starting
+// with "#" are ignored entirely.
+
+// Each stick axis' name, and which packet byte holds it
+const STICK_BYTES: [(&str, usize); 4] = [("rx", 3), ("ry", 4), ("lx", 5), ("ly", 6)];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputFrame {
+    buttons: Vec<&'static str>,
+    sticks: Vec<(usize, u8)>,
+}
+
+impl FromStr for InputFrame {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut frame = InputFrame::default();
+
+        for token in s
+            .split(|character: char| character.is_whitespace() || character == ',')
+            .filter(|token| !token.is_empty())
+        {
+            let (stick, value) = match token.split_once('=') {
+                Some(assignment) => assignment,
+                None => {
+                    frame.buttons.push(parse_packet_button(token)?);
+                    continue;
+                }
+            };
+
+            let byte = STICK_BYTES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(stick))
+                .map(|(_, byte)| *byte)
+                .ok_or_else(|| format!("Unexpected stick axis '{}'", stick))?;
+
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => value.parse::<u8>(),
+            }
+            .map_err(|error| format!("Unexpected stick value '{}': {}", value, error))?;
+
+            frame.sticks.push((byte, value));
+        }
+
+        Ok(frame)
+    }
+}
+
+impl InputFrame {
+    // Applies this frame's inputs on top of a neutral `packet`
+    pub fn apply(&self, packet: &mut [u8]) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("InputFrame#apply()");
+        for button in &self.buttons {
+            press_packet_button(packet, button);
+        }
+
+        for (byte, value) in &self.sticks {
+            packet[*byte] = *value;
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct InputQueue {
+    pub frames: Vec<InputFrame>,
+}
+
+impl FromStr for InputQueue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("InputQueue::from_str()");
+        let mut queue = InputQueue::default();
+
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let frame = InputFrame::from_str(line)
+                .map_err(|error| format!("line {}: {}", index + 1, error))?;
+            queue.frames.push(frame);
+        }
+
+        Ok(queue)
+    }
+}
+
+impl InputQueue {
+    pub fn load(path: &str) -> Result<InputQueue, String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("InputQueue::load()");
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Couldn't read input queue '{}': {}", path, error))?;
+
+        InputQueue::from_str(&contents)
+            .map_err(|error| format!("In input queue '{}', {}", path, error))
+    }
+}
+
+// Paces frames by their index rather than by how long the last one took,
+// so every frame is sent exactly once however late the machine runs
+pub struct FrameStepper {
+    started: Instant,
+    frame_length: Duration,
+}
+
+impl FrameStepper {
+    pub fn new(frame_rate: f64) -> FrameStepper {
+        FrameStepper {
+            started: Instant::now(),
+            frame_length: Duration::from_secs_f64(1.0 / frame_rate),
+        }
+    }
+
+    // When `frame` is due to be sent
+    fn due(&self, frame: usize) -> Instant {
+        self.started + self.frame_length.mul_f64(frame as f64)
+    }
+
+    // Waits until `frame` is due. If it's already a whole frame or more
+    // overdue, nothing waits, and how late it is gets returned instead.
+    pub fn wait_for(&self, frame: usize, sleeper: &spin_sleep::SpinSleeper) -> Option<Duration> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("FrameStepper#wait_for()");
+        let due = self.due(frame);
+        let now = Instant::now();
+
+        if now < due {
+            sleeper.sleep(due - now);
+            return None;
+        }
+
+        let lateness = now - due;
+        if lateness >= self.frame_length {
+            Some(lateness)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn input_queue_has_a_frame_per_line() {
+        use super::InputQueue;
+        use std::str::FromStr;
+
+        let queue =
+            InputQueue::from_str("# Jump, then run right\ncross\n\nright, lx=0xFF\n").unwrap();
+        assert_eq!(queue.frames.len(), 3);
+
+        // The DualShock protocol considers 0 to mean pressed
+        let mut packet = omnishock::protocol::NEUTRAL_PACKET.to_vec();
+        queue.frames[2].apply(&mut packet);
+        assert_eq!(packet[1], 0xDF);
+        assert_eq!(packet[2], 0xFF);
+        assert_eq!(packet[5], 0xFF);
+        assert_eq!(packet[7], 0xFF);
+
+        // Blank lines leave the packet as it was
+        let mut neutral = omnishock::protocol::NEUTRAL_PACKET.to_vec();
+        queue.frames[1].apply(&mut neutral);
+        assert_eq!(neutral, omnishock::protocol::NEUTRAL_PACKET.to_vec());
+
+        assert_eq!(
+            InputQueue::from_str("cross\njump").unwrap_err(),
+            "line 2: Unexpected button name 'jump'"
+        );
+        assert!(InputQueue::from_str("lz=0x80").is_err());
+        assert!(InputQueue::from_str("lx=256").is_err());
+    }
+}
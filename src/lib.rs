@@ -0,0 +1,35 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Omnishock
+// The pieces of Omnishock which don't need a terminal: game controller
+// access, and building packets for controller emulator hardware
+
+#[macro_use]
+extern crate bitflags;
+
+#[cfg(feature = "flamegraph-profiling")]
+extern crate flame;
+
+pub mod faux_controller;
+pub mod packet_delta;
+pub mod protocol;
+pub mod sdl_manager;
+pub mod spike_filter;
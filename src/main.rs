@@ -18,15 +18,10 @@
  * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-#[macro_use]
-extern crate bitflags;
-
 use hex_view::HexView;
-use std::cmp::{PartialEq, PartialOrd};
-use std::convert::From;
 use std::io::prelude::{Read, Write};
-use std::ops::{Add, Div, Neg};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use clap::Parser;
 
@@ -35,21 +30,76 @@ extern crate flame;
 #[cfg(feature = "flamegraph-profiling")]
 use std::fs::File;
 
-mod sdl_manager;
-use sdl_manager::GameController;
-use sdl_manager::SDLManager;
+mod anomaly_log;
+use anomaly_log::AnomalyLog;
+
+mod controller_selector;
+use controller_selector::{
+    ControllerBinding, ControllerRotation, ControllerSelector, CONTROLLER_ENV_VAR,
+};
+
+mod error;
+use error::OmnishockError;
+
+mod event_stream;
+use event_stream::EventStream;
+
+mod fifo_controller;
+use fifo_controller::FifoController;
+
+mod frame_clock;
+use frame_clock::{FrameClock, FrameStep, FrameTime, WallClock};
+
+mod input_queue;
+use input_queue::{FrameStepper, InputQueue};
+
+#[cfg(all(unix, feature = "shm-input"))]
+mod shm_controller;
+#[cfg(all(unix, feature = "shm-input"))]
+use shm_controller::ShmController;
 
-// The DualShock protocol uses 0x5A in many places!
-const DUALSHOCK_MAGIC: u8 = 0x5A;
+mod test_pattern;
 
-// Johnny Chung Lee's firmware responds with "k" on success,
-const SEVEN_BYTE_OK_RESPONSE: char = 'k';
-// and "x" when it recieves input it doesn't recognise.
-const SEVEN_BYTE_ERR_RESPONSE: char = 'x';
+#[cfg(feature = "metrics")]
+mod metrics;
 
-// Aaron Clovsky's firmware responds with vibration information
-// which begins with the DUALSHOCK_MAGIC.
-const TWENTY_BYTE_OK_HEADER: u8 = DUALSHOCK_MAGIC;
+mod paced_writer;
+use paced_writer::PacedWriter;
+
+mod profile;
+use profile::Profile;
+
+mod rumble_log;
+use rumble_log::RumbleLog;
+
+mod snapshot;
+
+mod status_line;
+use status_line::StatusLine;
+
+mod tee_writer;
+use tee_writer::{HexStdout, TeeWriter};
+
+#[cfg(all(target_os = "linux", feature = "uinput"))]
+mod uinput_sink;
+
+use omnishock::packet_delta::{
+    decode_recording, encode_recording, PacketLogFormat, RecordingHeader, TimedPacket,
+};
+use omnishock::protocol::{
+    axis_name, build_packet, button_name, centred_neutral_packet, convert_packet,
+    describe_protocol, neutral_packet, parse_button, AxisRoute, AxisRoutes, ButtonPolarity,
+    Buttons1, Buttons2, CombinedTriggerAxis, ControllerEmulatorPacketType, DPadPressureRoute,
+    DPadPressureRoutes, DPadStick, MappingOptions, ShiftLayer, StickByteRange, StickCentre,
+    StickEncoding, StickMirror, StickShape, TriggerButtons, TriggerMode, TriggerRange,
+    TriggerSource, NEUTRAL_PACKET, SEVEN_BYTE_ERR_RESPONSE, SEVEN_BYTE_OK_RESPONSE,
+    TWENTY_BYTE_OK_HEADER, TWENTY_BYTE_PACKET_LENGTH,
+};
+use omnishock::sdl_manager::GameController;
+use omnishock::sdl_manager::MergedController;
+use omnishock::sdl_manager::SDLManager;
+use omnishock::sdl_manager::{MappedControl, RawInput};
+use omnishock::spike_filter::{FilteredController, SpikeFilter};
 
 // Serial port name hint is different per-OS
 #[cfg(target_os = "macos")]
@@ -61,52 +111,25 @@ const SERIAL_HINT: &str =
 #[cfg(windows)]
 const SERIAL_HINT: &str = "Device to use to communcate.\n(Usually COM3 for USB Serial on Windows.)";
 
-// How many times you need to multiply a u8 converted
-// to u16 by to become a u16 of the same magnitude
-const U8_TO_U16_MAGNITUDE: u16 = u16::max_value() / u8::max_value() as u16;
-
-enum ControllerEmulatorPacketType {
-    None,       // Fallback, just log messages
-    SevenByte,  // For Johnny Chung Lee's firmware
-    TwentyByte, // For Aaron Clovsky's firmware
-}
-
-bitflags! {
-    struct Buttons1: u8 {
-        const LEFT = 0b1000_0000;
-        const DOWN = 0b0100_0000;
-        const RIGHT = 0b0010_0000;
-        const UP = 0b0001_0000;
-        const START = 0b0000_1000;
-        const R3 = 0b0000_0100;
-        const L3 = 0b0000_0010;
-        const SELECT = 0b0000_0001;
-    }
-}
-
-bitflags! {
-    struct Buttons2: u8 {
-        const SQUARE = 0b1000_0000;
-        const CROSS = 0b0100_0000;
-        const CIRCLE = 0b0010_0000;
-        const TRIANGLE = 0b0001_0000;
-        const R1 = 0b0000_1000;
-        const L1 = 0b0000_0100;
-        const R2 = 0b0000_0010;
-        const L2 = 0b0000_0001;
-    }
-}
-
 #[derive(Parser, Debug)]
 #[clap(version)]
 struct CLIArgs {
     /// Print more information about activity
     #[clap(short, long)]
     verbose: bool,
+    /// Don't load the controller mappings built into Omnishock.
+    ///
+    /// Useful if a built-in mapping gets your controller wrong. SDL's own
+    /// mappings, and any in the SDL_GAMECONTROLLERCONFIG environment
+    /// variable, are still used.
+    #[clap(long, global = true)]
+    no_builtin_mappings: bool,
     #[clap(subcommand)]
     subcommand: Subcommands,
 }
 
+// Only ever parsed once, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser, Debug)]
 enum Subcommands {
     /// Start a transliteration session using a PS2 Controller Emulator over Serial
@@ -114,318 +137,1413 @@ enum Subcommands {
     PS2CESubcommand(PS2CESubcommand),
     /// Tests the game controller subsystem
     #[clap(name = "test")]
-    Test,
+    Test(TestSubcommand),
+    /// Prints the byte layout of each packet format
+    #[clap(name = "protocol-info")]
+    ProtocolInfo,
+    /// Presses every button and sweeps every stick in turn, without a controller
+    #[clap(name = "testpattern")]
+    TestPattern(TestPatternSubcommand),
+    /// Sends one neutral packet and reports what the device sends back
+    #[clap(name = "serial-echo-test")]
+    SerialEchoTest(SerialEchoTestSubcommand),
+    /// Sends the packets from a --verbose log or recording again, or plays a file of inputs
+    #[clap(name = "resend-log")]
+    ResendLog(ResendLogSubcommand),
+    /// Re-encodes the "Sent:" lines of a --verbose log in another packet format
+    #[clap(name = "convert")]
+    Convert(ConvertSubcommand),
+    /// Checks profile files for mistakes, without running anything
+    #[clap(name = "validate-profile")]
+    ValidateProfile(ValidateProfileSubcommand),
 }
 
 #[derive(Parser, Debug)]
 #[clap(rename_all = "kebab-case")]
-struct PS2CESubcommand {
+struct ValidateProfileSubcommand {
+    /// Profile files to check
+    #[clap(required = true)]
+    files: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(rename_all = "kebab-case")]
+struct ConvertSubcommand {
+    /// Log file to read packets from
+    input: String,
+    /// File to write the converted packets to
+    output: String,
+
+    /// Which packet format to convert to
+    #[clap(long, possible_values = ControllerEmulatorPacketType::variants())]
+    to: ControllerEmulatorPacketType,
+    /// How to write the converted packets.
+    ///
+    /// "raw" writes "Sent:" lines, like a --verbose log. "delta" writes a
+    /// recording: a header giving the packet format and --rate, then a compact
+    /// binary stream of just the bytes which change each frame, which
+    /// resend-log can read back with --format delta.
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        possible_values = PacketLogFormat::variants(),
+        default_value = "raw"
+    )]
+    format: PacketLogFormat,
+    /// How many packets a second the log was sent at, for timing a recording
+    #[clap(long, value_name = "HZ", default_value = "60")]
+    rate: f64,
+}
+
+#[derive(Parser, Debug)]
+#[clap(rename_all = "kebab-case")]
+struct TestSubcommand {
+    /// Print every SDL event, not just those from game controllers.
+    ///
+    /// Handy for working out focus or hotplug problems which never
+    /// show up as controller events.
+    #[clap(long)]
+    all_events: bool,
+
+    /// Print each raw joystick axis, button and hat as it changes,
+    /// alongside what SDL's mapping makes of it.
+    ///
+    /// For checking a mapping line puts each of the device's inputs
+    /// where it should.
+    #[clap(long)]
+    mapping_debug: bool,
+
+    /// Warn of a button going down twice within this many milliseconds.
+    ///
+    /// Presses that close together are rarely deliberate, and are usually
+    /// a worn switch "chattering". 0 turns the warning off.
+    #[clap(long, value_name = "MS", default_value = "30")]
+    chatter_threshold_ms: u32,
+}
+
+#[derive(Parser, Debug)]
+#[clap(rename_all = "kebab-case")]
+struct ResendLogSubcommand {
+    /// Log file to read packets from
+    logfile: String,
+    // Serial port name hint is different per-OS
+    #[clap(help = SERIAL_HINT)]
+    device: String,
+
+    /// How the log file is laid out: "raw" "Sent:" lines, or a "delta"
+    /// recording as written by convert
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        possible_values = PacketLogFormat::variants(),
+        default_value = "raw"
+    )]
+    format: PacketLogFormat,
+    /// Read the log file as a list of inputs to play exactly, one per frame.
+    ///
+    /// Each line lists the buttons held during that frame, such as
+    /// "cross r1", and any stick positions, such as "lx=0xFF". A blank line
+    /// is a frame with nothing held, and lines starting with "#" are skipped.
+    /// Implies --frame-stepped, for tool-assisted playback.
+    #[clap(long, conflicts_with = "format")]
+    inputs: bool,
+    /// Pace packets by their frame number, rather than by how long the last took.
+    ///
+    /// Every packet goes out exactly once and in order, at its own frame
+    /// even after a stall, so falling behind delays playback rather than
+    /// letting it drift; a warning's given if the machine can't keep up.
+    #[clap(long)]
+    frame_stepped: bool,
+
+    /// How many packets to send per second [default: 60, or the recording's
+    /// own rate]
+    #[clap(long, value_name = "HZ")]
+    rate: Option<f64>,
+    /// Pass the rumble the device asks for on to this controller.
+    ///
+    /// Takes a selector, as for ps2ce's --controller, e.g. "id=0". Lets you
+    /// feel a logged session's rumble as the console plays it back.
+    #[clap(long, value_name = "SELECTOR")]
+    rumble_to: Option<ControllerSelector>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(rename_all = "kebab-case")]
+struct SerialEchoTestSubcommand {
+    // Serial port name hint is different per-OS
+    #[clap(help = SERIAL_HINT)]
+    device: String,
+}
+
+#[derive(Parser, Debug)]
+#[clap(rename_all = "kebab-case")]
+struct TestPatternSubcommand {
     // Serial port name hint is different per-OS
     #[clap(help = SERIAL_HINT)]
     device: String,
 
-    /// How to map the analog triggers
+    /// How long to hold each button, or take to sweep each axis
+    #[clap(long, short, value_name = "MS", default_value = "500")]
+    dwell_ms: u32,
+    /// Start the pattern over again once it's done, until interrupted
+    #[clap(long, short)]
+    repeat: bool,
+    /// Carry on even if the device's firmware isn't recognised.
+    ///
+    /// Normally Omnishock gives up if it can't tell which firmware the device
+    /// is running. With this option it instead carries on without sending
+    /// anything to the device, logging packets when --verbose is given.
+    #[clap(long)]
+    allow_unknown: bool,
+    /// Which protocol to use if the device's firmware isn't recognised.
+    ///
+    /// For firmware which works like one Omnishock knows, but answers
+    /// differently: "seven" or "twenty" carries on with that protocol, and
+    /// "none" is the same as --allow-unknown.
+    #[clap(
+        long,
+        value_name = "MODE",
+        parse(try_from_str = parse_default_mode),
+        possible_values = &["seven", "twenty", "none"],
+        conflicts_with = "allow-unknown"
+    )]
+    default_mode: Option<ControllerEmulatorPacketType>,
+    /// How many more times to try detecting the device's firmware.
+    ///
+    /// Some devices need a moment after connecting before they'll answer.
+    /// Each retry re-sends a neutral packet and checks the response again.
+    #[clap(long, value_name = "N", default_value = "0")]
+    detect_retries: u32,
+}
+
+#[derive(Parser, Debug)]
+#[clap(rename_all = "kebab-case")]
+struct PS2CESubcommand {
+    // Serial port name hint is different per-OS. When it's left out on a
+    // terminal, a menu of the serial devices found is offered instead.
+    #[clap(help = SERIAL_HINT)]
+    device: Option<String>,
+
+    /// How to map the analog triggers [default: normal]
     #[clap(
         possible_values = TriggerMode::variants(),
         ignore_case = true,
         long,
-        short,
-        default_value = "normal"
+        short
     )]
-    trigger_mode: TriggerMode,
+    trigger_mode: Option<TriggerMode>,
     /// Disable stick normalisation.
     ///
-    /// Normally, stick values are multiplied by 1.1, to simulate the prominent
-    /// outer deadzone exhibited by real DualShock 2 controllers. This option
-    /// removes this compensation. May be useful if you're using another
-    /// older-style analog controller.
+    /// Normally, stick values are multiplied by 1.1 (see --range-profile), to
+    /// simulate the prominent outer deadzone exhibited by real DualShock 2
+    /// controllers. This option removes this compensation. May be useful if
+    /// you're using another older-style analog controller.
     #[clap(long, short)]
     no_stick_normalise: bool,
+    /// Turn stick normalisation back on, where a --profile turns it off
+    #[clap(long, conflicts_with = "no-stick-normalise")]
+    stick_normalise: bool,
+    /// How far the analog sticks reach.
+    ///
+    /// Bundles the stick normalisation amount with limits on the values sent
+    /// for each stick. Built in are "dualshock2" (the default: 10%
+    /// normalisation over the full range), "linear" (no normalisation) and
+    /// "inset" (10% normalisation, keeping clear of the very ends of the
+    /// range). More can be defined in a --profile file, as
+    /// "range-profile.<name> = <normalise %>,<min>,<max>". Range profiles
+    /// don't set a deadzone or response curve; --deadzone and --anti-deadzone
+    /// still apply alongside whichever's chosen.
+    #[clap(long, value_name = "NAME")]
+    range_profile: Option<String>,
+    /// Normalise only the left stick by this percentage.
+    ///
+    /// Overrides the --range-profile's normalisation for the left stick, for
+    /// controllers where only one stick has an outer deadzone. 0 turns it off.
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        parse(try_from_str = parse_percentage),
+        conflicts_with = "no-stick-normalise"
+    )]
+    normalise_left: Option<u8>,
+    /// Normalise only the right stick by this percentage.
+    ///
+    /// Like --normalise-left, but for the right stick.
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        parse(try_from_str = parse_percentage),
+        conflicts_with = "no-stick-normalise"
+    )]
+    normalise_right: Option<u8>,
+    /// Read extra settings from this file
+    #[clap(long, value_name = "FILE")]
+    profile: Option<String>,
+    /// Write the mapping options in use, from flags and any --profile, to
+    /// this file as a profile, then carry on as normal.
+    ///
+    /// Loading it again with --profile sets everything up the same way,
+    /// apart from the --shift-profile layer.
+    #[clap(long, value_name = "FILE")]
+    save_profile: Option<String>,
+    /// Switch to the --shift-profile button layout while this button is held.
+    ///
+    /// One of SDL's button names, e.g. "back" or "right-shoulder". The
+    /// button itself is never passed on to the device.
+    #[clap(
+        long,
+        value_name = "BUTTON",
+        parse(try_from_str = parse_button),
+        requires = "shift-profile"
+    )]
+    shift_button: Option<sdl2::controller::Button>,
+    /// Switch between the usual stick settings and --deadzone-profile's with
+    /// each press of this button.
+    ///
+    /// One of SDL's button names, e.g. "back". For a tighter --deadzone in
+    /// menus and a looser one in play, or the other way round. The button
+    /// itself is never passed on to the device.
+    #[clap(
+        long,
+        value_name = "BUTTON",
+        parse(try_from_str = parse_button),
+        requires = "deadzone-profile"
+    )]
+    deadzone_toggle: Option<sdl2::controller::Button>,
+    /// Pause and resume passing on the controller with each press of this button.
+    ///
+    /// One of SDL's button names, e.g. "guide". While paused, a neutral state
+    /// is sent every frame, so the adapter keeps in sync with the console
+    /// but the game sees nothing pressed. The button itself is never passed
+    /// on to the device.
+    #[clap(long, value_name = "BUTTON", parse(try_from_str = parse_button))]
+    pause_button: Option<sdl2::controller::Button>,
+    /// Read the stick settings to switch to with --deadzone-toggle from this file.
+    ///
+    /// Only the file's "stick-normalise", "range", "stick-shape", "deadzone",
+    /// "anti-deadzone", "normalise-left", "normalise-right", "clamp-radial"
+    /// and "snap-cardinal" settings are used; everything else stays as it was.
+    #[clap(long, value_name = "FILE", requires = "deadzone-toggle")]
+    deadzone_profile: Option<String>,
+    /// Read the button routes to use while --shift-button is held from this file.
+    ///
+    /// Only the file's "button.<output> = <input>" settings are used.
+    #[clap(long, value_name = "FILE", requires = "shift-button")]
+    shift_profile: Option<String>,
+    /// Which controller to use.
+    ///
+    /// One of "id=<id>" (the default is "id=0"), "name=<part of name>",
+    /// "vidpid=<vendor>:<product>", with USB ids in hexadecimal, e.g.
+    /// "vidpid=054C:09CC", or "guid=<SDL GUID>". If more than one controller
+    /// matches, the one with the lowest id is used. Without this, the
+    /// selector in the OMNISHOCK_CONTROLLER environment variable is used, if
+    /// it's set.
+    #[clap(long, value_name = "SELECTOR")]
+    controller: Option<ControllerSelector>,
+    /// Merge two controllers into one virtual pad.
+    ///
+    /// Takes two controller ids, separated by a comma (e.g. "0,1"). Buttons
+    /// pressed on either controller are pressed, and each axis takes
+    /// whichever value is furthest from centre. Rumble is sent to both.
+    #[clap(long, value_name = "ID,ID")]
+    merge: Option<ControllerPair>,
+    /// Read the controller's state from this named pipe instead of SDL.
+    ///
+    /// Each line written to the pipe is a frame: the buttons held (named as
+    /// for --shift-button) and any axes away from rest as "<axis>=<value>",
+    /// e.g. "a dpad-up left-x=-32768". The last frame holds until the next.
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["merge", "controller"])]
+    input_fifo: Option<String>,
+    /// Read the controller's state from this POSIX shared memory object instead of SDL.
+    ///
+    /// For another program on the same machine to drive the console with as
+    /// little latency as possible. The layout it must write is described in
+    /// the README. The last state written holds until the next.
+    #[cfg(all(unix, feature = "shm-input"))]
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = &[
+            "merge",
+            "controller",
+            "input-fifo",
+            "rotate-every",
+            "sticky",
+            "blocking"
+        ]
+    )]
+    input_shm: Option<String>,
+    /// Hand the adapter to the next connected controller every so many seconds.
+    ///
+    /// For taking turns at a shared setup. Controllers go in id order,
+    /// starting from the --controller one, and any connected part way
+    /// round get their turn too. If the controller whose turn it is
+    /// disconnects, the next one takes over straight away.
+    #[clap(long, value_name = "SECONDS", conflicts_with_all = &["merge", "input-fifo"])]
+    rotate_every: Option<f64>,
+    /// Stick with the first controller chosen, by its GUID.
+    ///
+    /// Once a controller's been chosen, only a controller with the same SDL
+    /// GUID is used from then on; if it disconnects, neutral is sent until
+    /// it comes back. Controllers of the same model share a GUID, so another
+    /// of the same model can still take its place.
+    #[clap(long, conflicts_with_all = &["merge", "input-fifo", "rotate-every"])]
+    sticky: bool,
+    /// Ignore any axis which jumps by more than this in a single frame.
+    ///
+    /// For wireless controllers which now and then report one wild value.
+    /// The axis holds its last value until the jump has lasted for
+    /// --spike-frames frames in a row. Out of 65535; off by default.
+    #[clap(long, value_name = "DELTA")]
+    spike_filter: Option<u16>,
+    /// How many frames in a row an axis must jump by for --spike-filter to believe it
+    #[clap(long, value_name = "N", default_value = "3", requires = "spike-filter")]
+    spike_frames: u32,
+    /// Keep sending the last packet while the controller drops out.
+    ///
+    /// If the controller disappears for less than this many milliseconds (as
+    /// wireless controllers sometimes do), its last state is repeated rather
+    /// than released. After that, a neutral packet is sent.
+    #[clap(long, value_name = "MS", default_value = "0")]
+    hold_last_ms: u32,
+    /// While no controller is connected, send a neutral packet every N frames.
+    ///
+    /// Keeps the device in step while waiting for a controller. Defaults to
+    /// every 30 frames (half a second) for the twenty-byte firmware, which is
+    /// known to drift when left alone, and to never for anything else. 0
+    /// turns it off.
+    #[clap(long, value_name = "N")]
+    idle_heartbeat_frames: Option<u32>,
+    /// Send packets to the device at most this many times a second.
+    ///
+    /// Controllers are still polled 60 times a second, so hotplugging and
+    /// latency aren't affected; each send just carries the most recent
+    /// packet. For firmware which can't keep up with a packet every frame.
+    #[clap(long, value_name = "HZ")]
+    send_rate: Option<f64>,
+    /// Sleep until the controller changes, rather than sending 60 times a second.
+    ///
+    /// Uses much less CPU, for battery-powered or embedded hosts. A packet is
+    /// still sent at least once a second, but anything timed (scheduled
+    /// presses, soft presses, rumble limits) only moves on as events arrive.
+    #[clap(long, conflicts_with = "input-fifo")]
+    blocking: bool,
+    /// How each frame follows the last.
+    ///
+    /// With "fixed" (the default), a frame starts every sixtieth of a second,
+    /// like a real pad being polled, so timing is steady. With "variable",
+    /// each frame starts as soon as the last one's sent, so input reaches
+    /// the device with the least delay, but unevenly, and a whole CPU core
+    /// is kept busy unless --blocking is given too. Anything counted in
+    /// frames, like --turbo, runs faster with it.
+    #[clap(
+        long,
+        possible_values = FrameStep::variants(),
+        ignore_case = true,
+        default_value = "fixed"
+    )]
+    step: FrameStep,
+    /// Only send neutral packets, printing the rumble each response asks for.
+    ///
+    /// The controller is still read (so --quit-combo works), but never sent
+    /// on to the device. Useful for checking the device's rumble feedback on
+    /// its own, separately from how controller input is encoded.
+    #[clap(long)]
+    poll_only: bool,
+    /// Expect the device to echo each packet back, and check it did so exactly.
+    ///
+    /// For firmware which echoes its input rather than replying with rumble.
+    /// Any difference between the packet sent and the echo is printed (and
+    /// added to any --anomaly-log), to catch corruption on the serial link.
+    #[clap(long, conflicts_with = "poll-only")]
+    verify_echo: bool,
+    /// Add a diagnostic byte to the end of every packet sent.
+    ///
+    /// Its low four bits count frames, going back round to 0 after 15, and
+    /// its high four bits are status: 0x10 when frames are running slow, and
+    /// 0x20 when there's no controller. Only for firmware which is known to
+    /// cope with the extra byte, such as by logging it; the firmware listed
+    /// in the README would take it as the start of the next packet.
+    #[clap(long)]
+    diag_byte: bool,
+    /// Show which buttons are pressed, and where the sticks are.
+    ///
+    /// On a terminal, a coloured status line is redrawn every frame. When
+    /// output isn't going to a terminal, a plain line is printed once a
+    /// second instead.
+    #[clap(long)]
+    status_line: bool,
+    /// Write each button and axis change sent to the device to this file.
+    ///
+    /// One event per line, e.g. "press cross", "release cross", "axis left-x
+    /// 255" or "pressure cross 128", for other programs to follow along
+    /// without knowing the DualShock protocol. Given "-", events go to
+    /// standard output. A named pipe works too, once something's reading it.
+    #[clap(long, value_name = "PATH")]
+    emit_events: Option<String>,
+    /// Save a picture of what's being sent whenever this button is pressed.
+    ///
+    /// One of SDL's button names, e.g. "back". Each press writes an SVG of
+    /// the packet, with the pressed buttons lit up and where the sticks
+    /// are, to a file in the current directory named for when it was
+    /// taken. The button is still passed on to the device as usual.
+    #[clap(long, value_name = "BUTTON", parse(try_from_str = parse_button))]
+    snapshot_button: Option<sdl2::controller::Button>,
+    /// Briefly rumble the controller driving the device.
+    ///
+    /// The pulse plays when the session starts, and again whenever a
+    /// different controller takes over, to confirm which one is in use.
+    #[clap(long)]
+    confirm_rumble: bool,
+    /// Read an axis from a different controller axis.
+    ///
+    /// Takes the form "<output>=<input>", where both are one of "left-x",
+    /// "left-y", "right-x", "right-y", "trigger-left" or "trigger-right".
+    /// For instance, "right-y=trigger-left" drives the right stick's Y axis
+    /// from the left trigger. May be given more than once, but each output
+    /// may only be routed once.
+    #[clap(long, value_name = "OUTPUT=INPUT", multiple_occurrences = true)]
+    route_axis: Vec<AxisRoute>,
+    /// Take a D-Pad direction's pressure from an analog axis.
+    ///
+    /// Takes the form "<direction>=<axis>", where the direction is one of
+    /// "up", "down", "left" or "right", and the axis is one of the axes
+    /// `--route-axis` accepts. Stick axes are suffixed with "+" or "-" to
+    /// choose a direction of travel. For instance, "right=left-x+" presses
+    /// right harder the further the left stick is pushed right. Without this,
+    /// the D-Pad is only ever fully pressed or released.
+    #[clap(long, value_name = "DIRECTION=AXIS", multiple_occurrences = true)]
+    dpad_pressure: Vec<DPadPressureRoute>,
+    /// Report analog trigger pressure in cross-and-square trigger mode.
+    ///
+    /// Normally the cross-and-square mode turns L2 and R2 into plain buttons.
+    /// With this option, the L2 and R2 pressure values still follow the
+    /// triggers, for games which read analog pressure.
+    #[clap(long)]
+    trigger_pressure: bool,
+    /// Don't report analog trigger pressure, where a --profile does
+    #[clap(long, conflicts_with = "trigger-pressure")]
+    no_trigger_pressure: bool,
+    /// Only use part of the analog triggers' travel.
+    ///
+    /// Takes the form "<min>,<max>", out of 0 to 32767. Trigger values
+    /// between these are stretched to cover all of L2 and R2's range, so
+    /// for instance "0,16000" gives full L2 and R2 half way down.
+    #[clap(long, value_name = "MIN,MAX")]
+    trigger_range: Option<TriggerRange>,
+    /// Where L2 and R2 pressure comes from.
+    ///
+    /// Some controllers have a digital button alongside each analog
+    /// trigger, named with --trigger-buttons. "axis" (the default) uses just
+    /// the triggers, "button" just those buttons, and "max" whichever of the
+    /// button and trigger is pressed further, so a fully pressed button
+    /// always gives full L2 or R2 even if the trigger lags behind.
+    #[clap(
+        long,
+        possible_values = TriggerSource::variants(),
+        ignore_case = true
+    )]
+    trigger_source: Option<TriggerSource>,
+    /// The controller buttons which are the triggers' digital halves,
+    /// such as "left-stick,right-stick".
+    ///
+    /// Needed for the "button" and "max" trigger sources. These buttons
+    /// no longer press anything of their own while in use.
+    #[clap(
+        long,
+        value_name = "LEFT,RIGHT",
+        required_if_eq_any = &[("trigger-source", "button"), ("trigger-source", "max")]
+    )]
+    trigger_buttons: Option<TriggerButtons>,
+    /// Combine both analog triggers onto one stick axis.
+    ///
+    /// The right trigger pushes the axis up and the left one pulls it
+    /// down, like pedals. L2 and R2 still follow the trigger mode.
+    #[clap(long, possible_values = CombinedTriggerAxis::variants(), ignore_case = true)]
+    combine_triggers_to: Option<CombinedTriggerAxis>,
+    /// Copy one analog stick onto the other
+    #[clap(long, possible_values = StickMirror::variants(), ignore_case = true)]
+    mirror_stick: Option<StickMirror>,
+    /// Move the left analog stick with the D-Pad, for controllers without one.
+    ///
+    /// The stick is pushed all the way in each pressed direction, and
+    /// diagonals are kept the same distance out. The D-Pad buttons
+    /// themselves aren't sent, unless --keep-dpad is given too.
+    #[clap(long)]
+    dpad_as_lstick: bool,
+    /// Keep sending the D-Pad buttons along with --dpad-as-lstick
+    #[clap(long, requires = "dpad-as-lstick")]
+    keep_dpad: bool,
+    /// Leave the left analog stick alone, where a --profile moves it with the D-Pad
+    #[clap(long, conflicts_with = "dpad-as-lstick")]
+    no_dpad_as_lstick: bool,
+    /// The shape the analog sticks are kept within.
+    ///
+    /// With "square" (the default), each axis is limited separately, so
+    /// sticks can reach all the way into the corners. With "circle", the
+    /// distance from centre is limited instead, as with a round stick gate.
+    #[clap(
+        long,
+        possible_values = StickShape::variants(),
+        ignore_case = true
+    )]
+    stick_shape: Option<StickShape>,
+    /// The byte sent for an analog stick at rest.
+    ///
+    /// The DualShock®2 uses 0x80 (the default), but some firmware expects
+    /// another value, such as 0x7F. Sticks are stretched to still reach
+    /// 0x00 and 0xFF.
+    #[clap(long, value_name = "BYTE")]
+    stick_center: Option<StickCentre>,
+    /// Keep stick bytes within "<min>,<max>", such as "0x01,0xFE".
+    ///
+    /// For firmware which takes the very ends of a stick's range to mean
+    /// something else. Replaces the --range-profile's limits; pressure
+    /// bytes aren't affected.
+    #[clap(long, value_name = "MIN,MAX")]
+    clamp_axis_bytes: Option<StickByteRange>,
+    /// How stick values are turned into bytes.
+    ///
+    /// "signed-centered" (the default) is what most firmware expects.
+    /// "unsigned" scales each axis straight onto 0 to 255, with 128 at
+    /// rest whatever --stick-center is, for firmware forks which want that.
+    #[clap(
+        long,
+        possible_values = StickEncoding::variants(),
+        ignore_case = true
+    )]
+    stick_encoding: Option<StickEncoding>,
+    /// Treat each stick as centred until it's moved this far off centre.
+    ///
+    /// Out of 32767, and 0 (off) by default. For worn sticks which don't
+    /// quite come back to rest; the rest of the stick's travel is scaled
+    /// so it still starts from nothing and reaches full deflection.
+    #[clap(long, value_name = "0-32767", parse(try_from_str = parse_stick_radius))]
+    deadzone: Option<i16>,
+    /// The least each stick axis is sent as once it's moved off centre.
+    ///
+    /// Out of 32767, and 0 by default. For games with an inner deadzone of
+    /// their own, so even slight movements register; the rest of the
+    /// stick's travel is scaled to fit between this and full deflection.
+    #[clap(long, value_name = "0-32767")]
+    anti_deadzone: Option<u16>,
+    /// Keep each stick within this distance of centre, after normalisation.
+    ///
+    /// Out of 32767. Normalising stretches diagonals past where a real
+    /// stick can reach; this pulls them back onto a circle, for games
+    /// which don't expect that.
+    #[clap(long, value_name = "0-32767", parse(try_from_str = parse_stick_radius))]
+    clamp_radial: Option<i16>,
+    /// Snap each stick onto any of the eight directions it's within this many degrees of.
+    ///
+    /// Up to 22.5, and 0 (off) by default. Applied after normalisation, for
+    /// steadier eight-way movement: a stick pushed nearly straight up is
+    /// sent as pushed straight up, just as far.
+    #[clap(long, value_name = "DEGREES", parse(try_from_str = parse_snap_angle))]
+    snap_cardinal: Option<f32>,
+    /// Accept commands on standard input to change options while running.
+    ///
+    /// Type "help" once running to see which commands are available.
+    #[clap(long)]
+    interactive: bool,
+    /// Quit when these buttons are held together, e.g. "start+select+l1+r1".
+    ///
+    /// Handy when running without a window to close. Buttons are named as
+    /// for --at, and must be held for half a second (see --quit-combo-hold-ms).
+    /// A short rumble confirms the combo is being held.
+    #[clap(long, value_name = "BUTTON+BUTTON...")]
+    quit_combo: Option<ButtonCombo>,
+    /// How long --quit-combo must be held for
+    #[clap(long, value_name = "MS", default_value = "500")]
+    quit_combo_hold_ms: u32,
+    /// Restart the frame rate average when these buttons are pressed together.
+    ///
+    /// Handy for measuring the effect of a change after a rough patch,
+    /// without restarting. Buttons are named as for --at; holding them only
+    /// resets the average once.
+    #[clap(long, value_name = "BUTTON+BUTTON...")]
+    reset_stats_button: Option<ButtonCombo>,
+    /// How long --reset-stats-button must be held for
+    #[clap(long, value_name = "MS", default_value = "0")]
+    reset_stats_hold_ms: u32,
+    /// Ramp face button pressure up over this many milliseconds.
+    ///
+    /// Face buttons are normally either not pressed, or fully pressed. With
+    /// this option, a freshly pressed face button's pressure rises gradually
+    /// instead, for games which react to how hard they're pressed.
+    #[clap(long, value_name = "MS", default_value = "0")]
+    soft_press_ms: u32,
+    /// Toggle the analog mode footer with a gesture, rather than holding Guide.
+    ///
+    /// With "double-l3", double-tapping L3 flips the mode footer between its
+    /// normal value and the one sent while Guide is held.
+    #[clap(long, possible_values = AnalogGesture::variants(), ignore_case = true)]
+    analog_gesture: Option<AnalogGesture>,
+    /// The least time between changes of the analog mode footer.
+    ///
+    /// Some firmware dislikes the footer flipping back and forth quickly,
+    /// as it does when Guide is only tapped; with this, it changes at most
+    /// once in this many milliseconds. 0 lets it change every frame.
+    #[clap(long, value_name = "MS", default_value = "100")]
+    mode_cooldown_ms: u32,
+    /// Limit how much of the time the controller's motors may run.
+    ///
+    /// Takes a fraction from 0.0 to 1.0. Once the motors have used up their
+    /// share of the last few seconds, they're turned off until they've rested
+    /// long enough, however much rumble the console asks for. Protects cheap
+    /// controllers from overheating and flat batteries.
+    #[clap(long, value_name = "FRACTION")]
+    rumble_max_duty: Option<RumbleDuty>,
+    /// Bend the strength of rumble passed on to the controller.
+    ///
+    /// Each motor's intensity, from 0 to 1, is raised to this power: above
+    /// 1.0 makes gentle rumble gentler, for controllers with strong motors,
+    /// and below 1.0 makes it stronger, for weak ones. 1.0 by default, which
+    /// changes nothing. A profile can set a curve for each controller, too.
+    #[clap(long, value_name = "GAMMA", parse(try_from_str = parse_rumble_curve))]
+    rumble_curve: Option<f32>,
+    /// Record the rumble the device asks for each frame to this CSV file.
+    ///
+    /// Each line has the seconds since starting, then the small and large
+    /// motors' intensities from 0 to 255, for graphing a game's rumble.
+    /// Only twenty-byte firmware says anything about rumble.
+    #[clap(long, value_name = "FILE")]
+    rumble_log: Option<String>,
+    /// Record anything odd which happens during the session to this file.
+    ///
+    /// Short or unexpected responses from the device, device errors,
+    /// controller reconnects and slow frames are all written, with
+    /// timestamps, for looking over later.
+    #[clap(long, value_name = "FILE")]
+    anomaly_log: Option<String>,
+    /// Get your attention when frames run badly slow.
+    ///
+    /// With "bell", the terminal bell rings; with "rumble", the controller
+    /// in use gives a short pulse. Either happens at most once every few
+    /// seconds, however long things stay slow.
+    #[clap(
+        long,
+        value_name = "ALERT",
+        possible_values = SlowAlert::variants(),
+        ignore_case = true,
+        default_value = "none"
+    )]
+    alert_on_slow: SlowAlert,
+    /// Serve Prometheus-style metrics over HTTP on this port
+    #[cfg(feature = "metrics")]
+    #[clap(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    /// Which address to serve --metrics-port's metrics on.
+    ///
+    /// Only this machine can reach them by default. "0.0.0.0" serves them
+    /// to the whole network, e.g. for a Prometheus server elsewhere.
+    #[cfg(feature = "metrics")]
+    #[clap(
+        long,
+        value_name = "ADDRESS",
+        default_value = "127.0.0.1",
+        requires = "metrics-port"
+    )]
+    metrics_addr: std::net::IpAddr,
+    /// Carry on even if the device's firmware isn't recognised.
+    ///
+    /// Normally Omnishock gives up if it can't tell which firmware the device
+    /// is running. With this option it instead carries on without sending
+    /// anything to the device, logging packets when --verbose is given.
+    #[clap(long)]
+    allow_unknown: bool,
+    /// Which protocol to use if the device's firmware isn't recognised.
+    ///
+    /// For firmware which works like one Omnishock knows, but answers
+    /// differently: "seven" or "twenty" carries on with that protocol, and
+    /// "none" is the same as --allow-unknown.
+    #[clap(
+        long,
+        value_name = "MODE",
+        parse(try_from_str = parse_default_mode),
+        possible_values = &["seven", "twenty", "none"],
+        conflicts_with = "allow-unknown"
+    )]
+    default_mode: Option<ControllerEmulatorPacketType>,
+    /// How many more times to try detecting the device's firmware.
+    ///
+    /// Some devices need a moment after connecting before they'll answer.
+    /// Each retry re-sends a neutral packet and checks the response again.
+    #[clap(long, value_name = "N", default_value = "0")]
+    detect_retries: u32,
+    /// Ask the device which firmware it's running before detecting it.
+    ///
+    /// Experimental. Sends a single "?" byte, and prints any text the device
+    /// answers with. None of the firmware Omnishock knows answers it, so only
+    /// use this with firmware known to support it.
+    #[clap(long)]
+    query_firmware: bool,
+    /// How long to wait after opening the device before talking to it.
+    ///
+    /// Some adapters reset when their serial port is opened, and ignore
+    /// anything sent before they've finished starting up, so detection
+    /// fails. Boards which take longer than the default may need more.
+    /// There's no wait for a uinput or file descriptor device.
+    #[clap(long, value_name = "MS", default_value = "250")]
+    startup_delay_ms: u64,
+    /// Pause between chunks of each packet written to the device.
+    ///
+    /// Some firmware can't cope with a whole packet arriving at once. When
+    /// this is non-zero, packets are written --write-chunk-size bytes at a
+    /// time, pausing this many microseconds between each chunk.
+    #[clap(long, value_name = "US", default_value = "0")]
+    write_delay_us: u64,
+    /// How many bytes to write at a time when --write-delay-us is set
+    #[clap(long, value_name = "BYTES", default_value = "1")]
+    write_chunk_size: usize,
+    /// Also copy everything written to the device somewhere else.
+    ///
+    /// Given "-", each write is printed to standard output as hex; anything
+    /// else is a file which the raw bytes are written to. Only the device
+    /// itself is read from. May be given more than once.
+    #[clap(long, value_name = "PATH", multiple_occurrences = true)]
+    tee: Vec<String>,
+    /// Press a button at a set time into the session.
+    ///
+    /// Takes the form "<time>=<button>", e.g. "12.5s=cross". The button is
+    /// held for a tenth of a second, on top of whatever the controller is
+    /// doing. Buttons are named as on a DualShock: "cross", "circle",
+    /// "square", "triangle", "l1", "r1", "l2", "r2", "l3", "r3", "start",
+    /// "select", "up", "down", "left" and "right". May be given more than once.
+    #[clap(long, value_name = "TIME=BUTTON", multiple_occurrences = true)]
+    at: Vec<ScheduledPress>,
+    /// Pulse a button on and off while it's held, like a turbo controller.
+    ///
+    /// Takes the form "<button>=<on>:<off>", e.g. "cross=2:1" to hold Cross
+    /// down for two frames then let go for one; "<button>" on its own is
+    /// "<button>=1:1". The pattern runs off the frame count, so every turbo
+    /// button keeps in step. Buttons are named as for --at, which still
+    /// presses through any turbo. May be given more than once.
+    #[clap(long, value_name = "BUTTON=ON:OFF", multiple_occurrences = true)]
+    turbo: Vec<Turbo>,
+    /// Send a button's bit the other way up, for unusual firmware.
+    ///
+    /// The DualShock protocol sends a 0 bit for a pressed button, and a 1 for
+    /// a released one. Buttons given here (named as for --at) are sent the
+    /// other way around. May be given more than once.
+    #[clap(
+        long,
+        value_name = "BUTTON",
+        multiple_occurrences = true,
+        parse(try_from_str = parse_packet_button)
+    )]
+    invert_button: Vec<&'static str>,
 }
 
-#[derive(Debug)]
-enum TriggerMode {
-    Normal,
-    RightStick,
-    CrossAndSquare,
+#[derive(Debug, PartialEq)]
+struct ControllerPair(u32, u32);
+
+impl FromStr for ControllerPair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ids = s
+            .split(',')
+            .map(|id| id.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|error| format!("Unexpected controller id: {}", error))?;
+
+        match ids.as_slice() {
+            [first, second] => Ok(ControllerPair(*first, *second)),
+            _ => Err("Expected exactly two controller ids".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RumbleDuty(f64);
+
+impl FromStr for RumbleDuty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse::<f64>() {
+            Ok(duty) if (0.0..=1.0).contains(&duty) => Ok(RumbleDuty(duty)),
+            Ok(_) => Err("Expected a duty cycle between 0.0 and 1.0".to_string()),
+            Err(error) => Err(format!("Unexpected duty cycle: {}", error)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SlowAlert {
+    None,
+    Bell,
+    Rumble,
 }
 
-impl TriggerMode {
+impl SlowAlert {
     fn variants() -> [&'static str; 3] {
-        ["normal", "right-stick", "cross-and-square"]
+        ["none", "bell", "rumble"]
     }
 }
 
-impl FromStr for TriggerMode {
+impl FromStr for SlowAlert {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "normal" => Ok(TriggerMode::Normal),
-            "right-stick" => Ok(TriggerMode::RightStick),
-            "cross-and-square" => Ok(TriggerMode::CrossAndSquare),
-            _ => Err("Unexpected trigger mode type".to_string()),
+            "none" => Ok(SlowAlert::None),
+            "bell" => Ok(SlowAlert::Bell),
+            "rumble" => Ok(SlowAlert::Rumble),
+            _ => Err("Unexpected slow frame alert type".to_string()),
         }
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::start("Parse Arguments");
+// The shortest time, in seconds, between alerts of slow frames
+const SLOW_ALERT_INTERVAL: f64 = 5.0;
 
-    let arguments = CLIArgs::parse();
+// Keeps slow frame alerts from going off more than once an interval
+#[derive(Default)]
+struct SlowAlertLimiter {
+    // When the last alert went off, in seconds
+    last_alert: Option<f64>,
+}
 
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::end("Parse Arguments");
+impl SlowAlertLimiter {
+    // Whether an alert may go off at `now`, counting it as having done so
+    fn due(&mut self, now: f64) -> bool {
+        match self.last_alert {
+            Some(last_alert) if now - last_alert < SLOW_ALERT_INTERVAL => false,
+            _ => {
+                self.last_alert = Some(now);
+                true
+            }
+        }
+    }
+}
 
-    let mut sdl_manager = SDLManager::init()?;
+// The longest --blocking waits for an event before sending anyway, in ms
+const BLOCKING_WAIT_MS: u32 = 1000;
 
-    println!(
-        "(There are {} controllers connected)",
-        sdl_manager.active_controllers.len()
-    );
+// How often, in ms, `test` looks for Ctrl-C while waiting for events
+const INTERRUPT_CHECK_MS: u32 = 100;
 
-    match arguments.subcommand {
-        Subcommands::PS2CESubcommand(_) => {
-            send_to_ps2_controller_emulator(&arguments, &mut sdl_manager)?;
-        }
-        Subcommands::Test => {
-            print_events(&arguments, &mut sdl_manager)?;
-        }
-    }
+// Set once Ctrl-C's been pressed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::dump_html(&mut File::create("flame-graph.html")?)?;
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::dump_json(&mut File::create("flame-graph.json")?)?;
+extern "C" fn note_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // SAFETY: `signal` is async-signal-safe. A second Ctrl-C ends us outright.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
 
-    Ok(())
+// SDL reports Ctrl-C as an ordinary quit, the same as closing its window.
+// Catching it first (SDL leaves handlers which are already set alone)
+// lets us tell the two apart, while still letting go of everything.
+fn catch_interrupts() {
+    // SAFETY: The handler only stores to an atomic, and resets itself
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = note_interrupt;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
 }
 
-fn whats_the_midpoint_of_a<T: num::Bounded + Add<Output = T> + Div<Output = T> + From<u8>>() -> T {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("whats_the_midpoint_of_a()");
-    (T::max_value() + T::min_value()) / T::from(2)
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
 }
 
-fn convert_button_to_analog<T: num::Bounded>(button: bool) -> T {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("convert_button_to_analog()");
-    if button {
-        T::max_value()
-    } else {
-        T::min_value()
+// How long, in seconds, each scheduled press holds its button down
+const SCHEDULED_PRESS_LENGTH: f64 = 0.1;
+
+// Each DualShock button: its name, which packet byte holds it, its bit
+// within that byte, and which byte holds its pressure, if it has one
+const PACKET_BUTTONS: [(&str, usize, u8, Option<usize>); 16] = [
+    ("left", 1, Buttons1::LEFT.bits(), Some(8)),
+    ("down", 1, Buttons1::DOWN.bits(), Some(10)),
+    ("right", 1, Buttons1::RIGHT.bits(), Some(7)),
+    ("up", 1, Buttons1::UP.bits(), Some(9)),
+    ("start", 1, Buttons1::START.bits(), None),
+    ("r3", 1, Buttons1::R3.bits(), None),
+    ("l3", 1, Buttons1::L3.bits(), None),
+    ("select", 1, Buttons1::SELECT.bits(), None),
+    ("square", 2, Buttons2::SQUARE.bits(), Some(14)),
+    ("cross", 2, Buttons2::CROSS.bits(), Some(13)),
+    ("circle", 2, Buttons2::CIRCLE.bits(), Some(12)),
+    ("triangle", 2, Buttons2::TRIANGLE.bits(), Some(11)),
+    ("r1", 2, Buttons2::R1.bits(), Some(16)),
+    ("l1", 2, Buttons2::L1.bits(), Some(15)),
+    ("r2", 2, Buttons2::R2.bits(), Some(18)),
+    ("l2", 2, Buttons2::L2.bits(), Some(17)),
+];
+
+// Gathers the named buttons' bits, to be sent inverted
+fn button_polarity(inverted: &[&str]) -> ButtonPolarity {
+    let mut polarity = ButtonPolarity::default();
+
+    for (name, byte, bits, _) in PACKET_BUTTONS.iter() {
+        if !inverted.contains(name) {
+            continue;
+        }
+
+        match byte {
+            1 => polarity.inverted1 |= Buttons1::from_bits_truncate(*bits),
+            _ => polarity.inverted2 |= Buttons2::from_bits_truncate(*bits),
+        }
     }
+
+    polarity
 }
 
-fn convert_analog_to_button<
-    T: num::Bounded + Add<Output = T> + Div<Output = T> + From<u8> + PartialOrd,
->(
-    analog: T,
-) -> bool {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("convert_analog_to_button()");
+// Finds the name of a button in `PACKET_BUTTONS`, ignoring case and spacing
+fn parse_packet_button(name: &str) -> Result<&'static str, String> {
+    let name = name.trim().to_lowercase();
 
-    analog > whats_the_midpoint_of_a::<T>()
+    PACKET_BUTTONS
+        .iter()
+        .find(|(button, ..)| *button == name)
+        .map(|(button, ..)| *button)
+        .ok_or_else(|| format!("Unexpected button name '{}'", name))
 }
 
-fn convert_for_dualshock(number: i16) -> u8 {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("convert_for_dualshock()");
-    (number.wrapping_shr(8) + 0x80) as u8
+#[derive(Clone, Debug, PartialEq)]
+struct ScheduledPress {
+    // Seconds since the session started
+    at: f64,
+    button: &'static str,
 }
 
-fn convert_half_axis_positive<
-    T: num::Bounded + num::Saturating + Copy + Div<Output = T> + PartialEq + From<u8>,
->(
-    stick: T,
-) -> T {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("convert_half_axis_positive()");
-    // Special case the maximum values, so we don't end up with
-    if stick == T::max_value() {
-        return T::max_value();
-    }
-
-    let two_in_target_type = T::from(2);
-    let half_minimum = T::min_value().div(two_in_target_type);
-    let normalised_stick = stick.saturating_add(half_minimum);
-
-    // This is a weird way to multiply by two but it works eh
-    normalised_stick.saturating_add(normalised_stick)
-}
-
-fn convert_half_axis_negative<
-    T: num::Bounded
-        + num::Saturating
-        + Copy
-        + Neg<Output = T>
-        + Div<Output = T>
-        + PartialEq
-        + From<u8>,
->(
-    stick: T,
-) -> T {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("convert_half_axis_negative()");
-    convert_half_axis_positive(stick.saturating_add(T::from(1)).neg())
-}
+impl FromStr for ScheduledPress {
+    type Err = String;
 
-fn normalise_stick_as_dualshock2(x: &mut i16, y: &mut i16) {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("normalise_stick_as_dualshock2()");
-    // Adjust stick positions to match those of the DualShock®2.
-    // The DualShock®2 has a prominent outer deadzone,
-    // so we shrink the usable area here by 10%.
-    *x = x.saturating_add(*x / 10);
-    *y = y.saturating_add(*y / 10);
-}
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (time, button) = s
+            .split_once('=')
+            .ok_or_else(|| "Expected a scheduled press in the form <time>=<button>".to_string())?;
+
+        let time = time.trim();
+        let at = time
+            .strip_suffix('s')
+            .unwrap_or(time)
+            .parse::<f64>()
+            .map_err(|error| format!("Unexpected time '{}': {}", time, error))?;
+
+        if !at.is_finite() || at < 0.0 {
+            return Err(format!("Unexpected time '{}'", time));
+        }
 
-fn controller_map_seven_byte<T: GameController>(
-    controller: &T,
-    trigger_mode: &TriggerMode,
-    normalise_sticks: bool,
-) -> Vec<u8> {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("controller_map_seven_byte()");
-    // Seven byte controller map is the same as
-    // the first seven bytes of the twenty-byte map!
-    let mut map = controller_map_twenty_byte(controller, trigger_mode, normalise_sticks);
-    map.truncate(7);
-    map
+        Ok(ScheduledPress {
+            at,
+            button: parse_packet_button(button)?,
+        })
+    }
 }
 
-fn controller_map_twenty_byte<T: GameController>(
-    controller: &T,
-    trigger_mode: &TriggerMode,
-    normalise_sticks: bool,
-) -> Vec<u8> {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("controller_map_twenty_byte()");
-    use sdl2::controller::{Axis, Button};
+#[derive(Clone, Debug, PartialEq)]
+struct ButtonCombo(Vec<&'static str>);
 
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::start("buttons1");
-    // buttons1
-    let dpad_left_value: i16 = convert_button_to_analog(controller.button(Button::DPadLeft));
-    let dpad_down_value: i16 = convert_button_to_analog(controller.button(Button::DPadDown));
-    let dpad_right_value: i16 = convert_button_to_analog(controller.button(Button::DPadRight));
-    let dpad_up_value: i16 = convert_button_to_analog(controller.button(Button::DPadUp));
-    let start_value: i16 = convert_button_to_analog(controller.button(Button::Start));
-    let right_stick_value: i16 = convert_button_to_analog(controller.button(Button::RightStick));
-    let left_stick_value: i16 = convert_button_to_analog(controller.button(Button::LeftStick));
-    let select_value: i16 = convert_button_to_analog(controller.button(Button::Back));
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::end("buttons1");
+impl FromStr for ButtonCombo {
+    type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let buttons = s
+            .split('+')
+            .map(parse_packet_button)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ButtonCombo(buttons))
+    }
+}
+
+impl ButtonCombo {
+    // Whether every button in the combo is pressed in `packet`
+    fn is_held(&self, packet: &[u8]) -> bool {
+        self.0.iter().all(|button| {
+            PACKET_BUTTONS
+                .iter()
+                .filter(|(name, ..)| name == button)
+                // The DualShock protocol considers 0 to mean pressed
+                .all(|(_, byte, bits, _)| packet[*byte] & bits == 0)
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ComboEvent<A> {
+    // A combo which needs holding has just been pressed
+    Started(A),
+    // A combo has been held for long enough
+    Fired(A),
+}
+
+struct WatchedCombo<A> {
+    combo: ButtonCombo,
+    // Seconds the combo must be held before it fires
+    hold: f64,
+    action: A,
+    held_since: Option<f64>,
+    fired: bool,
+}
+
+// Watches for any number of button combos, firing each
+// once per press after it's been held for long enough
+struct ComboWatcher<A> {
+    combos: Vec<WatchedCombo<A>>,
+}
+
+impl<A: Copy> ComboWatcher<A> {
+    fn new() -> ComboWatcher<A> {
+        ComboWatcher { combos: Vec::new() }
+    }
+
+    fn watch(&mut self, combo: ButtonCombo, hold: f64, action: A) {
+        self.combos.push(WatchedCombo {
+            combo,
+            hold,
+            action,
+            held_since: None,
+            fired: false,
+        });
+    }
+
+    // Given this frame's packet (if there is one) at time `now`, in seconds,
+    // returns what's happened to each of the combos
+    fn update(&mut self, packet: Option<&[u8]>, now: f64) -> Vec<ComboEvent<A>> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ComboWatcher#update()");
+        let mut events = Vec::new();
+
+        for watched in &mut self.combos {
+            if !matches!(packet, Some(packet) if watched.combo.is_held(packet)) {
+                watched.held_since = None;
+                watched.fired = false;
+                continue;
+            }
+
+            let held_since = match watched.held_since {
+                Some(held_since) => held_since,
+                None => {
+                    if watched.hold > 0.0 {
+                        events.push(ComboEvent::Started(watched.action));
+                    }
+                    *watched.held_since.insert(now)
+                }
+            };
+
+            if !watched.fired && now - held_since >= watched.hold {
+                watched.fired = true;
+                events.push(ComboEvent::Fired(watched.action));
+            }
+        }
+
+        events
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ComboAction {
+    Quit,
+    ResetStats,
+}
+
+impl ComboAction {
+    fn describe(&self) -> &'static str {
+        match self {
+            ComboAction::Quit => "quit",
+            ComboAction::ResetStats => "reset the frame rate average",
+        }
+    }
+}
+
+// The shortest time, in seconds, between switches of --deadzone-toggle
+// or --pause-button, so a bouncing button doesn't switch straight back again
+const BUTTON_TOGGLE_DEBOUNCE: f64 = 0.2;
+
+// Switches something on & off with each press of a button
+struct ButtonToggle {
+    held: bool,
+    active: bool,
+    // When it last switched, in seconds
+    last_switch: Option<f64>,
+}
+
+impl ButtonToggle {
+    fn new() -> ButtonToggle {
+        ButtonToggle {
+            held: false,
+            active: false,
+            last_switch: None,
+        }
+    }
+
+    // Given whether the button's held at time `now`, returns whether it's on
+    fn update(&mut self, pressed: bool, now: f64) -> bool {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ButtonToggle#update()");
+        let debounced = match self.last_switch {
+            Some(last_switch) => now - last_switch >= BUTTON_TOGGLE_DEBOUNCE,
+            None => true,
+        };
+
+        if pressed && !self.held && debounced {
+            self.active = !self.active;
+            self.last_switch = Some(now);
+        }
+        self.held = pressed;
+
+        self.active
+    }
+}
+
+// How long, in seconds, the rumble pulse acknowledging a combo lasts
+const COMBO_FEEDBACK_LENGTH: f64 = 0.15;
+
+// Briefly rumbles `controller`, as a nudge that something's happening,
+// or to show which controller is in use
+fn rumble_pulse<T: GameController>(controller: &mut T) {
+    if controller.has_rumble() {
+        // Not worth mentioning if it doesn't work
+        let _ = controller.set_rumble(0x8000, 0x8000, (COMBO_FEEDBACK_LENGTH * 1000.0) as u32);
+    }
+}
+
+// --diag-byte's status bits
+const DIAG_RUNNING_SLOW: u8 = 0x10;
+const DIAG_IDLE: u8 = 0x20;
+
+// The byte --diag-byte adds: the frame count in the low nibble,
+// and what's going on in the high one
+fn diag_byte(frame_index: u64, running_slow: bool, idle: bool) -> u8 {
+    let mut byte = (frame_index % 16) as u8;
+
+    if running_slow {
+        byte |= DIAG_RUNNING_SLOW;
+    }
+    if idle {
+        byte |= DIAG_IDLE;
+    }
+
+    byte
+}
+
+// Press any scheduled buttons due at `now` (in seconds) on top of `packet`
+fn apply_scheduled_presses(packet: &mut [u8], presses: &[ScheduledPress], now: f64) {
     #[cfg(feature = "flamegraph-profiling")]
-    flame::start("buttons2");
-    // buttons2
-    let mut square_value: i16 = convert_button_to_analog(controller.button(Button::X));
-    let mut cross_value: i16 = convert_button_to_analog(controller.button(Button::A));
-    let circle_value: i16 = convert_button_to_analog(controller.button(Button::B));
-    let triangle_value: i16 = convert_button_to_analog(controller.button(Button::Y));
-    let r1_button_value: i16 = convert_button_to_analog(controller.button(Button::RightShoulder));
-    let l1_button_value: i16 = convert_button_to_analog(controller.button(Button::LeftShoulder));
-    let mut r2_button_value: i16 = convert_half_axis_positive(controller.axis(Axis::TriggerRight));
-    let mut l2_button_value: i16 = convert_half_axis_positive(controller.axis(Axis::TriggerLeft));
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::end("buttons2");
+    let _guard = flame::start_guard("apply_scheduled_presses()");
+
+    for press in presses {
+        if now < press.at || now >= press.at + SCHEDULED_PRESS_LENGTH {
+            continue;
+        }
+
+        press_packet_button(packet, press.button);
+    }
+}
+
+// Frames on, then frames off, repeated
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TurboPattern {
+    on: u32,
+    off: u32,
+}
+
+impl FromStr for TurboPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (on, off) = s
+            .split_once(':')
+            .ok_or_else(|| "Expected a turbo pattern in the form <on>:<off>".to_string())?;
+        let frames = |frames: &str| {
+            frames
+                .trim()
+                .parse::<u32>()
+                .map_err(|error| format!("Unexpected frame count '{}': {}", frames, error))
+        };
+
+        match (frames(on)?, frames(off)?) {
+            (0, _) | (_, 0) => Err("Turbo needs at least one frame on and one off".to_string()),
+            (on, off) => Ok(TurboPattern { on, off }),
+        }
+    }
+}
+
+// Whether a turbo button held through frame `frame_index` is pressed then
+fn turbo_state(pattern: &TurboPattern, frame_index: u64) -> bool {
+    let period = u64::from(pattern.on) + u64::from(pattern.off);
+    frame_index % period < u64::from(pattern.on)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Turbo {
+    button: &'static str,
+    pattern: TurboPattern,
+}
+
+impl FromStr for Turbo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (button, pattern) = match s.split_once('=') {
+            Some((button, pattern)) => (button, pattern.parse()?),
+            None => (s, TurboPattern { on: 1, off: 1 }),
+        };
 
+        Ok(Turbo {
+            button: parse_packet_button(button)?,
+            pattern,
+        })
+    }
+}
+
+// Lets go of any held turbo buttons which are in the off part of their pattern
+fn apply_turbo(packet: &mut [u8], turbos: &[Turbo], frame_index: u64) {
     #[cfg(feature = "flamegraph-profiling")]
-    flame::start("sticks");
-    let mut right_stick_x_value: i16 = controller.axis(Axis::RightX);
-    let mut right_stick_y_value: i16 = controller.axis(Axis::RightY);
-    let mut left_stick_x_value: i16 = controller.axis(Axis::LeftX);
-    let mut left_stick_y_value: i16 = controller.axis(Axis::LeftY);
+    let _guard = flame::start_guard("apply_turbo()");
+
+    for turbo in turbos {
+        if !turbo_state(&turbo.pattern, frame_index) {
+            release_packet_button(packet, turbo.button);
+        }
+    }
+}
+
+// Fully press the named button in `packet`
+fn press_packet_button(packet: &mut [u8], button: &str) {
+    for (name, byte, bits, pressure) in PACKET_BUTTONS.iter() {
+        if *name != button {
+            continue;
+        }
+
+        // The DualShock protocol considers 0 to mean pressed
+        packet[*byte] &= !bits;
+
+        // Only the twenty-byte protocol carries pressure
+        if let Some(pressure) = pressure {
+            if let Some(value) = packet.get_mut(*pressure) {
+                *value = 0xFF;
+            }
+        }
+    }
+}
+
+// Let go of the named button in `packet`
+fn release_packet_button(packet: &mut [u8], button: &str) {
+    for (name, byte, bits, pressure) in PACKET_BUTTONS.iter() {
+        if *name != button {
+            continue;
+        }
+
+        // The DualShock protocol considers 1 to mean released
+        packet[*byte] |= bits;
+
+        if let Some(pressure) = pressure {
+            if let Some(value) = packet.get_mut(*pressure) {
+                *value = 0x00;
+            }
+        }
+    }
+}
+
+fn main() {
     #[cfg(feature = "flamegraph-profiling")]
-    flame::end("sticks");
+    flame::start("Parse Arguments");
+
+    let arguments = CLIArgs::parse();
 
     #[cfg(feature = "flamegraph-profiling")]
-    flame::start("handle trigger_mode");
-    match trigger_mode {
-        TriggerMode::RightStick => {
-            l2_button_value = convert_half_axis_negative(controller.axis(Axis::RightY));
-            r2_button_value = convert_half_axis_positive(controller.axis(Axis::RightY));
+    flame::end("Parse Arguments");
 
-            cross_value = convert_button_to_analog(controller.button(Button::A));
-            square_value = convert_button_to_analog(controller.button(Button::X));
+    let result = run(&arguments);
 
-            // Combine the two raw trigger axes by subtracting one from the other
-            // NOTE: This doesn't allow for both to be used at once
-            right_stick_y_value =
-                controller.axis(Axis::TriggerLeft) - controller.axis(Axis::TriggerRight);
+    #[cfg(feature = "flamegraph-profiling")]
+    {
+        // We still want a graph of a session which failed!
+        File::create("flame-graph.html")
+            .and_then(|mut file| flame::dump_html(&mut file))
+            .expect("failed to write flame-graph.html");
+        File::create("flame-graph.json")
+            .and_then(|mut file| flame::dump_json(&mut file))
+            .expect("failed to write flame-graph.json");
+    }
+
+    match result {
+        Ok(_) => (),
+        // The user asked for this, so there's no need to shout about it
+        Err(OmnishockError::UserAbort) => std::process::exit(OmnishockError::UserAbort.exit_code()),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(error.exit_code());
         }
-        TriggerMode::CrossAndSquare => {
-            l2_button_value = convert_button_to_analog(controller.button(Button::A));
-            r2_button_value = convert_button_to_analog(controller.button(Button::X));
+    }
+}
 
-            cross_value = convert_half_axis_positive(controller.axis(Axis::TriggerRight));
-            square_value = convert_half_axis_positive(controller.axis(Axis::TriggerLeft));
+fn run(arguments: &CLIArgs) -> Result<(), OmnishockError> {
+    // None of these need any controllers,
+    // so we can skip setting up SDL entirely
+    match arguments.subcommand {
+        Subcommands::ProtocolInfo => {
+            print!("{}", describe_protocol());
+            return Ok(());
+        }
+        Subcommands::TestPattern(ref subcommand) => {
+            return send_test_pattern(arguments, subcommand);
+        }
+        Subcommands::SerialEchoTest(ref subcommand) => {
+            let mut serial = open_device(&subcommand.device, arguments.verbose)?;
+            return serial_echo_test(&mut serial);
+        }
+        // Rumble needs a controller, and so SDL
+        Subcommands::ResendLog(ref subcommand) if subcommand.rumble_to.is_none() => {
+            return resend_log(arguments, subcommand, None);
+        }
+        Subcommands::Convert(ref subcommand) => {
+            return convert_log(subcommand);
+        }
+        Subcommands::ValidateProfile(ref subcommand) => {
+            return validate_profiles(subcommand);
         }
         _ => (),
     }
-    #[cfg(feature = "flamegraph-profiling")]
-    flame::end("handle trigger_mode");
-
-    if normalise_sticks {
-        normalise_stick_as_dualshock2(&mut right_stick_x_value, &mut right_stick_y_value);
-        normalise_stick_as_dualshock2(&mut left_stick_x_value, &mut left_stick_y_value);
-    }
-
-    let mut buttons1 = Buttons1::empty();
-    buttons1.set(Buttons1::LEFT, convert_analog_to_button(dpad_left_value));
-    buttons1.set(Buttons1::DOWN, convert_analog_to_button(dpad_down_value));
-    buttons1.set(Buttons1::RIGHT, convert_analog_to_button(dpad_right_value));
-    buttons1.set(Buttons1::UP, convert_analog_to_button(dpad_up_value));
-    buttons1.set(Buttons1::START, convert_analog_to_button(start_value));
-    buttons1.set(Buttons1::R3, convert_analog_to_button(right_stick_value));
-    buttons1.set(Buttons1::L3, convert_analog_to_button(left_stick_value));
-    buttons1.set(Buttons1::SELECT, convert_analog_to_button(select_value));
-
-    let mut buttons2 = Buttons2::empty();
-    buttons2.set(Buttons2::SQUARE, convert_analog_to_button(square_value));
-    buttons2.set(Buttons2::CROSS, convert_analog_to_button(cross_value));
-    buttons2.set(Buttons2::CIRCLE, convert_analog_to_button(circle_value));
-    buttons2.set(Buttons2::TRIANGLE, convert_analog_to_button(triangle_value));
-    buttons2.set(Buttons2::R1, convert_analog_to_button(r1_button_value));
-    buttons2.set(Buttons2::L1, convert_analog_to_button(l1_button_value));
-    buttons2.set(Buttons2::R2, convert_analog_to_button(r2_button_value));
-    buttons2.set(Buttons2::L2, convert_analog_to_button(l2_button_value));
-
-    let mode_footer = if controller.button(Button::Guide) {
-        0xAA
-    } else {
-        0x55
-    };
 
-    return vec![
-        DUALSHOCK_MAGIC,
-        // DualShock protocol considers 0 to mean
-        // pressed and 1 to mean not pressed, so
-        // we NOT the our bitflags here
-        !buttons1.bits(),
-        !buttons2.bits(),
-        // Analog sticks
-        convert_for_dualshock(right_stick_x_value),
-        convert_for_dualshock(right_stick_y_value),
-        convert_for_dualshock(left_stick_x_value),
-        convert_for_dualshock(left_stick_y_value),
-        // Pressure values
-        convert_for_dualshock(dpad_right_value),
-        convert_for_dualshock(dpad_left_value),
-        convert_for_dualshock(dpad_up_value),
-        convert_for_dualshock(dpad_down_value),
-        convert_for_dualshock(triangle_value),
-        convert_for_dualshock(circle_value),
-        convert_for_dualshock(cross_value),
-        convert_for_dualshock(square_value),
-        convert_for_dualshock(l1_button_value),
-        convert_for_dualshock(r1_button_value),
-        convert_for_dualshock(l2_button_value),
-        convert_for_dualshock(r2_button_value),
-        mode_footer,
-    ];
+    catch_interrupts();
+    let mut sdl_manager =
+        SDLManager::init(!arguments.no_builtin_mappings).map_err(OmnishockError::Sdl)?;
+
+    println!(
+        "(There are {} controllers connected)",
+        sdl_manager.active_controllers.len()
+    );
+
+    if arguments.verbose {
+        let mut ids: Vec<u32> = sdl_manager.active_controllers.keys().copied().collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            if let Some(description) = sdl_manager.describe_mapping(id) {
+                println!("{}", description);
+            }
+        }
+    }
+
+    match arguments.subcommand {
+        Subcommands::PS2CESubcommand(_) => {
+            send_to_ps2_controller_emulator(arguments, &mut sdl_manager)
+        }
+        Subcommands::Test(ref subcommand) => print_events(arguments, subcommand, &mut sdl_manager),
+        Subcommands::ResendLog(ref subcommand) => {
+            // There's nothing to send rumble to, and no reason to wait for one
+            if sdl_manager.active_controllers.is_empty() {
+                return Err(OmnishockError::NoControllers(
+                    "--rumble-to needs a controller connected".to_string(),
+                ));
+            }
+            resend_log(arguments, subcommand, Some(&mut sdl_manager))
+        }
+        Subcommands::ProtocolInfo
+        | Subcommands::TestPattern(_)
+        | Subcommands::SerialEchoTest(_)
+        | Subcommands::Convert(_)
+        | Subcommands::ValidateProfile(_) => Ok(()),
+    }
 }
 
 fn clear_serial_buffer<T: Read>(serial: &mut T) {
@@ -455,22 +1573,290 @@ fn clear_serial_buffer<T: Read>(serial: &mut T) {
     } {}
 }
 
-fn send_to_ps2_controller_emulator(
-    arguments: &CLIArgs,
-    sdl_manager: &mut SDLManager,
-) -> Result<(), Box<dyn std::error::Error>> {
+// The range profile used unless a flag or profile says otherwise
+const DEFAULT_RANGE_PROFILE: &str = "dualshock2";
+
+// Works out the mapping options from a --profile and the command line.
+// The profile's settings go over the defaults, then any flag given goes
+// over the profile's setting for it. Axis and D-Pad pressure routes are
+// merged, with the flags' winning for any output routed in both.
+fn build_mapping_options(
+    command_arguments: &PS2CESubcommand,
+    profile: &Profile,
+) -> Result<MappingOptions, OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("build_mapping_options()");
+    let mut options = MappingOptions {
+        // A profile may redefine the default range profile, too
+        range_profile: profile
+            .range_profile(DEFAULT_RANGE_PROFILE)
+            .map_err(OmnishockError::InvalidArguments)?,
+        button_routes: profile.button_routes().clone(),
+        ..MappingOptions::new(TriggerMode::Normal, true)
+    };
+    profile.apply(&mut options);
+
+    if let Some(trigger_mode) = command_arguments.trigger_mode {
+        options.trigger_mode = trigger_mode;
+    }
+    if command_arguments.no_stick_normalise {
+        options.normalise_sticks = false;
+    }
+    if command_arguments.stick_normalise {
+        options.normalise_sticks = true;
+    }
+    if let Some(ref name) = command_arguments.range_profile {
+        options.range_profile = profile
+            .range_profile(name)
+            .map_err(OmnishockError::InvalidArguments)?;
+    }
+    if let Some(range) = command_arguments.clamp_axis_bytes {
+        options.range_profile = options.range_profile.with_byte_range(range);
+    }
+    if let Some(percentage) = command_arguments.normalise_left {
+        options.normalise_left = Some(percentage);
+    }
+    if let Some(percentage) = command_arguments.normalise_right {
+        options.normalise_right = Some(percentage);
+    }
+    if let Some(radius) = command_arguments.clamp_radial {
+        options.clamp_radial = Some(radius);
+    }
+    if let Some(degrees) = command_arguments.snap_cardinal {
+        options.snap_cardinal = degrees;
+    }
+    if let Some(deadzone) = command_arguments.deadzone {
+        options.deadzone = deadzone;
+    }
+    if let Some(anti_deadzone) = command_arguments.anti_deadzone {
+        options.anti_deadzone = i16::try_from(anti_deadzone).map_err(|_| {
+            OmnishockError::InvalidArguments("The anti-deadzone must be at most 32767".to_string())
+        })?;
+    }
+    if command_arguments.trigger_pressure {
+        options.trigger_pressure = true;
+    }
+    if command_arguments.no_trigger_pressure {
+        options.trigger_pressure = false;
+    }
+    if let Some(trigger_range) = command_arguments.trigger_range {
+        options.trigger_range = trigger_range;
+    }
+    if let Some(trigger_source) = command_arguments.trigger_source {
+        options.trigger_source = trigger_source;
+    }
+    if let Some(trigger_buttons) = command_arguments.trigger_buttons {
+        options.trigger_buttons = Some(trigger_buttons);
+    }
+    if let Some(mirror_stick) = command_arguments.mirror_stick {
+        options.mirror_stick = Some(mirror_stick);
+    }
+    if let Some(axis) = command_arguments.combine_triggers_to {
+        options.combine_triggers = Some(axis);
+    }
+    if command_arguments.dpad_as_lstick {
+        options.dpad_stick = match command_arguments.keep_dpad {
+            false => DPadStick::Move,
+            true => DPadStick::Copy,
+        };
+    }
+    if command_arguments.no_dpad_as_lstick {
+        options.dpad_stick = DPadStick::Off;
+    }
+    if let Some(stick_shape) = command_arguments.stick_shape {
+        options.stick_shape = stick_shape;
+    }
+    if let Some(stick_centre) = command_arguments.stick_center {
+        options.stick_centre = stick_centre;
+    }
+    if let Some(stick_encoding) = command_arguments.stick_encoding {
+        options.stick_encoding = stick_encoding;
+    }
+
+    let axis_routes = AxisRoutes::from_routes(&command_arguments.route_axis)
+        .map_err(OmnishockError::InvalidArguments)?;
+    for route in axis_routes.routes() {
+        options.axis_routes.insert(route);
+    }
+    let dpad_pressure = DPadPressureRoutes::from_routes(&command_arguments.dpad_pressure)
+        .map_err(OmnishockError::InvalidArguments)?;
+    for route in dpad_pressure.routes() {
+        options.dpad_pressure.insert(route);
+    }
+
+    Ok(options)
+}
+
+// Anything we can send packets to and read responses back from
+trait Transport: Read + Write {}
+
+impl<T: Read + Write> Transport for T {}
+
+// The --device value which selects a virtual uinput gamepad
+#[cfg(all(target_os = "linux", feature = "uinput"))]
+const UINPUT_DEVICE: &str = "uinput";
+
+// The --device prefix which selects a file descriptor already opened
+// by whichever process started us, e.g. "fd:3"
+#[cfg(all(unix, feature = "fd-device"))]
+const FD_DEVICE_PREFIX: &str = "fd:";
+
+// The file descriptor a --device value names, if it names one
+#[cfg(all(unix, feature = "fd-device"))]
+fn parse_fd_device(device_path: &str) -> Option<Result<std::os::unix::io::RawFd, String>> {
+    let fd = device_path.strip_prefix(FD_DEVICE_PREFIX)?;
+
+    Some(match fd.parse::<std::os::unix::io::RawFd>() {
+        Ok(fd) if fd >= 0 => Ok(fd),
+        _ => Err(format!("Expected a file descriptor number, not '{}'", fd)),
+    })
+}
+
+// Whether `open_device` opens `device_path` as a serial port, rather
+// than as a virtual gamepad or a descriptor someone else opened
+#[cfg_attr(
+    not(any(
+        all(target_os = "linux", feature = "uinput"),
+        all(unix, feature = "fd-device")
+    )),
+    allow(unused_variables)
+)]
+fn opens_serial_port(device_path: &str) -> bool {
+    #[cfg(all(target_os = "linux", feature = "uinput"))]
+    if device_path == UINPUT_DEVICE {
+        return false;
+    }
+
+    #[cfg(all(unix, feature = "fd-device"))]
+    if parse_fd_device(device_path).is_some() {
+        return false;
+    }
+
+    true
+}
+
+fn open_device(device_path: &str, verbose: bool) -> Result<Box<dyn Transport>, OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("open_device()");
+
+    #[cfg(all(target_os = "linux", feature = "uinput"))]
+    {
+        if device_path == UINPUT_DEVICE {
+            if verbose {
+                println!("Creating virtual gamepad via uinput...");
+            }
+
+            return uinput_sink::UInputSink::create("Omnishock Virtual DualShock")
+                .map(|sink| Box::new(sink) as Box<dyn Transport>)
+                .map_err(|error| OmnishockError::DeviceOpen(format!("uinput: {}", error)));
+        }
+    }
+
+    #[cfg(all(unix, feature = "fd-device"))]
+    {
+        use std::os::unix::io::FromRawFd;
+
+        if let Some(fd) = parse_fd_device(device_path) {
+            let fd = fd.map_err(OmnishockError::InvalidArguments)?;
+            if verbose {
+                println!("Using file descriptor {} as the device...", fd);
+            }
+
+            // SAFETY: the descriptor was passed to us to use as the device,
+            // so nothing else in this process owns it. It's only dropped
+            // (and so closed) once we know it's really open.
+            let file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+            file.metadata()
+                .map_err(|error| OmnishockError::DeviceOpen(format!("fd {}: {}", fd, error)))?;
+
+            return Ok(Box::new(std::mem::ManuallyDrop::into_inner(file)));
+        }
+    }
+
+    Ok(Box::new(open_serial_device(device_path, verbose)?))
+}
+
+// A line describing a serial device, for picking it out from the others
+fn describe_serial_port(port: &serialport::SerialPortInfo) -> String {
+    match port.port_type {
+        serialport::SerialPortType::UsbPort(ref usb) => format!(
+            "{} (USB {:04X}:{:04X}{})",
+            port.port_name,
+            usb.vid,
+            usb.pid,
+            match (&usb.manufacturer, &usb.product) {
+                (Some(manufacturer), Some(product)) => format!(", {} {}", manufacturer, product),
+                (None, Some(name)) | (Some(name), None) => format!(", {}", name),
+                (None, None) => String::new(),
+            }
+        ),
+        serialport::SerialPortType::BluetoothPort => format!("{} (Bluetooth)", port.port_name),
+        _ => port.port_name.clone(),
+    }
+}
+
+// Lists `ports` on `output`, then reads which one to use from `input`,
+// asking again until it gets an answer it understands
+fn choose_serial_port<R: std::io::BufRead, W: Write>(
+    ports: &[serialport::SerialPortInfo],
+    mut input: R,
+    mut output: W,
+) -> Result<String, OmnishockError> {
+    writeln!(output, "Which device should be used?")?;
+    for (index, port) in ports.iter().enumerate() {
+        writeln!(output, "  {}. {}", index + 1, describe_serial_port(port))?;
+    }
+
+    loop {
+        write!(output, "Device number (1-{}): ", ports.len())?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err(OmnishockError::InvalidArguments(
+                "No device was chosen".to_string(),
+            ));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=ports.len()).contains(&number) => {
+                return Ok(ports[number - 1].port_name.clone());
+            }
+            _ => writeln!(output, "Expected a number from 1 to {}", ports.len())?,
+        }
+    }
+}
+
+// Asks which serial device to use, for when none was given
+fn choose_serial_device() -> Result<String, OmnishockError> {
+    use std::io::IsTerminal;
+
+    if !std::io::stdin().is_terminal() {
+        return Err(OmnishockError::InvalidArguments(
+            "No device was given, and there's no terminal to ask which to use".to_string(),
+        ));
+    }
+
+    let ports = serialport::available_ports()
+        .map_err(|error| OmnishockError::DeviceOpen(format!("couldn't list devices: {}", error)))?;
+    if ports.is_empty() {
+        return Err(OmnishockError::DeviceOpen(
+            "no serial devices were found".to_string(),
+        ));
+    }
+
+    choose_serial_port(&ports, std::io::stdin().lock(), std::io::stdout())
+}
+
+fn open_serial_device(
+    device_path: &str,
+    verbose: bool,
+) -> Result<Box<dyn serialport::SerialPort>, OmnishockError> {
     use serialport::prelude::*;
     use std::time::Duration;
 
     #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("send_to_ps2_controller_emulator()");
-
-    let verbose = arguments.verbose;
-    let command_arguments = match arguments.subcommand {
-        Subcommands::PS2CESubcommand(ref subcommand) => subcommand,
-        _ => panic!("We got put into a subcommand we weren't expecting. Weird!"),
-    };
-    let device_path = &command_arguments.device;
+    let _guard = flame::start_guard("open_serial_device()");
 
     if verbose {
         println!(
@@ -491,27 +1877,91 @@ fn send_to_ps2_controller_emulator(
         timeout: Duration::from_millis(8),
     };
 
-    let serial = match serialport::open_with_settings(&device_path, &serial_settings) {
-        Ok(serial) => serial,
-        Err(error) => panic!("failed to open serial device: {}", error),
-    };
-
-    send_to_ps2_controller_emulator_via(arguments, sdl_manager, serial)
+    serialport::open_with_settings(device_path, &serial_settings)
+        .map_err(|error| OmnishockError::DeviceOpen(error.to_string()))
 }
 
-fn send_to_ps2_controller_emulator_via<I: Read + Write>(
-    arguments: &CLIArgs,
-    sdl_manager: &mut SDLManager,
-    mut serial: I,
-) -> Result<(), Box<dyn std::error::Error>> {
+// Sent on its own to ask the device for its firmware's name and version.
+// No known firmware answers it yet, and it's not the DualShock magic
+// byte, but what firmware which doesn't know it does with it is untested.
+const FIRMWARE_QUERY: u8 = b'?';
+
+// The most we'll read in answer to a firmware query
+const FIRMWARE_STRING_MAX_LENGTH: usize = 64;
+
+// Asks the device to identify its firmware, returning whatever text it
+// answers with. Anything which isn't a line of ASCII text is discarded,
+// and the buffer is cleared afterwards either way.
+fn query_firmware<I: Read + Write>(serial: &mut I) -> Result<Option<String>, OmnishockError> {
     #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("send_to_ps2_controller_emulator_via()");
-    let verbose = arguments.verbose;
-    let command_arguments = match arguments.subcommand {
-        Subcommands::PS2CESubcommand(ref subcommand) => subcommand,
-        _ => panic!("We got put into a subcommand we weren't expecting. Weird!"),
+    let _guard = flame::start_guard("query_firmware()");
+
+    clear_serial_buffer(serial);
+    serial.write_all(&[FIRMWARE_QUERY])?;
+
+    let mut answer = Vec::new();
+    let mut buffer = [0; FIRMWARE_STRING_MAX_LENGTH];
+
+    while answer.len() < FIRMWARE_STRING_MAX_LENGTH && !answer.contains(&b'\n') {
+        match serial.read(&mut buffer[..FIRMWARE_STRING_MAX_LENGTH - answer.len()]) {
+            Ok(0) => break,
+            Ok(count) => answer.extend_from_slice(&buffer[..count]),
+            Err(ref error) if error.kind() == std::io::ErrorKind::TimedOut => break,
+            Err(error) => return Err(OmnishockError::DetectionFailed(error.to_string())),
+        }
+    }
+
+    // Don't leave anything behind to confuse detection
+    clear_serial_buffer(serial);
+
+    let line = match answer.split(|&byte| byte == b'\n').next() {
+        Some(line) => String::from_utf8_lossy(line).trim().to_string(),
+        None => return Ok(None),
     };
 
+    if line.is_empty()
+        || !line
+            .chars()
+            .all(|character| character.is_ascii_graphic() || character == ' ')
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(line))
+}
+
+// Works out which firmware sent `response` to a neutral twenty-byte packet
+fn classify_detection_response(response: &[u8]) -> ControllerEmulatorPacketType {
+    match response.first() {
+        // Aaron Clovsky's firmware replies with its usual vibration response
+        Some(&TWENTY_BYTE_OK_HEADER) => ControllerEmulatorPacketType::TwentyByte,
+        // Johnny Chung Lee's firmware doesn't understand twenty bytes
+        Some(&byte) if byte == SEVEN_BYTE_ERR_RESPONSE as u8 => {
+            ControllerEmulatorPacketType::SevenByte
+        }
+        _ => ControllerEmulatorPacketType::None,
+    }
+}
+
+// What to fall back on if the firmware isn't recognised: the protocol given
+// with --default-mode, nothing with --allow-unknown, or otherwise giving up
+fn unrecognised_fallback(
+    default_mode: Option<ControllerEmulatorPacketType>,
+    allow_unknown: bool,
+) -> Option<ControllerEmulatorPacketType> {
+    default_mode.or_else(|| allow_unknown.then_some(ControllerEmulatorPacketType::None))
+}
+
+fn detect_communication_mode<I: Read + Write>(
+    serial: &mut I,
+    neutral: &[u8],
+    fallback: Option<ControllerEmulatorPacketType>,
+    retries: u32,
+    verbose: bool,
+) -> Result<ControllerEmulatorPacketType, OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("detect_communication_mode()");
+
     let mut communication_mode = ControllerEmulatorPacketType::None;
 
     // Create a four-byte response buffer
@@ -524,118 +1974,489 @@ fn send_to_ps2_controller_emulator_via<I: Read + Write>(
         println!("Clearing serial buffer...");
     }
 
-    clear_serial_buffer(&mut serial);
+    clear_serial_buffer(serial);
 
-    if verbose {
-        println!("Determining device type...");
-    }
-
-    // Send a twenty-byte, packet of a neutral controller state.
-    serial.write_all(&[
-        DUALSHOCK_MAGIC,
-        !Buttons1::empty().bits(),
-        !Buttons2::empty().bits(),
-        // Sticks
-        0x80, // Right stick X
-        0x80, // Right stick Y
-        0x80, // Left stick X
-        0x80, // Left stick Y
-        // Pressure
-        0x00, // Right
-        0x00, // Left
-        0x00, // Up
-        0x00, // Down
-        0x00, // Triangle
-        0x00, // Circle
-        0x00, // Cross
-        0x00, // Square
-        0x00, // [L1]
-        0x00, // [R1]
-        0x00, // [L2]
-        0x00, // [R2]
-        // Mode
-        0x55, // Normal
-    ])?;
-
-    // Check the response!
-    match serial.read(&mut response) {
-        Ok(_) => {
-            if response[0] == TWENTY_BYTE_OK_HEADER {
-                if verbose {
-                    println!(
-                        "Response began with '{}': this is probably Aaron Clovsky's work!",
-                        TWENTY_BYTE_OK_HEADER
-                    );
-                }
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            println!("Retrying device detection ({} of {})...", attempt, retries);
+            clear_serial_buffer(serial);
+        }
 
-                communication_mode = ControllerEmulatorPacketType::TwentyByte;
-            } else if response[0] == (SEVEN_BYTE_ERR_RESPONSE as u8) {
-                if verbose {
+        if verbose {
+            println!("Determining device type...");
+        }
+
+        // Send a twenty-byte, packet of a neutral controller state.
+        serial.write_all(neutral)?;
+
+        // Check the response!
+        match serial.read(&mut response) {
+            Ok(_) => {
+                communication_mode = classify_detection_response(&response);
+
+                if communication_mode == ControllerEmulatorPacketType::None {
+                    println!("Unrecognised response: {:x}", HexView::from(&response));
+                } else if verbose {
                     println!(
-                        "Response began with '{}': this is probably Johnny Chung Lee's work!",
-                        SEVEN_BYTE_ERR_RESPONSE
+                        "Response began with {:#04X}: this is probably the {}",
+                        response[0], communication_mode
                     );
                 }
+            }
+            // A timeout just means nothing answered, so we carry on and log,
+            // but anything else means we can't talk to the device at all
+            Err(error) => {
+                if error.kind() != std::io::ErrorKind::TimedOut {
+                    return Err(OmnishockError::DetectionFailed(error.to_string()));
+                }
 
-                communication_mode = ControllerEmulatorPacketType::SevenByte;
-            } else {
-                println!("Unrecognised response: {:x}", HexView::from(&response));
+                println!("failed reading from device: {}", error);
             }
+        };
+
+        if communication_mode != ControllerEmulatorPacketType::None {
+            break;
         }
-        Err(error) => {
-            println!("failed reading from device: {}", error);
-        }
-    };
+    }
 
     // Clear the buffer again!
     if verbose {
         println!("Clearing serial buffer...");
     }
 
-    clear_serial_buffer(&mut serial);
+    clear_serial_buffer(serial);
+
+    if communication_mode == ControllerEmulatorPacketType::None {
+        match fallback {
+            None => {
+                return Err(OmnishockError::DetectionFailed(
+                    concat!(
+                        "unrecognised firmware ",
+                        "(use --allow-unknown or --default-mode to carry on anyway)"
+                    )
+                    .to_string(),
+                ));
+            }
+            Some(ControllerEmulatorPacketType::None) => {
+                println!("WARNING: The device's firmware wasn't recognised!");
+                println!(
+                    "         Nothing will be sent to it, so the console won't see any input."
+                );
+            }
+            Some(default_mode) => {
+                println!("WARNING: The device's firmware wasn't recognised!");
+                println!("         Carrying on with the protocol given by --default-mode.");
+                communication_mode = default_mode;
+            }
+        }
+    }
+
+    // Whatever we settled on, make sure the user knows
+    println!("Using {}", communication_mode);
 
-    let trigger_mode = &command_arguments.trigger_mode;
+    Ok(communication_mode)
+}
 
-    if verbose {
-        println!("Using trigger mode '{:?}'...", trigger_mode);
+fn serial_echo_test<I: Read + Write>(serial: &mut I) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("serial_echo_test()");
+
+    clear_serial_buffer(serial);
+
+    println!("Sent:     {:x}", HexView::from(&NEUTRAL_PACKET[..]));
+    serial.write_all(&NEUTRAL_PACKET)?;
+
+    // Leave room for a whole packet, in case the device echoes it back
+    let mut response = vec![0; NEUTRAL_PACKET.len()];
+    let bytes_received = match serial.read(&mut response) {
+        Ok(bytes) => bytes,
+        Err(ref error) if error.kind() == std::io::ErrorKind::TimedOut => 0,
+        Err(error) => return Err(OmnishockError::DetectionFailed(error.to_string())),
+    };
+    response.truncate(bytes_received);
+
+    println!("Received: {:x}", HexView::from(&response));
+
+    match classify_detection_response(&response) {
+        ControllerEmulatorPacketType::None => Err(OmnishockError::DetectionFailed(
+            "the response didn't match any known firmware".to_string(),
+        )),
+        communication_mode => {
+            println!("This matches the {}", communication_mode);
+            Ok(())
+        }
+    }
+}
+
+fn send_to_ps2_controller_emulator(
+    arguments: &CLIArgs,
+    sdl_manager: &mut SDLManager,
+) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("send_to_ps2_controller_emulator()");
+
+    let command_arguments = match arguments.subcommand {
+        Subcommands::PS2CESubcommand(ref subcommand) => subcommand,
+        _ => panic!("We got put into a subcommand we weren't expecting. Weird!"),
+    };
+
+    let device = match command_arguments.device {
+        Some(ref device) => device.clone(),
+        None => choose_serial_device()?,
+    };
+    let serial = open_device(&device, arguments.verbose)?;
+
+    // Only real adapters reset when they're opened
+    if command_arguments.startup_delay_ms > 0 && opens_serial_port(&device) {
+        if arguments.verbose {
+            println!(
+                "Waiting {}ms for the device to start up...",
+                command_arguments.startup_delay_ms
+            );
+        }
+        std::thread::sleep(std::time::Duration::from_millis(
+            command_arguments.startup_delay_ms,
+        ));
+    }
+
+    let mut copies: Vec<Box<dyn Write>> = Vec::new();
+    for path in &command_arguments.tee {
+        if path == "-" {
+            copies.push(Box::new(HexStdout));
+        } else {
+            let file = std::fs::File::create(path).map_err(|error| {
+                OmnishockError::DeviceOpen(format!("couldn't create '{}': {}", path, error))
+            })?;
+            copies.push(Box::new(file));
+        }
+    }
+    let serial = TeeWriter::new(serial, copies);
+
+    let serial = PacedWriter::new(
+        serial,
+        command_arguments.write_chunk_size,
+        std::time::Duration::from_micros(command_arguments.write_delay_us),
+    );
+
+    let clock = WallClock::with_step(60.0, command_arguments.step);
+    send_to_ps2_controller_emulator_via(arguments, sdl_manager, serial, clock)
+}
+
+fn send_to_ps2_controller_emulator_via<I: Read + Write, C: FrameClock>(
+    arguments: &CLIArgs,
+    sdl_manager: &mut SDLManager,
+    mut serial: I,
+    mut clock: C,
+) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("send_to_ps2_controller_emulator_via()");
+    let verbose = arguments.verbose;
+    let command_arguments = match arguments.subcommand {
+        Subcommands::PS2CESubcommand(ref subcommand) => subcommand,
+        _ => panic!("We got put into a subcommand we weren't expecting. Weird!"),
+    };
+
+    if command_arguments.query_firmware {
+        match query_firmware(&mut serial)? {
+            Some(firmware) => println!("Device reports firmware: {}", firmware),
+            None => println!("The device didn't say which firmware it's running"),
+        }
     }
 
-    let normalise_sticks = !command_arguments.no_stick_normalise;
+    let profile = match command_arguments.profile {
+        Some(ref path) => Profile::load(path).map_err(OmnishockError::InvalidArguments)?,
+        None => Profile::default(),
+    };
+
+    let mut mapping_options = build_mapping_options(command_arguments, &profile)?;
+    mapping_options.shift_layer = match (
+        command_arguments.shift_button,
+        &command_arguments.shift_profile,
+    ) {
+        (Some(button), Some(path)) => Some(ShiftLayer {
+            button,
+            button_routes: Profile::load(path)
+                .map_err(OmnishockError::InvalidArguments)?
+                .button_routes()
+                .clone(),
+        }),
+        _ => None,
+    };
+
+    if let Some(button) = command_arguments.pause_button {
+        mapping_options.withheld_buttons.push(button);
+    }
+    let mut pause_toggle = ButtonToggle::new();
+
+    // The stick settings --deadzone-toggle switches to
+    let deadzone_profile = match (
+        command_arguments.deadzone_toggle,
+        &command_arguments.deadzone_profile,
+    ) {
+        (Some(button), Some(path)) => {
+            mapping_options.withheld_buttons.push(button);
+            Some(Profile::load(path).map_err(OmnishockError::InvalidArguments)?)
+        }
+        _ => None,
+    };
+    let mut deadzone_toggle = ButtonToggle::new();
+
+    let communication_mode = detect_communication_mode(
+        &mut serial,
+        &centred_neutral_packet(
+            &ControllerEmulatorPacketType::TwentyByte,
+            mapping_options.stick_centre,
+        ),
+        unrecognised_fallback(
+            command_arguments.default_mode,
+            command_arguments.allow_unknown,
+        ),
+        command_arguments.detect_retries,
+        verbose,
+    )?;
 
     if verbose {
-        if normalise_sticks {
-            println!("Normalising stick extents (stick values * 1.1)")
+        println!("Using trigger mode '{:?}'...", mapping_options.trigger_mode);
+        if let Some(axis) = mapping_options.combine_triggers {
+            println!("Combining the triggers onto {:?}...", axis);
+        }
+
+        if mapping_options.normalise_sticks {
+            println!(
+                "Normalising stick extents (stick values * {:.2})",
+                1.0 + f64::from(mapping_options.range_profile.normalise_percent) / 100.0
+            )
         } else {
             println!("Not normalising stick extents")
         }
+
+        for (stick, percentage) in [
+            ("left", mapping_options.normalise_left),
+            ("right", mapping_options.normalise_right),
+        ] {
+            if let Some(percentage) = percentage {
+                println!("Normalising the {} stick by {}%", stick, percentage);
+            }
+        }
+
+        for route in mapping_options.axis_routes.routes() {
+            println!("Reading {:?} from {:?}", route.output, route.input);
+        }
+        for route in mapping_options.dpad_pressure.routes() {
+            println!(
+                "Reading {:?} pressure from {:?}{}",
+                route.direction,
+                route.input.axis,
+                if route.input.negative {
+                    " (negative)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    let mut invert_buttons = command_arguments.invert_button.clone();
+    for button in profile.invert_buttons() {
+        if !invert_buttons.contains(button) {
+            invert_buttons.push(button);
+        }
     }
 
-    let mut event_pump = sdl_manager.context.event_pump()?;
+    if let Some(ref path) = command_arguments.save_profile {
+        std::fs::write(path, Profile::save(&mapping_options, &invert_buttons))?;
+        if verbose {
+            println!("Saved the mapping options to '{}'", path);
+        }
+    }
+
+    if verbose {
+        for press in &command_arguments.at {
+            println!("Pressing {} at {}s", press.button, press.at);
+        }
+    }
+
+    let neutral = centred_neutral_packet(&communication_mode, mapping_options.stick_centre);
 
-    // We use `game_time` to keep track of "frame" time and try to hit a
-    // consistent rate at all times. We use `spin_sleep` instead of
-    // `thread::Sleep` to get more accurate sleep times on all platforms.
-    use game_time::framerate::RunningAverageSampler;
-    use game_time::{FloatDuration, FrameCount, FrameCounter, GameClock};
+    let send_throttle = match command_arguments.send_rate {
+        Some(rate) if rate <= 0.0 => {
+            return Err(OmnishockError::InvalidArguments(
+                "The send rate must be more than zero".to_string(),
+            ));
+        }
+        Some(rate) => {
+            if verbose {
+                println!("Sending at most {}Hz", rate);
+            }
+            SendThrottle::new(1.0 / rate)
+        }
+        None => SendThrottle::new(0.0),
+    };
+
+    let mut event_pump = sdl_manager
+        .context
+        .event_pump()
+        .map_err(OmnishockError::Sdl)?;
+
+    use game_time::FloatDuration;
 
-    let mut clock = GameClock::new();
-    let mut counter = FrameCounter::new(60.0, RunningAverageSampler::with_max_samples(60));
     let mut sim_time;
     let warning_threshold = FloatDuration::milliseconds(500.0);
+    let mut slow_alert_limiter = SlowAlertLimiter::default();
+
+    let mut shaper = FrameShaper {
+        neutral: neutral.clone(),
+        packet_hold: PacketHold::new(FloatDuration::milliseconds(f64::from(
+            command_arguments.hold_last_ms,
+        ))),
+        soft_press: SoftPress::new(f64::from(command_arguments.soft_press_ms) / 1000.0),
+        turbo: command_arguments.turbo.clone(),
+        scheduled_presses: command_arguments.at.clone(),
+        analog_gesture: command_arguments
+            .analog_gesture
+            .map(|_| DoubleTap::new(DOUBLE_TAP_WINDOW)),
+        mode_cooldown: ModeCooldown::new(f64::from(command_arguments.mode_cooldown_ms) / 1000.0),
+        idle_heartbeat: IdleHeartbeat::new(command_arguments.idle_heartbeat_frames.unwrap_or(
+            match communication_mode {
+                ControllerEmulatorPacketType::TwentyByte => DEFAULT_IDLE_HEARTBEAT_FRAMES,
+                _ => 0,
+            },
+        )),
+        polarity: button_polarity(&invert_buttons),
+        send_throttle,
+        diag_byte: command_arguments.diag_byte,
+    };
 
-    // `spin_sleeper` gives us a more accurate sleep timer.
-    // With it we will trust `thread::Sleep` for all but the last 1ms
-    // (1,000,000ns) of the sleep timer, then it will spin for the remainder.
-    // With this in place we only dip below 95% of our speed target a handful
-    // of times in a 4-minute period, rather than nearly every iteration.
-    let spin_sleeper = spin_sleep::SpinSleeper::new(1_000_000);
+    let interactive_commands = if command_arguments.interactive {
+        println!("Interactive mode: type \"help\" for a list of commands");
+        Some(spawn_stdin_reader())
+    } else {
+        None
+    };
+
+    let mut rumble_limiter = command_arguments
+        .rumble_max_duty
+        .map(|RumbleDuty(max_duty)| RumbleLimiter::new(max_duty));
+    // What each controller was last told to rumble at
+    let mut sent_rumble: std::collections::HashMap<u32, SentRumble> =
+        std::collections::HashMap::new();
+
+    let mut combo_watcher = ComboWatcher::new();
+    if let Some(ref combo) = command_arguments.quit_combo {
+        combo_watcher.watch(
+            combo.clone(),
+            f64::from(command_arguments.quit_combo_hold_ms) / 1000.0,
+            ComboAction::Quit,
+        );
+    }
+    if let Some(ref combo) = command_arguments.reset_stats_button {
+        combo_watcher.watch(
+            combo.clone(),
+            f64::from(command_arguments.reset_stats_hold_ms) / 1000.0,
+            ComboAction::ResetStats,
+        );
+    }
+    let mut interrupted_by_user = false;
+    // Rumble from the device is held off until any feedback pulse is done
+    let mut feedback_until = 0.0;
+
+    let mut status_line = command_arguments.status_line.then(StatusLine::new);
+    let mut snapshot_button_held = false;
+    let mut event_stream = match command_arguments.emit_events.as_deref() {
+        Some("-") => Some(EventStream::new(Box::new(std::io::stdout()))),
+        Some(path) => {
+            // Opening a named pipe waits until something's reading it
+            if verbose {
+                println!("Opening '{}' for events...", path);
+            }
+            let file = std::fs::File::create(path).map_err(|error| {
+                OmnishockError::DeviceOpen(format!("couldn't create '{}': {}", path, error))
+            })?;
+            Some(EventStream::new(Box::new(file)))
+        }
+        None => None,
+    };
+
+    let mut spike_filter = command_arguments
+        .spike_filter
+        .map(|threshold| SpikeFilter::new(threshold, command_arguments.spike_frames));
+
+    let mut external_input = match command_arguments.input_fifo {
+        Some(ref path) => {
+            println!("Reading controller state from {}", path);
+            Some(ExternalInput::Fifo(
+                FifoController::open(path).map_err(OmnishockError::InvalidArguments)?,
+            ))
+        }
+        None => None,
+    };
+    #[cfg(all(unix, feature = "shm-input"))]
+    if let Some(ref name) = command_arguments.input_shm {
+        println!("Reading controller state from shared memory {}", name);
+        external_input = Some(ExternalInput::Shm(
+            ShmController::open(name).map_err(OmnishockError::InvalidArguments)?,
+        ));
+    }
+
+    let mut controller_selector = ControllerSelector::with_fallback(
+        command_arguments.controller.as_ref(),
+        std::env::var(CONTROLLER_ENV_VAR).ok().as_deref(),
+    )
+    .map_err(OmnishockError::InvalidArguments)?;
+    let mut controller_binding = ControllerBinding::default();
+    let mut controller_rotation = match command_arguments.rotate_every {
+        Some(period) if period <= 0.0 => {
+            return Err(OmnishockError::InvalidArguments(
+                "Controllers must take turns for more than zero seconds".to_string(),
+            ));
+        }
+        Some(period) => Some(ControllerRotation::new(period)),
+        None => None,
+    };
+
+    let mut anomaly_log = AnomalyLog::create(command_arguments.anomaly_log.as_deref())?;
+    let mut rumble_log = match command_arguments.rumble_log.as_deref() {
+        Some(path) => Some(
+            std::fs::File::create(path)
+                .and_then(|file| RumbleLog::new(Box::new(std::io::BufWriter::new(file))))
+                .map_err(|error| {
+                    OmnishockError::DeviceOpen(format!("couldn't create '{}': {}", path, error))
+                })?,
+        ),
+        None => None,
+    };
+
+    #[cfg(feature = "metrics")]
+    let metrics = match command_arguments.metrics_port {
+        Some(port) => {
+            let metrics = std::sync::Arc::new(metrics::Metrics::default());
+            let address = std::net::SocketAddr::new(command_arguments.metrics_addr, port);
+            metrics::serve(metrics.clone(), address)?;
+            println!("Serving metrics on {}", address);
+            Some(metrics)
+        }
+        None => None,
+    };
+
+    // Frames since the loop started, which turbo patterns run off
+    let mut frame_index: u64 = 0;
 
     'outer: loop {
         #[cfg(feature = "flamegraph-profiling")]
         let _outer_guard = flame::start_guard("frame");
         // Tick the "frame" timer and counters forward
-        sim_time = clock.tick(&game_time::step::FixedStep::new(&counter));
-        counter.tick(&sim_time);
+        sim_time = clock.tick();
+        frame_index += 1;
+        // Frames are meant to run long when blocking, so don't count them
+        let running_slow = sim_time.is_running_slow() && !command_arguments.blocking;
+
+        #[cfg(feature = "metrics")]
+        if let Some(ref metrics) = metrics {
+            metrics.set_frame_rate(sim_time.average_frame_rate());
+            if running_slow {
+                metrics::Metrics::increment(&metrics.slow_frames);
+            }
+        }
 
         if verbose {
             // If we're `--verbose`, we print out stats for every iteration
@@ -643,13 +2464,25 @@ fn send_to_ps2_controller_emulator_via<I: Read + Write>(
                 "Frame @ {:.2} ({:.2}ms, {:}fps avg / {:.2}fps target, slow: {})",
                 sim_time.total_wall_time(),
                 sim_time.elapsed_wall_time().as_milliseconds(),
-                counter.average_frame_rate(),
+                sim_time.average_frame_rate(),
                 sim_time.instantaneous_frame_rate(),
-                counter.is_running_slow(&sim_time),
+                sim_time.is_running_slow(),
             );
-        } else if counter.is_running_slow(&sim_time)
-            && sim_time.elapsed_wall_time() > warning_threshold
-        {
+        }
+
+        if running_slow {
+            anomaly_log.record(
+                "slow frame",
+                &format!(
+                    "{:.2}ms, {:.2}fps avg",
+                    sim_time.elapsed_wall_time().as_milliseconds(),
+                    sim_time.average_frame_rate()
+                ),
+                &[],
+            );
+        }
+
+        if !verbose && running_slow && sim_time.elapsed_wall_time() > warning_threshold {
             // If we're not `--verbose`, and in a debug build, we print out
             // stats only on slow iterations
             #[cfg(debug_assertions)]
@@ -657,14 +2490,47 @@ fn send_to_ps2_controller_emulator_via<I: Read + Write>(
                 "Warning: slow frame @ {:.2} ({:.2}ms, {:.2}fps avg / {:}fps target)",
                 sim_time.total_wall_time(),
                 sim_time.elapsed_wall_time().as_milliseconds(),
-                counter.average_frame_rate(),
+                sim_time.average_frame_rate(),
                 sim_time.instantaneous_frame_rate(),
             );
         }
 
+        if running_slow
+            && sim_time.elapsed_wall_time() > warning_threshold
+            && command_arguments.alert_on_slow != SlowAlert::None
+            && slow_alert_limiter.due(sim_time.total_wall_time().as_seconds())
+        {
+            match command_arguments.alert_on_slow {
+                SlowAlert::Bell => {
+                    print!("\x07");
+                    let _ = std::io::stdout().flush();
+                }
+                SlowAlert::Rumble => {
+                    if let Some((id, _)) =
+                        controller_selector.choose(&sdl_manager.active_controllers)
+                    {
+                        if let Some(controller) = sdl_manager.active_controllers.get_mut(&id) {
+                            rumble_pulse(controller);
+                            sent_rumble.remove(&id);
+                        }
+                    }
+                }
+                SlowAlert::None => {}
+            }
+        }
+
+        // When blocking, the frame starts by sleeping until there's an event
+        let waited_event = if command_arguments.blocking {
+            #[cfg(feature = "flamegraph-profiling")]
+            let _wait_guard = flame::start_guard("blocking wait");
+            event_pump.wait_event_timeout(BLOCKING_WAIT_MS)
+        } else {
+            None
+        };
+
         // Now that we've said we're restarting the frame,
         // let's iterate over controller events we've got from SDL2
-        for event in event_pump.poll_iter() {
+        for event in waited_event.into_iter().chain(event_pump.poll_iter()) {
             use sdl2::event::Event;
 
             match event {
@@ -673,11 +2539,28 @@ fn send_to_ps2_controller_emulator_via<I: Read + Write>(
                     let _guard = flame::start_guard("Event::ControllerDeviceAdded");
                     if !sdl_manager.has_controller(which).ok().unwrap_or(true) {
                         match sdl_manager.add_controller(which) {
-                            Ok(_) => {
+                            Ok(id) => {
                                 println!(
                                     "(There are {} controllers connected)",
                                     sdl_manager.active_controllers.len()
                                 );
+
+                                if verbose {
+                                    if let Some(description) = sdl_manager.describe_mapping(id) {
+                                        println!("{}", description);
+                                    }
+                                }
+
+                                #[cfg(feature = "metrics")]
+                                if let Some(ref metrics) = metrics {
+                                    metrics::Metrics::increment(&metrics.reconnects);
+                                }
+
+                                anomaly_log.record(
+                                    "reconnect",
+                                    &format!("controller #{} connected", which),
+                                    &[],
+                                );
                             }
                             Err(error) => println!(
                                 "could not initialise connected joystick {}: {:?}",
@@ -703,761 +2586,3907 @@ fn send_to_ps2_controller_emulator_via<I: Read + Write>(
             }
         }
 
-        // Now that we've kept track of controller additions & removals,
-        // post an update for the one controller we currently care about.
-        if let Some(controller) = sdl_manager.active_controllers.get_mut(&0) {
-            let response = send_event_to_controller(
-                &mut serial,
-                controller,
-                &communication_mode,
-                trigger_mode,
-                normalise_sticks,
-                verbose,
-            )?;
-
-            // If we've receieved a response from the controller,
-            // try updating its haptic state
-            if !response.is_empty() {
-                let small_motor_intensity = u16::from(response[1]) * U8_TO_U16_MAGNITUDE;
-                let large_motor_intensity = u16::from(response[2]) * U8_TO_U16_MAGNITUDE;
+        // Ctrl-C ends the session too, but isn't a clean finish
+        if interrupted() {
+            interrupted_by_user = true;
+            break 'outer;
+        }
 
-                if verbose {
-                    println!(
-                        "“{}”: Setting rumble to ({},{})",
-                        controller.name(),
-                        small_motor_intensity,
-                        large_motor_intensity
-                    );
+        // Pick up any option changes typed in since the last frame
+        if let Some(ref commands) = interactive_commands {
+            for command in commands.try_iter() {
+                match apply_interactive_command(&mut mapping_options, &command) {
+                    Ok(message) => println!("{}", message),
+                    Err(error) => println!("{}", error),
                 }
+            }
+        }
 
-                // We don't care if `set_rumble` actually worked,
-                // because if it's unsupported, it won't break anything,
-                // so we just ignore the result entirely here.
-                #[allow(unused_must_use)]
-                {
-                    controller.set_rumble(small_motor_intensity, large_motor_intensity, 500);
-                }
+        // Move on to the next controller once this one's turn is up
+        if let Some(ref mut controller_rotation) = controller_rotation {
+            if let Some(selector) = controller_rotation.update(
+                &controller_selector,
+                &sdl_manager.active_controllers,
+                sim_time.total_wall_time().as_seconds(),
+            ) {
+                println!("Next controller's turn!");
+                controller_selector = selector;
             }
         }
 
+        // Say whenever a different controller (or none) matches the selector,
+        // which is how a controller connected after start-up gets picked up
+        if let (None, &Some(ControllerPair(first_id, second_id))) =
+            (&external_input, &command_arguments.merge)
         {
-            #[cfg(feature = "flamegraph-profiling")]
-            let _sleep_guard = flame::start_guard("post-frame sleep");
-            // Having run all our processing for this iteration, accurately sleep
-            // until we need to process the next one
-            clock.sleep_remaining_via(&counter, |rem| spin_sleeper.sleep(rem.to_std().unwrap()));
-        };
-    }
-
-    Ok(())
-}
-
-fn send_event_to_controller<I: Read + Write, T: GameController>(
-    serial: &mut I,
-    controller: &T,
-    communication_mode: &ControllerEmulatorPacketType,
-    trigger_mode: &TriggerMode,
-    normalise_sticks: bool,
-    verbose: bool,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("send_event_to_controller()");
-    let sent;
-    let mut bytes_received = 0;
-    let mut received = vec![0; 4];
+            if let Some(message) = controller_binding.update_merged(
+                first_id,
+                second_id,
+                &sdl_manager.active_controllers,
+            ) {
+                println!("{}", message);
+
+                if let Some(ref mut spike_filter) = spike_filter {
+                    spike_filter.reset();
+                }
+            }
+        } else if command_arguments.merge.is_none() && external_input.is_none() {
+            if command_arguments.sticky {
+                if let Some(selector) = controller_selector.pinned(&sdl_manager.active_controllers)
+                {
+                    println!("Sticking with the controller matching {}", selector);
+                    controller_selector = selector;
+                }
+            }
 
-    match *communication_mode {
-        ControllerEmulatorPacketType::None => {
-            #[cfg(feature = "flamegraph-profiling")]
-            let _guard = flame::start_guard("ControllerEmulatorPacketType::None");
-            sent = controller_map_twenty_byte(controller, trigger_mode, normalise_sticks);
-        }
+            if let Some(message) =
+                controller_binding.update(&controller_selector, &sdl_manager.active_controllers)
+            {
+                println!("{}", message);
 
-        ControllerEmulatorPacketType::SevenByte => {
-            #[cfg(feature = "flamegraph-profiling")]
-            let _guard = flame::start_guard("ControllerEmulatorPacketType::SevenByte");
-            let state = controller_map_seven_byte(controller, trigger_mode, normalise_sticks);
+                // A different controller's axes are bound to jump
+                if let Some(ref mut spike_filter) = spike_filter {
+                    spike_filter.reset();
+                }
 
-            {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("serial write");
-                serial.write_all(&state)?;
-            };
-            bytes_received = {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("serial read");
-                match serial.read(&mut received) {
-                    Ok(bytes) => bytes,
-                    Err(error) => {
-                        if verbose {
-                            println!("Error reading response: {}", error);
+                if command_arguments.confirm_rumble {
+                    if let Some((id, _)) =
+                        controller_selector.choose(&sdl_manager.active_controllers)
+                    {
+                        if let Some(controller) = sdl_manager.active_controllers.get_mut(&id) {
+                            rumble_pulse(controller);
+                            sent_rumble.remove(&id);
+                            feedback_until =
+                                sim_time.total_wall_time().as_seconds() + COMBO_FEEDBACK_LENGTH;
                         }
-                        0
                     }
                 }
-            };
-
-            if received[0] != (SEVEN_BYTE_OK_RESPONSE as u8) {
-                println!("WARNING: Adapter responded with an error status.")
             }
-
-            sent = state;
         }
 
-        ControllerEmulatorPacketType::TwentyByte => {
-            #[cfg(feature = "flamegraph-profiling")]
-            let _guard = flame::start_guard("ControllerEmulatorPacketType::TwentyByte");
-            let state = controller_map_twenty_byte(controller, trigger_mode, normalise_sticks);
+        // Whether a button's held on whatever the packet's built from
+        let held = |button| match (&external_input, &command_arguments.merge) {
+            (Some(input), _) => input.button(button),
+            (None, &Some(ControllerPair(first_id, second_id))) => [first_id, second_id]
+                .iter()
+                .filter_map(|id| sdl_manager.active_controllers.get(id))
+                .any(|controller| controller.button(button)),
+            (None, &None) => controller_selector
+                .choose(&sdl_manager.active_controllers)
+                .is_some_and(|(_, controller)| controller.button(button)),
+        };
+        // Read now, while nothing else is borrowing the controllers
+        let snapshot_pressed = command_arguments.snapshot_button.is_some_and(&held);
 
-            {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("serial write");
-                serial.write_all(&state)?;
-            };
-            bytes_received = {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("serial read");
-                match serial.read(&mut received) {
-                    Ok(bytes) => bytes,
-                    Err(error) => {
-                        if verbose {
-                            println!("Error reading response: {}", error);
+        let paused = match command_arguments.pause_button {
+            Some(button) => {
+                let pressed = held(button);
+                let was_paused = pause_toggle.active;
+                let paused = pause_toggle.update(pressed, sim_time.total_wall_time().as_seconds());
+
+                if paused && !was_paused {
+                    println!(
+                        "Paused: sending a neutral state until --pause-button is pressed again"
+                    );
+                } else if was_paused && !paused {
+                    println!("Resumed");
+                }
+                paused
+            }
+            None => false,
+        };
+
+        let toggled_options;
+        let frame_mapping_options = match (command_arguments.deadzone_toggle, &deadzone_profile) {
+            (Some(button), Some(deadzone_profile)) => {
+                let pressed = held(button);
+                let was_active = deadzone_toggle.active;
+
+                if deadzone_toggle.update(pressed, sim_time.total_wall_time().as_seconds()) {
+                    if !was_active {
+                        println!("Switched to the --deadzone-profile stick settings");
+                    }
+                    // Laid over the live options, so interactive changes carry over
+                    let mut options = mapping_options.clone();
+                    deadzone_profile.apply_stick_settings(&mut options);
+                    toggled_options = options;
+                    &toggled_options
+                } else {
+                    if was_active {
+                        println!("Switched back to the usual stick settings");
+                    }
+                    &mapping_options
+                }
+            }
+            _ => &mapping_options,
+        };
+
+        // Now that we've kept track of controller additions & removals,
+        // build an update for the controller(s) we currently care about.
+        let packet = match (&mut external_input, &command_arguments.merge) {
+            // Nothing's sent until the first frame's been written
+            (Some(input), _) => {
+                if input.poll() {
+                    Some(build_filtered_packet(
+                        input,
+                        &mut spike_filter,
+                        &communication_mode,
+                        frame_mapping_options,
+                    ))
+                } else {
+                    None
+                }
+            }
+            (None, &Some(ControllerPair(first_id, second_id))) => {
+                let controllers = &sdl_manager.active_controllers;
+                match (controllers.get(&first_id), controllers.get(&second_id)) {
+                    (Some(first), Some(second)) => Some(build_filtered_packet(
+                        &MergedController::new(first, second),
+                        &mut spike_filter,
+                        &communication_mode,
+                        frame_mapping_options,
+                    )),
+                    _ => None,
+                }
+            }
+            (None, &None) => controller_selector
+                .choose(&sdl_manager.active_controllers)
+                .map(|(_, controller)| {
+                    build_filtered_packet(
+                        controller,
+                        &mut spike_filter,
+                        &communication_mode,
+                        frame_mapping_options,
+                    )
+                }),
+        };
+
+        // Look for combos held on the controller itself
+        let now = sim_time.total_wall_time().as_seconds();
+        for event in combo_watcher.update(packet.as_deref(), now) {
+            match event {
+                ComboEvent::Started(action) => {
+                    println!("Keep holding to {}...", action.describe());
+                    feedback_until = now + COMBO_FEEDBACK_LENGTH;
+
+                    if let Some((id, _)) =
+                        controller_selector.choose(&sdl_manager.active_controllers)
+                    {
+                        if let Some(controller) = sdl_manager.active_controllers.get_mut(&id) {
+                            rumble_pulse(controller);
+                            // The pulse replaces whatever rumble was playing
+                            sent_rumble.remove(&id);
                         }
-                        0
                     }
                 }
-            };
+                ComboEvent::Fired(ComboAction::Quit) => {
+                    println!("Quit combo held, finishing up");
+                    break 'outer;
+                }
+                ComboEvent::Fired(ComboAction::ResetStats) => {
+                    println!("Resetting frame rate average");
+                    clock.reset_average();
+                }
+            }
+        }
+
+        let (mut packet, idle) = shaper.shape(packet, &sim_time, frame_index, paused);
 
-            sent = state;
+        if let (Some(ref mut status_line), Some(ref packet)) = (&mut status_line, &packet) {
+            status_line.show(packet, sim_time.total_wall_time().as_seconds());
         }
-    };
 
-    received.truncate(bytes_received);
+        if snapshot_pressed && !snapshot_button_held {
+            match packet.as_deref().map(snapshot::save) {
+                Some(Ok(path)) => println!("Saved a snapshot to {}", path),
+                Some(Err(error)) => println!("Couldn't save a snapshot: {}", error),
+                None => println!("Nothing's being sent, so there's no snapshot to save"),
+            }
+        }
+        snapshot_button_held = snapshot_pressed;
 
-    if verbose {
-        println!("Sent: {:x}", HexView::from(&sent));
+        if let (Some(ref mut stream), Some(ref packet)) = (&mut event_stream, &packet) {
+            if let Err(error) = stream.update(packet) {
+                // Most likely whatever was reading has gone away
+                println!("Stopped writing events: {}", error);
+                event_stream = None;
+            }
+        }
 
-        if bytes_received > 0 {
-            println!("Received: {:x}", HexView::from(&received));
+        if command_arguments.poll_only {
+            packet = Some(neutral.clone());
         }
-    }
 
-    Ok(received)
-}
+        let packet = shaper.finish(packet, &sim_time, frame_index, running_slow, idle);
 
-fn print_events(
-    _arguments: &CLIArgs,
-    sdl_manager: &mut SDLManager,
-) -> Result<(), Box<dyn std::error::Error>> {
-    #[cfg(feature = "flamegraph-profiling")]
-    let _guard = flame::start_guard("print_events()");
-    println!("Printing all controller events...");
+        let response = match packet {
+            Some(packet) => {
+                let result = if command_arguments.verify_echo {
+                    send_checking_echo(&mut serial, &packet, verbose).map(|echo| {
+                        if let Some(mismatch) = describe_echo_mismatch(&packet, &echo) {
+                            println!("WARNING: {}", mismatch);
+                            anomaly_log.record("echo mismatch", &mismatch, &echo);
+                        }
+                        // Firmware which echoes has no rumble to tell us about
+                        None
+                    })
+                } else {
+                    send_event_to_controller(&mut serial, &packet, &communication_mode, verbose)
+                        .map(Some)
+                };
 
-    for event in sdl_manager.context.event_pump()?.wait_iter() {
-        use sdl2::event::Event;
+                // Without a protocol, nothing's actually written to the device
+                #[cfg(feature = "metrics")]
+                if let Some(ref metrics) = metrics {
+                    match result {
+                        Ok(_)
+                            if command_arguments.verify_echo
+                                || communication_mode != ControllerEmulatorPacketType::None =>
+                        {
+                            metrics::Metrics::increment(&metrics.frames_sent)
+                        }
+                        Ok(_) => (),
+                        Err(_) => metrics::Metrics::increment(&metrics.serial_errors),
+                    }
+                }
 
-        match event {
-            Event::ControllerDeviceAdded { which, .. } => {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("Event::ControllerDeviceAdded");
-                if !sdl_manager.has_controller(which).ok().unwrap_or(true) {
-                    match sdl_manager.add_controller(which) {
-                        Ok(_) => {
-                            println!(
-                                "(There are {} controllers connected)",
-                                sdl_manager.active_controllers.len()
-                            );
+                match result {
+                    Ok(Some(ref response)) => {
+                        if let Some(anomaly) = response_anomaly(&communication_mode, response) {
+                            anomaly_log.record(anomaly, "after sending packet", response);
                         }
-                        Err(error) => println!(
-                            "could not initialise connected joystick {}: {:?}",
-                            which, error
-                        ),
-                    };
+                    }
+                    Ok(None) => (),
+                    Err(ref error) => anomaly_log.record(
+                        if error.is_timeout() {
+                            "device timeout"
+                        } else {
+                            "device error"
+                        },
+                        &error.to_string(),
+                        &packet,
+                    ),
                 }
-            }
 
-            Event::ControllerDeviceRemoved { which, .. } => {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("Event::ControllerDeviceRemoved");
-                if sdl_manager.remove_controller(which).is_some() {
-                    println!(
-                        "(There are {} controllers connected)",
-                        sdl_manager.active_controllers.len()
-                    );
-                };
+                // A missed response has been logged, and only
+                // losing the device altogether ends the session
+                match result {
+                    Err(ref error) if error.is_timeout() => None,
+                    result => result?,
+                }
             }
+            None => None,
+        };
 
-            Event::ControllerAxisMotion {
-                which, axis, value, ..
-            } => {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("Event::ControllerAxisMotion");
-                println!(
-                    "“{}” (#{}): {:?}: {}",
-                    sdl_manager.active_controllers[&which].name(),
-                    which,
-                    axis,
-                    value
-                );
-
-                if let Some(controller) = sdl_manager.active_controllers.get_mut(&which) {
-                    #[cfg(feature = "flamegraph-profiling")]
-                    let _guard = flame::start_guard("set rumble");
+        // If we've receieved a response from the controller,
+        // try updating its haptic state
+        if let Some(mut response) = response {
+            if command_arguments.poll_only {
+                println!("{}", describe_rumble_response(&response));
+            }
 
-                    println!("“{}”: Rumbling", controller.name());
+            if let Some(ref mut rumble_limiter) = rumble_limiter {
+                rumble_limiter.apply(&mut response, sim_time.total_wall_time().as_seconds());
+            }
 
-                    // We don't care if `set_rumble` actually worked,
-                    // because if it's unsupported, it won't break anything,
-                    // so we just ignore the result entirely here.
-                    #[allow(unused_must_use)]
-                    {
-                        controller.set_rumble(0xFFFF, 0xFFFF, 500);
+            if response.len() >= ControllerEmulatorPacketType::TwentyByte.response_length() {
+                if let Some(ref mut log) = rumble_log {
+                    let now = sim_time.total_wall_time().as_seconds();
+                    if let Err(error) = log.record(now, response[1], response[2]) {
+                        println!("Stopped writing rumble log: {}", error);
+                        rumble_log = None;
                     }
-                };
+                }
             }
 
-            Event::ControllerButtonDown { which, button, .. } => {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("Event::ControllerButtonDown");
-                println!(
-                    "“{}” (#{}): {:?}: down",
-                    sdl_manager.active_controllers[&which].name(),
-                    which,
-                    button
-                );
+            let rumble_ids = match command_arguments.merge {
+                Some(ControllerPair(first_id, second_id)) => vec![first_id, second_id],
+                None => controller_selector
+                    .choose(&sdl_manager.active_controllers)
+                    .map(|(id, _)| id)
+                    .into_iter()
+                    .collect(),
+            };
+
+            let now = sim_time.total_wall_time().as_seconds();
+            if now >= feedback_until {
+                for id in rumble_ids {
+                    if let Some(controller) = sdl_manager.active_controllers.get_mut(&id) {
+                        let last_sent = sent_rumble.get(&id).copied();
+                        let gamma = command_arguments
+                            .rumble_curve
+                            .or_else(|| profile.rumble_curve(controller.usb_id()))
+                            .unwrap_or(DEFAULT_RUMBLE_CURVE);
+                        if let Some(sent) =
+                            update_rumble(controller, &response, last_sent, gamma, now, verbose)
+                        {
+                            sent_rumble.insert(id, sent);
+                        }
+                    }
+                }
             }
+        }
 
-            Event::ControllerButtonUp { which, button, .. } => {
-                #[cfg(feature = "flamegraph-profiling")]
-                let _guard = flame::start_guard("Event::ControllerButtonUp");
-                println!(
-                    "“{}” (#{}): {:?}: up",
-                    sdl_manager.active_controllers[&which].name(),
-                    which,
-                    button
-                );
+        {
+            #[cfg(feature = "flamegraph-profiling")]
+            let _sleep_guard = flame::start_guard("post-frame sleep");
+            // Having run all our processing for this iteration, accurately sleep
+            // until we need to process the next one. When blocking, the wait
+            // for the next event does that instead.
+            if !command_arguments.blocking {
+                clock.sleep_remaining();
             }
+        }
+    }
 
-            Event::Quit { .. } => break,
-            _ => (),
+    // Let go of everything, so nothing's left held down on the console
+    let mut released = neutral;
+    shaper.polarity.apply(&mut released);
+    ignoring_timeout(send_event_to_controller(
+        &mut serial,
+        &released,
+        &communication_mode,
+        verbose,
+    ))?;
+
+    if let Some(ref mut log) = rumble_log {
+        if let Err(error) = log.flush() {
+            println!("Couldn't finish writing rumble log: {}", error);
         }
     }
 
-    Ok(())
+    // The other ways out of the loop are quitting, or holding the combo
+    if interrupted_by_user {
+        Err(OmnishockError::UserAbort)
+    } else {
+        Ok(())
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate mockstream;
+// A controller driven by another program, rather than read through SDL
+enum ExternalInput {
+    Fifo(FifoController),
+    #[cfg(all(unix, feature = "shm-input"))]
+    Shm(ShmController),
+}
 
-    #[test]
-    fn convert_half_axis_positive_is_accurate() {
-        use super::convert_half_axis_positive;
+impl ExternalInput {
+    // Takes up the latest state written, returning whether there's any yet
+    fn poll(&mut self) -> bool {
+        match self {
+            ExternalInput::Fifo(fifo) => fifo.poll(),
+            #[cfg(all(unix, feature = "shm-input"))]
+            ExternalInput::Shm(shm) => shm.poll(),
+        }
+    }
 
-        assert_eq!(
-            convert_half_axis_positive(i16::min_value()),
-            i16::min_value()
-        );
-        assert_eq!(convert_half_axis_positive(0), i16::min_value());
-        assert_eq!(convert_half_axis_positive(i16::max_value() / 2 + 1), 0);
-        assert_eq!(
-            convert_half_axis_positive(i16::max_value()),
-            i16::max_value()
-        );
+    fn controller(&self) -> &dyn GameController {
+        match self {
+            ExternalInput::Fifo(fifo) => fifo,
+            #[cfg(all(unix, feature = "shm-input"))]
+            ExternalInput::Shm(shm) => shm,
+        }
     }
 
-    #[test]
-    fn convert_half_axis_negative_is_accurate() {
-        use super::convert_half_axis_negative;
+    fn controller_mut(&mut self) -> &mut dyn GameController {
+        match self {
+            ExternalInput::Fifo(fifo) => fifo,
+            #[cfg(all(unix, feature = "shm-input"))]
+            ExternalInput::Shm(shm) => shm,
+        }
+    }
+}
 
-        assert_eq!(
-            convert_half_axis_negative(i16::max_value()),
-            i16::min_value()
-        );
-        assert_eq!(convert_half_axis_negative(0), i16::min_value());
-        assert_eq!(convert_half_axis_negative(i16::min_value() / 2 - 1), 0);
-        assert_eq!(
-            convert_half_axis_negative(i16::min_value()),
-            i16::max_value()
-        );
+impl GameController for ExternalInput {
+    fn name(&self) -> String {
+        self.controller().name()
     }
 
-    #[test]
-    fn whats_the_midpoint_of_a_is_accurate() {
-        #![allow(clippy::float_cmp)]
-        use super::whats_the_midpoint_of_a;
+    fn button(&self, button: sdl2::controller::Button) -> bool {
+        self.controller().button(button)
+    }
 
-        assert_eq!(whats_the_midpoint_of_a::<u8>(), 127_u8);
-        assert_eq!(
-            whats_the_midpoint_of_a::<u64>(),
-            9_223_372_036_854_775_807_u64
-        );
-        assert_eq!(whats_the_midpoint_of_a::<i16>(), 0_i16);
-        assert_eq!(whats_the_midpoint_of_a::<i64>(), 0_i64);
-        // NOTE: This would normally trip the `clippy::float_cmp` rule,
-        //       which is why we've explicitly allowed it above.
-        assert_eq!(whats_the_midpoint_of_a::<f32>(), 0_f32);
+    fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
+        self.controller().axis(axis)
     }
 
-    #[test]
-    fn convert_button_to_analog_is_accurate() {
-        use super::convert_button_to_analog;
+    fn has_rumble(&self) -> bool {
+        self.controller().has_rumble()
+    }
 
-        assert_eq!(convert_button_to_analog::<u8>(true), u8::max_value());
-        assert_eq!(convert_button_to_analog::<i64>(true), i64::max_value());
-        assert_eq!(convert_button_to_analog::<u8>(false), u8::min_value());
-        assert_eq!(convert_button_to_analog::<i64>(false), i64::min_value());
+    fn attached(&self) -> bool {
+        self.controller().attached()
     }
 
-    #[test]
-    fn convert_analog_to_button_is_accurate() {
-        use super::convert_analog_to_button;
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        self.controller().usb_id()
+    }
 
-        assert!(!convert_analog_to_button(127u8));
-        assert!(convert_analog_to_button(128u8));
+    fn guid(&self) -> Option<String> {
+        self.controller().guid()
+    }
 
-        assert!(convert_analog_to_button(u8::max_value()));
-        assert!(convert_analog_to_button(i64::max_value()));
-        assert!(!convert_analog_to_button(u8::min_value()));
-        assert!(!convert_analog_to_button(i64::min_value()));
+    fn touchpad_finger(&self, index: u8) -> Option<(f32, f32)> {
+        self.controller().touchpad_finger(index)
     }
 
-    use crate::sdl_manager::GameController;
-    use std::collections::HashMap;
+    fn set_rumble(
+        &mut self,
+        low_frequency_rumble: u16,
+        high_frequency_rumble: u16,
+        duration_ms: u32,
+    ) -> Result<(), String> {
+        self.controller_mut()
+            .set_rumble(low_frequency_rumble, high_frequency_rumble, duration_ms)
+    }
+}
 
-    struct FauxController {
-        name: String,
-        buttons: HashMap<sdl2::controller::Button, bool>,
-        axes: HashMap<sdl2::controller::Axis, i16>,
+// Builds a packet from `controller`, through the spike filter if there is one
+fn build_filtered_packet<T: GameController>(
+    controller: &T,
+    spike_filter: &mut Option<SpikeFilter>,
+    communication_mode: &ControllerEmulatorPacketType,
+    mapping_options: &MappingOptions,
+) -> Vec<u8> {
+    match spike_filter {
+        Some(spike_filter) => {
+            spike_filter.update(controller);
+            build_packet(
+                &FilteredController::new(controller, spike_filter),
+                communication_mode,
+                mapping_options,
+            )
+        }
+        None => build_packet(controller, communication_mode, mapping_options),
     }
+}
+
+fn send_test_pattern(
+    arguments: &CLIArgs,
+    command_arguments: &TestPatternSubcommand,
+) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("send_test_pattern()");
+
+    let verbose = arguments.verbose;
+    let mut serial = open_device(&command_arguments.device, verbose)?;
+    let communication_mode = detect_communication_mode(
+        &mut serial,
+        &NEUTRAL_PACKET,
+        unrecognised_fallback(
+            command_arguments.default_mode,
+            command_arguments.allow_unknown,
+        ),
+        command_arguments.detect_retries,
+        verbose,
+    )?;
+
+    // We want to see the real extents of each axis, so nothing is normalised
+    let mapping_options = MappingOptions::new(TriggerMode::Normal, false);
+
+    let frame_rate = 60.0;
+    let frames_per_step = std::cmp::max(
+        1,
+        (f64::from(command_arguments.dwell_ms) * frame_rate / 1000.0) as u32,
+    );
+    let pattern = test_pattern::test_pattern(frames_per_step);
+
+    let mut clock = WallClock::new(frame_rate);
+
+    loop {
+        let mut last_label = None;
 
-    impl FauxController {
-        fn create_with_name(name: String) -> FauxController {
-            let buttons = HashMap::new();
-            let axes = HashMap::new();
-            FauxController {
-                name,
-                buttons,
-                axes,
+        for (label, controller) in &pattern {
+            clock.tick();
+
+            if last_label != Some(label) {
+                println!("{}...", label);
+                last_label = Some(label);
             }
+
+            let packet = build_packet(controller, &communication_mode, &mapping_options);
+            ignoring_timeout(send_event_to_controller(
+                &mut serial,
+                &packet,
+                &communication_mode,
+                verbose,
+            ))?;
+
+            clock.sleep_remaining();
         }
 
-        fn set_button(&mut self, button: sdl2::controller::Button, value: bool) {
-            self.buttons.insert(button, value);
+        if !command_arguments.repeat {
+            break;
         }
+    }
+
+    // Don't leave the last axis held at its extreme
+    ignoring_timeout(send_event_to_controller(
+        &mut serial,
+        &neutral_packet(&communication_mode),
+        &communication_mode,
+        verbose,
+    ))?;
+
+    Ok(())
+}
+
+// The line --verbose logs for a sent packet, which raw packet logs
+// are made of too
+fn sent_line(packet: &[u8]) -> String {
+    format!("Sent: {:x}", HexView::from(packet))
+}
+
+// Reads the packet back out of a "Sent: 5affff..." log line, as written
+// by `HexView`: two hex digits per byte, with nothing between them.
+// Returns `None` for lines which aren't about sent packets at all.
+fn parse_sent_line(line: &str) -> Option<Result<Vec<u8>, String>> {
+    let (_, digits) = line.split_once("Sent:")?;
+    let digits = digits.trim();
+
+    if digits.is_empty() {
+        return Some(Err("no bytes".to_string()));
+    }
+    if !digits.is_ascii() || digits.len() % 2 != 0 {
+        return Some(Err(format!("'{}' isn't a whole number of bytes", digits)));
+    }
+
+    Some(
+        (0..digits.len())
+            .step_by(2)
+            .map(|index| {
+                let byte = &digits[index..index + 2];
+                u8::from_str_radix(byte, 16)
+                    .map_err(|error| format!("unexpected byte '{}': {}", byte, error))
+            })
+            .collect(),
+    )
+}
 
-        fn set_axis(&mut self, axis: sdl2::controller::Axis, value: i16) {
-            self.axes.insert(axis, value);
+// Lays packets out in a file, taking `rate` packets a second
+fn encode_packet_log(
+    packets: &[Vec<u8>],
+    format: PacketLogFormat,
+    packet_type: ControllerEmulatorPacketType,
+    rate: f64,
+) -> Result<Vec<u8>, String> {
+    match format {
+        PacketLogFormat::Raw => Ok(packets
+            .iter()
+            .map(|packet| sent_line(packet) + "\n")
+            .collect::<String>()
+            .into_bytes()),
+        PacketLogFormat::Delta => {
+            // The header only has room for a whole number of Hz
+            if rate.fract() != 0.0 || !(1.0..=f64::from(u16::MAX)).contains(&rate) {
+                return Err(format!(
+                    "A recording's rate must be a whole number of Hz, up to {}",
+                    u16::MAX
+                ));
+            }
+            let header = RecordingHeader::new(rate as u16, packet_type);
+
+            let frames: Vec<TimedPacket> = packets
+                .iter()
+                .enumerate()
+                .map(|(index, packet)| TimedPacket {
+                    at_ms: (index as f64 * 1000.0 / rate).round() as u64,
+                    packet: packet.clone(),
+                })
+                .collect();
+            encode_recording(&header, &frames)
         }
     }
+}
 
-    impl GameController for FauxController {
-        fn name(&self) -> String {
-            self.name.clone()
+// Reads the packets back out of a file, along with the recording's
+// header if it has one, skipping any "Sent:" lines which can't be read
+fn decode_packet_log(
+    contents: &[u8],
+    format: PacketLogFormat,
+) -> Result<(Option<RecordingHeader>, Vec<Vec<u8>>), String> {
+    match format {
+        PacketLogFormat::Raw => {
+            let log = std::str::from_utf8(contents)
+                .map_err(|_| "It isn't a text log; is it a delta recording?".to_string())?;
+
+            let mut packets = Vec::new();
+            for (index, line) in log.lines().enumerate() {
+                match parse_sent_line(line) {
+                    Some(Ok(packet)) => packets.push(packet),
+                    Some(Err(error)) => println!("Skipping line {}: {}", index + 1, error),
+                    None => (),
+                }
+            }
+            Ok((None, packets))
+        }
+        PacketLogFormat::Delta => {
+            let (header, frames) = decode_recording(contents)?;
+            Ok((
+                Some(header),
+                frames.into_iter().map(|frame| frame.packet).collect(),
+            ))
         }
+    }
+}
+
+// How fast to resend a log, checking --rate against the recording's
+// own rate when there is one
+fn resend_rate(header: Option<&RecordingHeader>, rate: Option<f64>) -> Result<f64, String> {
+    match (header, rate) {
+        (Some(header), Some(rate)) if rate != f64::from(header.rate_hz) => Err(format!(
+            "The recording was made at {}Hz, but --rate is {}Hz",
+            header.rate_hz, rate
+        )),
+        (Some(header), _) => Ok(f64::from(header.rate_hz)),
+        (None, rate) => Ok(rate.unwrap_or(60.0)),
+    }
+}
 
-        fn button(&self, button: sdl2::controller::Button) -> bool {
-            *self.buttons.get(&button).unwrap_or(&false)
+// Whether the device takes the packets a recording holds
+fn check_recording_format(
+    header: Option<&RecordingHeader>,
+    communication_mode: &ControllerEmulatorPacketType,
+) -> Result<(), String> {
+    match header {
+        Some(header)
+            if header.format != ControllerEmulatorPacketType::None
+                && *communication_mode != ControllerEmulatorPacketType::None
+                && header.format != *communication_mode =>
+        {
+            Err(format!(
+                "The recording is for the {}, but the device uses the {}",
+                header.format, communication_mode
+            ))
         }
+        _ => Ok(()),
+    }
+}
+
+fn convert_log(command_arguments: &ConvertSubcommand) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_log()");
+
+    if command_arguments.rate <= 0.0 {
+        return Err(OmnishockError::InvalidArguments(
+            "The rate must be more than zero".to_string(),
+        ));
+    }
+
+    let log = std::fs::read_to_string(&command_arguments.input)?;
+    let converted = convert_log_lines(&log, &command_arguments.to);
+
+    let output = encode_packet_log(
+        &converted,
+        command_arguments.format,
+        command_arguments.to,
+        command_arguments.rate,
+    )?;
+    std::fs::write(&command_arguments.output, output)?;
+
+    println!(
+        "Converted {} packets to the {}, as {}",
+        converted.len(),
+        command_arguments.to,
+        command_arguments.format
+    );
+
+    Ok(())
+}
 
-        fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
-            *self.axes.get(&axis).unwrap_or(&0)
+// Re-encodes each packet from the "Sent:" lines of a --verbose log as
+// `to`, skipping any which can't be read or converted
+fn convert_log_lines(log: &str, to: &ControllerEmulatorPacketType) -> Vec<Vec<u8>> {
+    let mut converted = Vec::new();
+    for (index, line) in log.lines().enumerate() {
+        match parse_sent_line(line).map(|packet| convert_packet(&packet?, to)) {
+            Some(Ok(packet)) => converted.push(packet),
+            Some(Err(error)) => println!("Skipping line {}: {}", index + 1, error),
+            None => (),
         }
+    }
+    converted
+}
 
-        fn set_rumble(
-            &mut self,
-            _low_frequency_rumble: u16,
-            _high_frequency_rumble: u16,
-            _duration_ms: u32,
-        ) -> Result<(), String> {
-            Ok(())
+// Checks a profile's contents, returning anything worth a warning,
+// or why it can't be used
+fn check_profile(contents: &str) -> Result<Vec<String>, String> {
+    Profile::from_str(contents)?;
+    Ok(Profile::repeated_settings(contents))
+}
+
+fn validate_profiles(command_arguments: &ValidateProfileSubcommand) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("validate_profiles()");
+
+    let mut failures = 0;
+    for path in &command_arguments.files {
+        let result = std::fs::read_to_string(path)
+            .map_err(|error| format!("couldn't read it: {}", error))
+            .and_then(|contents| check_profile(&contents));
+
+        match result {
+            Ok(warnings) => {
+                println!("{}: OK", path);
+                for warning in warnings {
+                    println!("  Warning: {}", warning);
+                }
+            }
+            Err(error) => {
+                println!("{}: FAILED, {}", path, error);
+                failures += 1;
+            }
         }
     }
 
-    #[test]
-    fn controller_map_twenty_byte_works() {
-        use super::controller_map_twenty_byte;
-        use super::{Buttons1, Buttons2, TriggerMode};
-        use crate::DUALSHOCK_MAGIC;
-        use sdl2::controller::{Axis, Button};
+    if failures > 0 {
+        return Err(OmnishockError::InvalidArguments(format!(
+            "{} of {} profiles failed validation",
+            failures,
+            command_arguments.files.len()
+        )));
+    }
 
-        let mut controller =
-            FauxController::create_with_name(String::from("Applejack Game-player Pad"));
+    Ok(())
+}
+
+fn resend_log(
+    arguments: &CLIArgs,
+    command_arguments: &ResendLogSubcommand,
+    mut sdl_manager: Option<&mut SDLManager>,
+) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("resend_log()");
+
+    if command_arguments.rate.is_some_and(|rate| rate <= 0.0) {
+        return Err(OmnishockError::InvalidArguments(
+            "The rate must be more than zero".to_string(),
+        ));
+    }
+
+    // Inputs are only turned into packets once the protocol's known
+    let (header, mut packets, queue) = if command_arguments.inputs {
+        let queue = InputQueue::load(&command_arguments.logfile)
+            .map_err(OmnishockError::InvalidArguments)?;
+        (None, Vec::new(), Some(queue))
+    } else {
+        let log = std::fs::read(&command_arguments.logfile)?;
+        let (header, packets) =
+            decode_packet_log(&log, command_arguments.format).map_err(|error| {
+                format!(
+                    "Couldn't read {} as a {} log: {}",
+                    command_arguments.logfile, command_arguments.format, error
+                )
+            })?;
+        (header, packets, None)
+    };
+    let rate = resend_rate(header.as_ref(), command_arguments.rate)
+        .map_err(OmnishockError::InvalidArguments)?;
+
+    println!(
+        "Resending {} packets from {}",
+        packets.len(),
+        command_arguments.logfile
+    );
+
+    let verbose = arguments.verbose;
+    let mut serial = open_device(&command_arguments.device, verbose)?;
+    let communication_mode =
+        detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, verbose)?;
+    check_recording_format(header.as_ref(), &communication_mode)
+        .map_err(OmnishockError::InvalidArguments)?;
+    if let Some(ref queue) = queue {
+        packets = queue
+            .frames
+            .iter()
+            .map(|frame| {
+                let mut packet = neutral_packet(&communication_mode);
+                frame.apply(&mut packet);
+                packet
+            })
+            .collect();
+    }
+
+    let mut event_pump = match sdl_manager {
+        Some(ref sdl_manager) => Some(
+            sdl_manager
+                .context
+                .event_pump()
+                .map_err(OmnishockError::Sdl)?,
+        ),
+        None => None,
+    };
+    let mut sent_rumble: Option<(u32, SentRumble)> = None;
+
+    let mut clock = WallClock::new(rate);
+    let stepper = (command_arguments.frame_stepped || command_arguments.inputs)
+        .then(|| FrameStepper::new(rate));
+    let spin_sleeper = spin_sleep::SpinSleeper::new(1_000_000);
+    let mut late_frames = 0;
+
+    for (index, packet) in packets.iter().enumerate() {
+        if interrupted() {
+            return Err(OmnishockError::UserAbort);
+        }
+        let frame_time = clock.tick();
+
+        // Every frame goes out however late it is, which the game
+        // will see, so that's worth a warning
+        if let Some(ref stepper) = stepper {
+            if let Some(lateness) = stepper.wait_for(index, &spin_sleeper) {
+                if late_frames == 0 {
+                    println!(
+                        "Warning: frame {} was {:.1}ms late; this machine can't keep up with \
+                         {}fps, so playback won't line up with the game's frames",
+                        index + 1,
+                        lateness.as_secs_f64() * 1000.0,
+                        rate
+                    );
+                }
+                late_frames += 1;
+            }
+        }
+
+        let response = ignoring_timeout(send_event_to_controller(
+            &mut serial,
+            packet,
+            &communication_mode,
+            verbose,
+        ))?;
+
+        if let (Some(sdl_manager), Some(event_pump), Some(selector)) = (
+            sdl_manager.as_deref_mut(),
+            event_pump.as_mut(),
+            &command_arguments.rumble_to,
+        ) {
+            // Keep up with controllers coming and going
+            for event in event_pump.poll_iter() {
+                match event {
+                    sdl2::event::Event::ControllerDeviceAdded { which, .. } => {
+                        if !sdl_manager.has_controller(which).ok().unwrap_or(true) {
+                            if let Err(error) = sdl_manager.add_controller(which) {
+                                println!(
+                                    "could not initialise connected joystick {}: {:?}",
+                                    which, error
+                                );
+                            }
+                        }
+                    }
+                    sdl2::event::Event::ControllerDeviceRemoved { which, .. } => {
+                        sdl_manager.remove_controller(which);
+                    }
+                    sdl2::event::Event::Quit { .. } => return Ok(()),
+                    _ => (),
+                }
+            }
+
+            if let Some((id, _)) = selector.choose(&sdl_manager.active_controllers) {
+                // A different controller hasn't been sent anything yet
+                let last_sent = sent_rumble
+                    .filter(|(sent_to, _)| *sent_to == id)
+                    .map(|(_, sent)| sent);
+                if let Some(controller) = sdl_manager.active_controllers.get_mut(&id) {
+                    if let Some(sent) = update_rumble(
+                        controller,
+                        &response,
+                        last_sent,
+                        DEFAULT_RUMBLE_CURVE,
+                        frame_time.total_wall_time().as_seconds(),
+                        verbose,
+                    ) {
+                        sent_rumble = Some((id, sent));
+                    }
+                }
+            }
+        }
+
+        if stepper.is_none() {
+            clock.sleep_remaining();
+        }
+    }
+
+    // Don't leave the last frame's buttons held
+    if queue.is_some() {
+        ignoring_timeout(send_event_to_controller(
+            &mut serial,
+            &neutral_packet(&communication_mode),
+            &communication_mode,
+            verbose,
+        ))?;
+    }
+
+    if late_frames > 0 {
+        println!(
+            "Warning: {} of {} frames were sent late",
+            late_frames,
+            packets.len()
+        );
+    }
+
+    Ok(())
+}
+
+const INTERACTIVE_HELP: &str = "Commands:
+  trigger-mode <normal|right-stick|cross-and-square>
+  stick-normalise <on|off>
+  trigger-pressure <on|off>
+  show
+  help";
+
+// Reads lines from standard input on another thread,
+// so the frame loop can pick them up without blocking
+fn spawn_stdin_reader() -> std::sync::mpsc::Receiver<String> {
+    use std::io::BufRead;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    receiver
+}
+
+fn parse_stick_radius(value: &str) -> Result<i16, String> {
+    value
+        .parse::<u16>()
+        .ok()
+        .and_then(|radius| i16::try_from(radius).ok())
+        .ok_or_else(|| "Expected a distance from 0 to 32767".to_string())
+}
+
+// Past 22.5 degrees, every direction's within reach of two of the eight
+fn parse_snap_angle(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(degrees) if (0.0..=22.5).contains(&degrees) => Ok(degrees),
+        _ => Err("Expected an angle from 0 to 22.5 degrees".to_string()),
+    }
+}
+
+// Like the packet format's own parsing, but "none" is allowed too
+fn parse_default_mode(value: &str) -> Result<ControllerEmulatorPacketType, String> {
+    match value {
+        "none" => Ok(ControllerEmulatorPacketType::None),
+        _ => value.parse(),
+    }
+}
+
+fn parse_percentage(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(percentage) if percentage <= 100 => Ok(percentage),
+        _ => Err("Expected a percentage from 0 to 100".to_string()),
+    }
+}
+
+fn parse_switch(value: Option<&str>) -> Result<bool, String> {
+    match value {
+        Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        _ => Err("Expected \"on\" or \"off\"".to_string()),
+    }
+}
+
+// Applies one line typed in interactive mode to the mapping options,
+// returning a message describing the result
+fn apply_interactive_command(
+    options: &mut MappingOptions,
+    command: &str,
+) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+
+    match words.next() {
+        Some("trigger-mode") => {
+            options.trigger_mode = words
+                .next()
+                .ok_or_else(|| "Expected a trigger mode".to_string())?
+                .to_lowercase()
+                .parse()?;
+            Ok(format!("Using trigger mode '{:?}'", options.trigger_mode))
+        }
+        Some("stick-normalise") => {
+            options.normalise_sticks = parse_switch(words.next())?;
+            Ok(format!("Stick normalisation: {}", options.normalise_sticks))
+        }
+        Some("trigger-pressure") => {
+            options.trigger_pressure = parse_switch(words.next())?;
+            Ok(format!("Trigger pressure: {}", options.trigger_pressure))
+        }
+        Some("show") => Ok(format!("{:?}", options)),
+        Some("help") => Ok(INTERACTIVE_HELP.to_string()),
+        Some(other) => Err(format!(
+            "Unknown command '{}' (type \"help\" for a list)",
+            other
+        )),
+        None => Err(INTERACTIVE_HELP.to_string()),
+    }
+}
+
+// Each face button, and which byte holds its pressure
+const FACE_BUTTONS: [(Buttons2, usize); 4] = [
+    (Buttons2::TRIANGLE, 11),
+    (Buttons2::CIRCLE, 12),
+    (Buttons2::CROSS, 13),
+    (Buttons2::SQUARE, 14),
+];
+
+// Ramps face button pressure up from nothing after each press
+struct SoftPress {
+    // Seconds taken to reach full pressure
+    ramp: f64,
+    // When each of `FACE_BUTTONS` started being pressed, in seconds
+    onsets: [Option<f64>; 4],
+}
+
+impl SoftPress {
+    fn new(ramp: f64) -> SoftPress {
+        SoftPress {
+            ramp,
+            onsets: [None; 4],
+        }
+    }
+
+    fn apply(&mut self, packet: &mut [u8], now: f64) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("SoftPress#apply()");
+
+        // Only the twenty-byte protocol carries pressure
+        if self.ramp <= 0.0 || packet.len() < TWENTY_BYTE_PACKET_LENGTH {
+            return;
+        }
+
+        // The DualShock protocol considers 0 to mean pressed
+        let pressed = Buttons2::from_bits_truncate(!packet[2]);
+
+        for ((button, pressure), onset) in FACE_BUTTONS.iter().zip(self.onsets.iter_mut()) {
+            if !pressed.contains(*button) {
+                *onset = None;
+                continue;
+            }
+
+            let held = now - *onset.get_or_insert(now);
+            let ramped = (held / self.ramp * f64::from(u8::max_value())).min(255.0) as u8;
+            packet[*pressure] = std::cmp::min(packet[*pressure], ramped);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AnalogGesture {
+    DoubleL3,
+}
+
+impl AnalogGesture {
+    fn variants() -> [&'static str; 1] {
+        ["double-l3"]
+    }
+}
+
+impl FromStr for AnalogGesture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "double-l3" => Ok(AnalogGesture::DoubleL3),
+            _ => Err("Unexpected analog gesture type".to_string()),
+        }
+    }
+}
+
+// How close together, in seconds, the presses of a double-tap must be
+const DOUBLE_TAP_WINDOW: f64 = 0.3;
+
+// Latches the analog mode footer on, or back off, with each double-tap of L3
+struct DoubleTap {
+    window: f64,
+    held: bool,
+    // When the first press of a possible double-tap happened, in seconds
+    first_press: Option<f64>,
+    latched: bool,
+}
+
+impl DoubleTap {
+    fn new(window: f64) -> DoubleTap {
+        DoubleTap {
+            window,
+            held: false,
+            first_press: None,
+            latched: false,
+        }
+    }
+
+    // Given whether the button is held at time `now`,
+    // returns whether the footer is currently latched
+    fn update(&mut self, pressed: bool, now: f64) -> bool {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("DoubleTap#update()");
+        if pressed && !self.held {
+            match self.first_press {
+                Some(first_press) if now - first_press <= self.window => {
+                    self.latched = !self.latched;
+                    self.first_press = None;
+                }
+                _ => self.first_press = Some(now),
+            }
+        }
+
+        self.held = pressed;
+        self.latched
+    }
+
+    fn apply(&mut self, packet: &mut [u8], now: f64) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("DoubleTap#apply()");
+
+        // Only the twenty-byte protocol has a mode footer
+        if packet.len() < TWENTY_BYTE_PACKET_LENGTH {
+            return;
+        }
+
+        // The DualShock protocol considers 0 to mean pressed
+        let pressed = Buttons1::from_bits_truncate(!packet[1]).contains(Buttons1::L3);
+
+        if self.update(pressed, now) {
+            packet[TWENTY_BYTE_PACKET_LENGTH - 1] = 0xAA;
+        }
+    }
+}
+
+// Holds the mode footer steady for a while after each change of it
+struct ModeCooldown {
+    // Seconds a new footer holds for before it can change again
+    cooldown: f64,
+    // The footer last sent, and when it was first sent
+    footer: Option<(u8, f64)>,
+}
+
+impl ModeCooldown {
+    fn new(cooldown: f64) -> ModeCooldown {
+        ModeCooldown {
+            cooldown,
+            footer: None,
+        }
+    }
+
+    // Given the footer wanted at time `now`, returns the footer to send
+    fn update(&mut self, wanted: u8, now: f64) -> u8 {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ModeCooldown#update()");
+        match self.footer {
+            Some((footer, since)) if footer != wanted && now - since < self.cooldown => footer,
+            Some((footer, _)) if footer == wanted => footer,
+            _ => {
+                self.footer = Some((wanted, now));
+                wanted
+            }
+        }
+    }
+
+    fn apply(&mut self, packet: &mut [u8], now: f64) {
+        // Only the twenty-byte protocol has a mode footer
+        if packet.len() < TWENTY_BYTE_PACKET_LENGTH {
+            return;
+        }
+
+        let footer = &mut packet[TWENTY_BYTE_PACKET_LENGTH - 1];
+        *footer = self.update(*footer, now);
+    }
+}
+
+// How often the twenty-byte firmware gets a neutral packet while idle
+const DEFAULT_IDLE_HEARTBEAT_FRAMES: u32 = 30;
+
+// Sends a neutral packet every so often when there's nothing else to send
+struct IdleHeartbeat {
+    // Frames between neutral packets, or 0 to never send them
+    every: u32,
+    idle_frames: u32,
+}
+
+impl IdleHeartbeat {
+    fn new(every: u32) -> IdleHeartbeat {
+        IdleHeartbeat {
+            every,
+            idle_frames: 0,
+        }
+    }
+
+    fn update(&mut self, packet: Option<Vec<u8>>, neutral: &[u8]) -> Option<Vec<u8>> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("IdleHeartbeat#update()");
+        if packet.is_some() || self.every == 0 {
+            self.idle_frames = 0;
+            return packet;
+        }
+
+        self.idle_frames += 1;
+
+        if self.idle_frames < self.every {
+            return None;
+        }
+
+        self.idle_frames = 0;
+        Some(neutral.to_vec())
+    }
+}
+
+struct PacketHold {
+    window: game_time::FloatDuration,
+    last: Option<(Vec<u8>, game_time::FloatDuration)>,
+}
+
+impl PacketHold {
+    fn new(window: game_time::FloatDuration) -> PacketHold {
+        PacketHold { window, last: None }
+    }
+
+    // Given this frame's packet (if we have a controller to build one from),
+    // decide what should actually be sent at time `now`
+    fn update(
+        &mut self,
+        packet: Option<Vec<u8>>,
+        now: game_time::FloatDuration,
+        neutral: &[u8],
+    ) -> Option<Vec<u8>> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("PacketHold#update()");
+        // With no window, we don't hold anything at all
+        if self.window <= game_time::FloatDuration::milliseconds(0.0) {
+            return packet;
+        }
+
+        match packet {
+            Some(packet) => {
+                self.last = Some((packet.clone(), now));
+                Some(packet)
+            }
+            None => match self.last.take() {
+                Some((packet, built_at)) => {
+                    if now - built_at <= self.window {
+                        self.last = Some((packet.clone(), built_at));
+                        Some(packet)
+                    } else {
+                        println!("Controller dropped out, releasing all inputs");
+                        Some(neutral.to_vec())
+                    }
+                }
+                None => None,
+            },
+        }
+    }
+}
+
+// Sends packets on a cadence of its own, rather than every frame,
+// always picking the most recent packet built since the last send
+struct SendThrottle {
+    // Seconds between sends, or 0 to send every frame
+    interval: f64,
+    next_send: f64,
+    pending: Option<Vec<u8>>,
+}
+
+impl SendThrottle {
+    fn new(interval: f64) -> SendThrottle {
+        SendThrottle {
+            interval,
+            next_send: 0.0,
+            pending: None,
+        }
+    }
+
+    fn update(&mut self, packet: Option<Vec<u8>>, now: f64) -> Option<Vec<u8>> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("SendThrottle#update()");
+        if packet.is_some() {
+            self.pending = packet;
+        }
+
+        if now < self.next_send {
+            return None;
+        }
+
+        // Keep to the cadence, but don't try to catch up after a stall
+        self.next_send = if now - self.next_send >= self.interval {
+            now + self.interval
+        } else {
+            self.next_send + self.interval
+        };
+
+        self.pending.take()
+    }
+}
+
+// Everything timed which a packet goes through between being built and
+// being sent, kept together so the frame loop and its tests share it
+struct FrameShaper {
+    neutral: Vec<u8>,
+    packet_hold: PacketHold,
+    soft_press: SoftPress,
+    turbo: Vec<Turbo>,
+    scheduled_presses: Vec<ScheduledPress>,
+    analog_gesture: Option<DoubleTap>,
+    mode_cooldown: ModeCooldown,
+    idle_heartbeat: IdleHeartbeat,
+    polarity: ButtonPolarity,
+    send_throttle: SendThrottle,
+    diag_byte: bool,
+}
+
+impl FrameShaper {
+    // Runs the packet built this frame (if there was a controller to build
+    // it from) through everything timed. Returns the packet, and whether
+    // there was nothing to build it from.
+    fn shape(
+        &mut self,
+        packet: Option<Vec<u8>>,
+        frame: &FrameTime,
+        frame_index: u64,
+        paused: bool,
+    ) -> (Option<Vec<u8>>, bool) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("FrameShaper#shape()");
+        let now = frame.total_wall_time().as_seconds();
+
+        // Smooth over any brief dropouts, then post the update
+        let mut packet = self
+            .packet_hold
+            .update(packet, frame.total_wall_time(), &self.neutral);
+
+        if let Some(ref mut packet) = packet {
+            self.soft_press.apply(packet, now);
+            apply_turbo(packet, &self.turbo, frame_index);
+            apply_scheduled_presses(packet, &self.scheduled_presses, now);
+
+            if let Some(ref mut analog_gesture) = self.analog_gesture {
+                analog_gesture.apply(packet, now);
+            }
+
+            self.mode_cooldown.apply(packet, now);
+        }
+
+        // While paused, the device still gets a packet every frame,
+        // just not one with anything pressed
+        if paused {
+            packet = Some(self.neutral.clone());
+        }
+
+        // Keep the device ticking over while there's no controller
+        let idle = packet.is_none();
+        (self.idle_heartbeat.update(packet, &self.neutral), idle)
+    }
+
+    // Readies a shaped packet for the device, returning what's to be sent
+    // this frame, if anything
+    fn finish(
+        &mut self,
+        mut packet: Option<Vec<u8>>,
+        frame: &FrameTime,
+        frame_index: u64,
+        running_slow: bool,
+        idle: bool,
+    ) -> Option<Vec<u8>> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("FrameShaper#finish()");
+        // Everything before expects the usual polarity, so this comes last
+        if let Some(ref mut packet) = packet {
+            self.polarity.apply(packet);
+        }
+
+        let mut packet = self
+            .send_throttle
+            .update(packet, frame.total_wall_time().as_seconds());
+
+        // Added after throttling, as the frame count changes every frame
+        if let (true, Some(ref mut packet)) = (self.diag_byte, &mut packet) {
+            packet.push(diag_byte(frame_index, running_slow, idle));
+        }
+
+        packet
+    }
+}
+
+// Scales a rumble intensity from the device's u8 range to SDL's u16 range,
+// so that 0x00 is off, 0xFF is full and everything between is proportional
+fn rumble_magnitude(value: u8) -> u16 {
+    (u32::from(value) * u32::from(u16::max_value()) / u32::from(u8::max_value())) as u16
+}
+
+// The rumble curve which leaves intensities as they are
+const DEFAULT_RUMBLE_CURVE: f32 = 1.0;
+
+fn parse_rumble_curve(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(gamma) if gamma.is_finite() && gamma > 0.0 => Ok(gamma),
+        _ => Err("Expected a rumble curve greater than 0".to_string()),
+    }
+}
+
+// Bends a rumble intensity along a curve, so that different controllers'
+// motors can be made to feel alike. Off and full stay as they are.
+fn apply_rumble_gamma(intensity: u16, gamma: f32) -> u16 {
+    let full = f64::from(u16::max_value());
+    ((f64::from(intensity) / full).powf(f64::from(gamma)) * full).round() as u16
+}
+
+// How long each rumble sent to a controller lasts, in milliseconds
+const RUMBLE_DURATION_MS: u32 = 1000;
+// How often, in seconds, unchanging rumble is sent again,
+// so it doesn't run out while the device still wants it
+const RUMBLE_REFRESH_INTERVAL: f64 = 0.5;
+
+// The rumble last sent to a controller, and when it was sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SentRumble {
+    intensity: (u16, u16),
+    at: f64,
+}
+
+// Whether rumble at `intensity` needs sending at `now`, given what was
+// sent last. Sending it every frame would keep restarting its duration,
+// so it's only sent when it changes, or before the last lot runs out.
+fn rumble_needs_sending(last_sent: Option<SentRumble>, intensity: (u16, u16), now: f64) -> bool {
+    match last_sent {
+        None => true,
+        Some(last_sent) if last_sent.intensity != intensity => true,
+        // Stopped rumble doesn't need keeping stopped
+        Some(_) if intensity == (0, 0) => false,
+        Some(last_sent) => now - last_sent.at >= RUMBLE_REFRESH_INTERVAL,
+    }
+}
+
+// Passes the rumble a response asks for on to `controller`,
+// returning what was sent, if anything needed to be
+fn update_rumble<T: GameController>(
+    controller: &mut T,
+    response: &[u8],
+    last_sent: Option<SentRumble>,
+    gamma: f32,
+    now: f64,
+    verbose: bool,
+) -> Option<SentRumble> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("update_rumble()");
+    // Only Aaron Clovsky's firmware tells us about rumble,
+    // and there's no point passing it on to a controller without any
+    if response.len() < ControllerEmulatorPacketType::TwentyByte.response_length()
+        || !controller.has_rumble()
+    {
+        return None;
+    }
+
+    let small_motor_intensity = apply_rumble_gamma(rumble_magnitude(response[1]), gamma);
+    let large_motor_intensity = apply_rumble_gamma(rumble_magnitude(response[2]), gamma);
+    let intensity = (small_motor_intensity, large_motor_intensity);
+
+    if !rumble_needs_sending(last_sent, intensity, now) {
+        return None;
+    }
+
+    if verbose {
+        println!(
+            "“{}”: Setting rumble to ({},{})",
+            controller.name(),
+            small_motor_intensity,
+            large_motor_intensity
+        );
+    }
+
+    // If `set_rumble` fails, the controller stops reporting rumble support,
+    // so we'll only ever end up here (and say so) once
+    if let Err(error) = controller.set_rumble(
+        small_motor_intensity,
+        large_motor_intensity,
+        RUMBLE_DURATION_MS,
+    ) {
+        println!(
+            "Note: “{}” doesn't support rumble ({}), so it won't be sent any",
+            controller.name(),
+            error
+        );
+        return None;
+    }
+
+    Some(SentRumble { intensity, at: now })
+}
+
+// Puts the rumble a response asks for into words, for --poll-only
+fn describe_rumble_response(response: &[u8]) -> String {
+    if response.len() < ControllerEmulatorPacketType::TwentyByte.response_length() {
+        return format!("No rumble in response: {:x}", HexView::from(response));
+    }
+
+    format!(
+        "Rumble: small motor 0x{:02X}, large motor 0x{:02X}",
+        response[1], response[2]
+    )
+}
+
+// How many seconds the rumble duty cycle is measured over
+const RUMBLE_DUTY_WINDOW: f64 = 5.0;
+
+// Keeps the motors from running more than `max_duty` of the time.
+// Time passing earns the motors a budget (up to `RUMBLE_DUTY_WINDOW`'s
+// worth) which running spends, and with none left they're forced
+// off until it's been earned back in full.
+struct RumbleLimiter {
+    max_duty: f64,
+    // Seconds of running the motors have left
+    budget: f64,
+    resting: bool,
+    last_update: Option<f64>,
+}
+
+impl RumbleLimiter {
+    fn new(max_duty: f64) -> RumbleLimiter {
+        RumbleLimiter {
+            max_duty,
+            budget: RUMBLE_DUTY_WINDOW * max_duty,
+            resting: false,
+            last_update: None,
+        }
+    }
+
+    // Given a twenty-byte response received at time `now`,
+    // zeroes its rumble if the motors have run for too long
+    fn apply(&mut self, response: &mut [u8], now: f64) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("RumbleLimiter#apply()");
+        if response.len() < ControllerEmulatorPacketType::TwentyByte.response_length() {
+            return;
+        }
+
+        let elapsed = now - self.last_update.unwrap_or(now);
+        self.last_update = Some(now);
+
+        let full_budget = RUMBLE_DUTY_WINDOW * self.max_duty;
+        self.budget = (self.budget + elapsed * self.max_duty).min(full_budget);
+
+        if self.resting && self.budget >= full_budget {
+            self.resting = false;
+        }
+
+        if response[1] == 0 && response[2] == 0 {
+            return;
+        }
+
+        if !self.resting {
+            self.budget -= elapsed;
+
+            if self.budget <= 0.0 {
+                self.budget = 0.0;
+                self.resting = true;
+            }
+        }
+
+        if self.resting {
+            response[1] = 0;
+            response[2] = 0;
+        }
+    }
+}
+
+// Describes anything unexpected about a response from the device
+fn response_anomaly(
+    communication_mode: &ControllerEmulatorPacketType,
+    response: &[u8],
+) -> Option<&'static str> {
+    if response.len() < communication_mode.response_length() {
+        return Some("short read");
+    }
+
+    let expected_header = match communication_mode {
+        ControllerEmulatorPacketType::None => return None,
+        ControllerEmulatorPacketType::SevenByte => SEVEN_BYTE_OK_RESPONSE as u8,
+        ControllerEmulatorPacketType::TwentyByte => TWENTY_BYTE_OK_HEADER,
+    };
+
+    if response[0] != expected_header {
+        return Some("unexpected response");
+    }
+
+    None
+}
+
+fn send_event_to_controller<I: Read + Write>(
+    serial: &mut I,
+    sent: &[u8],
+    communication_mode: &ControllerEmulatorPacketType,
+    verbose: bool,
+) -> Result<Vec<u8>, OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("send_event_to_controller()");
+    let mut bytes_received = 0;
+    let mut received = vec![0; communication_mode.response_length()];
+
+    match *communication_mode {
+        ControllerEmulatorPacketType::None => {
+            #[cfg(feature = "flamegraph-profiling")]
+            let _guard = flame::start_guard("ControllerEmulatorPacketType::None");
+        }
+
+        ControllerEmulatorPacketType::SevenByte => {
+            #[cfg(feature = "flamegraph-profiling")]
+            let _guard = flame::start_guard("ControllerEmulatorPacketType::SevenByte");
+
+            {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("serial write");
+                serial
+                    .write_all(sent)
+                    .map_err(OmnishockError::from_serial)?;
+            };
+            bytes_received = {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("serial read");
+                match serial.read(&mut received) {
+                    Ok(bytes) => bytes,
+                    Err(error) => {
+                        if verbose {
+                            println!("{}", sent_line(sent));
+                            println!("Error reading response: {}", error);
+                        }
+                        return Err(OmnishockError::from_serial(error));
+                    }
+                }
+            };
+
+            if received[0] != (SEVEN_BYTE_OK_RESPONSE as u8) {
+                println!("WARNING: Adapter responded with an error status.")
+            }
+        }
+
+        ControllerEmulatorPacketType::TwentyByte => {
+            #[cfg(feature = "flamegraph-profiling")]
+            let _guard = flame::start_guard("ControllerEmulatorPacketType::TwentyByte");
+
+            {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("serial write");
+                serial
+                    .write_all(sent)
+                    .map_err(OmnishockError::from_serial)?;
+            };
+            bytes_received = {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("serial read");
+                match serial.read(&mut received) {
+                    Ok(bytes) => bytes,
+                    Err(error) => {
+                        if verbose {
+                            println!("{}", sent_line(sent));
+                            println!("Error reading response: {}", error);
+                        }
+                        return Err(OmnishockError::from_serial(error));
+                    }
+                }
+            };
+        }
+    };
+
+    received.truncate(bytes_received);
+
+    if verbose {
+        println!("{}", sent_line(sent));
+
+        if bytes_received > 0 {
+            println!("Received: {:x}", HexView::from(&received));
+        }
+    }
+
+    Ok(received)
+}
+
+// A device which misses one response has usually just been kept busy, so
+// outside the frame loop a timeout carries on as though nothing came back
+fn ignoring_timeout(result: Result<Vec<u8>, OmnishockError>) -> Result<Vec<u8>, OmnishockError> {
+    match result {
+        Err(ref error) if error.is_timeout() => Ok(Vec::new()),
+        other => other,
+    }
+}
+
+// Sends a packet to firmware which echoes it back, returning as much of the
+// echo as arrives before the device goes quiet
+fn send_checking_echo<I: Read + Write>(
+    serial: &mut I,
+    sent: &[u8],
+    verbose: bool,
+) -> Result<Vec<u8>, OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("send_checking_echo()");
+    serial
+        .write_all(sent)
+        .map_err(OmnishockError::from_serial)?;
+
+    let mut echo = vec![0; sent.len()];
+    let mut bytes_received = 0;
+    while bytes_received < echo.len() {
+        match serial.read(&mut echo[bytes_received..]) {
+            Ok(0) => break,
+            Ok(bytes) => bytes_received += bytes,
+            Err(error) => {
+                if verbose {
+                    println!("Error reading echo: {}", error);
+                }
+                break;
+            }
+        }
+    }
+    echo.truncate(bytes_received);
+
+    if verbose {
+        println!("{}", sent_line(sent));
+        println!("Echoed: {:x}", HexView::from(&echo));
+    }
+
+    Ok(echo)
+}
+
+// Describes how an echo differs from the packet sent, if it does
+fn describe_echo_mismatch(sent: &[u8], echo: &[u8]) -> Option<String> {
+    if sent == echo {
+        return None;
+    }
+
+    let mut problems: Vec<String> = sent
+        .iter()
+        .zip(echo.iter())
+        .enumerate()
+        .filter(|(_, (sent, echoed))| sent != echoed)
+        .map(|(index, (sent, echoed))| {
+            format!("byte {} was {:#04X}, not {:#04X}", index, echoed, sent)
+        })
+        .collect();
+
+    if echo.len() < sent.len() {
+        problems.push(format!(
+            "only {} of {} bytes came back",
+            echo.len(),
+            sent.len()
+        ));
+    }
+
+    Some(format!(
+        "Echo differs from what was sent: {}",
+        problems.join(", ")
+    ))
+}
+
+// Running minimum & maximum values seen on each controller's axes,
+// to help with working out calibration and deadzones
+#[derive(Default)]
+struct AxisExtents(std::collections::HashMap<(u32, sdl2::controller::Axis), (i16, i16)>);
+
+impl AxisExtents {
+    fn observe(&mut self, which: u32, axis: sdl2::controller::Axis, value: i16) -> (i16, i16) {
+        let extents = self.0.entry((which, axis)).or_insert((value, value));
+        extents.0 = std::cmp::min(extents.0, value);
+        extents.1 = std::cmp::max(extents.1, value);
+        *extents
+    }
+
+    fn summary(&self) -> Vec<String> {
+        let mut extents: Vec<_> = self.0.iter().collect();
+        extents.sort_by_key(|((which, axis), _)| (*which, *axis as i32));
+
+        extents
+            .into_iter()
+            .map(|((which, axis), (min, max))| format!("#{} {:?}: {} to {}", which, axis, min, max))
+            .collect()
+    }
+}
+
+// When each controller's held buttons went down, from SDL's event
+// timestamps (in milliseconds), for telling taps from holds
+#[derive(Default)]
+struct ButtonHolds(std::collections::HashMap<(u32, sdl2::controller::Button), u32>);
+
+impl ButtonHolds {
+    fn press(&mut self, which: u32, button: sdl2::controller::Button, timestamp: u32) {
+        self.0.insert((which, button), timestamp);
+    }
+
+    // How many milliseconds the button was held for,
+    // if we saw it go down in the first place
+    fn release(
+        &mut self,
+        which: u32,
+        button: sdl2::controller::Button,
+        timestamp: u32,
+    ) -> Option<u32> {
+        self.0
+            .remove(&(which, button))
+            .map(|pressed| timestamp.wrapping_sub(pressed))
+    }
+}
+
+// Describes a raw joystick input's value, and the value of each control
+// SDL's mapping takes from it
+fn describe_raw_input<T: GameController>(
+    controller: &T,
+    input: RawInput,
+    value: i32,
+    controls: &[MappedControl],
+) -> String {
+    let raw = match input {
+        RawInput::Axis(index) => format!("axis {}", index),
+        RawInput::Button(index) => format!("button {}", index),
+        RawInput::Hat(index) => format!("hat {}", index),
+    };
+
+    let mapped = if controls.is_empty() {
+        "unmapped".to_string()
+    } else {
+        controls
+            .iter()
+            .map(|control| match control {
+                MappedControl::Axis(axis) => {
+                    format!("{} {}", axis_name(*axis), controller.axis(*axis))
+                }
+                MappedControl::Button(button) => format!(
+                    "{} {}",
+                    button_name(*button),
+                    if controller.button(*button) {
+                        "down"
+                    } else {
+                        "up"
+                    }
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    format!("raw {}: {} → {}", raw, value, mapped)
+}
+
+// When each controller's buttons last went down, from SDL's event
+// timestamps (in milliseconds), for spotting switches which chatter
+#[derive(Default)]
+struct ButtonChatter(std::collections::HashMap<(u32, sdl2::controller::Button), u32>);
+
+impl ButtonChatter {
+    // Whether this press came within `threshold` milliseconds of the last
+    fn press(
+        &mut self,
+        which: u32,
+        button: sdl2::controller::Button,
+        timestamp: u32,
+        threshold: u32,
+    ) -> bool {
+        match self.0.insert((which, button), timestamp) {
+            Some(last) => timestamp.wrapping_sub(last) < threshold,
+            None => false,
+        }
+    }
+}
+
+fn print_events(
+    arguments: &CLIArgs,
+    command_arguments: &TestSubcommand,
+    sdl_manager: &mut SDLManager,
+) -> Result<(), OmnishockError> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("print_events()");
+    if command_arguments.all_events {
+        println!("Printing all events...");
+    } else {
+        println!("Printing all controller events...");
+    }
+
+    let mut axis_extents = AxisExtents::default();
+    let mut button_holds = ButtonHolds::default();
+    let mut button_chatter = ButtonChatter::default();
+
+    let mut event_pump = sdl_manager
+        .context
+        .event_pump()
+        .map_err(OmnishockError::Sdl)?;
+
+    loop {
+        use sdl2::event::Event;
+
+        if interrupted() {
+            print_axis_summary(&axis_extents);
+            return Err(OmnishockError::UserAbort);
+        }
+        let event = match event_pump.wait_event_timeout(INTERRUPT_CHECK_MS) {
+            Some(event) => event,
+            None => continue,
+        };
+
+        match event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("Event::ControllerDeviceAdded");
+                if !sdl_manager.has_controller(which).ok().unwrap_or(true) {
+                    match sdl_manager.add_controller(which) {
+                        Ok(id) => {
+                            println!(
+                                "(There are {} controllers connected)",
+                                sdl_manager.active_controllers.len()
+                            );
+
+                            if arguments.verbose {
+                                if let Some(description) = sdl_manager.describe_mapping(id) {
+                                    println!("{}", description);
+                                }
+                            }
+                        }
+                        Err(error) => println!(
+                            "could not initialise connected joystick {}: {:?}",
+                            which, error
+                        ),
+                    };
+                }
+            }
+
+            Event::ControllerDeviceRemoved { which, .. } => {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("Event::ControllerDeviceRemoved");
+                if sdl_manager.remove_controller(which).is_some() {
+                    println!(
+                        "(There are {} controllers connected)",
+                        sdl_manager.active_controllers.len()
+                    );
+                };
+            }
+
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("Event::ControllerAxisMotion");
+                let (min, max) = axis_extents.observe(which, axis, value);
+                println!(
+                    "“{}” (#{}): {:?}: {} (seen {} to {})",
+                    sdl_manager.active_controllers[&which].name(),
+                    which,
+                    axis,
+                    value,
+                    min,
+                    max
+                );
+
+                if let Some(controller) = sdl_manager.active_controllers.get_mut(&which) {
+                    #[cfg(feature = "flamegraph-profiling")]
+                    let _guard = flame::start_guard("set rumble");
+
+                    println!("“{}”: Rumbling", controller.name());
+
+                    // We don't care if `set_rumble` actually worked,
+                    // because if it's unsupported, it won't break anything,
+                    // so we just ignore the result entirely here.
+                    #[allow(unused_must_use)]
+                    {
+                        controller.set_rumble(0xFFFF, 0xFFFF, 500);
+                    }
+                };
+            }
+
+            Event::ControllerButtonDown {
+                timestamp,
+                which,
+                button,
+            } => {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("Event::ControllerButtonDown");
+                button_holds.press(which, button, timestamp);
+                println!(
+                    "“{}” (#{}): {:?}: down",
+                    sdl_manager.active_controllers[&which].name(),
+                    which,
+                    button
+                );
+                if button_chatter.press(
+                    which,
+                    button,
+                    timestamp,
+                    command_arguments.chatter_threshold_ms,
+                ) {
+                    println!(
+                        "“{}” (#{}): possible chatter on {:?}",
+                        sdl_manager.active_controllers[&which].name(),
+                        which,
+                        button
+                    );
+                }
+            }
+
+            Event::ControllerButtonUp {
+                timestamp,
+                which,
+                button,
+            } => {
+                #[cfg(feature = "flamegraph-profiling")]
+                let _guard = flame::start_guard("Event::ControllerButtonUp");
+                let held = match button_holds.release(which, button, timestamp) {
+                    Some(held) => format!(" (held {}ms)", held),
+                    None => String::new(),
+                };
+                println!(
+                    "“{}” (#{}): {:?}: up{}",
+                    sdl_manager.active_controllers[&which].name(),
+                    which,
+                    button,
+                    held
+                );
+            }
+
+            Event::JoyAxisMotion {
+                which,
+                axis_idx,
+                value,
+                ..
+            } if command_arguments.mapping_debug => {
+                print_raw_input(
+                    sdl_manager,
+                    which,
+                    RawInput::Axis(axis_idx),
+                    i32::from(value),
+                );
+            }
+
+            Event::JoyButtonDown {
+                which, button_idx, ..
+            } if command_arguments.mapping_debug => {
+                print_raw_input(sdl_manager, which, RawInput::Button(button_idx), 1);
+            }
+
+            Event::JoyButtonUp {
+                which, button_idx, ..
+            } if command_arguments.mapping_debug => {
+                print_raw_input(sdl_manager, which, RawInput::Button(button_idx), 0);
+            }
+
+            Event::JoyHatMotion {
+                which,
+                hat_idx,
+                state,
+                ..
+            } if command_arguments.mapping_debug => {
+                print_raw_input(
+                    sdl_manager,
+                    which,
+                    RawInput::Hat(hat_idx),
+                    i32::from(state.to_raw()),
+                );
+            }
+
+            Event::Quit { .. } => {
+                print_axis_summary(&axis_extents);
+                return Ok(());
+            }
+            other => {
+                if command_arguments.all_events {
+                    println!("{:?}", other);
+                }
+            }
+        }
+    }
+}
+
+fn print_axis_summary(axis_extents: &AxisExtents) {
+    let summary = axis_extents.summary();
+    if !summary.is_empty() {
+        println!("Axis ranges observed:");
+        for line in summary {
+            println!("  {}", line);
+        }
+    }
+}
+
+fn print_raw_input(sdl_manager: &SDLManager, which: u32, input: RawInput, value: i32) {
+    // Joysticks SDL has no mapping for never become controllers
+    if let Some(controller) = sdl_manager.active_controllers.get(&which) {
+        println!(
+            "“{}” (#{}): {}",
+            controller.name(),
+            which,
+            describe_raw_input(
+                controller,
+                input,
+                value,
+                &controller.controls_reading(input)
+            )
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mockstream;
+
+    use omnishock::faux_controller::FauxController;
+    use omnishock::sdl_manager::GameController;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+
+    // Behaves like a serial device: each write queues up the next scripted
+    // response, and reading with nothing left to read times out.
+    struct FauxSerial {
+        responses: VecDeque<Vec<u8>>,
+        pending: VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    impl FauxSerial {
+        fn with_responses(responses: Vec<Vec<u8>>) -> FauxSerial {
+            FauxSerial {
+                responses: responses.into_iter().collect(),
+                pending: VecDeque::new(),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for FauxSerial {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Operation timed out",
+                ));
+            }
+
+            let mut count = 0;
+            while count < buffer.len() {
+                match self.pending.pop_front() {
+                    Some(byte) => buffer[count] = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+
+            Ok(count)
+        }
+    }
+
+    impl Write for FauxSerial {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buffer);
+
+            if let Some(response) = self.responses.pop_front() {
+                self.pending.extend(response);
+            }
+
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A frame shaper which leaves packets as they are,
+    // for tests to switch on just what they're testing
+    fn plain_shaper(neutral: &[u8]) -> super::FrameShaper {
+        use super::{
+            DoubleTap, FrameShaper, IdleHeartbeat, ModeCooldown, PacketHold, SendThrottle,
+            SoftPress,
+        };
+
+        FrameShaper {
+            neutral: neutral.to_vec(),
+            packet_hold: PacketHold::new(game_time::FloatDuration::zero()),
+            soft_press: SoftPress::new(0.0),
+            turbo: Vec::new(),
+            scheduled_presses: Vec::new(),
+            analog_gesture: None::<DoubleTap>,
+            mode_cooldown: ModeCooldown::new(0.0),
+            idle_heartbeat: IdleHeartbeat::new(0),
+            polarity: super::button_polarity(&[]),
+            send_throttle: SendThrottle::new(0.0),
+            diag_byte: false,
+        }
+    }
+
+    // Runs `frames` frames of `controller` through `shaper` on `clock`, the
+    // way the frame loop does, sending each packet to `serial`. Gives when
+    // each frame was, in seconds, and what was sent then.
+    fn run_frames<C: super::FrameClock, T: GameController>(
+        clock: &mut C,
+        controller: Option<&T>,
+        shaper: &mut super::FrameShaper,
+        serial: &mut FauxSerial,
+        frames: u64,
+    ) -> Vec<(f64, Option<Vec<u8>>)> {
+        use super::{build_packet, ignoring_timeout, send_event_to_controller};
+        use super::{ControllerEmulatorPacketType, MappingOptions};
+
+        let mode = ControllerEmulatorPacketType::TwentyByte;
+        (1..=frames)
+            .map(|frame_index| {
+                let frame = clock.tick();
+                let packet = controller
+                    .map(|controller| build_packet(controller, &mode, &MappingOptions::default()));
+                let (packet, idle) = shaper.shape(packet, &frame, frame_index, false);
+                let packet =
+                    shaper.finish(packet, &frame, frame_index, frame.is_running_slow(), idle);
+
+                if let Some(ref packet) = packet {
+                    ignoring_timeout(send_event_to_controller(serial, packet, &mode, false))
+                        .unwrap();
+                }
+                clock.sleep_remaining();
+
+                (frame.total_wall_time().as_seconds(), packet)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_loop_runs_to_a_virtual_clock() {
+        use super::{Buttons2, ScheduledPress, SendThrottle, Turbo};
+        use crate::frame_clock::VirtualClock;
+        use game_time::FloatDuration;
+        use omnishock::protocol::NEUTRAL_PACKET;
+        use sdl2::controller::Button;
+
+        let pressed = |packet: &Option<Vec<u8>>, button: Buttons2| {
+            packet.as_ref().unwrap()[2] & button.bits() == 0
+        };
+
+        // Cross held throughout, pulsed by turbo, with Square pressed at 50ms
+        let mut controller = FauxController::create_with_name(String::from("Turbo Pad"));
+        controller.set_button(Button::A, true);
+        let mut shaper = plain_shaper(&NEUTRAL_PACKET);
+        shaper.turbo = vec!["cross=2:1".parse::<Turbo>().unwrap()];
+        shaper.scheduled_presses = vec!["0.05=square".parse::<ScheduledPress>().unwrap()];
+
+        let mut clock = VirtualClock::steady(50.0);
+        let mut serial = FauxSerial::with_responses(Vec::new());
+        let frames = run_frames(&mut clock, Some(&controller), &mut shaper, &mut serial, 12);
+
+        for (index, (at, packet)) in frames.iter().enumerate() {
+            let frame_index = index as u64 + 1;
+            assert!((at - frame_index as f64 * 0.02).abs() < 1e-9);
+            assert_eq!(pressed(packet, Buttons2::CROSS), frame_index % 3 < 2);
+            // Held for 100ms from 50ms, so frames at 60ms to 140ms
+            assert_eq!(
+                pressed(packet, Buttons2::SQUARE),
+                (3..=7).contains(&frame_index)
+            );
+        }
+        assert_eq!(serial.written.len(), 12 * NEUTRAL_PACKET.len());
+
+        // Sending every 90ms skips frames, and a slow frame doesn't catch up
+        let mut shaper = plain_shaper(&NEUTRAL_PACKET);
+        shaper.send_throttle = SendThrottle::new(0.09);
+        let mut clock = VirtualClock::new(
+            50.0,
+            vec![
+                FloatDuration::milliseconds(20.0),
+                FloatDuration::milliseconds(20.0),
+                FloatDuration::milliseconds(250.0),
+                FloatDuration::milliseconds(20.0),
+            ],
+        );
+        let mut serial = FauxSerial::with_responses(Vec::new());
+        let sent_at: Vec<f64> =
+            run_frames(&mut clock, Some(&controller), &mut shaper, &mut serial, 10)
+                .into_iter()
+                .filter_map(|(at, packet)| packet.map(|_| at))
+                .collect();
+        let expected = [0.02, 0.29, 0.39];
+        assert_eq!(sent_at.len(), expected.len());
+        for (at, expected) in sent_at.iter().zip(expected.iter()) {
+            assert!((at - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn send_event_to_controller_works() -> Result<(), Box<dyn std::error::Error>> {
+        use self::mockstream::SharedMockStream;
+        use super::ControllerEmulatorPacketType;
+        use super::{build_packet, send_event_to_controller};
+        use super::{Buttons1, Buttons2, MappingOptions, TriggerMode};
+        use omnishock::protocol::DUALSHOCK_MAGIC;
+        use omnishock::protocol::SEVEN_BYTE_OK_RESPONSE;
+        use omnishock::protocol::TWENTY_BYTE_OK_HEADER;
+
+        let controller = FauxController::create_with_name(String::from("Apple Pippin Controller"));
+
+        let seven_byte_console_response = vec![SEVEN_BYTE_OK_RESPONSE as u8];
+
+        let mut serial = SharedMockStream::new();
+        serial.push_bytes_to_read(&seven_byte_console_response);
+
+        assert_eq!(
+            send_event_to_controller(
+                &mut serial,
+                &build_packet(
+                    &controller,
+                    &ControllerEmulatorPacketType::SevenByte,
+                    &MappingOptions::new(TriggerMode::Normal, false),
+                ),
+                &ControllerEmulatorPacketType::SevenByte,
+                false,
+            )?,
+            seven_byte_console_response
+        );
+        assert_eq!(
+            serial.pop_bytes_written(),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+            ]
+        );
+
+        let twenty_byte_console_response = vec![TWENTY_BYTE_OK_HEADER, 0x00, 0x00, 0x55];
+
+        serial.push_bytes_to_read(&twenty_byte_console_response);
+
+        assert_eq!(
+            send_event_to_controller(
+                &mut serial,
+                &build_packet(
+                    &controller,
+                    &ControllerEmulatorPacketType::TwentyByte,
+                    &MappingOptions::new(TriggerMode::Normal, false),
+                ),
+                &ControllerEmulatorPacketType::TwentyByte,
+                false,
+            )?,
+            twenty_byte_console_response
+        );
+        assert_eq!(
+            serial.pop_bytes_written(),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+                // Pressure values
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn merged_controller_combines_inputs() {
+        use omnishock::sdl_manager::MergedController;
+        use sdl2::controller::{Axis, Button};
+
+        let mut first = FauxController::create_with_name(String::from("Atari CX40"));
+        let mut second = FauxController::create_with_name(String::from("Atari CX78"));
+
+        first.set_button(Button::A, true);
+        second.set_button(Button::B, true);
+
+        first.set_axis(Axis::LeftX, -12_000);
+        second.set_axis(Axis::LeftX, 8_000);
+        first.set_axis(Axis::LeftY, 100);
+        second.set_axis(Axis::LeftY, i16::min_value());
+        first.set_axis(Axis::RightX, 500);
+        second.set_axis(Axis::RightX, -500);
+
+        let merged = MergedController::new(&first, &second);
+
+        assert_eq!(merged.name(), "Atari CX40 + Atari CX78");
+
+        // Buttons on either controller are OR'd together
+        assert!(merged.button(Button::A));
+        assert!(merged.button(Button::B));
+        assert!(!merged.button(Button::X));
+
+        // Axes take whichever value is furthest from centre
+        assert_eq!(merged.axis(Axis::LeftX), -12_000);
+        assert_eq!(merged.axis(Axis::LeftY), i16::min_value());
+        assert_eq!(merged.axis(Axis::RightY), 0);
+        // Ties go to the first controller
+        assert_eq!(merged.axis(Axis::RightX), 500);
+    }
+
+    #[test]
+    fn controller_pair_parses() {
+        use super::ControllerPair;
+        use std::str::FromStr;
+
+        assert_eq!(ControllerPair::from_str("0,1"), Ok(ControllerPair(0, 1)));
+        assert_eq!(ControllerPair::from_str("3, 12"), Ok(ControllerPair(3, 12)));
+        assert!(ControllerPair::from_str("0").is_err());
+        assert!(ControllerPair::from_str("0,1,2").is_err());
+        assert!(ControllerPair::from_str("zero,one").is_err());
+    }
+
+    #[test]
+    fn button_holds_measure_each_press() {
+        use super::ButtonHolds;
+        use sdl2::controller::Button;
+
+        let mut holds = ButtonHolds::default();
+
+        holds.press(0, Button::A, 1_000);
+        holds.press(1, Button::A, 1_010);
+        holds.press(0, Button::B, 1_020);
+        assert_eq!(holds.release(0, Button::A, 1_016), Some(16));
+        assert_eq!(holds.release(0, Button::B, 1_520), Some(500));
+        assert_eq!(holds.release(1, Button::A, 1_110), Some(100));
+
+        // A release we never saw go down has nothing to measure
+        assert_eq!(holds.release(0, Button::A, 2_000), None);
+    }
+
+    #[test]
+    fn button_chatter_spots_quick_repeats() {
+        use super::ButtonChatter;
+        use sdl2::controller::Button;
+
+        let mut chatter = ButtonChatter::default();
+
+        assert!(!chatter.press(0, Button::A, 1_000, 30));
+        assert!(chatter.press(0, Button::A, 1_012, 30));
+        // Each controller and button is timed separately
+        assert!(!chatter.press(1, Button::A, 1_020, 30));
+        assert!(!chatter.press(0, Button::B, 1_025, 30));
+        // Deliberate mashing is slower than that
+        assert!(!chatter.press(0, Button::A, 1_112, 30));
+        // And nothing's flagged with no threshold
+        assert!(!chatter.press(0, Button::A, 1_113, 0));
+    }
+
+    #[test]
+    fn axis_extents_track_range_per_controller() {
+        use super::AxisExtents;
+        use sdl2::controller::Axis;
+
+        let mut extents = AxisExtents::default();
+
+        assert_eq!(extents.observe(0, Axis::LeftX, 100), (100, 100));
+        assert_eq!(extents.observe(0, Axis::LeftX, -3000), (-3000, 100));
+        assert_eq!(extents.observe(0, Axis::LeftX, 32767), (-3000, 32767));
+        assert_eq!(extents.observe(1, Axis::LeftX, 5), (5, 5));
+        assert_eq!(extents.observe(0, Axis::TriggerLeft, 0), (0, 0));
+
+        assert_eq!(
+            extents.summary(),
+            vec![
+                "#0 LeftX: -3000 to 32767",
+                "#0 TriggerLeft: 0 to 0",
+                "#1 LeftX: 5 to 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn scheduled_presses_apply_at_their_time() {
+        use super::{apply_scheduled_presses, ScheduledPress, NEUTRAL_PACKET};
+
+        let press: ScheduledPress = "12.500s=Cross".parse().unwrap();
+        assert_eq!(
+            press,
+            ScheduledPress {
+                at: 12.5,
+                button: "cross"
+            }
+        );
+        assert_eq!("3=l3".parse::<ScheduledPress>().unwrap().at, 3.0);
+        assert!("12.5s".parse::<ScheduledPress>().is_err());
+        assert!("-1s=cross".parse::<ScheduledPress>().is_err());
+        assert!("1s=guide".parse::<ScheduledPress>().is_err());
+
+        let presses = vec![press, "12.55s=l3".parse().unwrap()];
+
+        // Before the press is due, nothing changes
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        apply_scheduled_presses(&mut packet, &presses, 12.4);
+        assert_eq!(packet, NEUTRAL_PACKET.to_vec());
+
+        // Once it is, it's pressed with full pressure
+        apply_scheduled_presses(&mut packet, &presses, 12.5);
+        assert_eq!(packet[2], 0b1011_1111);
+        assert_eq!(packet[13], 0xFF);
+        assert_eq!(packet[1], 0xFF);
+
+        // Overlapping presses combine
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        apply_scheduled_presses(&mut packet, &presses, 12.58);
+        assert_eq!(packet[1], 0b1111_1101);
+        assert_eq!(packet[2], 0b1011_1111);
+
+        // And after it's been held a moment, it's released
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        apply_scheduled_presses(&mut packet, &presses, 12.7);
+        assert_eq!(packet, NEUTRAL_PACKET.to_vec());
+
+        // Seven-byte packets don't have any pressure to set
+        let mut packet = NEUTRAL_PACKET[..7].to_vec();
+        apply_scheduled_presses(&mut packet, &presses, 12.5);
+        assert_eq!(packet.len(), 7);
+        assert_eq!(packet[2], 0b1011_1111);
+    }
+
+    #[test]
+    fn choose_serial_port_asks_until_answered() {
+        use super::choose_serial_port;
+        use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+        let ports = vec![
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+            SerialPortInfo {
+                port_name: "/dev/ttyUSB0".to_string(),
+                port_type: SerialPortType::UsbPort(UsbPortInfo {
+                    vid: 0x2341,
+                    pid: 0x8036,
+                    serial_number: None,
+                    manufacturer: Some("Arduino LLC".to_string()),
+                    product: Some("Arduino Leonardo".to_string()),
+                }),
+            },
+        ];
+
+        let mut output = Vec::new();
+        let chosen = choose_serial_port(&ports, &b"3\nfirst\n2\n"[..], &mut output);
+        assert_eq!(chosen.ok(), Some("/dev/ttyUSB0".to_string()));
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("  1. /dev/ttyS0\n"));
+        assert!(
+            output.contains("  2. /dev/ttyUSB0 (USB 2341:8036, Arduino LLC Arduino Leonardo)\n")
+        );
+        assert_eq!(output.matches("Expected a number from 1 to 2").count(), 2);
+
+        // Giving up without answering
+        assert!(choose_serial_port(&ports, &b"0\n"[..], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn turbo_state_follows_the_pattern() {
+        use super::{turbo_state, TurboPattern};
+
+        let states = |pattern: &str| -> String {
+            let pattern: TurboPattern = pattern.parse().unwrap();
+            (0..9)
+                .map(|frame| {
+                    if turbo_state(&pattern, frame) {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect()
+        };
+
+        assert_eq!(states("1:1"), "#.#.#.#.#");
+        assert_eq!(states("2:1"), "##.##.##.");
+        assert_eq!(states("1:2"), "#..#..#..");
+        assert_eq!(states("3:3"), "###...###");
+
+        // The frame count can run for a very long time
+        let pattern = TurboPattern {
+            on: u32::max_value(),
+            off: u32::max_value(),
+        };
+        assert!(!turbo_state(&pattern, u64::max_value()));
+
+        assert!("0:1".parse::<TurboPattern>().is_err());
+        assert!("2:0".parse::<TurboPattern>().is_err());
+        assert!("2".parse::<TurboPattern>().is_err());
+    }
+
+    #[test]
+    fn turbo_pulses_held_buttons() {
+        use super::{apply_scheduled_presses, apply_turbo, ScheduledPress, Turbo, NEUTRAL_PACKET};
+
+        let turbos: Vec<Turbo> = vec!["cross=2:1".parse().unwrap(), "l1".parse().unwrap()];
+        assert!("jump=1:1".parse::<Turbo>().is_err());
+
+        // Cross and L1 held down
+        let mut held = NEUTRAL_PACKET.to_vec();
+        held[2] = 0b1011_1011;
+        held[13] = 0xFF;
+        held[15] = 0xFF;
+
+        let mut packet = held.clone();
+        apply_turbo(&mut packet, &turbos, 0);
+        assert_eq!(packet, held);
+
+        // L1 lets go every other frame
+        let mut packet = held.clone();
+        apply_turbo(&mut packet, &turbos, 1);
+        assert_eq!(packet[2], 0b1011_1111);
+        assert_eq!(packet[13], 0xFF);
+        assert_eq!(packet[15], 0x00);
+
+        // And Cross every third frame
+        let mut packet = held.clone();
+        apply_turbo(&mut packet, &turbos, 2);
+        assert_eq!(packet[2], 0b1111_1011);
+        assert_eq!(packet[13], 0x00);
+
+        // Buttons which aren't held stay that way
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        apply_turbo(&mut packet, &turbos, 2);
+        assert_eq!(packet, NEUTRAL_PACKET.to_vec());
+
+        // A scheduled press still gets through
+        let presses: Vec<ScheduledPress> = vec!["1s=cross".parse().unwrap()];
+        let mut packet = held;
+        apply_turbo(&mut packet, &turbos, 2);
+        apply_scheduled_presses(&mut packet, &presses, 1.0);
+        assert_eq!(packet[2], 0b1011_1011);
+    }
+
+    #[test]
+    fn apply_rumble_gamma_bends_intensities() {
+        use super::{apply_rumble_gamma, parse_rumble_curve, DEFAULT_RUMBLE_CURVE};
+
+        // The default curve changes nothing
+        for intensity in (0..=u16::max_value()).step_by(257) {
+            assert_eq!(
+                apply_rumble_gamma(intensity, DEFAULT_RUMBLE_CURVE),
+                intensity
+            );
+        }
+
+        // Off and full stay put, whatever the curve
+        for gamma in [0.5, 2.0] {
+            assert_eq!(apply_rumble_gamma(0, gamma), 0);
+            assert_eq!(apply_rumble_gamma(0xFFFF, gamma), 0xFFFF);
+        }
+
+        assert_eq!(apply_rumble_gamma(0x8000, 2.0), 0x4000);
+        assert_eq!(apply_rumble_gamma(0x4000, 0.5), 0x8000);
+
+        assert_eq!(parse_rumble_curve("1.5"), Ok(1.5));
+        assert!(parse_rumble_curve("0").is_err());
+        assert!(parse_rumble_curve("-1").is_err());
+        assert!(parse_rumble_curve("inf").is_err());
+    }
+
+    #[test]
+    fn rumble_magnitude_is_proportional() {
+        use super::rumble_magnitude;
+
+        assert_eq!(rumble_magnitude(0x00), 0x0000);
+        assert_eq!(rumble_magnitude(0xFF), 0xFFFF);
+
+        for value in 0..=u8::max_value() {
+            let magnitude = rumble_magnitude(value);
+
+            // As 0xFFFF is exactly 0xFF * 257, the old fixed multiplier was
+            // already exact; make sure we agree with it everywhere
+            assert_eq!(magnitude, u16::from(value) * 257);
+
+            if value > 0 {
+                assert!(magnitude > rumble_magnitude(value - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn update_rumble_skips_controllers_without_rumble() {
+        use super::update_rumble;
+        use omnishock::protocol::{SEVEN_BYTE_OK_RESPONSE, TWENTY_BYTE_OK_HEADER};
+
+        let response = vec![TWENTY_BYTE_OK_HEADER, 0xFF, 0x80, 0x55];
+
+        let mut controller = FauxController::create_with_name(String::from("Sony DualShock"));
+        assert!(update_rumble(&mut controller, &response, None, 1.0, 0.0, false).is_some());
+        assert_eq!(
+            controller.rumble(),
+            Some((0xFFFF, 0x8080, super::RUMBLE_DURATION_MS))
+        );
+
+        let mut controller = FauxController::create_with_name(String::from("Sony Dual Analog"));
+        controller.set_has_rumble(false);
+        assert_eq!(
+            update_rumble(&mut controller, &response, None, 1.0, 0.0, false),
+            None
+        );
+        assert_eq!(controller.rumble(), None);
+
+        // The seven-byte protocol's response has no rumble in it
+        let mut controller = FauxController::create_with_name(String::from("Sony DualShock"));
+        update_rumble(
+            &mut controller,
+            &[SEVEN_BYTE_OK_RESPONSE as u8],
+            None,
+            1.0,
+            0.0,
+            false,
+        );
+        assert_eq!(controller.rumble(), None);
+    }
+
+    #[test]
+    fn rumble_is_only_sent_when_it_changes() {
+        use super::{rumble_needs_sending, SentRumble, RUMBLE_REFRESH_INTERVAL};
+
+        let sent = Some(SentRumble {
+            intensity: (0xFFFF, 0x8080),
+            at: 1.0,
+        });
+
+        // The first rumble is always sent
+        assert!(rumble_needs_sending(None, (0, 0), 0.0));
+
+        // The same rumble isn't sent again until it's about to run out...
+        assert!(!rumble_needs_sending(sent, (0xFFFF, 0x8080), 1.1));
+        assert!(rumble_needs_sending(
+            sent,
+            (0xFFFF, 0x8080),
+            1.0 + RUMBLE_REFRESH_INTERVAL
+        ));
+
+        // ...but a change, including stopping, is sent straight away
+        assert!(rumble_needs_sending(sent, (0x8080, 0x8080), 1.1));
+        assert!(rumble_needs_sending(sent, (0, 0), 1.1));
+
+        // Once stopped, it's left stopped
+        let stopped = Some(SentRumble {
+            intensity: (0, 0),
+            at: 1.0,
+        });
+        assert!(!rumble_needs_sending(stopped, (0, 0), 60.0));
+    }
+
+    #[test]
+    fn describe_rumble_response_reads_both_motors() {
+        use super::describe_rumble_response;
+        use omnishock::protocol::{SEVEN_BYTE_OK_RESPONSE, TWENTY_BYTE_OK_HEADER};
+
+        assert_eq!(
+            describe_rumble_response(&[TWENTY_BYTE_OK_HEADER, 0x01, 0xC0, 0x55]),
+            "Rumble: small motor 0x01, large motor 0xC0"
+        );
+        assert!(describe_rumble_response(&[SEVEN_BYTE_OK_RESPONSE as u8])
+            .starts_with("No rumble in response"));
+    }
+
+    #[test]
+    fn rumble_limiter_caps_the_duty_cycle() {
+        use super::{RumbleDuty, RumbleLimiter};
+        use omnishock::protocol::TWENTY_BYTE_OK_HEADER;
+
+        let full = [TWENTY_BYTE_OK_HEADER, 0xFF, 0xFF, 0x55];
+        let frame = 1.0 / 60.0;
+
+        // Ask for continuous rumble for a minute, counting how long it ran
+        let run_for = |limiter: &mut RumbleLimiter, start: f64| {
+            (0..3600)
+                .filter(|frame_number| {
+                    let mut response = full;
+                    limiter.apply(&mut response, start + f64::from(*frame_number) * frame);
+                    response != [TWENTY_BYTE_OK_HEADER, 0, 0, 0x55]
+                })
+                .count() as f64
+                * frame
+        };
+
+        // Runs for a while, then rests, for a quarter of the minute overall
+        let mut limiter = RumbleLimiter::new(0.25);
+        assert!((run_for(&mut limiter, 0.0) - 15.0).abs() < 0.5);
+
+        // A full duty cycle never interferes
+        let mut limiter = RumbleLimiter::new(1.0);
+        assert!((run_for(&mut limiter, 0.0) - 60.0).abs() < 0.1);
+
+        // No rumble at all is left alone
+        let mut limiter = RumbleLimiter::new(0.0);
+        let mut response = [TWENTY_BYTE_OK_HEADER, 0x00, 0x00, 0x55];
+        limiter.apply(&mut response, 0.0);
+        assert_eq!(response, [TWENTY_BYTE_OK_HEADER, 0x00, 0x00, 0x55]);
+        let mut response = full;
+        limiter.apply(&mut response, 0.0);
+        assert_eq!(response, [TWENTY_BYTE_OK_HEADER, 0x00, 0x00, 0x55]);
+
+        assert_eq!("0.5".parse(), Ok(RumbleDuty(0.5)));
+        assert!("1.5".parse::<RumbleDuty>().is_err());
+        assert!("-0.1".parse::<RumbleDuty>().is_err());
+        assert!("lots".parse::<RumbleDuty>().is_err());
+    }
+
+    #[test]
+    fn interactive_commands_change_options() {
+        use super::{apply_interactive_command, MappingOptions, TriggerMode};
+
+        let mut options = MappingOptions::new(TriggerMode::Normal, true);
+
+        assert!(apply_interactive_command(&mut options, "trigger-mode right-stick").is_ok());
+        assert_eq!(options.trigger_mode, TriggerMode::RightStick);
+
+        assert!(apply_interactive_command(&mut options, "  stick-normalise off ").is_ok());
+        assert!(!options.normalise_sticks);
+
+        assert!(apply_interactive_command(&mut options, "trigger-pressure on").is_ok());
+        assert!(options.trigger_pressure);
+
+        // Nonsense leaves everything as it was
+        let before = options.clone();
+        assert!(apply_interactive_command(&mut options, "trigger-mode sideways").is_err());
+        assert!(apply_interactive_command(&mut options, "stick-normalise maybe").is_err());
+        assert!(apply_interactive_command(&mut options, "self-destruct").is_err());
+        assert!(apply_interactive_command(&mut options, "").is_err());
+        assert_eq!(options, before);
+    }
+
+    #[test]
+    fn combo_watcher_fires_after_holding() {
+        use super::{ButtonCombo, Buttons1, ComboEvent, ComboWatcher, NEUTRAL_PACKET};
+
+        let mut watcher = ComboWatcher::new();
+        watcher.watch("start+select".parse::<ButtonCombo>().unwrap(), 0.5, "quit");
+        watcher.watch("select".parse::<ButtonCombo>().unwrap(), 0.0, "reset");
+
+        let neutral = NEUTRAL_PACKET.to_vec();
+        let mut select = NEUTRAL_PACKET.to_vec();
+        select[1] = !Buttons1::SELECT.bits();
+        let mut both = NEUTRAL_PACKET.to_vec();
+        both[1] = !(Buttons1::START | Buttons1::SELECT).bits();
+
+        let frames: Vec<(f64, Option<&[u8]>)> = vec![
+            (0.0, Some(&neutral)),
+            // Combos without a hold time fire straight away
+            (0.1, Some(&select)),
+            // Holding them doesn't fire them again
+            (0.2, Some(&select)),
+            // Others say when they start being held (while the one
+            // already held doesn't fire again)...
+            (0.3, Some(&both)),
+            (0.6, Some(&both)),
+            // ...but letting go too early starts over
+            (0.7, Some(&neutral)),
+            (0.8, Some(&both)),
+            (1.0, Some(&both)),
+            (1.3, Some(&both)),
+            // And they only fire once per press
+            (1.4, Some(&both)),
+            // Losing the controller lets go too
+            (1.5, None),
+            (1.6, Some(&select)),
+        ];
+
+        let events: Vec<(f64, Vec<ComboEvent<&str>>)> = frames
+            .into_iter()
+            .map(|(now, packet)| (now, watcher.update(packet, now)))
+            .filter(|(_, events)| !events.is_empty())
+            .collect();
 
         assert_eq!(
-            controller_map_twenty_byte(&controller, &TriggerMode::Normal, true),
+            events,
             vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-                // Pressure values
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                // Mode footer
-                0x55,
+                (0.1, vec![ComboEvent::Fired("reset")]),
+                (0.3, vec![ComboEvent::Started("quit")]),
+                (
+                    0.8,
+                    vec![ComboEvent::Started("quit"), ComboEvent::Fired("reset")]
+                ),
+                (1.3, vec![ComboEvent::Fired("quit")]),
+                (1.6, vec![ComboEvent::Fired("reset")]),
             ]
         );
+    }
+
+    #[test]
+    fn button_polarity_follows_button_names() {
+        use super::{button_polarity, Buttons1, Buttons2};
+
+        let polarity = button_polarity(&["l3", "cross", "r2"]);
+        assert_eq!(polarity.inverted1, Buttons1::L3);
+        assert_eq!(polarity.inverted2, Buttons2::CROSS | Buttons2::R2);
+
+        let polarity = button_polarity(&[]);
+        assert!(polarity.inverted1.is_empty() && polarity.inverted2.is_empty());
+    }
+
+    #[test]
+    fn button_combo_parses_and_matches() {
+        use super::{ButtonCombo, Buttons1, Buttons2, NEUTRAL_PACKET};
+
+        let combo: ButtonCombo = "Start + select+l1+R1".parse().unwrap();
+        assert_eq!(combo, ButtonCombo(vec!["start", "select", "l1", "r1"]));
+        assert!("start+home".parse::<ButtonCombo>().is_err());
+
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        assert!(!combo.is_held(&packet));
+
+        packet[1] = !(Buttons1::START | Buttons1::SELECT).bits();
+        packet[2] = !Buttons2::L1.bits();
+        assert!(!combo.is_held(&packet));
+
+        // Extra buttons don't get in the way
+        packet[2] = !(Buttons2::L1 | Buttons2::R1 | Buttons2::CROSS).bits();
+        assert!(combo.is_held(&packet));
+    }
+
+    #[test]
+    fn soft_press_ramps_face_button_pressure() {
+        use super::{Buttons2, SoftPress, NEUTRAL_PACKET};
+
+        let mut pressed = NEUTRAL_PACKET.to_vec();
+        pressed[2] = !(Buttons2::CROSS | Buttons2::L1).bits();
+        pressed[13] = 0xFF;
+        pressed[15] = 0xFF;
+
+        let mut soft_press = SoftPress::new(0.1);
+
+        let mut packet = pressed.clone();
+        soft_press.apply(&mut packet, 1.0);
+        assert_eq!(packet[13], 0x00);
+
+        let mut packet = pressed.clone();
+        soft_press.apply(&mut packet, 1.05);
+        assert_eq!(packet[13], 0x7F);
+        // Only face buttons are affected
+        assert_eq!(packet[15], 0xFF);
+
+        let mut packet = pressed.clone();
+        soft_press.apply(&mut packet, 1.2);
+        assert_eq!(packet[13], 0xFF);
+
+        // Letting go starts the ramp over again
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        soft_press.apply(&mut packet, 1.3);
+        assert_eq!(packet, NEUTRAL_PACKET.to_vec());
+
+        let mut packet = pressed.clone();
+        soft_press.apply(&mut packet, 1.4);
+        assert_eq!(packet[13], 0x00);
+
+        // With no ramp, nothing changes
+        let mut packet = pressed.clone();
+        SoftPress::new(0.0).apply(&mut packet, 1.0);
+        assert_eq!(packet, pressed);
+    }
+
+    #[test]
+    fn check_profile_reports_problems() {
+        use super::check_profile;
+
+        assert_eq!(
+            check_profile("# Nothing but a comment\ntrigger-mode = right-stick\n"),
+            Ok(vec![])
+        );
+
+        // Settings given twice work, but only the last one counts
+        assert_eq!(
+            check_profile(
+                "anti-deadzone = 100\ninvert-button = l3\ninvert-button = r3\nanti-deadzone = 200"
+            ),
+            Ok(vec![
+                "line 4: 'anti-deadzone' was already set on line 1, and replaces it".to_string()
+            ])
+        );
+
+        // Unknown names, out of range values and outputs routed twice don't
+        assert!(check_profile("button.a = jump").is_err());
+        assert!(check_profile("axis.left-x = wobble").is_err());
+        assert!(check_profile("normalise-left = 150").is_err());
+        assert!(check_profile("button.a = b\nbutton.a = x").is_err());
+        assert_eq!(
+            check_profile("trigger-mode = normal\nwibble = 1"),
+            Err("line 2: Unknown setting 'wibble'".to_string())
+        );
+    }
+
+    #[test]
+    fn diag_byte_carries_the_frame_count_and_status() {
+        use super::diag_byte;
+
+        assert_eq!(diag_byte(0, false, false), 0x00);
+        assert_eq!(diag_byte(15, false, false), 0x0F);
+        // The count goes back round after 15
+        assert_eq!(diag_byte(16, false, false), 0x00);
+        assert_eq!(diag_byte(33, true, false), 0x11);
+        assert_eq!(diag_byte(2, false, true), 0x22);
+        assert_eq!(diag_byte(5, true, true), 0x35);
+    }
+
+    #[test]
+    fn diag_byte_is_sent_only_when_asked_for() {
+        use crate::frame_clock::VirtualClock;
+        use game_time::FloatDuration;
+        use omnishock::protocol::NEUTRAL_PACKET;
+
+        let controller = FauxController::create_with_name(String::from("Diagnostic Pad"));
+        // The third frame runs slow
+        let schedule = vec![
+            FloatDuration::milliseconds(20.0),
+            FloatDuration::milliseconds(20.0),
+            FloatDuration::milliseconds(45.0),
+            FloatDuration::milliseconds(20.0),
+        ];
+
+        for diag_byte in [false, true] {
+            let mut shaper = plain_shaper(&NEUTRAL_PACKET);
+            shaper.diag_byte = diag_byte;
+            let mut clock = VirtualClock::new(50.0, schedule.clone());
+            let mut serial = FauxSerial::with_responses(Vec::new());
+            run_frames(&mut clock, Some(&controller), &mut shaper, &mut serial, 18);
+
+            let length = NEUTRAL_PACKET.len() + usize::from(diag_byte);
+            assert_eq!(serial.written.len(), 18 * length);
+
+            for (index, sent) in serial.written.chunks(length).enumerate() {
+                let frame_index = index + 1;
+                assert_eq!(&sent[..NEUTRAL_PACKET.len()], &NEUTRAL_PACKET[..]);
+                if diag_byte {
+                    let slow = if frame_index == 3 { 0x10 } else { 0x00 };
+                    assert_eq!(sent[NEUTRAL_PACKET.len()], (frame_index % 16) as u8 | slow);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn button_toggle_switches_with_each_press() {
+        use super::ButtonToggle;
+
+        let mut toggle = ButtonToggle::new();
+        assert!(!toggle.update(false, 1.0));
+
+        // Holding the button down only switches once
+        assert!(toggle.update(true, 1.1));
+        assert!(toggle.update(true, 1.5));
+        assert!(toggle.update(false, 1.6));
+        assert!(!toggle.update(true, 2.0));
+        assert!(!toggle.update(false, 2.1));
+
+        // A bounce straight after a switch is ignored
+        assert!(toggle.update(true, 3.0));
+        assert!(toggle.update(false, 3.05));
+        assert!(toggle.update(true, 3.1));
+        assert!(toggle.update(false, 3.3));
+        assert!(!toggle.update(true, 3.4));
+    }
+
+    #[test]
+    fn slow_alerts_are_rate_limited() {
+        use super::{SlowAlert, SlowAlertLimiter};
+        use std::str::FromStr;
+
+        assert_eq!(SlowAlert::from_str("bell"), Ok(SlowAlert::Bell));
+        assert!(SlowAlert::from_str("siren").is_err());
+
+        let mut limiter = SlowAlertLimiter::default();
+        assert!(limiter.due(1.0));
+        // Still slow a moment later, but it's too soon to go off again
+        assert!(!limiter.due(1.5));
+        assert!(!limiter.due(5.9));
+        assert!(limiter.due(6.0));
+        assert!(!limiter.due(7.0));
+    }
+
+    #[test]
+    fn double_tap_latches_the_mode_footer() {
+        use super::{Buttons1, DoubleTap, NEUTRAL_PACKET};
+
+        let mut double_tap = DoubleTap::new(0.3);
+
+        // A single tap, or two taps too far apart, do nothing
+        assert!(!double_tap.update(true, 1.0));
+        assert!(!double_tap.update(false, 1.1));
+        assert!(!double_tap.update(true, 1.5));
+        assert!(!double_tap.update(false, 1.6));
+
+        // Holding the button down isn't tapping it again
+        assert!(!double_tap.update(true, 3.0));
+        assert!(!double_tap.update(true, 3.1));
+        assert!(!double_tap.update(true, 3.2));
+        assert!(!double_tap.update(false, 3.4));
+
+        // Two taps in quick succession latch, and stay latched
+        assert!(!double_tap.update(true, 5.0));
+        assert!(!double_tap.update(false, 5.1));
+        assert!(double_tap.update(true, 5.2));
+        assert!(double_tap.update(false, 5.3));
+        assert!(double_tap.update(false, 9.0));
+
+        // The third tap doesn't count towards another double-tap
+        assert!(double_tap.update(true, 9.1));
+        assert!(double_tap.update(false, 9.2));
+        assert!(!double_tap.update(true, 9.3));
+
+        // Applied to a packet, L3 drives the footer
+        let mut double_tap = DoubleTap::new(0.3);
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        packet[1] &= !Buttons1::L3.bits();
+        double_tap.apply(&mut packet, 0.0);
+        packet[1] = NEUTRAL_PACKET[1];
+        double_tap.apply(&mut packet, 0.1);
+        assert_eq!(packet[19], 0x55);
+        packet[1] &= !Buttons1::L3.bits();
+        double_tap.apply(&mut packet, 0.2);
+        assert_eq!(packet[19], 0xAA);
+    }
+
+    #[test]
+    fn mode_cooldown_debounces_footer_changes() {
+        use super::{ModeCooldown, NEUTRAL_PACKET};
+
+        let mut cooldown = ModeCooldown::new(0.1);
+
+        // The first footer is taken up straight away
+        assert_eq!(cooldown.update(0x55, 0.0), 0x55);
+        assert_eq!(cooldown.update(0xAA, 0.2), 0xAA);
+
+        // A one-frame tap of Guide holds for the whole cooldown...
+        assert_eq!(cooldown.update(0x55, 0.25), 0xAA);
+        assert_eq!(cooldown.update(0x55, 0.29), 0xAA);
+        // ...then lets go
+        assert_eq!(cooldown.update(0x55, 0.31), 0x55);
+
+        // Rapid toggling only gets through once per cooldown
+        let sent: Vec<u8> = (0..10)
+            .map(|frame| {
+                let wanted = if frame % 2 == 0 { 0xAA } else { 0x55 };
+                cooldown.update(wanted, 1.0 + f64::from(frame) * 0.02)
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            [0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0x55, 0x55, 0x55, 0x55, 0x55]
+        );
+
+        // Without a cooldown every change goes through
+        let mut cooldown = ModeCooldown::new(0.0);
+        assert_eq!(cooldown.update(0xAA, 0.0), 0xAA);
+        assert_eq!(cooldown.update(0x55, 0.0), 0x55);
+
+        // Seven-byte packets have no footer to hold
+        let mut cooldown = ModeCooldown::new(0.1);
+        let mut packet = NEUTRAL_PACKET[..7].to_vec();
+        cooldown.apply(&mut packet, 0.0);
+        assert_eq!(packet, NEUTRAL_PACKET[..7]);
+    }
+
+    #[test]
+    fn idle_heartbeat_fills_in_while_idle() {
+        use super::IdleHeartbeat;
+
+        let neutral = vec![0x00];
+        let packet = vec![0x01];
+
+        let mut heartbeat = IdleHeartbeat::new(3);
+        let sent: Vec<Option<Vec<u8>>> = [true, false, false, false, false, false, false, true]
+            .iter()
+            .map(|connected| heartbeat.update(connected.then(|| packet.clone()), &neutral))
+            .collect();
 
         assert_eq!(
-            controller_map_twenty_byte(&controller, &TriggerMode::RightStick, true),
+            sent,
             vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-                // Pressure values
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                // Mode footer
-                0x55,
+                Some(packet.clone()),
+                None,
+                None,
+                Some(neutral.clone()),
+                None,
+                None,
+                Some(neutral.clone()),
+                Some(packet.clone()),
             ]
         );
 
+        // Turned off, nothing's sent while idle
+        let mut heartbeat = IdleHeartbeat::new(0);
+        assert!((0..10).all(|_| heartbeat.update(None, &neutral).is_none()));
+    }
+
+    #[test]
+    fn packet_hold_smooths_dropouts() {
+        use super::PacketHold;
+        use game_time::FloatDuration;
+
+        let neutral = vec![0x00];
+        let packet = vec![0x01];
+
+        // Without a window, nothing is held
+        let mut hold = PacketHold::new(FloatDuration::milliseconds(0.0));
+
+        assert_eq!(
+            hold.update(
+                Some(packet.clone()),
+                FloatDuration::milliseconds(0.0),
+                &neutral
+            ),
+            Some(packet.clone())
+        );
+        assert_eq!(
+            hold.update(None, FloatDuration::milliseconds(16.0), &neutral),
+            None
+        );
+
+        // With a window, the last packet repeats until it expires
+        let mut hold = PacketHold::new(FloatDuration::milliseconds(100.0));
+
+        assert_eq!(
+            hold.update(None, FloatDuration::milliseconds(0.0), &neutral),
+            None
+        );
+        assert_eq!(
+            hold.update(
+                Some(packet.clone()),
+                FloatDuration::milliseconds(16.0),
+                &neutral
+            ),
+            Some(packet.clone())
+        );
+        assert_eq!(
+            hold.update(None, FloatDuration::milliseconds(32.0), &neutral),
+            Some(packet.clone())
+        );
+        assert_eq!(
+            hold.update(None, FloatDuration::milliseconds(116.0), &neutral),
+            Some(packet.clone())
+        );
+        assert_eq!(
+            hold.update(None, FloatDuration::milliseconds(132.0), &neutral),
+            Some(neutral.clone())
+        );
+        // Once released, we go back to sending nothing
+        assert_eq!(
+            hold.update(None, FloatDuration::milliseconds(148.0), &neutral),
+            None
+        );
+    }
+
+    #[test]
+    fn send_throttle_sends_the_latest_packet() {
+        use super::SendThrottle;
+
+        // Without an interval, every packet goes straight through
+        let mut throttle = SendThrottle::new(0.0);
+        assert_eq!(throttle.update(Some(vec![0x01]), 0.0), Some(vec![0x01]));
+        assert_eq!(throttle.update(Some(vec![0x02]), 0.016), Some(vec![0x02]));
+        assert_eq!(throttle.update(None, 0.032), None);
+
+        // At 20Hz, only every third 60Hz frame is sent
+        let mut throttle = SendThrottle::new(0.05);
+        assert_eq!(throttle.update(Some(vec![0x01]), 0.0), Some(vec![0x01]));
+        assert_eq!(throttle.update(Some(vec![0x02]), 0.0167), None);
+        assert_eq!(throttle.update(Some(vec![0x03]), 0.0333), None);
+        assert_eq!(throttle.update(None, 0.05), Some(vec![0x03]));
+        // Nothing new has been built, so there's nothing to send
+        assert_eq!(throttle.update(None, 0.1), None);
+
+        // After a stall, it picks the cadence up from now
+        assert_eq!(throttle.update(Some(vec![0x04]), 0.5), Some(vec![0x04]));
+        assert_eq!(throttle.update(Some(vec![0x05]), 0.51), None);
+        assert_eq!(throttle.update(Some(vec![0x06]), 0.55), Some(vec![0x06]));
+    }
+
+    #[test]
+    fn query_firmware_reads_a_line_of_text() {
+        use super::{detect_communication_mode, query_firmware, ControllerEmulatorPacketType};
+        use omnishock::protocol::{NEUTRAL_PACKET, TWENTY_BYTE_OK_HEADER};
+
+        let mut serial = FauxSerial::with_responses(vec![
+            b"example-adapter 0.1\r\n".to_vec(),
+            vec![TWENTY_BYTE_OK_HEADER, 0, 0, 0x55],
+        ]);
+        assert_eq!(
+            query_firmware(&mut serial).unwrap(),
+            Some("example-adapter 0.1".to_string())
+        );
+        assert_eq!(serial.written, vec![b'?']);
+
+        // Detection carries on as though nothing happened
+        assert_eq!(
+            detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, false).unwrap(),
+            ControllerEmulatorPacketType::TwentyByte
+        );
+
+        // Firmware which ignores the query has nothing to say
+        let mut serial = FauxSerial::with_responses(vec![]);
+        assert_eq!(query_firmware(&mut serial).unwrap(), None);
+
+        // Nor does a stray vibration response count as a name
+        let mut serial = FauxSerial::with_responses(vec![vec![TWENTY_BYTE_OK_HEADER, 0, 0, 0x55]]);
+        assert_eq!(query_firmware(&mut serial).unwrap(), None);
+        assert!(serial.pending.is_empty());
+    }
+
+    #[test]
+    fn detect_communication_mode_recognises_firmware() {
+        use super::{detect_communication_mode, ControllerEmulatorPacketType};
+        use omnishock::protocol::{NEUTRAL_PACKET, SEVEN_BYTE_ERR_RESPONSE, TWENTY_BYTE_OK_HEADER};
+
+        let mut serial = FauxSerial::with_responses(vec![vec![TWENTY_BYTE_OK_HEADER, 0, 0, 0x55]]);
+        assert_eq!(
+            detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, false).unwrap(),
+            ControllerEmulatorPacketType::TwentyByte
+        );
+        assert_eq!(serial.written, NEUTRAL_PACKET.to_vec());
+
+        let mut serial = FauxSerial::with_responses(vec![vec![SEVEN_BYTE_ERR_RESPONSE as u8]]);
+        assert_eq!(
+            detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, false).unwrap(),
+            ControllerEmulatorPacketType::SevenByte
+        );
+    }
+
+    #[test]
+    fn detect_communication_mode_refuses_unknown_firmware() {
+        use super::{detect_communication_mode, ControllerEmulatorPacketType, NEUTRAL_PACKET};
+        use crate::error::OmnishockError;
+
+        let mut serial = FauxSerial::with_responses(vec![vec![0xDE, 0xAD, 0xBE, 0xEF]]);
+        match detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, false) {
+            Err(OmnishockError::DetectionFailed(_)) => (),
+            other => panic!("expected detection to fail, got {:?}", other),
+        }
+
+        // Unless we've been told that's alright
+        let mut serial = FauxSerial::with_responses(vec![vec![0xDE, 0xAD, 0xBE, 0xEF]]);
+        assert_eq!(
+            detect_communication_mode(
+                &mut serial,
+                &NEUTRAL_PACKET,
+                Some(ControllerEmulatorPacketType::None),
+                0,
+                false
+            )
+            .unwrap(),
+            ControllerEmulatorPacketType::None
+        );
+
+        // Silence is no better than gibberish
+        let mut serial = FauxSerial::with_responses(vec![]);
+        assert!(detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, false).is_err());
+    }
+
+    #[test]
+    fn detect_communication_mode_falls_back_to_the_default_mode() {
+        use super::{
+            detect_communication_mode, parse_default_mode, unrecognised_fallback,
+            ControllerEmulatorPacketType, NEUTRAL_PACKET,
+        };
+        use omnishock::protocol::SEVEN_BYTE_ERR_RESPONSE;
+
+        let twenty = parse_default_mode("twenty").unwrap();
+        assert_eq!(twenty, ControllerEmulatorPacketType::TwentyByte);
+        assert_eq!(
+            parse_default_mode("none"),
+            Ok(ControllerEmulatorPacketType::None)
+        );
+        assert!(parse_default_mode("forty").is_err());
+
+        let mut serial = FauxSerial::with_responses(vec![vec![0xDE, 0xAD, 0xBE, 0xEF]]);
+        assert_eq!(
+            detect_communication_mode(
+                &mut serial,
+                &NEUTRAL_PACKET,
+                unrecognised_fallback(Some(twenty), false),
+                0,
+                false
+            )
+            .unwrap(),
+            ControllerEmulatorPacketType::TwentyByte
+        );
+
+        // Firmware which is recognised is still used as it is
+        let mut serial = FauxSerial::with_responses(vec![vec![SEVEN_BYTE_ERR_RESPONSE as u8]]);
+        assert_eq!(
+            detect_communication_mode(&mut serial, &NEUTRAL_PACKET, Some(twenty), 0, false)
+                .unwrap(),
+            ControllerEmulatorPacketType::SevenByte
+        );
+
+        assert_eq!(unrecognised_fallback(None, false), None);
+        assert_eq!(
+            unrecognised_fallback(None, true),
+            Some(ControllerEmulatorPacketType::None)
+        );
+    }
+
+    #[test]
+    fn packet_logs_round_trip_in_every_format() {
+        use super::{decode_packet_log, encode_packet_log, sent_line};
+        use omnishock::packet_delta::{decode_recording, PacketLogFormat};
+        use omnishock::protocol::{ControllerEmulatorPacketType, NEUTRAL_PACKET};
+
+        let twenty = ControllerEmulatorPacketType::TwentyByte;
+
+        let mut pressed = NEUTRAL_PACKET.to_vec();
+        pressed[2] = 0xBF;
+        let packets = vec![NEUTRAL_PACKET.to_vec(), pressed, NEUTRAL_PACKET.to_vec()];
+
+        for format in [PacketLogFormat::Raw, PacketLogFormat::Delta] {
+            let encoded = encode_packet_log(&packets, format, twenty, 60.0).unwrap();
+            assert_eq!(
+                decode_packet_log(&encoded, format).map(|(_, packets)| packets),
+                Ok(packets.clone())
+            );
+        }
+
+        // Raw logs are just what --verbose would have written
+        let raw = encode_packet_log(&packets[..1], PacketLogFormat::Raw, twenty, 60.0).unwrap();
+        assert_eq!(
+            String::from_utf8(raw).unwrap(),
+            format!("{}\n", sent_line(&NEUTRAL_PACKET))
+        );
+
+        // Recordings are headed, timed by the rate, and much smaller
+        let delta = encode_packet_log(&packets, PacketLogFormat::Delta, twenty, 60.0).unwrap();
+        let (header, frames) = decode_recording(&delta).unwrap();
+        assert_eq!((header.rate_hz, header.format), (60, twenty));
+        let timings: Vec<u64> = frames.iter().map(|frame| frame.at_ms).collect();
+        assert_eq!(timings, vec![0, 17, 33]);
+        assert!(delta.len() < packets.len() * NEUTRAL_PACKET.len());
+        assert!(encode_packet_log(&packets, PacketLogFormat::Delta, twenty, 59.94).is_err());
+
+        // Reading one format as the other fails, rather than sending nonsense
+        assert!(decode_packet_log(&delta, PacketLogFormat::Raw).is_err());
         assert_eq!(
-            controller_map_twenty_byte(&controller, &TriggerMode::CrossAndSquare, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-                // Pressure values
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                // Mode footer
-                0x55,
-            ]
+            decode_packet_log(b"Sent: 5aff\n", PacketLogFormat::Delta),
+            Err("This isn't an Omnishock recording".to_string())
+        );
+    }
+
+    #[test]
+    fn convert_reads_verbose_logs() {
+        use super::convert_log_lines;
+        use omnishock::protocol::ControllerEmulatorPacketType;
+
+        // As a seven-byte --verbose session logs it, holding cross
+        let log = concat!(
+            "Connecting to PS2 Controller Emulator device at '/dev/ttyUSB0'...\n",
+            "Sent: 5affbf80808080\n",
+            "Received: 00\n",
+            "Sent: 5affff80808080\n",
+            "Sent: 5aff\n",
         );
 
-        // Do some stuff to the controller state, and test again
-        controller.set_button(Button::DPadLeft, true);
-        controller.set_button(Button::A, true);
-        controller.set_axis(Axis::TriggerLeft, i16::max_value());
-        controller.set_axis(Axis::RightX, -24_000);
-        controller.set_axis(Axis::RightY, 16_500);
-        controller.set_axis(Axis::LeftX, 255);
-        controller.set_axis(Axis::LeftY, -4_096);
+        let mut held = vec![0x5A, 0xFF, 0xBF, 0x80, 0x80, 0x80, 0x80];
+        held.extend([0, 0, 0, 0, 0, 0, 0xFF, 0, 0, 0, 0, 0, 0x55]);
+        let mut released = vec![0x5A, 0xFF, 0xFF, 0x80, 0x80, 0x80, 0x80];
+        released.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x55]);
 
+        // The too-short packet's skipped
         assert_eq!(
-            controller_map_twenty_byte(&controller, &TriggerMode::Normal, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::LEFT.bits(),
-                !(Buttons2::CROSS | Buttons2::L2).bits(),
-                // Analog sticks
-                0x18,
-                0xC6,
-                0x81,
-                0x6E,
-                // Pressure values
-                0x00,
-                0xFF,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0xFF,
-                0x00,
-                0x00,
-                0x00,
-                0xFF,
-                0x00,
-                // Mode footer
-                0x55,
-            ]
+            convert_log_lines(log, &ControllerEmulatorPacketType::TwentyByte),
+            vec![held, released]
         );
+        assert_eq!(
+            convert_log_lines(log, &ControllerEmulatorPacketType::SevenByte).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn recordings_are_checked_before_resending() {
+        use super::{check_recording_format, resend_rate};
+        use omnishock::packet_delta::RecordingHeader;
+        use omnishock::protocol::ControllerEmulatorPacketType;
+
+        let header = RecordingHeader::new(50, ControllerEmulatorPacketType::SevenByte);
 
+        // The recording's rate is used, unless --rate disagrees with it
+        assert_eq!(resend_rate(Some(&header), None), Ok(50.0));
+        assert_eq!(resend_rate(Some(&header), Some(50.0)), Ok(50.0));
         assert_eq!(
-            controller_map_twenty_byte(&controller, &TriggerMode::RightStick, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::LEFT.bits(),
-                !(Buttons2::CROSS | Buttons2::R2).bits(),
-                // Analog sticks
-                0x18,
-                0xFF,
-                0x81,
-                0x6E,
-                // Pressure values
-                0x00,
-                0xFF,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0xFF,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x80,
-                // Mode footer
-                0x55,
-            ]
+            resend_rate(Some(&header), Some(60.0)),
+            Err("The recording was made at 50Hz, but --rate is 60Hz".to_string())
+        );
+        // A raw log has no rate of its own
+        assert_eq!(resend_rate(None, None), Ok(60.0));
+        assert_eq!(resend_rate(None, Some(30.0)), Ok(30.0));
+
+        assert!(
+            check_recording_format(Some(&header), &ControllerEmulatorPacketType::SevenByte).is_ok()
+        );
+        assert!(
+            check_recording_format(Some(&header), &ControllerEmulatorPacketType::TwentyByte)
+                .unwrap_err()
+                .contains("seven-byte protocol")
         );
+        // Nothing's sent without a protocol, so anything goes
+        assert!(check_recording_format(Some(&header), &ControllerEmulatorPacketType::None).is_ok());
+        assert!(check_recording_format(None, &ControllerEmulatorPacketType::TwentyByte).is_ok());
+    }
+
+    #[test]
+    fn parse_sent_line_reads_logged_packets() {
+        use super::{parse_sent_line, NEUTRAL_PACKET};
+        use hex_view::HexView;
 
+        // Just as --verbose writes them
         assert_eq!(
-            controller_map_twenty_byte(&controller, &TriggerMode::CrossAndSquare, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::LEFT.bits(),
-                !(Buttons2::SQUARE | Buttons2::L2).bits(),
-                // Analog sticks
-                0x18,
-                0xC6,
-                0x81,
-                0x6E,
-                // Pressure values
-                0x00,
-                0xFF,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0xFF,
-                0x00,
-                0x00,
-                0xFF,
-                0x00,
-                // Mode footer
-                0x55,
-            ]
+            parse_sent_line("Sent: 5aff7f80808080"),
+            Some(Ok(vec![0x5A, 0xFF, 0x7F, 0x80, 0x80, 0x80, 0x80]))
+        );
+        assert_eq!(
+            parse_sent_line(&format!(
+                "Sent:     {:x}",
+                HexView::from(&NEUTRAL_PACKET[..])
+            )),
+            Some(Ok(NEUTRAL_PACKET.to_vec()))
         );
+        assert_eq!(parse_sent_line("Sent: 5AFF"), Some(Ok(vec![0x5A, 0xFF])));
+
+        // Other lines are ignored, but broken ones are errors
+        assert_eq!(parse_sent_line("Received: 0102"), None);
+        assert_eq!(parse_sent_line("Using trigger mode 'Normal'..."), None);
+        assert!(matches!(parse_sent_line("Sent: 5afg"), Some(Err(_))));
+        assert!(matches!(parse_sent_line("Sent: 5af"), Some(Err(_))));
+        assert!(matches!(parse_sent_line("Sent: 5a ff"), Some(Err(_))));
+        assert!(matches!(parse_sent_line("Sent: 5aé"), Some(Err(_))));
+        assert!(matches!(parse_sent_line("Sent: "), Some(Err(_))));
     }
 
     #[test]
-    fn controller_map_seven_byte_works() {
-        use super::controller_map_seven_byte;
-        use super::{Buttons1, Buttons2, TriggerMode};
-        use crate::DUALSHOCK_MAGIC;
-        use sdl2::controller::{Axis, Button};
+    fn serial_echo_test_reports_firmware() {
+        use super::{serial_echo_test, NEUTRAL_PACKET};
+        use crate::error::OmnishockError;
+        use omnishock::protocol::{SEVEN_BYTE_ERR_RESPONSE, TWENTY_BYTE_OK_HEADER};
+
+        let mut serial = FauxSerial::with_responses(vec![vec![TWENTY_BYTE_OK_HEADER, 0, 0, 0x55]]);
+        assert!(serial_echo_test(&mut serial).is_ok());
+        // Exactly one packet, and nothing more
+        assert_eq!(serial.written, NEUTRAL_PACKET.to_vec());
+
+        let mut serial = FauxSerial::with_responses(vec![vec![SEVEN_BYTE_ERR_RESPONSE as u8]]);
+        assert!(serial_echo_test(&mut serial).is_ok());
+
+        let mut serial = FauxSerial::with_responses(vec![vec![]]);
+        match serial_echo_test(&mut serial) {
+            Err(OmnishockError::DetectionFailed(_)) => (),
+            other => panic!("expected the echo test to fail, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn detect_communication_mode_retries() {
+        use super::{detect_communication_mode, ControllerEmulatorPacketType};
+        use omnishock::protocol::{NEUTRAL_PACKET, TWENTY_BYTE_OK_HEADER};
 
-        let mut controller =
-            FauxController::create_with_name(String::from("Apple Pippin Controller"));
+        let responses = vec![
+            vec![0xDE, 0xAD, 0xBE, 0xEF],
+            vec![TWENTY_BYTE_OK_HEADER, 0, 0, 0x55],
+        ];
 
+        // Without retries, the first bit of gibberish is the end of it
+        let mut serial = FauxSerial::with_responses(responses.clone());
+        assert!(detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 0, false).is_err());
+
+        // But with one, we get to hear the real answer
+        let mut serial = FauxSerial::with_responses(responses);
         assert_eq!(
-            controller_map_seven_byte(&controller, &TriggerMode::Normal, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-            ]
+            detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 1, false).unwrap(),
+            ControllerEmulatorPacketType::TwentyByte
         );
+        assert_eq!(serial.written, [NEUTRAL_PACKET, NEUTRAL_PACKET].concat());
 
+        // Retries stop as soon as the firmware is recognised
+        let mut serial = FauxSerial::with_responses(vec![vec![TWENTY_BYTE_OK_HEADER, 0, 0, 0x55]]);
         assert_eq!(
-            controller_map_seven_byte(&controller, &TriggerMode::RightStick, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-            ]
+            detect_communication_mode(&mut serial, &NEUTRAL_PACKET, None, 5, false).unwrap(),
+            ControllerEmulatorPacketType::TwentyByte
         );
+        assert_eq!(serial.written, NEUTRAL_PACKET.to_vec());
+    }
+
+    #[test]
+    fn response_anomaly_spots_bad_responses() {
+        use super::{response_anomaly, ControllerEmulatorPacketType};
+        use omnishock::protocol::{
+            SEVEN_BYTE_ERR_RESPONSE, SEVEN_BYTE_OK_RESPONSE, TWENTY_BYTE_OK_HEADER,
+        };
+
+        let seven = ControllerEmulatorPacketType::SevenByte;
+        let twenty = ControllerEmulatorPacketType::TwentyByte;
 
         assert_eq!(
-            controller_map_seven_byte(&controller, &TriggerMode::CrossAndSquare, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-            ]
+            response_anomaly(&seven, &[SEVEN_BYTE_OK_RESPONSE as u8]),
+            None
         );
+        assert_eq!(
+            response_anomaly(&seven, &[SEVEN_BYTE_ERR_RESPONSE as u8]),
+            Some("unexpected response")
+        );
+        assert_eq!(response_anomaly(&seven, &[]), Some("short read"));
 
-        // Do some stuff to the controller state, and test again
-        controller.set_button(Button::DPadLeft, true);
-        controller.set_button(Button::A, true);
-        controller.set_axis(Axis::TriggerLeft, i16::max_value());
-        controller.set_axis(Axis::RightX, -24_000);
-        controller.set_axis(Axis::RightY, 16_500);
-        controller.set_axis(Axis::LeftX, 255);
-        controller.set_axis(Axis::LeftY, -4_096);
+        assert_eq!(
+            response_anomaly(&twenty, &[TWENTY_BYTE_OK_HEADER, 0, 0, 0x55]),
+            None
+        );
+        assert_eq!(
+            response_anomaly(&twenty, &[TWENTY_BYTE_OK_HEADER, 0]),
+            Some("short read")
+        );
+        assert_eq!(
+            response_anomaly(&twenty, &[0x00, 0, 0, 0x55]),
+            Some("unexpected response")
+        );
 
         assert_eq!(
-            controller_map_seven_byte(&controller, &TriggerMode::Normal, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::LEFT.bits(),
-                !(Buttons2::CROSS | Buttons2::L2).bits(),
-                // Analog sticks
-                0x18,
-                0xC6,
-                0x81,
-                0x6E,
-            ]
+            response_anomaly(&ControllerEmulatorPacketType::None, &[]),
+            None
         );
+    }
+
+    #[test]
+    fn send_checking_echo_spots_corruption() -> Result<(), Box<dyn std::error::Error>> {
+        use self::mockstream::SharedMockStream;
+        use super::{describe_echo_mismatch, send_checking_echo, NEUTRAL_PACKET};
 
+        // A faithful echo
+        let mut serial = SharedMockStream::new();
+        serial.push_bytes_to_read(&NEUTRAL_PACKET);
+        let echo = send_checking_echo(&mut serial, &NEUTRAL_PACKET, false)?;
+        assert_eq!(serial.pop_bytes_written(), NEUTRAL_PACKET.to_vec());
+        assert_eq!(describe_echo_mismatch(&NEUTRAL_PACKET, &echo), None);
+
+        // One flipped bit
+        let mut corrupted = NEUTRAL_PACKET;
+        corrupted[3] ^= 0x01;
+        serial.push_bytes_to_read(&corrupted);
+        let echo = send_checking_echo(&mut serial, &NEUTRAL_PACKET, false)?;
         assert_eq!(
-            controller_map_seven_byte(&controller, &TriggerMode::RightStick, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::LEFT.bits(),
-                !(Buttons2::CROSS | Buttons2::R2).bits(),
-                // Analog sticks
-                0x18,
-                0xFF,
-                0x81,
-                0x6E,
-            ]
+            describe_echo_mismatch(&NEUTRAL_PACKET, &echo),
+            Some("Echo differs from what was sent: byte 3 was 0x81, not 0x80".to_string())
         );
 
+        // A dropped byte
+        serial.push_bytes_to_read(&NEUTRAL_PACKET[..19]);
+        let echo = send_checking_echo(&mut serial, &NEUTRAL_PACKET, false)?;
         assert_eq!(
-            controller_map_seven_byte(&controller, &TriggerMode::CrossAndSquare, true),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::LEFT.bits(),
-                !(Buttons2::SQUARE | Buttons2::L2).bits(),
-                // Analog sticks
-                0x18,
-                0xC6,
-                0x81,
-                0x6E,
-            ]
+            describe_echo_mismatch(&NEUTRAL_PACKET, &echo),
+            Some("Echo differs from what was sent: only 19 of 20 bytes came back".to_string())
         );
+
+        Ok(())
     }
 
     #[test]
-    fn send_event_to_controller_works() -> Result<(), Box<dyn std::error::Error>> {
+    fn send_event_to_controller_reports_timeouts() {
+        use super::{ignoring_timeout, send_event_to_controller};
+        use super::{ControllerEmulatorPacketType, NEUTRAL_PACKET};
+
+        let mode = ControllerEmulatorPacketType::TwentyByte;
+
+        // Nothing comes back at all
+        let mut serial = FauxSerial::with_responses(Vec::new());
+        let result = send_event_to_controller(&mut serial, &NEUTRAL_PACKET, &mode, false);
+        assert!(result.as_ref().unwrap_err().is_timeout());
+        assert_eq!(ignoring_timeout(result).unwrap(), Vec::<u8>::new());
+        assert_eq!(serial.written, NEUTRAL_PACKET.to_vec());
+    }
+
+    #[test]
+    fn send_event_to_controller_reads_whole_responses() -> Result<(), Box<dyn std::error::Error>> {
         use self::mockstream::SharedMockStream;
-        use super::send_event_to_controller;
-        use super::ControllerEmulatorPacketType;
-        use super::{Buttons1, Buttons2, TriggerMode};
-        use crate::DUALSHOCK_MAGIC;
-        use crate::SEVEN_BYTE_OK_RESPONSE;
-        use crate::TWENTY_BYTE_OK_HEADER;
+        use super::{send_event_to_controller, ControllerEmulatorPacketType, NEUTRAL_PACKET};
+        use omnishock::protocol::{SEVEN_BYTE_OK_RESPONSE, TWENTY_BYTE_OK_HEADER};
+
+        for (mode, expected) in [
+            (ControllerEmulatorPacketType::None, vec![]),
+            (
+                ControllerEmulatorPacketType::SevenByte,
+                vec![SEVEN_BYTE_OK_RESPONSE as u8],
+            ),
+            (
+                ControllerEmulatorPacketType::TwentyByte,
+                vec![TWENTY_BYTE_OK_HEADER, 0x00, 0xFF, 0x55],
+            ),
+        ] {
+            assert_eq!(mode.response_length(), expected.len());
+
+            // Anything past the response belongs to the next frame
+            let mut serial = SharedMockStream::new();
+            serial.push_bytes_to_read(&expected);
+            serial.push_bytes_to_read(&[0xEE; 4]);
+
+            assert_eq!(
+                send_event_to_controller(&mut serial, &NEUTRAL_PACKET, &mode, false)?,
+                expected
+            );
+        }
 
-        let controller = FauxController::create_with_name(String::from("Apple Pippin Controller"));
+        Ok(())
+    }
 
-        let seven_byte_console_response = vec![SEVEN_BYTE_OK_RESPONSE as u8];
+    #[test]
+    fn describe_raw_input_shows_what_the_mapping_made_of_it() {
+        use super::describe_raw_input;
+        use omnishock::sdl_manager::{MappedControl, RawInput};
+        use sdl2::controller::{Axis, Button};
 
-        let mut serial = SharedMockStream::new();
-        serial.push_bytes_to_read(&seven_byte_console_response);
+        let mut controller = FauxController::create_with_name(String::from("Arcade Stick"));
+        controller.set_axis(Axis::TriggerLeft, 32767);
+        controller.set_button(Button::DPadUp, true);
 
         assert_eq!(
-            send_event_to_controller(
-                &mut serial,
+            describe_raw_input(
                 &controller,
-                &ControllerEmulatorPacketType::SevenByte,
-                &TriggerMode::Normal,
-                false,
-                false,
-            )?,
-            seven_byte_console_response
+                RawInput::Axis(4),
+                32767,
+                &[MappedControl::Axis(Axis::TriggerLeft)]
+            ),
+            "raw axis 4: 32767 → trigger-left 32767"
         );
+        // A hat usually feeds all four directions
         assert_eq!(
-            serial.pop_bytes_written(),
-            vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-            ]
+            describe_raw_input(
+                &controller,
+                RawInput::Hat(0),
+                1,
+                &[
+                    MappedControl::Button(Button::DPadUp),
+                    MappedControl::Button(Button::DPadDown)
+                ]
+            ),
+            "raw hat 0: 1 → dpad-up down, dpad-down up"
+        );
+        assert_eq!(
+            describe_raw_input(&controller, RawInput::Button(11), 1, &[]),
+            "raw button 11: 1 → unmapped"
         );
+    }
 
-        let twenty_byte_console_response = vec![TWENTY_BYTE_OK_HEADER, 0x00, 0x00, 0x55];
+    #[cfg(all(unix, feature = "fd-device"))]
+    #[test]
+    fn parse_fd_device_reads_the_descriptor() {
+        use super::parse_fd_device;
 
-        serial.push_bytes_to_read(&twenty_byte_console_response);
+        assert_eq!(parse_fd_device("fd:3"), Some(Ok(3)));
+        assert!(parse_fd_device("fd:-1").unwrap().is_err());
+        assert!(parse_fd_device("fd:serial").unwrap().is_err());
+        assert_eq!(parse_fd_device("/dev/ttyUSB0"), None);
+    }
+
+    #[test]
+    fn flags_go_over_the_profile() {
+        use super::{build_mapping_options, CLIArgs, Subcommands};
+        use crate::profile::Profile;
+        use clap::Parser;
+        use omnishock::protocol::{
+            AxisRoute, DPadStick, MappingOptions, RangeProfile, StickCentre, StickShape,
+            TriggerMode,
+        };
+        use sdl2::controller::Axis;
+        use std::str::FromStr;
+
+        let options = |flags: &[&str], profile: &str| {
+            let arguments =
+                CLIArgs::try_parse_from(["omnishock", "ps2ce", "/dev/null"].iter().chain(flags))
+                    .unwrap();
+            let command_arguments = match arguments.subcommand {
+                Subcommands::PS2CESubcommand(subcommand) => subcommand,
+                _ => unreachable!(),
+            };
+            build_mapping_options(&command_arguments, &Profile::from_str(profile).unwrap()).unwrap()
+        };
 
+        // With neither, everything's at its default
+        let defaults = options(&[], "");
         assert_eq!(
-            send_event_to_controller(
-                &mut serial,
-                &controller,
-                &ControllerEmulatorPacketType::TwentyByte,
-                &TriggerMode::Normal,
-                false,
-                false,
-            )?,
-            twenty_byte_console_response
+            defaults,
+            MappingOptions {
+                range_profile: RangeProfile::default(),
+                ..MappingOptions::new(TriggerMode::Normal, true)
+            }
+        );
+
+        let profile = "trigger-mode = cross-and-square
+            stick-shape = circle
+            stick-centre = 0x7F
+            stick-normalise = off
+            anti-deadzone = 2000
+            axis.left-x = right-x
+            axis.left-y = right-y";
+
+        // The profile's settings go over the defaults...
+        let from_profile = options(&[], profile);
+        assert_eq!(from_profile.trigger_mode, TriggerMode::CrossAndSquare);
+        assert_eq!(from_profile.stick_shape, StickShape::Circle);
+        assert!(!from_profile.normalise_sticks);
+
+        // ...and flags go over the profile, leaving the rest alone
+        let from_flags = options(
+            &[
+                "--trigger-mode",
+                "right-stick",
+                "--anti-deadzone",
+                "4000",
+                "--route-axis",
+                "left-x=trigger-left",
+            ],
+            profile,
         );
+        assert_eq!(from_flags.trigger_mode, TriggerMode::RightStick);
+        assert_eq!(from_flags.anti_deadzone, 4000);
+        assert_eq!(from_flags.stick_centre, StickCentre(0x7F));
+        assert_eq!(from_flags.stick_shape, StickShape::Circle);
+        assert!(!from_flags.normalise_sticks);
+
+        // Routes from both are kept, with the flag's winning for an output
         assert_eq!(
-            serial.pop_bytes_written(),
+            from_flags.axis_routes.input_for(Axis::LeftX),
+            Axis::TriggerLeft
+        );
+        assert_eq!(from_flags.axis_routes.input_for(Axis::LeftY), Axis::RightY);
+        assert_eq!(
+            from_flags.axis_routes.routes(),
             vec![
-                DUALSHOCK_MAGIC,
-                !Buttons1::empty().bits(),
-                !Buttons2::empty().bits(),
-                // Analog sticks
-                0x80,
-                0x80,
-                0x80,
-                0x80,
-                // Pressure values
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                // Mode footer
-                0x55,
+                AxisRoute::from_str("left-x=trigger-left").unwrap(),
+                AxisRoute::from_str("left-y=right-y").unwrap(),
             ]
         );
 
-        Ok(())
+        // A named range profile on the command line beats the profile's own
+        let range = options(&["--range-profile", "linear"], "range = 20,4,251");
+        assert_eq!(
+            range.range_profile,
+            RangeProfile::builtin("linear").unwrap()
+        );
+        let range = options(&[], "range = 20,4,251");
+        assert_eq!(range.range_profile.normalise_percent, 20);
+
+        // Switches the profile turns on or off can be switched back
+        let profile = "stick-normalise = off\ntrigger-pressure = on\ndpad-stick = copy";
+        let from_profile = options(&[], profile);
+        assert!(!from_profile.normalise_sticks);
+        assert!(from_profile.trigger_pressure);
+        assert_eq!(from_profile.dpad_stick, DPadStick::Copy);
+        let switched_back = options(
+            &[
+                "--stick-normalise",
+                "--no-trigger-pressure",
+                "--no-dpad-as-lstick",
+            ],
+            profile,
+        );
+        assert!(switched_back.normalise_sticks);
+        assert!(!switched_back.trigger_pressure);
+        assert_eq!(switched_back.dpad_stick, DPadStick::Off);
     }
 }
@@ -0,0 +1,158 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Metrics
+// Counters describing a running session, served over HTTP
+// in Prometheus' text exposition format for monitoring
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub frames_sent: AtomicU64,
+    pub slow_frames: AtomicU64,
+    pub serial_errors: AtomicU64,
+    pub reconnects: AtomicU64,
+    // Stored as the bits of an f64, as there's no atomic float type
+    frame_rate: AtomicU64,
+}
+
+impl Metrics {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_frame_rate(&self, frame_rate: f64) {
+        self.frame_rate
+            .store(frame_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "omnishock_frames_sent_total",
+                "Packets sent to the device",
+                &self.frames_sent,
+            ),
+            (
+                "omnishock_slow_frames_total",
+                "Frames which ran slower than the target frame rate",
+                &self.slow_frames,
+            ),
+            (
+                "omnishock_serial_errors_total",
+                "Errors communicating with the device",
+                &self.serial_errors,
+            ),
+            (
+                "omnishock_reconnects_total",
+                "Controllers connected after start-up",
+                &self.reconnects,
+            ),
+        ];
+
+        let mut output = String::new();
+
+        for (name, help, counter) in counters.iter() {
+            output.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n",
+                name = name,
+                help = help,
+                value = counter.load(Ordering::Relaxed)
+            ));
+        }
+
+        output.push_str(&format!(
+            "# HELP omnishock_frame_rate Average frames per second\n\
+             # TYPE omnishock_frame_rate gauge\n\
+             omnishock_frame_rate {}\n",
+            f64::from_bits(self.frame_rate.load(Ordering::Relaxed))
+        ));
+
+        output
+    }
+}
+
+fn respond(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // We serve the same thing for every path, so only need
+    // to wait for the end of the request headers
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let body = metrics.render();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {}",
+        body.len(),
+        body
+    )
+}
+
+// Starts serving `metrics` on the given address from a background thread
+pub fn serve(metrics: Arc<Metrics>, address: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+
+            if let Err(error) = result {
+                println!("Warning: failed to serve metrics: {}", error);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn render_lists_every_metric() {
+        use super::Metrics;
+
+        let metrics = Metrics::default();
+        Metrics::increment(&metrics.frames_sent);
+        Metrics::increment(&metrics.frames_sent);
+        Metrics::increment(&metrics.reconnects);
+        metrics.set_frame_rate(59.5);
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("# TYPE omnishock_frames_sent_total counter\n"));
+        assert!(rendered.contains("\nomnishock_frames_sent_total 2\n"));
+        assert!(rendered.contains("\nomnishock_slow_frames_total 0\n"));
+        assert!(rendered.contains("\nomnishock_serial_errors_total 0\n"));
+        assert!(rendered.contains("\nomnishock_reconnects_total 1\n"));
+        assert!(rendered.contains("# TYPE omnishock_frame_rate gauge\n"));
+        assert!(rendered.ends_with("\nomnishock_frame_rate 59.5\n"));
+    }
+}
@@ -0,0 +1,105 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// Paced Writer
+// Wraps a stream so that writes are broken into chunks, with a short
+// pause between each, for firmware which can't keep up with a whole
+// packet arriving at once. Reads pass straight through.
+
+pub struct PacedWriter<I> {
+    inner: I,
+    chunk_size: usize,
+    delay: Duration,
+    spin_sleeper: spin_sleep::SpinSleeper,
+}
+
+impl<I> PacedWriter<I> {
+    pub fn new(inner: I, chunk_size: usize, delay: Duration) -> PacedWriter<I> {
+        PacedWriter {
+            inner,
+            // A chunk size of zero would never make any progress
+            chunk_size: std::cmp::max(1, chunk_size),
+            delay,
+            // As with the frame timer, only spin for the last 1ms
+            spin_sleeper: spin_sleep::SpinSleeper::new(1_000_000),
+        }
+    }
+}
+
+impl<I: Read> Read for PacedWriter<I> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buffer)
+    }
+}
+
+impl<I: Write> Write for PacedWriter<I> {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        // Without a delay there's no reason to split anything up
+        if self.delay == Duration::from_secs(0) {
+            return self.inner.write(buffer);
+        }
+
+        let written = self
+            .inner
+            .write(&buffer[..std::cmp::min(self.chunk_size, buffer.len())])?;
+
+        // `write_all` will be straight back for the rest of the packet,
+        // so give the firmware a moment before it gets the next chunk
+        if written < buffer.len() {
+            self.spin_sleeper.sleep(self.delay);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PacedWriter;
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn paced_writer_writes_in_chunks() {
+        let mut writer = PacedWriter::new(Vec::new(), 3, Duration::from_micros(1));
+
+        assert_eq!(writer.write(&[1, 2, 3, 4, 5]).unwrap(), 3);
+        assert_eq!(writer.write(&[4, 5]).unwrap(), 2);
+        assert_eq!(writer.inner, vec![1, 2, 3, 4, 5]);
+
+        writer.write_all(&[6, 7, 8, 9, 10, 11, 12]).unwrap();
+        assert_eq!(writer.inner, (1..=12).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn paced_writer_passes_through_without_delay() {
+        let mut writer = PacedWriter::new(Vec::new(), 1, Duration::from_secs(0));
+
+        assert_eq!(writer.write(&[1, 2, 3, 4, 5]).unwrap(), 5);
+        assert_eq!(writer.inner, vec![1, 2, 3, 4, 5]);
+    }
+}
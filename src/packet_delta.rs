@@ -0,0 +1,440 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Packet Delta
+// A compact encoding for a timed stream of packets. Most frames only
+// change a byte or two of the last packet, so each frame is stored as
+// the time since the one before, followed by just the bytes which
+// changed:
+//
+//   <ms since last frame, LEB128> <packet length> <change count>
+//   then <index> <value> for each change
+//
+// A packet of a different length from the last is compared against
+// zeroes, so the first frame lists every non-zero byte.
+//
+// A recording is a header followed by a delta encoded stream. The header
+// is eight bytes, so a reader can tell it's been given a recording, and
+// how to play it back:
+//
+//   "OMSK" <version> <frame rate in Hz, u16 little-endian> <packet format>
+//
+// where the packet format is the length of each packet (7 or 20), or 0
+// for packets recorded without a protocol.
+
+use crate::protocol::{
+    ControllerEmulatorPacketType, SEVEN_BYTE_PACKET_LENGTH, TWENTY_BYTE_PACKET_LENGTH,
+};
+use std::str::FromStr;
+
+const RECORDING_MAGIC: [u8; 4] = *b"OMSK";
+pub const RECORDING_VERSION: u8 = 1;
+const RECORDING_HEADER_LENGTH: usize = 8;
+
+// How a file of packets is laid out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketLogFormat {
+    // The "Sent:" lines of a --verbose log
+    Raw,
+    // A delta encoded stream
+    Delta,
+}
+
+impl PacketLogFormat {
+    pub fn variants() -> [&'static str; 2] {
+        ["raw", "delta"]
+    }
+}
+
+impl FromStr for PacketLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(PacketLogFormat::Raw),
+            "delta" => Ok(PacketLogFormat::Delta),
+            _ => Err("Unexpected packet log format".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for PacketLogFormat {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            PacketLogFormat::Raw => "raw",
+            PacketLogFormat::Delta => "delta",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedPacket {
+    // Milliseconds since the stream started
+    pub at_ms: u64,
+    pub packet: Vec<u8>,
+}
+
+fn write_leb128(output: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+fn read_leb128(input: &mut impl Iterator<Item = u8>) -> Result<Option<u64>, String> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = match input.next() {
+            Some(byte) => byte,
+            // Running out before the first byte is just the end of the stream
+            None if shift == 0 => return Ok(None),
+            None => return Err("Stream ended part way through a frame time".to_string()),
+        };
+
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+
+    Err("Frame time is too long".to_string())
+}
+
+// The packet `previous` becomes when compared against one `length` bytes long
+fn baseline(previous: &[u8], length: usize) -> Vec<u8> {
+    if previous.len() == length {
+        previous.to_vec()
+    } else {
+        vec![0; length]
+    }
+}
+
+pub fn encode_delta(frames: &[TimedPacket]) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("encode_delta()");
+    let mut output = Vec::new();
+    let mut previous: &[u8] = &[];
+    let mut previous_at = 0;
+
+    for frame in frames {
+        let since = frame.at_ms.checked_sub(previous_at).ok_or_else(|| {
+            format!(
+                "Frame at {}ms comes before the one at {}ms",
+                frame.at_ms, previous_at
+            )
+        })?;
+        let length = u8::try_from(frame.packet.len())
+            .map_err(|_| format!("A {}-byte packet is too long", frame.packet.len()))?;
+
+        let changes: Vec<(u8, u8)> = baseline(previous, frame.packet.len())
+            .iter()
+            .zip(frame.packet.iter())
+            .enumerate()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(index, (_, after))| (index as u8, *after))
+            .collect();
+
+        write_leb128(&mut output, since);
+        output.push(length);
+        // There can't be more changes than bytes, so this fits too
+        output.push(changes.len() as u8);
+        for (index, value) in changes {
+            output.push(index);
+            output.push(value);
+        }
+
+        previous = &frame.packet;
+        previous_at = frame.at_ms;
+    }
+
+    Ok(output)
+}
+
+pub fn decode_delta(input: &[u8]) -> Result<Vec<TimedPacket>, String> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("decode_delta()");
+    let mut input = input.iter().copied();
+    let mut frames: Vec<TimedPacket> = Vec::new();
+
+    loop {
+        let since = match read_leb128(&mut input)? {
+            Some(since) => since,
+            None => return Ok(frames),
+        };
+        let mut next = |what: &str| {
+            input
+                .next()
+                .ok_or_else(|| format!("Stream ended before the {}", what))
+        };
+        let length = usize::from(next("packet length")?);
+        let change_count = next("change count")?;
+
+        let previous = frames.last();
+        let mut packet = baseline(
+            previous.map(|frame| frame.packet.as_slice()).unwrap_or(&[]),
+            length,
+        );
+        for _ in 0..change_count {
+            let index = usize::from(next("change index")?);
+            let value = next("change value")?;
+            *packet
+                .get_mut(index)
+                .ok_or_else(|| format!("Change at byte {} of a {}-byte packet", index, length))? =
+                value;
+        }
+
+        frames.push(TimedPacket {
+            at_ms: previous.map(|frame| frame.at_ms).unwrap_or(0) + since,
+            packet,
+        });
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RecordingHeader {
+    pub version: u8,
+    pub rate_hz: u16,
+    pub format: ControllerEmulatorPacketType,
+}
+
+impl RecordingHeader {
+    pub fn new(rate_hz: u16, format: ControllerEmulatorPacketType) -> RecordingHeader {
+        RecordingHeader {
+            version: RECORDING_VERSION,
+            rate_hz,
+            format,
+        }
+    }
+
+    // How long each packet in the recording should be, if it's known
+    fn packet_length(&self) -> Option<usize> {
+        match self.format {
+            ControllerEmulatorPacketType::None => None,
+            ControllerEmulatorPacketType::SevenByte => Some(SEVEN_BYTE_PACKET_LENGTH),
+            ControllerEmulatorPacketType::TwentyByte => Some(TWENTY_BYTE_PACKET_LENGTH),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORDING_HEADER_LENGTH] {
+        let rate = self.rate_hz.to_le_bytes();
+        let format = self.packet_length().unwrap_or(0) as u8;
+
+        [
+            RECORDING_MAGIC[0],
+            RECORDING_MAGIC[1],
+            RECORDING_MAGIC[2],
+            RECORDING_MAGIC[3],
+            self.version,
+            rate[0],
+            rate[1],
+            format,
+        ]
+    }
+
+    // Reads the header from the start of a recording, returning
+    // it along with the rest of the recording
+    pub fn parse(input: &[u8]) -> Result<(RecordingHeader, &[u8]), String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("RecordingHeader::parse()");
+        if !input.starts_with(&RECORDING_MAGIC[..input.len().min(RECORDING_MAGIC.len())]) {
+            return Err("This isn't an Omnishock recording".to_string());
+        }
+        if input.len() < RECORDING_HEADER_LENGTH {
+            return Err(format!(
+                "The recording's header is cut short, at {} of {} bytes",
+                input.len(),
+                RECORDING_HEADER_LENGTH
+            ));
+        }
+
+        let (header, rest) = input.split_at(RECORDING_HEADER_LENGTH);
+        if header[4] != RECORDING_VERSION {
+            return Err(format!(
+                "The recording is version {}, but only version {} can be read",
+                header[4], RECORDING_VERSION
+            ));
+        }
+
+        let rate_hz = u16::from_le_bytes([header[5], header[6]]);
+        if rate_hz == 0 {
+            return Err("The recording's frame rate is zero".to_string());
+        }
+
+        let format = match usize::from(header[7]) {
+            0 => ControllerEmulatorPacketType::None,
+            SEVEN_BYTE_PACKET_LENGTH => ControllerEmulatorPacketType::SevenByte,
+            TWENTY_BYTE_PACKET_LENGTH => ControllerEmulatorPacketType::TwentyByte,
+            other => return Err(format!("Unexpected packet format {} in recording", other)),
+        };
+
+        Ok((
+            RecordingHeader {
+                version: header[4],
+                rate_hz,
+                format,
+            },
+            rest,
+        ))
+    }
+}
+
+pub fn encode_recording(
+    header: &RecordingHeader,
+    frames: &[TimedPacket],
+) -> Result<Vec<u8>, String> {
+    let mut output = header.to_bytes().to_vec();
+    output.extend(encode_delta(frames)?);
+    Ok(output)
+}
+
+// Reads a whole recording, checking its packets are all
+// the length its header says they are
+pub fn decode_recording(input: &[u8]) -> Result<(RecordingHeader, Vec<TimedPacket>), String> {
+    let (header, rest) = RecordingHeader::parse(input)?;
+    let frames = decode_delta(rest)?;
+
+    if let Some(length) = header.packet_length() {
+        if let Some(frame) = frames.iter().find(|frame| frame.packet.len() != length) {
+            return Err(format!(
+                "The recording is of {}-byte packets, but has a {}-byte packet at {}ms",
+                length,
+                frame.packet.len(),
+                frame.at_ms
+            ));
+        }
+    }
+
+    Ok((header, frames))
+}
+
+#[cfg(test)]
+mod tests {
+    fn frames() -> Vec<super::TimedPacket> {
+        use super::TimedPacket;
+        use crate::protocol::NEUTRAL_PACKET;
+
+        let mut pressed = NEUTRAL_PACKET.to_vec();
+        pressed[2] = 0xBF;
+        pressed[13] = 0xFF;
+
+        vec![
+            TimedPacket {
+                at_ms: 0,
+                packet: NEUTRAL_PACKET.to_vec(),
+            },
+            TimedPacket {
+                at_ms: 17,
+                packet: NEUTRAL_PACKET.to_vec(),
+            },
+            TimedPacket {
+                at_ms: 33,
+                packet: pressed,
+            },
+            // A long pause, then a switch to seven-byte packets
+            TimedPacket {
+                at_ms: 600_000,
+                packet: NEUTRAL_PACKET[..7].to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn delta_encoding_round_trips() {
+        use super::{decode_delta, encode_delta};
+
+        let frames = frames();
+        let encoded = encode_delta(&frames).unwrap();
+        assert_eq!(decode_delta(&encoded), Ok(frames.clone()));
+
+        // Unchanged frames are only their timing and length
+        let first = encode_delta(&frames[..1]).unwrap().len();
+        assert_eq!(encode_delta(&frames[..2]).unwrap().len(), first + 3);
+        assert!(encoded.len() < frames.len() * frames[0].packet.len());
+
+        assert_eq!(decode_delta(&[]), Ok(Vec::new()));
+        assert_eq!(encode_delta(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn delta_decoding_rejects_broken_streams() {
+        use super::{decode_delta, encode_delta};
+
+        let encoded = encode_delta(&frames()).unwrap();
+        for length in 1..encoded.len() {
+            // Cutting a stream anywhere but between frames is noticed
+            if let Ok(decoded) = decode_delta(&encoded[..length]) {
+                assert!(decoded.len() < frames().len());
+                assert_eq!(encode_delta(&decoded).unwrap(), encoded[..length].to_vec());
+            }
+        }
+
+        // A change past the end of its packet
+        assert!(decode_delta(&[0, 2, 1, 5, 0xFF]).is_err());
+
+        // Frames out of order can't be encoded
+        let mut frames = frames();
+        frames.swap(1, 2);
+        assert!(encode_delta(&frames).is_err());
+    }
+
+    #[test]
+    fn recording_header_is_checked() {
+        use super::{decode_recording, encode_recording, RecordingHeader};
+        use crate::protocol::ControllerEmulatorPacketType;
+
+        let header = RecordingHeader::new(60, ControllerEmulatorPacketType::TwentyByte);
+        assert_eq!(header.to_bytes(), [b'O', b'M', b'S', b'K', 1, 60, 0, 20]);
+
+        let frames = frames()[..3].to_vec();
+        let recording = encode_recording(&header, &frames).unwrap();
+        assert_eq!(decode_recording(&recording), Ok((header, frames)));
+
+        // Cut short anywhere in the header
+        for length in 0..8 {
+            assert!(RecordingHeader::parse(&recording[..length])
+                .unwrap_err()
+                .contains("cut short"));
+        }
+        // Just the header is an empty recording
+        let (_, rest) = RecordingHeader::parse(&recording[..8]).unwrap();
+        assert!(rest.is_empty());
+
+        let with_header = |bytes: [u8; 8]| {
+            let mut recording = recording.clone();
+            recording[..8].copy_from_slice(&bytes);
+            decode_recording(&recording)
+        };
+        assert_eq!(
+            with_header(*b"Sent: 5A"),
+            Err("This isn't an Omnishock recording".to_string())
+        );
+        assert!(with_header([b'O', b'M', b'S', b'K', 2, 60, 0, 20]).is_err());
+        assert!(with_header([b'O', b'M', b'S', b'K', 1, 0, 0, 20]).is_err());
+        assert!(with_header([b'O', b'M', b'S', b'K', 1, 60, 0, 8]).is_err());
+        // A seven-byte recording can't hold twenty-byte packets
+        assert!(with_header([b'O', b'M', b'S', b'K', 1, 60, 0, 7]).is_err());
+    }
+}
@@ -0,0 +1,786 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{
+    parse_packet_button, parse_percentage, parse_rumble_curve, parse_snap_angle,
+    parse_stick_radius, parse_switch,
+};
+use omnishock::protocol::{
+    axis_name, button_name, dpad_direction_name, AxisRoute, AxisRoutes, ButtonRoute, ButtonRoutes,
+    CombinedTriggerAxis, DPadPressureRoute, DPadPressureRoutes, DPadStick, MappingOptions,
+    RangeProfile, StickCentre, StickEncoding, StickMirror, StickShape, TouchpadMode,
+    TriggerButtons, TriggerMode, TriggerRange, TriggerSource,
+};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+// Profile
+// Settings read from a file, for those too fiddly to give as flags.
+// Each line is "<key> = <value>"; blank lines and lines
+// starting with "#" are ignored. Most of the mapping flags can be given
+// here too, though any flag given goes over its setting in a profile.
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Profile {
+    range_profiles: HashMap<String, RangeProfile>,
+    touchpad_mode: Option<TouchpadMode>,
+    button_routes: ButtonRoutes,
+    options: ProfileOptions,
+    invert_buttons: Vec<&'static str>,
+    rumble_curve: Option<f32>,
+    // Rumble curves for particular controllers, by USB vendor & product id
+    rumble_curves: HashMap<(u16, u16), f32>,
+}
+
+// Mapping options set by a profile, with `None` for those left alone.
+// Options which can be turned off altogether are `Some(None)` when they are.
+#[derive(Debug, Default, PartialEq)]
+struct ProfileOptions {
+    trigger_mode: Option<TriggerMode>,
+    normalise_sticks: Option<bool>,
+    trigger_pressure: Option<bool>,
+    trigger_range: Option<TriggerRange>,
+    trigger_source: Option<TriggerSource>,
+    trigger_buttons: Option<Option<TriggerButtons>>,
+    mirror_stick: Option<Option<StickMirror>>,
+    combine_triggers: Option<Option<CombinedTriggerAxis>>,
+    range_profile: Option<RangeProfile>,
+    stick_shape: Option<StickShape>,
+    dpad_stick: Option<DPadStick>,
+    stick_centre: Option<StickCentre>,
+    stick_encoding: Option<StickEncoding>,
+    deadzone: Option<i16>,
+    anti_deadzone: Option<i16>,
+    normalise_left: Option<Option<u8>>,
+    normalise_right: Option<Option<u8>>,
+    clamp_radial: Option<Option<i16>>,
+    snap_cardinal: Option<f32>,
+    axis_routes: AxisRoutes,
+    dpad_pressure: DPadPressureRoutes,
+}
+
+// Reads a value which may also be "off"
+fn parse_optional<T, F: Fn(&str) -> Result<T, String>>(
+    value: &str,
+    parse: F,
+) -> Result<Option<T>, String> {
+    match value {
+        "off" => Ok(None),
+        _ => parse(value).map(Some),
+    }
+}
+
+// Reads USB ids in the form "<vendor>:<product>", in hexadecimal
+fn parse_usb_id(value: &str) -> Result<(u16, u16), String> {
+    let parse_hex = |id: &str| {
+        u16::from_str_radix(id.trim(), 16)
+            .map_err(|error| format!("Unexpected USB id '{}': {}", id, error))
+    };
+
+    match value.split_once(':') {
+        Some((vendor_id, product_id)) => Ok((parse_hex(vendor_id)?, parse_hex(product_id)?)),
+        None => Err("Expected USB ids in the form <vendor>:<product>".to_string()),
+    }
+}
+
+// Writes a value which may also be "off"
+fn optional_to_string<T: std::fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "off".to_string(),
+    }
+}
+
+fn switch_to_string(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("Profile::from_str()");
+        let mut profile = Profile::default();
+        let mut button_routes = Vec::new();
+        let mut axis_routes = Vec::new();
+        let mut dpad_pressure = Vec::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line_error = |error: String| format!("line {}: {}", index + 1, error);
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| {
+                    line_error("Expected a setting in the form <key> = <value>".into())
+                })?;
+
+            let options = &mut profile.options;
+            let handled = match key {
+                "touchpad" => {
+                    let touchpad_mode = TouchpadMode::from_str(value).map_err(line_error)?;
+                    // Otherwise the setting would do nothing, without a word as to why
+                    if touchpad_mode != TouchpadMode::Off && !cfg!(feature = "touchpad") {
+                        return Err(line_error(
+                            "The touchpad can only be read when built with the touchpad feature"
+                                .to_string(),
+                        ));
+                    }
+                    profile.touchpad_mode = Some(touchpad_mode);
+                    true
+                }
+                "trigger-mode" => {
+                    options.trigger_mode = Some(TriggerMode::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "stick-normalise" => {
+                    options.normalise_sticks = Some(parse_switch(Some(value)).map_err(line_error)?);
+                    true
+                }
+                "trigger-pressure" => {
+                    options.trigger_pressure = Some(parse_switch(Some(value)).map_err(line_error)?);
+                    true
+                }
+                "trigger-range" => {
+                    options.trigger_range =
+                        Some(TriggerRange::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "trigger-source" => {
+                    options.trigger_source =
+                        Some(TriggerSource::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "trigger-buttons" => {
+                    options.trigger_buttons =
+                        Some(parse_optional(value, TriggerButtons::from_str).map_err(line_error)?);
+                    true
+                }
+                "mirror-stick" => {
+                    options.mirror_stick =
+                        Some(parse_optional(value, StickMirror::from_str).map_err(line_error)?);
+                    true
+                }
+                "combine-triggers" => {
+                    options.combine_triggers = Some(
+                        parse_optional(value, CombinedTriggerAxis::from_str).map_err(line_error)?,
+                    );
+                    true
+                }
+                // The stick range to use, rather than one named by --range-profile
+                "range" => {
+                    options.range_profile =
+                        Some(RangeProfile::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "stick-shape" => {
+                    options.stick_shape = Some(StickShape::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "dpad-stick" => {
+                    options.dpad_stick = Some(DPadStick::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "stick-centre" => {
+                    options.stick_centre = Some(StickCentre::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "stick-encoding" => {
+                    options.stick_encoding =
+                        Some(StickEncoding::from_str(value).map_err(line_error)?);
+                    true
+                }
+                "deadzone" => {
+                    options.deadzone = Some(parse_stick_radius(value).map_err(line_error)?);
+                    true
+                }
+                "anti-deadzone" => {
+                    options.anti_deadzone = Some(
+                        value
+                            .parse::<u16>()
+                            .ok()
+                            .and_then(|value| i16::try_from(value).ok())
+                            .ok_or_else(|| {
+                                line_error("Expected an anti-deadzone from 0 to 32767".into())
+                            })?,
+                    );
+                    true
+                }
+                "normalise-left" => {
+                    options.normalise_left =
+                        Some(parse_optional(value, parse_percentage).map_err(line_error)?);
+                    true
+                }
+                "normalise-right" => {
+                    options.normalise_right =
+                        Some(parse_optional(value, parse_percentage).map_err(line_error)?);
+                    true
+                }
+                "clamp-radial" => {
+                    options.clamp_radial =
+                        Some(parse_optional(value, parse_stick_radius).map_err(line_error)?);
+                    true
+                }
+                "snap-cardinal" => {
+                    options.snap_cardinal = Some(parse_snap_angle(value).map_err(line_error)?);
+                    true
+                }
+                "rumble-curve" => {
+                    profile.rumble_curve = Some(parse_rumble_curve(value).map_err(line_error)?);
+                    true
+                }
+                "invert-button" => {
+                    profile
+                        .invert_buttons
+                        .push(parse_packet_button(value).map_err(line_error)?);
+                    true
+                }
+                _ => false,
+            };
+            if handled {
+                continue;
+            }
+
+            match key.split_once('.') {
+                // range-profile.<name> = <normalise %>,<min>,<max>
+                Some(("range-profile", name)) if !name.is_empty() => {
+                    let range_profile = RangeProfile::from_str(value).map_err(line_error)?;
+                    profile
+                        .range_profiles
+                        .insert(name.to_lowercase(), range_profile);
+                }
+                // rumble-curve.<vendor>:<product> = <gamma>
+                Some(("rumble-curve", usb_id)) => {
+                    profile.rumble_curves.insert(
+                        parse_usb_id(usb_id).map_err(line_error)?,
+                        parse_rumble_curve(value).map_err(line_error)?,
+                    );
+                }
+                // button.<output> = <input>
+                Some(("button", output)) => {
+                    let route = ButtonRoute::from_str(&format!("{}={}", output, value))
+                        .map_err(line_error)?;
+                    button_routes.push(route);
+                }
+                // axis.<output> = <input>
+                Some(("axis", output)) => {
+                    let route = AxisRoute::from_str(&format!("{}={}", output, value))
+                        .map_err(line_error)?;
+                    axis_routes.push(route);
+                }
+                // dpad-pressure.<direction> = <axis>[-]
+                Some(("dpad-pressure", direction)) => {
+                    let route = DPadPressureRoute::from_str(&format!("{}={}", direction, value))
+                        .map_err(line_error)?;
+                    dpad_pressure.push(route);
+                }
+                _ => return Err(line_error(format!("Unknown setting '{}'", key))),
+            }
+        }
+
+        profile.button_routes = ButtonRoutes::from_routes(&button_routes)?;
+        profile.options.axis_routes = AxisRoutes::from_routes(&axis_routes)?;
+        profile.options.dpad_pressure = DPadPressureRoutes::from_routes(&dpad_pressure)?;
+
+        Ok(profile)
+    }
+}
+
+impl Profile {
+    // Settings given more than once in `s`, which load fine but with only
+    // the last one counting, as that's probably a mistake
+    pub fn repeated_settings(s: &str) -> Vec<String> {
+        let mut first_lines = HashMap::new();
+        let mut repeated = Vec::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let key = match line.trim().split_once('=') {
+                Some((key, _)) if !line.trim().starts_with('#') => key.trim(),
+                _ => continue,
+            };
+
+            // The one setting which is meant to be given again and again
+            if key == "invert-button" {
+                continue;
+            }
+
+            match first_lines.get(key) {
+                Some(first_line) => repeated.push(format!(
+                    "line {}: '{}' was already set on line {}, and replaces it",
+                    index + 1,
+                    key,
+                    first_line
+                )),
+                None => {
+                    first_lines.insert(key, index + 1);
+                }
+            }
+        }
+
+        repeated
+    }
+
+    pub fn load(path: &str) -> Result<Profile, String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("Profile::load()");
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Couldn't read profile '{}': {}", path, error))?;
+
+        Profile::from_str(&contents).map_err(|error| format!("In profile '{}', {}", path, error))
+    }
+
+    pub fn button_routes(&self) -> &ButtonRoutes {
+        &self.button_routes
+    }
+
+    // The rumble curve for the controller with `usb_id`: its own,
+    // if it has one, otherwise the one for every controller
+    pub fn rumble_curve(&self, usb_id: Option<(u16, u16)>) -> Option<f32> {
+        usb_id
+            .and_then(|usb_id| self.rumble_curves.get(&usb_id).copied())
+            .or(self.rumble_curve)
+    }
+
+    // The DualShock buttons to send inverted, on top of any --invert-button
+    pub fn invert_buttons(&self) -> &[&'static str] {
+        &self.invert_buttons
+    }
+
+    // Puts this profile's settings over `mapping_options`. Axis and D-Pad
+    // pressure routes are merged, with the profile's winning. Flags given
+    // on the command line then go over these (see build_mapping_options).
+    pub fn apply(&self, mapping_options: &mut MappingOptions) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("Profile#apply()");
+        let options = &self.options;
+
+        if let Some(trigger_mode) = options.trigger_mode {
+            mapping_options.trigger_mode = trigger_mode;
+        }
+        if let Some(trigger_pressure) = options.trigger_pressure {
+            mapping_options.trigger_pressure = trigger_pressure;
+        }
+        if let Some(trigger_range) = options.trigger_range {
+            mapping_options.trigger_range = trigger_range;
+        }
+        if let Some(trigger_source) = options.trigger_source {
+            mapping_options.trigger_source = trigger_source;
+        }
+        if let Some(trigger_buttons) = options.trigger_buttons {
+            mapping_options.trigger_buttons = trigger_buttons;
+        }
+        if let Some(mirror_stick) = options.mirror_stick {
+            mapping_options.mirror_stick = mirror_stick;
+        }
+        if let Some(combine_triggers) = options.combine_triggers {
+            mapping_options.combine_triggers = combine_triggers;
+        }
+        if let Some(dpad_stick) = options.dpad_stick {
+            mapping_options.dpad_stick = dpad_stick;
+        }
+        if let Some(stick_centre) = options.stick_centre {
+            mapping_options.stick_centre = stick_centre;
+        }
+        if let Some(stick_encoding) = options.stick_encoding {
+            mapping_options.stick_encoding = stick_encoding;
+        }
+        if let Some(touchpad_mode) = self.touchpad_mode {
+            mapping_options.touchpad_mode = touchpad_mode;
+        }
+        self.apply_stick_settings(mapping_options);
+
+        for route in options.axis_routes.routes() {
+            mapping_options.axis_routes.insert(route);
+        }
+        for route in options.dpad_pressure.routes() {
+            mapping_options.dpad_pressure.insert(route);
+        }
+    }
+
+    // Puts just this profile's settings for how far the sticks reach over
+    // `mapping_options`: normalisation, range, shape, both deadzones
+    // and snapping
+    pub fn apply_stick_settings(&self, mapping_options: &mut MappingOptions) {
+        let options = &self.options;
+
+        if let Some(normalise_sticks) = options.normalise_sticks {
+            mapping_options.normalise_sticks = normalise_sticks;
+        }
+        if let Some(range_profile) = options.range_profile {
+            mapping_options.range_profile = range_profile;
+        }
+        if let Some(stick_shape) = options.stick_shape {
+            mapping_options.stick_shape = stick_shape;
+        }
+        if let Some(deadzone) = options.deadzone {
+            mapping_options.deadzone = deadzone;
+        }
+        if let Some(anti_deadzone) = options.anti_deadzone {
+            mapping_options.anti_deadzone = anti_deadzone;
+        }
+        if let Some(normalise_left) = options.normalise_left {
+            mapping_options.normalise_left = normalise_left;
+        }
+        if let Some(normalise_right) = options.normalise_right {
+            mapping_options.normalise_right = normalise_right;
+        }
+        if let Some(clamp_radial) = options.clamp_radial {
+            mapping_options.clamp_radial = clamp_radial;
+        }
+        if let Some(snap_cardinal) = options.snap_cardinal {
+            mapping_options.snap_cardinal = snap_cardinal;
+        }
+    }
+
+    // Writes out a profile which, loaded with --profile, sets everything
+    // just as `mapping_options` and `invert_buttons` have it. The shift
+    // layer lives in its own profile, so isn't included.
+    pub fn save(mapping_options: &MappingOptions, invert_buttons: &[&str]) -> String {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("Profile::save()");
+        let mut profile = String::from("# Saved by omnishock\n");
+
+        let mut setting = |key: &str, value: &dyn std::fmt::Display| {
+            // Writing to a `String` can't fail
+            let _ = writeln!(profile, "{} = {}", key, value);
+        };
+
+        setting("trigger-mode", &mapping_options.trigger_mode);
+        setting(
+            "stick-normalise",
+            &switch_to_string(mapping_options.normalise_sticks),
+        );
+        setting(
+            "trigger-pressure",
+            &switch_to_string(mapping_options.trigger_pressure),
+        );
+        setting("trigger-range", &mapping_options.trigger_range);
+        setting("trigger-source", &mapping_options.trigger_source);
+        setting(
+            "trigger-buttons",
+            &optional_to_string(mapping_options.trigger_buttons),
+        );
+        setting(
+            "mirror-stick",
+            &optional_to_string(mapping_options.mirror_stick),
+        );
+        setting(
+            "combine-triggers",
+            &optional_to_string(mapping_options.combine_triggers),
+        );
+        setting("range", &mapping_options.range_profile);
+        setting("stick-shape", &mapping_options.stick_shape);
+        setting("dpad-stick", &mapping_options.dpad_stick);
+        setting("stick-centre", &mapping_options.stick_centre);
+        setting("stick-encoding", &mapping_options.stick_encoding);
+        setting("deadzone", &mapping_options.deadzone);
+        setting("anti-deadzone", &mapping_options.anti_deadzone);
+        setting(
+            "normalise-left",
+            &optional_to_string(mapping_options.normalise_left),
+        );
+        setting(
+            "normalise-right",
+            &optional_to_string(mapping_options.normalise_right),
+        );
+        setting(
+            "clamp-radial",
+            &optional_to_string(mapping_options.clamp_radial),
+        );
+        setting("snap-cardinal", &mapping_options.snap_cardinal);
+        setting("touchpad", &mapping_options.touchpad_mode);
+
+        for route in mapping_options.axis_routes.routes() {
+            setting(
+                &format!("axis.{}", axis_name(route.output)),
+                &axis_name(route.input),
+            );
+        }
+        for route in mapping_options.button_routes.routes() {
+            let inputs: Vec<&str> = route
+                .inputs
+                .iter()
+                .map(|input| button_name(*input))
+                .collect();
+            setting(
+                &format!("button.{}", button_name(route.output)),
+                &inputs.join(", "),
+            );
+        }
+        for route in mapping_options.dpad_pressure.routes() {
+            setting(
+                &format!("dpad-pressure.{}", dpad_direction_name(route.direction)),
+                &route.input,
+            );
+        }
+        for button in invert_buttons {
+            setting("invert-button", button);
+        }
+
+        profile
+    }
+
+    // Looks for a range profile defined here first,
+    // then falls back to the built-in ones
+    pub fn range_profile(&self, name: &str) -> Result<RangeProfile, String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("Profile#range_profile()");
+        self.range_profiles
+            .get(&name.to_lowercase())
+            .copied()
+            .or_else(|| RangeProfile::builtin(name))
+            .ok_or_else(|| {
+                let mut names: Vec<&str> = RangeProfile::builtin_names();
+                names.extend(self.range_profiles.keys().map(String::as_str));
+                format!(
+                    "Unknown range profile '{}' (expected one of {})",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn profile_defines_range_profiles() {
+        use super::Profile;
+        use omnishock::protocol::RangeProfile;
+        use std::str::FromStr;
+
+        let profile = Profile::from_str(
+            "# Tuned for a worn-out stick\n\
+             \n\
+             range-profile.Tired = 20, 4, 251\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            profile.range_profile("tired"),
+            Ok(RangeProfile {
+                normalise_percent: 20,
+                min: 4,
+                max: 251
+            })
+        );
+        assert_eq!(
+            profile.range_profile("linear"),
+            Ok(RangeProfile::builtin("linear").unwrap())
+        );
+        assert!(profile.range_profile("nonsense").is_err());
+
+        assert!(Profile::from_str("range-profile.broken = 10,0")
+            .unwrap_err()
+            .starts_with("line 1:"));
+        assert!(Profile::from_str("\nstick-speed = 11").is_err());
+        assert!(Profile::from_str("range-profile.x").is_err());
+    }
+
+    #[test]
+    fn profile_sets_the_touchpad_mode() {
+        use super::Profile;
+        use omnishock::protocol::{MappingOptions, TouchpadMode};
+        use std::str::FromStr;
+
+        let touchpad_mode = |profile: &Profile| {
+            let mut options = MappingOptions {
+                touchpad_mode: TouchpadMode::RightStick,
+                ..MappingOptions::default()
+            };
+            profile.apply(&mut options);
+            options.touchpad_mode
+        };
+
+        // Without a setting, the touchpad's left as it was
+        assert_eq!(touchpad_mode(&Profile::default()), TouchpadMode::RightStick);
+        assert_eq!(
+            touchpad_mode(&Profile::from_str("touchpad = off").unwrap()),
+            TouchpadMode::Off
+        );
+        if cfg!(feature = "touchpad") {
+            assert_eq!(
+                touchpad_mode(&Profile::from_str("touchpad = dpad").unwrap()),
+                TouchpadMode::DPad
+            );
+        } else {
+            // There's no reading the touchpad without the feature
+            assert!(Profile::from_str("touchpad = dpad").is_err());
+        }
+        assert!(Profile::from_str("touchpad = mouse").is_err());
+    }
+
+    #[test]
+    fn profile_routes_buttons() {
+        use super::Profile;
+        use sdl2::controller::Button;
+        use std::str::FromStr;
+
+        let profile = Profile::from_str("button.a = b\nbutton.b = a\n").unwrap();
+        assert_eq!(profile.button_routes().inputs_for(Button::A), [Button::B]);
+        assert_eq!(profile.button_routes().inputs_for(Button::B), [Button::A]);
+        assert_eq!(profile.button_routes().inputs_for(Button::X), [Button::X]);
+
+        // Either shoulder fires R1
+        let profile =
+            Profile::from_str("button.right-shoulder = right-shoulder, left-shoulder").unwrap();
+        assert_eq!(
+            profile.button_routes().inputs_for(Button::RightShoulder),
+            [Button::RightShoulder, Button::LeftShoulder]
+        );
+
+        assert!(Profile::from_str("button.a = jump").is_err());
+        assert!(Profile::from_str("button.a = b\nbutton.a = x").is_err());
+        assert!(Profile::from_str("button.a = b, jump").is_err());
+        assert!(Profile::from_str("button.a = b, b").is_err());
+    }
+
+    #[test]
+    fn saved_profile_reproduces_the_options() {
+        use super::Profile;
+        use omnishock::protocol::{
+            AxisRoute, AxisRoutes, ButtonRoute, ButtonRoutes, CombinedTriggerAxis,
+            DPadPressureRoute, DPadPressureRoutes, DPadStick, MappingOptions, RangeProfile,
+            StickCentre, StickEncoding, StickMirror, StickShape, TouchpadMode, TriggerButtons,
+            TriggerMode, TriggerRange, TriggerSource,
+        };
+        use std::str::FromStr;
+
+        let options = MappingOptions {
+            trigger_pressure: true,
+            trigger_range: TriggerRange::from_str("1000,30000").unwrap(),
+            trigger_source: TriggerSource::Max,
+            trigger_buttons: Some(TriggerButtons::from_str("left-stick,right-stick").unwrap()),
+            mirror_stick: Some(StickMirror::RightToLeft),
+            combine_triggers: Some(CombinedTriggerAxis::LeftStickY),
+            range_profile: RangeProfile {
+                normalise_percent: 20,
+                min: 4,
+                max: 251,
+            },
+            stick_shape: StickShape::Circle,
+            dpad_stick: DPadStick::Copy,
+            stick_centre: StickCentre(0x7F),
+            stick_encoding: StickEncoding::Unsigned,
+            deadzone: 3000,
+            anti_deadzone: 4000,
+            normalise_left: Some(15),
+            clamp_radial: Some(30000),
+            snap_cardinal: 12.5,
+            touchpad_mode: if cfg!(feature = "touchpad") {
+                TouchpadMode::DPad
+            } else {
+                TouchpadMode::Off
+            },
+            axis_routes: AxisRoutes::from_routes(&[
+                AxisRoute::from_str("left-x=right-x").unwrap(),
+                AxisRoute::from_str("right-x=left-x").unwrap(),
+            ])
+            .unwrap(),
+            button_routes: ButtonRoutes::from_routes(&[
+                ButtonRoute::from_str("a=b").unwrap(),
+                ButtonRoute::from_str("right-shoulder=right-shoulder,left-shoulder").unwrap(),
+            ])
+            .unwrap(),
+            dpad_pressure: DPadPressureRoutes::from_routes(&[DPadPressureRoute::from_str(
+                "up=left-y-",
+            )
+            .unwrap()])
+            .unwrap(),
+            ..MappingOptions::new(TriggerMode::RightStick, false)
+        };
+
+        let saved = Profile::save(&options, &["l3", "cross"]);
+        let profile = Profile::from_str(&saved).unwrap();
+
+        let mut loaded = MappingOptions {
+            button_routes: profile.button_routes().clone(),
+            ..MappingOptions::default()
+        };
+        profile.apply(&mut loaded);
+        assert_eq!(loaded, options);
+        assert_eq!(profile.invert_buttons(), &["l3", "cross"]);
+
+        // Settings which are off by default can be turned off again
+        let mut loaded = options.clone();
+        Profile::from_str("mirror-stick = off\nnormalise-left = off")
+            .unwrap()
+            .apply(&mut loaded);
+        assert_eq!(loaded.mirror_stick, None);
+        assert_eq!(loaded.normalise_left, None);
+
+        assert!(Profile::from_str("deadzone = -1").is_err());
+        assert!(Profile::from_str("anti-deadzone = 40000").is_err());
+        assert!(Profile::from_str("snap-cardinal = 30").is_err());
+        assert!(Profile::from_str("invert-button = jump").is_err());
+    }
+
+    #[test]
+    fn profile_sets_rumble_curves() {
+        use super::Profile;
+        use std::str::FromStr;
+
+        let profile = Profile::from_str("rumble-curve = 0.5\nrumble-curve.054C:09cc = 2").unwrap();
+        assert_eq!(profile.rumble_curve(Some((0x054C, 0x09CC))), Some(2.0));
+        assert_eq!(profile.rumble_curve(Some((0x045E, 0x02EA))), Some(0.5));
+        assert_eq!(profile.rumble_curve(None), Some(0.5));
+        assert_eq!(Profile::default().rumble_curve(None), None);
+
+        assert!(Profile::from_str("rumble-curve = 0").is_err());
+        assert!(Profile::from_str("rumble-curve.054C = 2").is_err());
+    }
+
+    #[test]
+    fn profile_applies_just_stick_settings() {
+        use super::Profile;
+        use omnishock::protocol::{MappingOptions, StickShape, TriggerMode};
+        use std::str::FromStr;
+
+        let profile = Profile::from_str(concat!(
+            "trigger-mode = right-stick\n",
+            "stick-shape = circle\n",
+            "deadzone = 2500\n",
+            "anti-deadzone = 2000\n",
+            "normalise-left = 10\n",
+            "snap-cardinal = 5",
+        ))
+        .unwrap();
+
+        let mut options = MappingOptions::new(TriggerMode::Normal, true);
+        profile.apply_stick_settings(&mut options);
+        assert_eq!(options.trigger_mode, TriggerMode::Normal);
+        assert_eq!(options.stick_shape, StickShape::Circle);
+        assert_eq!(options.deadzone, 2500);
+        assert_eq!(options.anti_deadzone, 2000);
+        assert_eq!(options.normalise_left, Some(10));
+        assert_eq!(options.snap_cardinal, 5.0);
+    }
+}
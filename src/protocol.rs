@@ -0,0 +1,3251 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::sdl_manager::GameController;
+use std::ops::{Add, Div, Neg};
+use std::str::FromStr;
+
+// Protocol
+// Everything about the packets the controller emulator firmware expects,
+// and how a game controller's state is turned into them
+
+// The DualShock protocol uses 0x5A in many places!
+pub const DUALSHOCK_MAGIC: u8 = 0x5A;
+
+// Johnny Chung Lee's firmware responds with "k" on success,
+pub const SEVEN_BYTE_OK_RESPONSE: char = 'k';
+// and "x" when it recieves input it doesn't recognise.
+pub const SEVEN_BYTE_ERR_RESPONSE: char = 'x';
+
+// Aaron Clovsky's firmware responds with vibration information
+// which begins with the DUALSHOCK_MAGIC.
+pub const TWENTY_BYTE_OK_HEADER: u8 = DUALSHOCK_MAGIC;
+
+// Johnny Chung Lee's firmware takes the first seven bytes of the packet,
+pub const SEVEN_BYTE_PACKET_LENGTH: usize = 7;
+// while Aaron Clovsky's takes all twenty.
+pub const TWENTY_BYTE_PACKET_LENGTH: usize = 20;
+
+// What each byte of the packet means, in the order that
+// `controller_map_twenty_byte` builds them.
+pub const PACKET_LAYOUT: [&str; TWENTY_BYTE_PACKET_LENGTH] = [
+    "Magic (0x5A)",
+    "Buttons 1 (0 = pressed)",
+    "Buttons 2 (0 = pressed)",
+    "Right stick X (0x00 = left, 0x80 = centre, 0xFF = right)",
+    "Right stick Y (0x00 = up, 0x80 = centre, 0xFF = down)",
+    "Left stick X (0x00 = left, 0x80 = centre, 0xFF = right)",
+    "Left stick Y (0x00 = up, 0x80 = centre, 0xFF = down)",
+    "Right pressure",
+    "Left pressure",
+    "Up pressure",
+    "Down pressure",
+    "Triangle pressure",
+    "Circle pressure",
+    "Cross pressure",
+    "Square pressure",
+    "L1 pressure",
+    "R1 pressure",
+    "L2 pressure",
+    "R2 pressure",
+    "Mode footer (0x55 = normal, 0xAA = Guide held)",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControllerEmulatorPacketType {
+    // Fallback for firmware we couldn't recognise. Packets are still built
+    // (and logged when `--verbose`), but nothing is written to the device,
+    // so the console won't see any input. Only used with `--allow-unknown`
+    // or `--default-mode none`.
+    None,
+    SevenByte,  // For Johnny Chung Lee's firmware
+    TwentyByte, // For Aaron Clovsky's firmware
+}
+
+impl std::fmt::Display for ControllerEmulatorPacketType {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            ControllerEmulatorPacketType::None => {
+                "no protocol (unrecognised firmware, logging only)"
+            }
+            ControllerEmulatorPacketType::SevenByte => {
+                "seven-byte protocol (Johnny Chung Lee's firmware)"
+            }
+            ControllerEmulatorPacketType::TwentyByte => {
+                "twenty-byte protocol (Aaron Clovsky's firmware)"
+            }
+        })
+    }
+}
+
+// Only the formats which packets can actually be written in
+impl FromStr for ControllerEmulatorPacketType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seven" => Ok(ControllerEmulatorPacketType::SevenByte),
+            "twenty" => Ok(ControllerEmulatorPacketType::TwentyByte),
+            _ => Err("Unexpected packet format".to_string()),
+        }
+    }
+}
+
+impl ControllerEmulatorPacketType {
+    pub fn variants() -> [&'static str; 2] {
+        ["seven", "twenty"]
+    }
+
+    // How many bytes the firmware sends back after each packet, so we
+    // read all of it and don't leave any behind to confuse the next frame
+    pub fn response_length(&self) -> usize {
+        match self {
+            ControllerEmulatorPacketType::None => 0,
+            // Just 'k' or 'x'
+            ControllerEmulatorPacketType::SevenByte => 1,
+            // Header, both motors' intensity, then the mode footer
+            ControllerEmulatorPacketType::TwentyByte => 4,
+        }
+    }
+}
+
+// A packet of a neutral controller state: nothing pressed and sticks centred
+pub const NEUTRAL_PACKET: [u8; TWENTY_BYTE_PACKET_LENGTH] = [
+    DUALSHOCK_MAGIC,
+    !Buttons1::empty().bits(),
+    !Buttons2::empty().bits(),
+    // Sticks
+    0x80, // Right stick X
+    0x80, // Right stick Y
+    0x80, // Left stick X
+    0x80, // Left stick Y
+    // Pressure
+    0x00, // Right
+    0x00, // Left
+    0x00, // Up
+    0x00, // Down
+    0x00, // Triangle
+    0x00, // Circle
+    0x00, // Cross
+    0x00, // Square
+    0x00, // [L1]
+    0x00, // [R1]
+    0x00, // [L2]
+    0x00, // [R2]
+    // Mode
+    0x55, // Normal
+];
+
+bitflags! {
+    pub struct Buttons1: u8 {
+        const LEFT = 0b1000_0000;
+        const DOWN = 0b0100_0000;
+        const RIGHT = 0b0010_0000;
+        const UP = 0b0001_0000;
+        const START = 0b0000_1000;
+        const R3 = 0b0000_0100;
+        const L3 = 0b0000_0010;
+        const SELECT = 0b0000_0001;
+    }
+}
+
+bitflags! {
+    pub struct Buttons2: u8 {
+        const SQUARE = 0b1000_0000;
+        const CROSS = 0b0100_0000;
+        const CIRCLE = 0b0010_0000;
+        const TRIANGLE = 0b0001_0000;
+        const R1 = 0b0000_1000;
+        const L1 = 0b0000_0100;
+        const R2 = 0b0000_0010;
+        const L2 = 0b0000_0001;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TriggerMode {
+    #[default]
+    Normal,
+    RightStick,
+    CrossAndSquare,
+}
+
+impl TriggerMode {
+    pub fn variants() -> [&'static str; 3] {
+        ["normal", "right-stick", "cross-and-square"]
+    }
+}
+
+impl FromStr for TriggerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(TriggerMode::Normal),
+            "right-stick" => Ok(TriggerMode::RightStick),
+            "cross-and-square" => Ok(TriggerMode::CrossAndSquare),
+            _ => Err("Unexpected trigger mode type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for TriggerMode {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            TriggerMode::Normal => "normal",
+            TriggerMode::RightStick => "right-stick",
+            TriggerMode::CrossAndSquare => "cross-and-square",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StickMirror {
+    LeftToRight,
+    RightToLeft,
+}
+
+impl StickMirror {
+    pub fn variants() -> [&'static str; 2] {
+        ["left-to-right", "right-to-left"]
+    }
+}
+
+impl FromStr for StickMirror {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left-to-right" => Ok(StickMirror::LeftToRight),
+            "right-to-left" => Ok(StickMirror::RightToLeft),
+            _ => Err("Unexpected stick mirror type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for StickMirror {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            StickMirror::LeftToRight => "left-to-right",
+            StickMirror::RightToLeft => "right-to-left",
+        })
+    }
+}
+
+// Which stick axis both triggers are combined onto
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CombinedTriggerAxis {
+    LeftStickY,
+    RightStickY,
+}
+
+impl CombinedTriggerAxis {
+    pub fn variants() -> [&'static str; 2] {
+        ["lstick-y", "rstick-y"]
+    }
+}
+
+impl FromStr for CombinedTriggerAxis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lstick-y" => Ok(CombinedTriggerAxis::LeftStickY),
+            "rstick-y" => Ok(CombinedTriggerAxis::RightStickY),
+            _ => Err("Unexpected combined trigger axis".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for CombinedTriggerAxis {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            CombinedTriggerAxis::LeftStickY => "lstick-y",
+            CombinedTriggerAxis::RightStickY => "rstick-y",
+        })
+    }
+}
+
+// Combines the two raw trigger axes onto one stick axis by subtracting
+// one from the other, so the right trigger pushes up and the left down
+// NOTE: This doesn't allow for both to be used at once
+pub fn combine_triggers(left: i16, right: i16) -> i16 {
+    left.saturating_sub(right)
+}
+
+// What a finger on the controller's touchpad does
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TouchpadMode {
+    #[default]
+    Off,
+    // The finger's position drives the right stick
+    RightStick,
+    // The pad is split into a 3×3 grid of directions
+    DPad,
+}
+
+impl TouchpadMode {
+    pub fn variants() -> [&'static str; 3] {
+        ["off", "right-stick", "dpad"]
+    }
+}
+
+impl FromStr for TouchpadMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(TouchpadMode::Off),
+            "right-stick" => Ok(TouchpadMode::RightStick),
+            "dpad" => Ok(TouchpadMode::DPad),
+            _ => Err("Unexpected touchpad mode type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for TouchpadMode {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            TouchpadMode::Off => "off",
+            TouchpadMode::RightStick => "right-stick",
+            TouchpadMode::DPad => "dpad",
+        })
+    }
+}
+
+// Turns a touchpad coordinate (0 to 1) into a stick axis value
+fn convert_touchpad_to_axis(position: f32) -> i16 {
+    let centred = (position.clamp(0.0, 1.0) * 2.0 - 1.0) * f32::from(i16::max_value());
+    centred as i16
+}
+
+// Which D-Pad directions a touchpad position presses,
+// as (left, down, right, up)
+fn convert_touchpad_to_dpad((x, y): (f32, f32)) -> (bool, bool, bool, bool) {
+    let third = 1.0 / 3.0;
+    (x < third, y > 1.0 - third, x > 1.0 - third, y < third)
+}
+
+// Reads a byte given in decimal, or hexadecimal starting "0x"
+fn parse_byte(s: &str) -> Result<u8, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse::<u8>(),
+    }
+}
+
+// The byte sent for an analog stick at rest. The DualShock®2 uses 0x80,
+// but some firmware expects something else.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StickCentre(pub u8);
+
+impl Default for StickCentre {
+    fn default() -> Self {
+        StickCentre(0x80)
+    }
+}
+
+impl FromStr for StickCentre {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        parse_byte(s)
+            .map(StickCentre)
+            .map_err(|error| format!("Unexpected stick centre '{}': {}", s, error))
+    }
+}
+
+impl std::fmt::Display for StickCentre {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "{:#04X}", self.0)
+    }
+}
+
+// How stick values become bytes. Most firmware wants the top byte of
+// SDL's signed value shifted up to sit around the centre, but some forks
+// want the axis scaled onto 0 to 255 instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StickEncoding {
+    #[default]
+    SignedCentred,
+    Unsigned,
+}
+
+impl StickEncoding {
+    pub fn variants() -> [&'static str; 2] {
+        ["signed-centered", "unsigned"]
+    }
+}
+
+impl FromStr for StickEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signed-centered" => Ok(StickEncoding::SignedCentred),
+            "unsigned" => Ok(StickEncoding::Unsigned),
+            _ => Err("Unexpected stick encoding type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for StickEncoding {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            StickEncoding::SignedCentred => "signed-centered",
+            StickEncoding::Unsigned => "unsigned",
+        })
+    }
+}
+
+impl StickCentre {
+    // Converts an SDL axis value into a stick byte, stretching each half of
+    // the axis to fill the range on its side of the centre
+    pub fn convert(&self, value: i16) -> u8 {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("StickCentre#convert()");
+        let centre = i32::from(self.0);
+        let offset = i32::from(value.wrapping_shr(8));
+
+        let byte = if offset < 0 {
+            centre + offset * centre / 128
+        } else {
+            centre + offset * (255 - centre) / 127
+        };
+
+        byte.clamp(0, 255) as u8
+    }
+
+    // Puts the sticks of a packet of either length back at rest
+    pub fn apply(&self, packet: &mut [u8]) {
+        if packet.len() < SEVEN_BYTE_PACKET_LENGTH {
+            return;
+        }
+
+        for byte in &mut packet[3..SEVEN_BYTE_PACKET_LENGTH] {
+            *byte = self.0;
+        }
+    }
+}
+
+// Whether the D-Pad also drives the left stick, for controllers without one
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DPadStick {
+    #[default]
+    Off,
+    // The D-Pad moves the left stick instead of pressing its own buttons
+    Move,
+    // The D-Pad moves the left stick and still presses its own buttons
+    Copy,
+}
+
+impl FromStr for DPadStick {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(DPadStick::Off),
+            "move" => Ok(DPadStick::Move),
+            "copy" => Ok(DPadStick::Copy),
+            _ => Err("Unexpected D-Pad stick type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for DPadStick {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            DPadStick::Off => "off",
+            DPadStick::Move => "move",
+            DPadStick::Copy => "copy",
+        })
+    }
+}
+
+// Where the D-Pad would put an analog stick, as (x, y), with diagonals
+// kept to the same distance from centre as the straight directions.
+// Opposite directions cancel out. Returns `None` if nothing's pressed.
+pub fn convert_dpad_to_stick(left: bool, down: bool, right: bool, up: bool) -> Option<(i16, i16)> {
+    if !(left || down || right || up) {
+        return None;
+    }
+
+    let direction = |negative: bool, positive: bool| i32::from(positive) - i32::from(negative);
+    let (x, y) = (direction(left, right), direction(up, down));
+
+    let extent = if x != 0 && y != 0 {
+        // 32767 / √2
+        23_170
+    } else {
+        i16::MAX as i32
+    };
+
+    Some(((x * extent) as i16, (y * extent) as i16))
+}
+
+// The outer edge analog stick positions are kept within
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StickShape {
+    // Each axis is limited separately, so the corners are reachable
+    #[default]
+    Square,
+    // The distance from centre is limited, as with a round stick gate
+    Circle,
+}
+
+impl StickShape {
+    pub fn variants() -> [&'static str; 2] {
+        ["square", "circle"]
+    }
+}
+
+impl FromStr for StickShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(StickShape::Square),
+            "circle" => Ok(StickShape::Circle),
+            _ => Err("Unexpected stick shape type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for StickShape {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            StickShape::Square => "square",
+            StickShape::Circle => "circle",
+        })
+    }
+}
+
+// Brings a stick position, which normalisation may have pushed
+// out of range, back within `shape`
+pub fn clamp_stick_to_shape(x: i32, y: i32, shape: StickShape) -> (i16, i16) {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("clamp_stick_to_shape()");
+    let clamp =
+        |value: i32| value.clamp(i32::from(i16::min_value()), i32::from(i16::max_value())) as i16;
+
+    match shape {
+        StickShape::Square => (clamp(x), clamp(y)),
+        StickShape::Circle => {
+            let (x, y) = clamp_radial(x, y, i16::max_value());
+            (clamp(x), clamp(y))
+        }
+    }
+}
+
+// Pulls a stick position further than `radius` from centre back onto
+// the circle of that radius, keeping its direction
+pub fn clamp_radial(x: i32, y: i32, radius: i16) -> (i32, i32) {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("clamp_radial()");
+    let radius = f64::from(radius.max(0));
+    let distance = f64::from(x).hypot(f64::from(y));
+
+    if distance <= radius {
+        (x, y)
+    } else {
+        // Truncating towards zero keeps us on or inside the circle
+        let scale = radius / distance;
+        ((f64::from(x) * scale) as i32, (f64::from(y) * scale) as i32)
+    }
+}
+
+// Turns a stick position within `tolerance_deg` degrees of one of the
+// eight directions (up, down, left, right and the diagonals between them)
+// to point exactly that way, keeping how far it's pushed
+pub fn snap_to_octant(x: i16, y: i16, tolerance_deg: f32) -> (i16, i16) {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("snap_to_octant()");
+    if tolerance_deg <= 0.0 || (x == 0 && y == 0) {
+        return (x, y);
+    }
+
+    let (x_value, y_value) = (f64::from(x), f64::from(y));
+    let angle = y_value.atan2(x_value).to_degrees();
+    let octant = (angle / 45.0).round() * 45.0;
+    if (angle - octant).abs() > f64::from(tolerance_deg) {
+        return (x, y);
+    }
+
+    let distance = x_value.hypot(y_value);
+    let clamp = |value: f64| {
+        value
+            .round()
+            .clamp(f64::from(i16::min_value()), f64::from(i16::max_value())) as i16
+    };
+    let octant = octant.to_radians();
+    (
+        clamp(distance * octant.cos()),
+        clamp(distance * octant.sin()),
+    )
+}
+
+// The names axes are given on the command line and in profiles
+const AXIS_NAMES: [(&str, sdl2::controller::Axis); 6] = [
+    ("left-x", sdl2::controller::Axis::LeftX),
+    ("left-y", sdl2::controller::Axis::LeftY),
+    ("right-x", sdl2::controller::Axis::RightX),
+    ("right-y", sdl2::controller::Axis::RightY),
+    ("trigger-left", sdl2::controller::Axis::TriggerLeft),
+    ("trigger-right", sdl2::controller::Axis::TriggerRight),
+];
+
+pub fn parse_axis(name: &str) -> Result<sdl2::controller::Axis, String> {
+    AXIS_NAMES
+        .iter()
+        .find(|(axis_name, _)| *axis_name == name)
+        .map(|(_, axis)| *axis)
+        .ok_or_else(|| format!("Unexpected axis name '{}'", name))
+}
+
+pub fn axis_name(axis: sdl2::controller::Axis) -> &'static str {
+    AXIS_NAMES
+        .iter()
+        .find(|(_, named)| *named == axis)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisRoute {
+    pub output: sdl2::controller::Axis,
+    pub input: sdl2::controller::Axis,
+}
+
+impl FromStr for AxisRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((output, input)) => Ok(AxisRoute {
+                output: parse_axis(output.trim())?,
+                input: parse_axis(input.trim())?,
+            }),
+            None => Err("Expected an axis route in the form <output>=<input>".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AxisRoutes(std::collections::HashMap<sdl2::controller::Axis, sdl2::controller::Axis>);
+
+impl AxisRoutes {
+    pub fn from_routes(routes: &[AxisRoute]) -> Result<AxisRoutes, String> {
+        let mut map = std::collections::HashMap::new();
+
+        for route in routes {
+            if map.insert(route.output, route.input).is_some() {
+                return Err(format!("Axis {:?} is routed more than once", route.output));
+            }
+        }
+
+        Ok(AxisRoutes(map))
+    }
+
+    // Which controller axis should be read in place of `output`
+    pub fn input_for(&self, output: sdl2::controller::Axis) -> sdl2::controller::Axis {
+        *self.0.get(&output).unwrap_or(&output)
+    }
+
+    // Adds `route`, replacing any route already set for its output
+    pub fn insert(&mut self, route: AxisRoute) {
+        self.0.insert(route.output, route.input);
+    }
+
+    // Every route, in a stable order
+    pub fn routes(&self) -> Vec<AxisRoute> {
+        let mut routes: Vec<AxisRoute> = self
+            .0
+            .iter()
+            .map(|(output, input)| AxisRoute {
+                output: *output,
+                input: *input,
+            })
+            .collect();
+        routes.sort_by_key(|route| axis_name(route.output));
+        routes
+    }
+}
+
+// The names buttons are given on the command line and in profiles
+const BUTTON_NAMES: [(&str, sdl2::controller::Button); 15] = [
+    ("a", sdl2::controller::Button::A),
+    ("b", sdl2::controller::Button::B),
+    ("x", sdl2::controller::Button::X),
+    ("y", sdl2::controller::Button::Y),
+    ("back", sdl2::controller::Button::Back),
+    ("guide", sdl2::controller::Button::Guide),
+    ("start", sdl2::controller::Button::Start),
+    ("left-stick", sdl2::controller::Button::LeftStick),
+    ("right-stick", sdl2::controller::Button::RightStick),
+    ("left-shoulder", sdl2::controller::Button::LeftShoulder),
+    ("right-shoulder", sdl2::controller::Button::RightShoulder),
+    ("dpad-up", sdl2::controller::Button::DPadUp),
+    ("dpad-down", sdl2::controller::Button::DPadDown),
+    ("dpad-left", sdl2::controller::Button::DPadLeft),
+    ("dpad-right", sdl2::controller::Button::DPadRight),
+];
+
+pub fn parse_button(name: &str) -> Result<sdl2::controller::Button, String> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(button_name, _)| *button_name == name)
+        .map(|(_, button)| *button)
+        .ok_or_else(|| format!("Unexpected button name '{}'", name))
+}
+
+pub fn button_name(button: sdl2::controller::Button) -> &'static str {
+    BUTTON_NAMES
+        .iter()
+        .find(|(_, named)| *named == button)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
+
+// A button read from one or more others, pressed while any of them are
+#[derive(Clone, Debug, PartialEq)]
+pub struct ButtonRoute {
+    pub output: sdl2::controller::Button,
+    pub inputs: Vec<sdl2::controller::Button>,
+}
+
+impl FromStr for ButtonRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (output, inputs) = s.split_once('=').ok_or_else(|| {
+            "Expected a button route in the form <output>=<input>[,<input>...]".to_string()
+        })?;
+
+        let mut route = ButtonRoute {
+            output: parse_button(output.trim())?,
+            inputs: Vec::new(),
+        };
+        for input in inputs.split(',') {
+            let input = parse_button(input.trim())?;
+            if route.inputs.contains(&input) {
+                return Err(format!("Button {:?} is listed more than once", input));
+            }
+            route.inputs.push(input);
+        }
+
+        Ok(route)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ButtonRoutes(
+    std::collections::HashMap<sdl2::controller::Button, Vec<sdl2::controller::Button>>,
+);
+
+impl ButtonRoutes {
+    pub fn from_routes(routes: &[ButtonRoute]) -> Result<ButtonRoutes, String> {
+        let mut map = std::collections::HashMap::new();
+
+        for route in routes {
+            if map.insert(route.output, route.inputs.clone()).is_some() {
+                return Err(format!(
+                    "Button {:?} is routed more than once",
+                    route.output
+                ));
+            }
+        }
+
+        Ok(ButtonRoutes(map))
+    }
+
+    // Which controller buttons should be read in place of `output`;
+    // it's pressed while any of them are
+    pub fn inputs_for(&self, output: sdl2::controller::Button) -> Vec<sdl2::controller::Button> {
+        match self.0.get(&output) {
+            Some(inputs) => inputs.clone(),
+            None => vec![output],
+        }
+    }
+
+    // Every route, in a stable order
+    pub fn routes(&self) -> Vec<ButtonRoute> {
+        let mut routes: Vec<ButtonRoute> = self
+            .0
+            .iter()
+            .map(|(output, inputs)| ButtonRoute {
+                output: *output,
+                inputs: inputs.clone(),
+            })
+            .collect();
+        routes.sort_by_key(|route| button_name(route.output));
+        routes
+    }
+}
+
+// A second set of button routes, used in place of the usual
+// ones while a modifier button is held, like a keyboard's Fn key
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShiftLayer {
+    // Never passed on itself, in either layer
+    pub button: sdl2::controller::Button,
+    pub button_routes: ButtonRoutes,
+}
+
+// One direction of travel along a controller axis; the triggers
+// only have the one, while each stick axis has two
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HalfAxis {
+    pub axis: sdl2::controller::Axis,
+    pub negative: bool,
+}
+
+impl HalfAxis {
+    // How far along this direction the axis is, from i16::MIN to i16::MAX
+    fn read(&self, value: i16) -> i16 {
+        if self.negative {
+            convert_half_axis_negative(value)
+        } else {
+            convert_half_axis_positive(value)
+        }
+    }
+}
+
+impl FromStr for HalfAxis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use sdl2::controller::Axis;
+
+        let (name, negative) = match s.strip_suffix('-') {
+            Some(name) => (name, true),
+            None => (s.strip_suffix('+').unwrap_or(s), false),
+        };
+        let axis = parse_axis(name)?;
+
+        if negative && matches!(axis, Axis::TriggerLeft | Axis::TriggerRight) {
+            return Err(format!("The '{}' axis has no negative direction", name));
+        }
+
+        Ok(HalfAxis { axis, negative })
+    }
+}
+
+impl std::fmt::Display for HalfAxis {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{}{}",
+            axis_name(self.axis),
+            if self.negative { "-" } else { "" }
+        )
+    }
+}
+
+const DPAD_DIRECTIONS: [(&str, sdl2::controller::Button); 4] = [
+    ("up", sdl2::controller::Button::DPadUp),
+    ("down", sdl2::controller::Button::DPadDown),
+    ("left", sdl2::controller::Button::DPadLeft),
+    ("right", sdl2::controller::Button::DPadRight),
+];
+
+fn parse_dpad_direction(name: &str) -> Result<sdl2::controller::Button, String> {
+    DPAD_DIRECTIONS
+        .iter()
+        .find(|(direction_name, _)| *direction_name == name)
+        .map(|(_, direction)| *direction)
+        .ok_or_else(|| format!("Unexpected D-Pad direction '{}'", name))
+}
+
+pub fn dpad_direction_name(direction: sdl2::controller::Button) -> &'static str {
+    DPAD_DIRECTIONS
+        .iter()
+        .find(|(_, named)| *named == direction)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DPadPressureRoute {
+    pub direction: sdl2::controller::Button,
+    pub input: HalfAxis,
+}
+
+impl FromStr for DPadPressureRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((direction, input)) => Ok(DPadPressureRoute {
+                direction: parse_dpad_direction(direction.trim())?,
+                input: HalfAxis::from_str(input.trim())?,
+            }),
+            None => {
+                Err("Expected a D-Pad pressure source in the form <direction>=<axis>".to_string())
+            }
+        }
+    }
+}
+
+// Which D-Pad directions take their pressure from an axis
+// rather than just being fully pressed or released
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DPadPressureRoutes(std::collections::HashMap<sdl2::controller::Button, HalfAxis>);
+
+impl DPadPressureRoutes {
+    pub fn from_routes(routes: &[DPadPressureRoute]) -> Result<DPadPressureRoutes, String> {
+        let mut map = std::collections::HashMap::new();
+
+        for route in routes {
+            if map.insert(route.direction, route.input).is_some() {
+                return Err(format!(
+                    "D-Pad direction {:?} is given more than one pressure source",
+                    route.direction
+                ));
+            }
+        }
+
+        Ok(DPadPressureRoutes(map))
+    }
+
+    // Which axis, if any, drives the pressure of `direction`
+    pub fn input_for(&self, direction: sdl2::controller::Button) -> Option<HalfAxis> {
+        self.0.get(&direction).copied()
+    }
+
+    // Adds `route`, replacing any source already set for its direction
+    pub fn insert(&mut self, route: DPadPressureRoute) {
+        self.0.insert(route.direction, route.input);
+    }
+
+    // Every route, in a stable order
+    pub fn routes(&self) -> Vec<DPadPressureRoute> {
+        let mut routes: Vec<DPadPressureRoute> = self
+            .0
+            .iter()
+            .map(|(direction, input)| DPadPressureRoute {
+                direction: *direction,
+                input: *input,
+            })
+            .collect();
+        routes.sort_by_key(|route| dpad_direction_name(route.direction));
+        routes
+    }
+}
+
+// The part of the analog triggers' travel which is mapped
+// to the full range of L2 & R2, in SDL's units (0 to 32767)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriggerRange {
+    pub min: i16,
+    pub max: i16,
+}
+
+impl Default for TriggerRange {
+    fn default() -> Self {
+        TriggerRange {
+            min: 0,
+            max: i16::max_value(),
+        }
+    }
+}
+
+impl FromStr for TriggerRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<i16>())
+            .collect::<Result<Vec<i16>, _>>()
+            .map_err(|error| format!("Unexpected trigger value: {}", error))?;
+
+        match values.as_slice() {
+            [min, max] if 0 <= *min && min < max => Ok(TriggerRange {
+                min: *min,
+                max: *max,
+            }),
+            [_, _] => Err("Expected 0 <= min < max".to_string()),
+            _ => Err("Expected a trigger range in the form <min>,<max>".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for TriggerRange {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "{},{}", self.min, self.max)
+    }
+}
+
+// Where L2 & R2 pressure comes from, for controllers with a digital
+// button alongside each analog trigger
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TriggerSource {
+    #[default]
+    Axis,
+    Button,
+    // Whichever of the two is pressed further
+    Max,
+}
+
+impl TriggerSource {
+    pub fn variants() -> [&'static str; 3] {
+        ["axis", "button", "max"]
+    }
+}
+
+impl FromStr for TriggerSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "axis" => Ok(TriggerSource::Axis),
+            "button" => Ok(TriggerSource::Button),
+            "max" => Ok(TriggerSource::Max),
+            _ => Err("Unexpected trigger source type".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for TriggerSource {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(match self {
+            TriggerSource::Axis => "axis",
+            TriggerSource::Button => "button",
+            TriggerSource::Max => "max",
+        })
+    }
+}
+
+// The controller buttons which are the triggers' digital halves
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriggerButtons {
+    pub left: sdl2::controller::Button,
+    pub right: sdl2::controller::Button,
+}
+
+impl FromStr for TriggerButtons {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(',').map(str::trim).collect::<Vec<_>>().as_slice() {
+            [left, right] => Ok(TriggerButtons {
+                left: parse_button(left)?,
+                right: parse_button(right)?,
+            }),
+            _ => Err("Expected trigger buttons in the form <left>,<right>".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for TriggerButtons {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{},{}",
+            button_name(self.left),
+            button_name(self.right)
+        )
+    }
+}
+
+// Combines a trigger's L2 or R2 value from its axis with its button.
+// A fully pressed button always gives full pressure with `Max`, even
+// when the axis lags behind.
+pub fn combine_trigger_source(source: TriggerSource, axis: i16, button: bool) -> i16 {
+    let digital = convert_button_to_analog(button);
+    match source {
+        TriggerSource::Axis => axis,
+        TriggerSource::Button => digital,
+        TriggerSource::Max => std::cmp::max(axis, digital),
+    }
+}
+
+impl TriggerRange {
+    // Stretches `value` so the range covers all of 0 to 32767,
+    // clamping anything outside of it
+    pub fn rescale(&self, value: i16) -> i16 {
+        let clamped = i32::from(value.max(self.min).min(self.max));
+        let span = i32::from(self.max) - i32::from(self.min);
+
+        ((clamped - i32::from(self.min)) * i32::from(i16::max_value()) / span) as i16
+    }
+}
+
+// How far the analog sticks reach: positions are pushed outward by
+// `normalise_percent` to clear the DualShock®2's outer deadzone,
+// then each stick byte is kept within `min..=max`. Deadzones and the
+// like are separate options, so choosing a range profile leaves them be
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeProfile {
+    pub normalise_percent: u8,
+    pub min: u8,
+    pub max: u8,
+}
+
+const BUILTIN_RANGE_PROFILES: [(&str, RangeProfile); 3] = [
+    (
+        "dualshock2",
+        RangeProfile {
+            normalise_percent: 10,
+            min: 0x00,
+            max: 0xFF,
+        },
+    ),
+    (
+        "linear",
+        RangeProfile {
+            normalise_percent: 0,
+            min: 0x00,
+            max: 0xFF,
+        },
+    ),
+    // For games which misread sticks pinned against their endpoints
+    (
+        "inset",
+        RangeProfile {
+            normalise_percent: 10,
+            min: 0x08,
+            max: 0xF7,
+        },
+    ),
+];
+
+impl Default for RangeProfile {
+    fn default() -> Self {
+        BUILTIN_RANGE_PROFILES[0].1
+    }
+}
+
+impl FromStr for RangeProfile {
+    type Err = String;
+
+    // Parses a definition in the form <normalise %>,<min>,<max>
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|error| format!("Unexpected range value: {}", error))?;
+
+        match values.as_slice() {
+            [normalise_percent, min, max] if *normalise_percent <= 100 && min < max => {
+                Ok(RangeProfile {
+                    normalise_percent: *normalise_percent,
+                    min: *min,
+                    max: *max,
+                })
+            }
+            [_, _, _] => Err("Expected a percentage of at most 100, and min < max".to_string()),
+            _ => Err("Expected a range profile in the form <normalise %>,<min>,<max>".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for RangeProfile {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{},{},{}",
+            self.normalise_percent, self.min, self.max
+        )
+    }
+}
+
+impl RangeProfile {
+    pub fn builtin(name: &str) -> Option<RangeProfile> {
+        BUILTIN_RANGE_PROFILES
+            .iter()
+            .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+            .map(|(_, profile)| *profile)
+    }
+
+    pub fn builtin_names() -> Vec<&'static str> {
+        BUILTIN_RANGE_PROFILES
+            .iter()
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    fn normalise(&self, value: i16) -> i32 {
+        let value = i32::from(value);
+        value + value * i32::from(self.normalise_percent) / 100
+    }
+
+    fn clamp(&self, value: u8) -> u8 {
+        value.max(self.min).min(self.max)
+    }
+
+    // The same profile, keeping stick bytes within `range` instead
+    pub fn with_byte_range(self, range: StickByteRange) -> RangeProfile {
+        RangeProfile {
+            min: range.min,
+            max: range.max,
+            ..self
+        }
+    }
+}
+
+// The lowest and highest bytes a stick axis may be sent as, for firmware
+// which takes the very ends (such as 0x00 or 0xFF) to mean something else
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StickByteRange {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl FromStr for StickByteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| parse_byte(value.trim()))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|error| format!("Unexpected stick byte: {}", error))?;
+
+        match values.as_slice() {
+            // The range has to leave the centre of the stick alone
+            [min, max] if *min < 0x80 && *max > 0x80 => Ok(StickByteRange {
+                min: *min,
+                max: *max,
+            }),
+            [_, _] => Err("Expected min < 0x80 < max".to_string()),
+            _ => Err("Expected a stick byte range in the form <min>,<max>".to_string()),
+        }
+    }
+}
+
+// Buttons whose bits are sent the other way up, for firmware which
+// doesn't follow the DualShock's "0 means pressed" convention
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ButtonPolarity {
+    pub inverted1: Buttons1,
+    pub inverted2: Buttons2,
+}
+
+impl Default for ButtonPolarity {
+    fn default() -> Self {
+        ButtonPolarity {
+            inverted1: Buttons1::empty(),
+            inverted2: Buttons2::empty(),
+        }
+    }
+}
+
+impl ButtonPolarity {
+    // Flips the inverted buttons' bits in a finished packet of either length
+    pub fn apply(&self, packet: &mut [u8]) {
+        if packet.len() < SEVEN_BYTE_PACKET_LENGTH {
+            return;
+        }
+
+        packet[1] ^= self.inverted1.bits();
+        packet[2] ^= self.inverted2.bits();
+    }
+}
+
+// Everything which affects how a controller's state becomes a packet
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MappingOptions {
+    pub trigger_mode: TriggerMode,
+    pub normalise_sticks: bool,
+    pub axis_routes: AxisRoutes,
+    // Keep L2 & R2 pressure on the triggers in cross-and-square mode
+    pub trigger_pressure: bool,
+    pub trigger_range: TriggerRange,
+    pub trigger_source: TriggerSource,
+    pub trigger_buttons: Option<TriggerButtons>,
+    // Copy one stick onto the other
+    pub mirror_stick: Option<StickMirror>,
+    // Put both triggers onto one stick axis, whatever the trigger mode
+    pub combine_triggers: Option<CombinedTriggerAxis>,
+    pub range_profile: RangeProfile,
+    pub stick_shape: StickShape,
+    pub touchpad_mode: TouchpadMode,
+    pub dpad_pressure: DPadPressureRoutes,
+    pub button_routes: ButtonRoutes,
+    pub shift_layer: Option<ShiftLayer>,
+    pub dpad_stick: DPadStick,
+    pub stick_centre: StickCentre,
+    // The unsigned encoding always rests at 128, whatever `stick_centre` is
+    pub stick_encoding: StickEncoding,
+    // How far a stick must move off centre before it counts, or 0 for none
+    pub deadzone: i16,
+    // The least a stick axis moved off centre is sent as, or 0 for no minimum
+    pub anti_deadzone: i16,
+    // Normalisation for just one stick, instead of the range profile's
+    pub normalise_left: Option<u8>,
+    pub normalise_right: Option<u8>,
+    // The furthest either stick may be sent from centre once normalised
+    pub clamp_radial: Option<i16>,
+    // How many degrees either side of each of the eight directions a stick
+    // is snapped onto it, or 0 for no snapping
+    pub snap_cardinal: f32,
+    // Buttons Omnishock itself listens for, which are never passed on
+    pub withheld_buttons: Vec<sdl2::controller::Button>,
+}
+
+impl MappingOptions {
+    pub fn new(trigger_mode: TriggerMode, normalise_sticks: bool) -> MappingOptions {
+        MappingOptions {
+            trigger_mode,
+            normalise_sticks,
+            ..MappingOptions::default()
+        }
+    }
+}
+
+fn whats_the_midpoint_of_a<T: num::Bounded + Add<Output = T> + Div<Output = T> + From<u8>>() -> T {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("whats_the_midpoint_of_a()");
+    (T::max_value() + T::min_value()) / T::from(2)
+}
+
+fn convert_button_to_analog<T: num::Bounded>(button: bool) -> T {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_button_to_analog()");
+    if button {
+        T::max_value()
+    } else {
+        T::min_value()
+    }
+}
+
+fn convert_analog_to_button<
+    T: num::Bounded + Add<Output = T> + Div<Output = T> + From<u8> + PartialOrd,
+>(
+    analog: T,
+) -> bool {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_analog_to_button()");
+
+    analog > whats_the_midpoint_of_a::<T>()
+}
+
+fn convert_for_dualshock(number: i16) -> u8 {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_for_dualshock()");
+    (number.wrapping_shr(8) + 0x80) as u8
+}
+
+// Scales an SDL axis value straight onto 0 to 255, rounding to the
+// nearest byte, so the centre of the axis lands on 128
+fn convert_unsigned_for_dualshock(number: i16) -> u8 {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_unsigned_for_dualshock()");
+    let offset = i32::from(number) - i32::from(i16::MIN);
+    ((offset * 255 + 0x7FFF) / 0xFFFF) as u8
+}
+
+fn convert_half_axis_positive<
+    T: num::Bounded + num::Saturating + Copy + Div<Output = T> + PartialEq + From<u8>,
+>(
+    stick: T,
+) -> T {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_half_axis_positive()");
+    // Special case the maximum values, so we don't end up with
+    if stick == T::max_value() {
+        return T::max_value();
+    }
+
+    let two_in_target_type = T::from(2);
+    let half_minimum = T::min_value().div(two_in_target_type);
+    let normalised_stick = stick.saturating_add(half_minimum);
+
+    // This is a weird way to multiply by two but it works eh
+    normalised_stick.saturating_add(normalised_stick)
+}
+
+fn convert_half_axis_negative<
+    T: num::Bounded
+        + num::Saturating
+        + Copy
+        + Neg<Output = T>
+        + Div<Output = T>
+        + PartialEq
+        + From<u8>,
+>(
+    stick: T,
+) -> T {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_half_axis_negative()");
+    convert_half_axis_positive(stick.saturating_add(T::from(1)).neg())
+}
+
+fn normalise_stick_as_dualshock2(
+    x: &mut i16,
+    y: &mut i16,
+    range_profile: &RangeProfile,
+    shape: StickShape,
+) {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("normalise_stick_as_dualshock2()");
+    // Adjust stick positions to match those of the DualShock®2.
+    // The DualShock®2 has a prominent outer deadzone,
+    // so we shrink the usable area here (by 10% by default).
+    let (normalised_x, normalised_y) = clamp_stick_to_shape(
+        range_profile.normalise(*x),
+        range_profile.normalise(*y),
+        shape,
+    );
+    *x = normalised_x;
+    *y = normalised_y;
+}
+
+// Treats a stick within `radius` of centre as centred, scaling the rest of
+// its travel so it still starts from nothing and reaches full deflection
+fn apply_radial_deadzone(x: i16, y: i16, radius: i16) -> (i16, i16) {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("apply_radial_deadzone()");
+    if radius <= 0 {
+        return (x, y);
+    }
+
+    let (x_value, y_value) = (f64::from(x), f64::from(y));
+    let distance = x_value.hypot(y_value);
+    let radius = f64::from(radius);
+    if distance <= radius {
+        return (0, 0);
+    }
+
+    let full_scale = f64::from(i16::max_value());
+    let scale = ((distance - radius) / (full_scale - radius)).min(1.0) * full_scale / distance;
+    let clamp = |value: f64| {
+        value
+            .round()
+            .clamp(f64::from(i16::min_value()), f64::from(i16::max_value())) as i16
+    };
+    (clamp(x_value * scale), clamp(y_value * scale))
+}
+
+// Lifts any deflection off centre to at least `min_out`, so games with
+// an inner deadzone of their own still respond to small movements.
+// The sign is kept, and full deflection still reaches full scale.
+fn scale_anti_deadzone(value: i16, min_out: i16) -> i16 {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("scale_anti_deadzone()");
+    if value == 0 || min_out <= 0 {
+        return value;
+    }
+
+    let full_scale = i32::from(i16::max_value());
+    let min_out = i32::from(min_out);
+    let magnitude = i32::from(value).abs().min(full_scale);
+    let scaled = min_out + magnitude * (full_scale - min_out) / full_scale;
+
+    (scaled * i32::from(value).signum()) as i16
+}
+
+pub fn controller_map_seven_byte<T: GameController>(
+    controller: &T,
+    options: &MappingOptions,
+) -> Vec<u8> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("controller_map_seven_byte()");
+    // Seven byte controller map is the same as
+    // the first seven bytes of the twenty-byte map!
+    let mut map = controller_map_twenty_byte(controller, options);
+    map.truncate(SEVEN_BYTE_PACKET_LENGTH);
+    map
+}
+
+pub fn controller_map_twenty_byte<T: GameController>(
+    controller: &T,
+    options: &MappingOptions,
+) -> Vec<u8> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("controller_map_twenty_byte()");
+    use sdl2::controller::{Axis, Button};
+
+    // Read axes through any routes the user has set up,
+    // limiting the travel of the physical triggers
+    let axis = |output: Axis| {
+        let input = options.axis_routes.input_for(output);
+        match input {
+            Axis::TriggerLeft | Axis::TriggerRight => {
+                options.trigger_range.rescale(controller.axis(input))
+            }
+            _ => controller.axis(input),
+        }
+    };
+
+    // Buttons standing in for L2 & R2 don't also press their own
+    let trigger_buttons = options
+        .trigger_buttons
+        .filter(|_| options.trigger_source != TriggerSource::Axis);
+
+    // Read buttons through the routes of whichever layer is active,
+    // keeping the shift button itself out of the packet
+    let shifted = options
+        .shift_layer
+        .as_ref()
+        .filter(|layer| controller.button(layer.button));
+    let button_routes = match shifted {
+        Some(layer) => &layer.button_routes,
+        None => &options.button_routes,
+    };
+    let button = |output: Button| {
+        button_routes.inputs_for(output).into_iter().any(|input| {
+            match (&options.shift_layer, trigger_buttons) {
+                _ if options.withheld_buttons.contains(&input) => false,
+                (Some(layer), _) if layer.button == input => false,
+                (_, Some(buttons)) if buttons.left == input || buttons.right == input => false,
+                _ => controller.button(input),
+            }
+        })
+    };
+
+    let touch = match options.touchpad_mode {
+        TouchpadMode::Off => None,
+        _ => controller.touchpad_finger(0),
+    };
+    let (touch_left, touch_down, touch_right, touch_up) = match (options.touchpad_mode, touch) {
+        (TouchpadMode::DPad, Some(position)) => convert_touchpad_to_dpad(position),
+        _ => (false, false, false, false),
+    };
+
+    // A D-Pad direction pressed digitally is always fully pressed,
+    // but one with a pressure source can also be partly pressed
+    let dpad = |direction: Button, touched: bool| -> i16 {
+        let digital = convert_button_to_analog(button(direction) || touched);
+        match options.dpad_pressure.input_for(direction) {
+            Some(input) => std::cmp::max(digital, input.read(axis(input.axis))),
+            None => digital,
+        }
+    };
+
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::start("buttons1");
+    // buttons1
+    let mut dpad_left_value: i16 = dpad(Button::DPadLeft, touch_left);
+    let mut dpad_down_value: i16 = dpad(Button::DPadDown, touch_down);
+    let mut dpad_right_value: i16 = dpad(Button::DPadRight, touch_right);
+    let mut dpad_up_value: i16 = dpad(Button::DPadUp, touch_up);
+    let start_value: i16 = convert_button_to_analog(button(Button::Start));
+    let right_stick_value: i16 = convert_button_to_analog(button(Button::RightStick));
+    let left_stick_value: i16 = convert_button_to_analog(button(Button::LeftStick));
+    let select_value: i16 = convert_button_to_analog(button(Button::Back));
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::end("buttons1");
+
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::start("buttons2");
+    // buttons2
+    let mut square_value: i16 = convert_button_to_analog(button(Button::X));
+    let mut cross_value: i16 = convert_button_to_analog(button(Button::A));
+    let circle_value: i16 = convert_button_to_analog(button(Button::B));
+    let triangle_value: i16 = convert_button_to_analog(button(Button::Y));
+    let r1_button_value: i16 = convert_button_to_analog(button(Button::RightShoulder));
+    let l1_button_value: i16 = convert_button_to_analog(button(Button::LeftShoulder));
+    let mut r2_button_value: i16 = convert_half_axis_positive(axis(Axis::TriggerRight));
+    let mut l2_button_value: i16 = convert_half_axis_positive(axis(Axis::TriggerLeft));
+    if let Some(buttons) = trigger_buttons {
+        l2_button_value = combine_trigger_source(
+            options.trigger_source,
+            l2_button_value,
+            controller.button(buttons.left),
+        );
+        r2_button_value = combine_trigger_source(
+            options.trigger_source,
+            r2_button_value,
+            controller.button(buttons.right),
+        );
+    }
+    let mut r2_pressure_value: Option<i16> = None;
+    let mut l2_pressure_value: Option<i16> = None;
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::end("buttons2");
+
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::start("sticks");
+    let mut right_stick_x_value: i16 = axis(Axis::RightX);
+    let mut right_stick_y_value: i16 = axis(Axis::RightY);
+    let mut left_stick_x_value: i16 = axis(Axis::LeftX);
+    let mut left_stick_y_value: i16 = axis(Axis::LeftY);
+
+    match options.mirror_stick {
+        Some(StickMirror::LeftToRight) => {
+            right_stick_x_value = left_stick_x_value;
+            right_stick_y_value = left_stick_y_value;
+        }
+        Some(StickMirror::RightToLeft) => {
+            left_stick_x_value = right_stick_x_value;
+            left_stick_y_value = right_stick_y_value;
+        }
+        None => (),
+    }
+
+    if let (TouchpadMode::RightStick, Some((x, y))) = (options.touchpad_mode, touch) {
+        right_stick_x_value = convert_touchpad_to_axis(x);
+        right_stick_y_value = convert_touchpad_to_axis(y);
+    }
+
+    if options.dpad_stick != DPadStick::Off {
+        // The real stick still works whenever the D-Pad's left alone
+        if let Some((x, y)) = convert_dpad_to_stick(
+            convert_analog_to_button(dpad_left_value),
+            convert_analog_to_button(dpad_down_value),
+            convert_analog_to_button(dpad_right_value),
+            convert_analog_to_button(dpad_up_value),
+        ) {
+            left_stick_x_value = x;
+            left_stick_y_value = y;
+        }
+
+        if options.dpad_stick == DPadStick::Move {
+            dpad_left_value = i16::MIN;
+            dpad_down_value = i16::MIN;
+            dpad_right_value = i16::MIN;
+            dpad_up_value = i16::MIN;
+        }
+    }
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::end("sticks");
+
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::start("handle trigger_mode");
+    match options.trigger_mode {
+        TriggerMode::RightStick => {
+            l2_button_value = convert_half_axis_negative(axis(Axis::RightY));
+            r2_button_value = convert_half_axis_positive(axis(Axis::RightY));
+
+            cross_value = convert_button_to_analog(button(Button::A));
+            square_value = convert_button_to_analog(button(Button::X));
+
+            right_stick_y_value =
+                combine_triggers(axis(Axis::TriggerLeft), axis(Axis::TriggerRight));
+        }
+        TriggerMode::CrossAndSquare => {
+            // Games which read pressure can still see the real triggers
+            if options.trigger_pressure {
+                l2_pressure_value = Some(l2_button_value);
+                r2_pressure_value = Some(r2_button_value);
+            }
+
+            l2_button_value = convert_button_to_analog(button(Button::A));
+            r2_button_value = convert_button_to_analog(button(Button::X));
+
+            cross_value = convert_half_axis_positive(axis(Axis::TriggerRight));
+            square_value = convert_half_axis_positive(axis(Axis::TriggerLeft));
+        }
+        _ => (),
+    }
+
+    match options.combine_triggers {
+        Some(CombinedTriggerAxis::LeftStickY) => {
+            left_stick_y_value =
+                combine_triggers(axis(Axis::TriggerLeft), axis(Axis::TriggerRight));
+        }
+        Some(CombinedTriggerAxis::RightStickY) => {
+            right_stick_y_value =
+                combine_triggers(axis(Axis::TriggerLeft), axis(Axis::TriggerRight));
+        }
+        None => (),
+    }
+    #[cfg(feature = "flamegraph-profiling")]
+    flame::end("handle trigger_mode");
+
+    for (x, y) in [
+        (&mut right_stick_x_value, &mut right_stick_y_value),
+        (&mut left_stick_x_value, &mut left_stick_y_value),
+    ] {
+        let (outside_x, outside_y) = apply_radial_deadzone(*x, *y, options.deadzone);
+        *x = scale_anti_deadzone(outside_x, options.anti_deadzone);
+        *y = scale_anti_deadzone(outside_y, options.anti_deadzone);
+    }
+
+    // Without normalisation the sticks are only clamped to their shape
+    let range_profile = if options.normalise_sticks {
+        options.range_profile
+    } else {
+        RangeProfile {
+            normalise_percent: 0,
+            ..options.range_profile
+        }
+    };
+    let stick_range_profile = |normalise_percent: Option<u8>| RangeProfile {
+        normalise_percent: normalise_percent.unwrap_or(range_profile.normalise_percent),
+        ..range_profile
+    };
+    normalise_stick_as_dualshock2(
+        &mut right_stick_x_value,
+        &mut right_stick_y_value,
+        &stick_range_profile(options.normalise_right),
+        options.stick_shape,
+    );
+    normalise_stick_as_dualshock2(
+        &mut left_stick_x_value,
+        &mut left_stick_y_value,
+        &stick_range_profile(options.normalise_left),
+        options.stick_shape,
+    );
+
+    if options.snap_cardinal > 0.0 {
+        for (x, y) in [
+            (&mut right_stick_x_value, &mut right_stick_y_value),
+            (&mut left_stick_x_value, &mut left_stick_y_value),
+        ] {
+            let (snapped_x, snapped_y) = snap_to_octant(*x, *y, options.snap_cardinal);
+            *x = snapped_x;
+            *y = snapped_y;
+        }
+    }
+
+    if let Some(radius) = options.clamp_radial {
+        for (x, y) in [
+            (&mut right_stick_x_value, &mut right_stick_y_value),
+            (&mut left_stick_x_value, &mut left_stick_y_value),
+        ] {
+            // Both values already fit in an i16, and only get closer to 0
+            let (clamped_x, clamped_y) = clamp_radial(i32::from(*x), i32::from(*y), radius);
+            *x = clamped_x as i16;
+            *y = clamped_y as i16;
+        }
+    }
+
+    let stick = |value: i16| {
+        options.range_profile.clamp(match options.stick_encoding {
+            StickEncoding::SignedCentred => options.stick_centre.convert(value),
+            StickEncoding::Unsigned => convert_unsigned_for_dualshock(value),
+        })
+    };
+
+    let mut buttons1 = Buttons1::empty();
+    buttons1.set(Buttons1::LEFT, convert_analog_to_button(dpad_left_value));
+    buttons1.set(Buttons1::DOWN, convert_analog_to_button(dpad_down_value));
+    buttons1.set(Buttons1::RIGHT, convert_analog_to_button(dpad_right_value));
+    buttons1.set(Buttons1::UP, convert_analog_to_button(dpad_up_value));
+    buttons1.set(Buttons1::START, convert_analog_to_button(start_value));
+    buttons1.set(Buttons1::R3, convert_analog_to_button(right_stick_value));
+    buttons1.set(Buttons1::L3, convert_analog_to_button(left_stick_value));
+    buttons1.set(Buttons1::SELECT, convert_analog_to_button(select_value));
+
+    let mut buttons2 = Buttons2::empty();
+    buttons2.set(Buttons2::SQUARE, convert_analog_to_button(square_value));
+    buttons2.set(Buttons2::CROSS, convert_analog_to_button(cross_value));
+    buttons2.set(Buttons2::CIRCLE, convert_analog_to_button(circle_value));
+    buttons2.set(Buttons2::TRIANGLE, convert_analog_to_button(triangle_value));
+    buttons2.set(Buttons2::R1, convert_analog_to_button(r1_button_value));
+    buttons2.set(Buttons2::L1, convert_analog_to_button(l1_button_value));
+    buttons2.set(Buttons2::R2, convert_analog_to_button(r2_button_value));
+    buttons2.set(Buttons2::L2, convert_analog_to_button(l2_button_value));
+
+    let mode_footer = if button(Button::Guide) { 0xAA } else { 0x55 };
+
+    return vec![
+        DUALSHOCK_MAGIC,
+        // DualShock protocol considers 0 to mean
+        // pressed and 1 to mean not pressed, so
+        // we NOT the our bitflags here
+        !buttons1.bits(),
+        !buttons2.bits(),
+        // Analog sticks
+        stick(right_stick_x_value),
+        stick(right_stick_y_value),
+        stick(left_stick_x_value),
+        stick(left_stick_y_value),
+        // Pressure values
+        convert_for_dualshock(dpad_right_value),
+        convert_for_dualshock(dpad_left_value),
+        convert_for_dualshock(dpad_up_value),
+        convert_for_dualshock(dpad_down_value),
+        convert_for_dualshock(triangle_value),
+        convert_for_dualshock(circle_value),
+        convert_for_dualshock(cross_value),
+        convert_for_dualshock(square_value),
+        convert_for_dualshock(l1_button_value),
+        convert_for_dualshock(r1_button_value),
+        convert_for_dualshock(l2_pressure_value.unwrap_or(l2_button_value)),
+        convert_for_dualshock(r2_pressure_value.unwrap_or(r2_button_value)),
+        mode_footer,
+    ];
+}
+
+fn describe_button_bits(byte: usize) -> Vec<(u8, String)> {
+    // Read the names straight out of the bitflags definitions,
+    // from the most significant bit down, so they can't drift
+    (0..8)
+        .rev()
+        .filter_map(|bit| {
+            let mask = 1 << bit;
+            let name = match byte {
+                1 => Buttons1::from_bits(mask).map(|flag| format!("{:?}", flag)),
+                2 => Buttons2::from_bits(mask).map(|flag| format!("{:?}", flag)),
+                _ => None,
+            };
+
+            name.map(|name| (bit, name))
+        })
+        .collect()
+}
+
+pub fn describe_protocol() -> String {
+    let mut description = String::new();
+
+    for (title, length) in &[
+        (
+            "Seven-byte packet (Johnny Chung Lee's firmware)",
+            SEVEN_BYTE_PACKET_LENGTH,
+        ),
+        (
+            "Twenty-byte packet (Aaron Clovsky's firmware)",
+            TWENTY_BYTE_PACKET_LENGTH,
+        ),
+    ] {
+        description.push_str(&format!("{}:\n", title));
+
+        for (byte, label) in PACKET_LAYOUT.iter().take(*length).enumerate() {
+            description.push_str(&format!("  Byte {:>2}: {}\n", byte, label));
+
+            for (bit, name) in describe_button_bits(byte) {
+                description.push_str(&format!("    Bit {}: {}\n", bit, name));
+            }
+        }
+
+        description.push('\n');
+    }
+
+    description
+}
+
+pub fn build_packet<T: GameController>(
+    controller: &T,
+    communication_mode: &ControllerEmulatorPacketType,
+    options: &MappingOptions,
+) -> Vec<u8> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("build_packet()");
+    match *communication_mode {
+        ControllerEmulatorPacketType::SevenByte => controller_map_seven_byte(controller, options),
+        ControllerEmulatorPacketType::None | ControllerEmulatorPacketType::TwentyByte => {
+            controller_map_twenty_byte(controller, options)
+        }
+    }
+}
+
+// Which button bit each pressure byte after the sticks belongs to
+const PRESSURE_BUTTONS: [(usize, u8); 12] = [
+    (1, Buttons1::RIGHT.bits()),
+    (1, Buttons1::LEFT.bits()),
+    (1, Buttons1::UP.bits()),
+    (1, Buttons1::DOWN.bits()),
+    (2, Buttons2::TRIANGLE.bits()),
+    (2, Buttons2::CIRCLE.bits()),
+    (2, Buttons2::CROSS.bits()),
+    (2, Buttons2::SQUARE.bits()),
+    (2, Buttons2::L1.bits()),
+    (2, Buttons2::R1.bits()),
+    (2, Buttons2::L2.bits()),
+    (2, Buttons2::R2.bits()),
+];
+
+// Re-encodes an already-built packet in another format. Going down to
+// seven bytes is the same truncation `controller_map_seven_byte` makes;
+// going up to twenty, each button is given full pressure if it's pressed,
+// just as a digital button would be, and the mode footer is normal.
+pub fn convert_packet(
+    packet: &[u8],
+    communication_mode: &ControllerEmulatorPacketType,
+) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("convert_packet()");
+    if packet.len() != SEVEN_BYTE_PACKET_LENGTH && packet.len() != TWENTY_BYTE_PACKET_LENGTH {
+        return Err(format!("unexpected packet length {}", packet.len()));
+    }
+
+    let mut converted = packet.to_vec();
+
+    match communication_mode {
+        ControllerEmulatorPacketType::None => {
+            return Err("no format to convert to".to_string());
+        }
+        ControllerEmulatorPacketType::SevenByte => converted.truncate(SEVEN_BYTE_PACKET_LENGTH),
+        ControllerEmulatorPacketType::TwentyByte => {
+            if packet.len() == SEVEN_BYTE_PACKET_LENGTH {
+                // The DualShock protocol considers 0 to mean pressed
+                converted.extend(PRESSURE_BUTTONS.iter().map(|(byte, bit)| {
+                    if packet[*byte] & bit == 0 {
+                        0xFF
+                    } else {
+                        0x00
+                    }
+                }));
+                converted.push(NEUTRAL_PACKET[TWENTY_BYTE_PACKET_LENGTH - 1]);
+            }
+        }
+    }
+
+    Ok(converted)
+}
+
+// A neutral packet for `communication_mode`, with the sticks resting at `centre`
+pub fn centred_neutral_packet(
+    communication_mode: &ControllerEmulatorPacketType,
+    centre: StickCentre,
+) -> Vec<u8> {
+    let mut packet = neutral_packet(communication_mode);
+    centre.apply(&mut packet);
+    packet
+}
+
+pub fn neutral_packet(communication_mode: &ControllerEmulatorPacketType) -> Vec<u8> {
+    match *communication_mode {
+        ControllerEmulatorPacketType::SevenByte => {
+            NEUTRAL_PACKET[..SEVEN_BYTE_PACKET_LENGTH].to_vec()
+        }
+        ControllerEmulatorPacketType::None | ControllerEmulatorPacketType::TwentyByte => {
+            NEUTRAL_PACKET.to_vec()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::faux_controller::FauxController;
+
+    #[test]
+    fn convert_half_axis_positive_is_accurate() {
+        use super::convert_half_axis_positive;
+
+        assert_eq!(
+            convert_half_axis_positive(i16::min_value()),
+            i16::min_value()
+        );
+        assert_eq!(convert_half_axis_positive(0), i16::min_value());
+        assert_eq!(convert_half_axis_positive(i16::max_value() / 2 + 1), 0);
+        assert_eq!(
+            convert_half_axis_positive(i16::max_value()),
+            i16::max_value()
+        );
+    }
+
+    #[test]
+    fn convert_half_axis_negative_is_accurate() {
+        use super::convert_half_axis_negative;
+
+        assert_eq!(
+            convert_half_axis_negative(i16::max_value()),
+            i16::min_value()
+        );
+        assert_eq!(convert_half_axis_negative(0), i16::min_value());
+        assert_eq!(convert_half_axis_negative(i16::min_value() / 2 - 1), 0);
+        assert_eq!(
+            convert_half_axis_negative(i16::min_value()),
+            i16::max_value()
+        );
+    }
+
+    #[test]
+    fn whats_the_midpoint_of_a_is_accurate() {
+        #![allow(clippy::float_cmp)]
+        use super::whats_the_midpoint_of_a;
+
+        assert_eq!(whats_the_midpoint_of_a::<u8>(), 127_u8);
+        assert_eq!(
+            whats_the_midpoint_of_a::<u64>(),
+            9_223_372_036_854_775_807_u64
+        );
+        assert_eq!(whats_the_midpoint_of_a::<i16>(), 0_i16);
+        assert_eq!(whats_the_midpoint_of_a::<i64>(), 0_i64);
+        // NOTE: This would normally trip the `clippy::float_cmp` rule,
+        //       which is why we've explicitly allowed it above.
+        assert_eq!(whats_the_midpoint_of_a::<f32>(), 0_f32);
+    }
+
+    #[test]
+    fn convert_button_to_analog_is_accurate() {
+        use super::convert_button_to_analog;
+
+        assert_eq!(convert_button_to_analog::<u8>(true), u8::max_value());
+        assert_eq!(convert_button_to_analog::<i64>(true), i64::max_value());
+        assert_eq!(convert_button_to_analog::<u8>(false), u8::min_value());
+        assert_eq!(convert_button_to_analog::<i64>(false), i64::min_value());
+    }
+
+    #[test]
+    fn convert_analog_to_button_is_accurate() {
+        use super::convert_analog_to_button;
+
+        assert!(!convert_analog_to_button(127u8));
+        assert!(convert_analog_to_button(128u8));
+
+        assert!(convert_analog_to_button(u8::max_value()));
+        assert!(convert_analog_to_button(i64::max_value()));
+        assert!(!convert_analog_to_button(u8::min_value()));
+        assert!(!convert_analog_to_button(i64::min_value()));
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_works() {
+        use super::controller_map_twenty_byte;
+        use super::DUALSHOCK_MAGIC;
+        use super::{Buttons1, Buttons2, MappingOptions, TriggerMode};
+        use sdl2::controller::{Axis, Button};
+
+        let mut controller =
+            FauxController::create_with_name(String::from("Applejack Game-player Pad"));
+
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::Normal, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+                // Pressure values
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::RightStick, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+                // Pressure values
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::CrossAndSquare, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+                // Pressure values
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+
+        // Do some stuff to the controller state, and test again
+        controller.set_button(Button::DPadLeft, true);
+        controller.set_button(Button::A, true);
+        controller.set_axis(Axis::TriggerLeft, i16::max_value());
+        controller.set_axis(Axis::RightX, -24_000);
+        controller.set_axis(Axis::RightY, 16_500);
+        controller.set_axis(Axis::LeftX, 255);
+        controller.set_axis(Axis::LeftY, -4_096);
+
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::Normal, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::LEFT.bits(),
+                !(Buttons2::CROSS | Buttons2::L2).bits(),
+                // Analog sticks
+                0x18,
+                0xC6,
+                0x81,
+                0x6E,
+                // Pressure values
+                0x00,
+                0xFF,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0xFF,
+                0x00,
+                0x00,
+                0x00,
+                0xFF,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::RightStick, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::LEFT.bits(),
+                !(Buttons2::CROSS | Buttons2::R2).bits(),
+                // Analog sticks
+                0x18,
+                0xFF,
+                0x81,
+                0x6E,
+                // Pressure values
+                0x00,
+                0xFF,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0xFF,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x80,
+                // Mode footer
+                0x55,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::CrossAndSquare, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::LEFT.bits(),
+                !(Buttons2::SQUARE | Buttons2::L2).bits(),
+                // Analog sticks
+                0x18,
+                0xC6,
+                0x81,
+                0x6E,
+                // Pressure values
+                0x00,
+                0xFF,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0xFF,
+                0x00,
+                0x00,
+                0xFF,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+    }
+
+    #[test]
+    fn controller_map_seven_byte_works() {
+        use super::controller_map_seven_byte;
+        use super::DUALSHOCK_MAGIC;
+        use super::{Buttons1, Buttons2, MappingOptions, TriggerMode};
+        use sdl2::controller::{Axis, Button};
+
+        let mut controller =
+            FauxController::create_with_name(String::from("Apple Pippin Controller"));
+
+        assert_eq!(
+            controller_map_seven_byte(&controller, &MappingOptions::new(TriggerMode::Normal, true)),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_seven_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::RightStick, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_seven_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::CrossAndSquare, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                !Buttons2::empty().bits(),
+                // Analog sticks
+                0x80,
+                0x80,
+                0x80,
+                0x80,
+            ]
+        );
+
+        // Do some stuff to the controller state, and test again
+        controller.set_button(Button::DPadLeft, true);
+        controller.set_button(Button::A, true);
+        controller.set_axis(Axis::TriggerLeft, i16::max_value());
+        controller.set_axis(Axis::RightX, -24_000);
+        controller.set_axis(Axis::RightY, 16_500);
+        controller.set_axis(Axis::LeftX, 255);
+        controller.set_axis(Axis::LeftY, -4_096);
+
+        assert_eq!(
+            controller_map_seven_byte(&controller, &MappingOptions::new(TriggerMode::Normal, true)),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::LEFT.bits(),
+                !(Buttons2::CROSS | Buttons2::L2).bits(),
+                // Analog sticks
+                0x18,
+                0xC6,
+                0x81,
+                0x6E,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_seven_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::RightStick, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::LEFT.bits(),
+                !(Buttons2::CROSS | Buttons2::R2).bits(),
+                // Analog sticks
+                0x18,
+                0xFF,
+                0x81,
+                0x6E,
+            ]
+        );
+
+        assert_eq!(
+            controller_map_seven_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::CrossAndSquare, true)
+            ),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::LEFT.bits(),
+                !(Buttons2::SQUARE | Buttons2::L2).bits(),
+                // Analog sticks
+                0x18,
+                0xC6,
+                0x81,
+                0x6E,
+            ]
+        );
+    }
+
+    #[test]
+    fn axis_routes_parse_and_validate() {
+        use super::{AxisRoute, AxisRoutes};
+        use sdl2::controller::Axis;
+        use std::str::FromStr;
+
+        let route = AxisRoute::from_str("right-y=trigger-left").unwrap();
+        assert_eq!(route.output, Axis::RightY);
+        assert_eq!(route.input, Axis::TriggerLeft);
+
+        assert!(AxisRoute::from_str("right-y").is_err());
+        assert!(AxisRoute::from_str("right-z=left-x").is_err());
+
+        let routes = AxisRoutes::from_routes(&[
+            AxisRoute::from_str("left-x=left-y").unwrap(),
+            AxisRoute::from_str("left-y=left-x").unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(routes.input_for(Axis::LeftX), Axis::LeftY);
+        assert_eq!(routes.input_for(Axis::LeftY), Axis::LeftX);
+        assert_eq!(routes.input_for(Axis::RightX), Axis::RightX);
+
+        // Each output may only be routed once
+        assert!(AxisRoutes::from_routes(&[
+            AxisRoute::from_str("right-x=trigger-left").unwrap(),
+            AxisRoute::from_str("right-x=trigger-right").unwrap(),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_keeps_trigger_pressure() {
+        use super::controller_map_twenty_byte;
+        use super::{Buttons2, MappingOptions, TriggerMode};
+        use sdl2::controller::{Axis, Button};
+
+        let mut controller = FauxController::create_with_name(String::from("Nyko Airflo"));
+        controller.set_axis(Axis::TriggerLeft, i16::max_value());
+        controller.set_axis(Axis::TriggerRight, i16::max_value() / 2);
+        controller.set_button(Button::X, true);
+
+        let options = MappingOptions {
+            trigger_pressure: true,
+            ..MappingOptions::new(TriggerMode::CrossAndSquare, false)
+        };
+        let packet = controller_map_twenty_byte(&controller, &options);
+
+        // The buttons are still remapped (a half-pulled trigger isn't a press)...
+        assert_eq!(packet[2], !(Buttons2::SQUARE | Buttons2::R2).bits());
+        // ...but L2 & R2 pressure follow the triggers
+        assert_eq!(packet[17], 0xFF);
+        assert_eq!(packet[18], 0x7F);
+
+        // Without the option, L2 & R2 pressure follow the buttons
+        let packet = controller_map_twenty_byte(
+            &controller,
+            &MappingOptions::new(TriggerMode::CrossAndSquare, false),
+        );
+        assert_eq!(packet[17], 0x00);
+        assert_eq!(packet[18], 0xFF);
+    }
+
+    #[test]
+    fn trigger_source_combines_axis_and_button() {
+        use super::{combine_trigger_source, controller_map_twenty_byte};
+        use super::{Buttons1, MappingOptions, TriggerButtons, TriggerSource};
+        use sdl2::controller::{Axis, Button};
+        use std::str::FromStr;
+
+        assert_eq!(combine_trigger_source(TriggerSource::Axis, 0, true), 0);
+        assert_eq!(
+            combine_trigger_source(TriggerSource::Button, 0, true),
+            i16::MAX
+        );
+        assert_eq!(
+            combine_trigger_source(TriggerSource::Button, 0, false),
+            i16::MIN
+        );
+        assert_eq!(
+            combine_trigger_source(TriggerSource::Max, 0, true),
+            i16::MAX
+        );
+        assert_eq!(combine_trigger_source(TriggerSource::Max, 0, false), 0);
+
+        // A lagging trigger, with its button already fully pressed
+        let mut controller = FauxController::create_with_name(String::from("GameCube Adapter"));
+        controller.set_axis(Axis::TriggerLeft, i16::max_value() / 2);
+        controller.set_button(Button::LeftStick, true);
+
+        let mut options = MappingOptions {
+            trigger_buttons: Some(TriggerButtons::from_str("left-stick,right-stick").unwrap()),
+            ..MappingOptions::default()
+        };
+
+        // By default the axis alone is used, and the button's its usual self
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[17], 0x7F);
+        assert_eq!(packet[1] & Buttons1::L3.bits(), 0);
+
+        options.trigger_source = TriggerSource::Max;
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[17], 0xFF);
+        assert_eq!(packet[18], 0x00);
+        assert_eq!(packet[1] & Buttons1::L3.bits(), Buttons1::L3.bits());
+
+        assert!(TriggerButtons::from_str("left-stick").is_err());
+        assert!(TriggerButtons::from_str("left-stick,l2").is_err());
+    }
+
+    #[test]
+    fn dpad_pressure_follows_its_source_axis() {
+        use super::controller_map_twenty_byte;
+        use super::TriggerMode;
+        use super::{Buttons1, DPadPressureRoute, DPadPressureRoutes, HalfAxis, MappingOptions};
+        use sdl2::controller::{Axis, Button};
+        use std::str::FromStr;
+
+        assert!(HalfAxis::from_str("left-x-").unwrap().negative);
+        assert!(!HalfAxis::from_str("left-x+").unwrap().negative);
+        assert!(HalfAxis::from_str("trigger-left-").is_err());
+        assert!(DPadPressureRoute::from_str("sideways=left-x").is_err());
+        assert!(DPadPressureRoutes::from_routes(&[
+            DPadPressureRoute::from_str("up=left-y-").unwrap(),
+            DPadPressureRoute::from_str("up=trigger-left").unwrap(),
+        ])
+        .is_err());
+
+        // The D-Pad's pressure bytes come straight after the sticks
+        let (right_pressure, left_pressure) = (7, 8);
+
+        let mut controller = FauxController::create_with_name(String::from("Logitech WingMan"));
+        controller.set_axis(Axis::LeftX, 24_576);
+        controller.set_button(Button::DPadLeft, true);
+
+        // By default the D-Pad is only ever fully pressed or released
+        let options = MappingOptions::new(TriggerMode::Normal, false);
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[right_pressure], 0x00);
+        assert_eq!(packet[left_pressure], 0xFF);
+
+        let options = MappingOptions {
+            dpad_pressure: DPadPressureRoutes::from_routes(&[
+                DPadPressureRoute::from_str("right=left-x+").unwrap(),
+                DPadPressureRoute::from_str("left=left-x-").unwrap(),
+            ])
+            .unwrap(),
+            ..MappingOptions::new(TriggerMode::Normal, false)
+        };
+        let packet = controller_map_twenty_byte(&controller, &options);
+
+        // Three quarters of the way right is half of the pressure range
+        assert_eq!(packet[right_pressure], 0xC0);
+        assert_eq!(packet[1] & Buttons1::RIGHT.bits(), 0);
+        // Pressing the D-Pad itself still gives full pressure
+        assert_eq!(packet[left_pressure], 0xFF);
+        assert_eq!(packet[1] & Buttons1::LEFT.bits(), 0);
+    }
+
+    #[test]
+    fn stick_encodings_are_accurate() {
+        use super::{convert_for_dualshock, convert_unsigned_for_dualshock};
+
+        assert_eq!(convert_for_dualshock(i16::MIN), 0x00);
+        assert_eq!(convert_for_dualshock(0), 0x80);
+        assert_eq!(convert_for_dualshock(i16::MAX), 0xFF);
+        // Anything just below zero shifts down to the byte below the centre
+        assert_eq!(convert_for_dualshock(-1), 0x7F);
+        assert_eq!(convert_for_dualshock(-256), 0x7F);
+        assert_eq!(convert_for_dualshock(-257), 0x7E);
+        assert_eq!(convert_for_dualshock(255), 0x80);
+        assert_eq!(convert_for_dualshock(256), 0x81);
+
+        assert_eq!(convert_unsigned_for_dualshock(i16::MIN), 0);
+        assert_eq!(convert_unsigned_for_dualshock(0), 128);
+        assert_eq!(convert_unsigned_for_dualshock(i16::MAX), 255);
+        // Scaling rounds to the nearest byte, rather than always down
+        assert_eq!(convert_unsigned_for_dualshock(-1), 127);
+        assert_eq!(convert_unsigned_for_dualshock(-257), 127);
+        assert_eq!(convert_unsigned_for_dualshock(-258), 126);
+        assert_eq!(convert_unsigned_for_dualshock(256), 128);
+        assert_eq!(convert_unsigned_for_dualshock(257), 129);
+    }
+
+    #[test]
+    fn stick_byte_range_keeps_sticks_off_the_ends() {
+        use super::controller_map_twenty_byte;
+        use super::{MappingOptions, RangeProfile, StickByteRange};
+        use sdl2::controller::{Axis, Button};
+        use std::str::FromStr;
+
+        let range = StickByteRange::from_str("0x01,0xFE").unwrap();
+        assert_eq!(range, StickByteRange { min: 1, max: 254 });
+        assert_eq!(StickByteRange::from_str("1, 254"), Ok(range));
+        assert!(StickByteRange::from_str("0x90,0xFE").is_err());
+        assert!(StickByteRange::from_str("0x01").is_err());
+
+        let mut controller = FauxController::create_with_name(String::from("Retro Adapter"));
+        let options = MappingOptions {
+            range_profile: RangeProfile::default().with_byte_range(range),
+            ..MappingOptions::default()
+        };
+
+        // The centre's left as it is
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[3..7], [0x80; 4]);
+
+        controller.set_axis(Axis::LeftX, i16::MIN);
+        controller.set_axis(Axis::LeftY, i16::MAX);
+        controller.set_axis(Axis::RightX, i16::MAX);
+        controller.set_axis(Axis::RightY, i16::MIN);
+        controller.set_button(Button::A, true);
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[3..7], [0xFE, 0x01, 0x01, 0xFE]);
+        // Pressure still reaches the end of its range
+        assert_eq!(packet[13], 0xFF);
+    }
+
+    #[test]
+    fn stick_centre_moves_the_resting_byte() {
+        use super::controller_map_twenty_byte;
+        use super::{centred_neutral_packet, ControllerEmulatorPacketType};
+        use super::{MappingOptions, StickCentre, TriggerMode};
+        use std::str::FromStr;
+
+        // The usual centre converts just as before
+        let usual = StickCentre::default();
+        for value in [i16::MIN, -16_384, -1, 0, 1, 255, 256, 16_384, i16::MAX] {
+            assert_eq!(usual.convert(value), super::convert_for_dualshock(value));
+        }
+
+        let centre = StickCentre::from_str("0x7F").unwrap();
+        assert_eq!(centre, StickCentre(0x7F));
+        assert_eq!(centre.convert(0), 0x7F);
+        assert_eq!(centre.convert(i16::MIN), 0x00);
+        assert_eq!(centre.convert(i16::MAX), 0xFF);
+        assert!(StickCentre::from_str("0x180").is_err());
+
+        let controller = FauxController::create_with_name(String::from("Logitech WingMan"));
+        let options = MappingOptions {
+            stick_centre: centre,
+            ..MappingOptions::new(TriggerMode::Normal, false)
+        };
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[3..7], [0x7F; 4]);
+        assert_eq!(
+            centred_neutral_packet(&ControllerEmulatorPacketType::SevenByte, centre)[3..7],
+            [0x7F; 4]
+        );
+    }
+
+    #[test]
+    fn dpad_converts_to_stick_directions() {
+        use super::convert_dpad_to_stick;
+
+        assert_eq!(convert_dpad_to_stick(false, false, false, false), None);
+        assert_eq!(
+            convert_dpad_to_stick(false, false, true, false),
+            Some((32767, 0))
+        );
+        assert_eq!(
+            convert_dpad_to_stick(false, false, false, true),
+            Some((0, -32767))
+        );
+        // Diagonals are no further out than straight directions
+        assert_eq!(
+            convert_dpad_to_stick(true, true, false, false),
+            Some((-23170, 23170))
+        );
+        // Opposites cancel out
+        assert_eq!(
+            convert_dpad_to_stick(true, false, true, true),
+            Some((0, -32767))
+        );
+        assert_eq!(
+            convert_dpad_to_stick(true, false, true, false),
+            Some((0, 0))
+        );
+    }
+
+    #[test]
+    fn radial_deadzone_centres_small_movements() {
+        use super::apply_radial_deadzone;
+
+        // Off, nothing changes
+        assert_eq!(apply_radial_deadzone(100, -100, 0), (100, -100));
+
+        assert_eq!(apply_radial_deadzone(3000, 0, 4000), (0, 0));
+        assert_eq!(apply_radial_deadzone(2800, 2800, 4000), (0, 0));
+        // Just outside starts again from (nearly) nothing, keeping direction
+        assert_eq!(apply_radial_deadzone(0, -4001, 4000), (0, -1));
+        let (x, y) = apply_radial_deadzone(3000, 3000, 4000);
+        assert_eq!(x, y);
+        assert!(x > 0 && x < 500);
+        // Full deflection still reaches full scale
+        assert_eq!(
+            apply_radial_deadzone(i16::max_value(), 0, 4000),
+            (i16::max_value(), 0)
+        );
+        assert_eq!(
+            apply_radial_deadzone(i16::min_value(), 0, 4000),
+            (-i16::max_value(), 0)
+        );
+    }
+
+    #[test]
+    fn scale_anti_deadzone_lifts_small_movements() {
+        use super::scale_anti_deadzone;
+
+        // Off, nothing changes
+        assert_eq!(scale_anti_deadzone(100, 0), 100);
+
+        assert_eq!(scale_anti_deadzone(0, 8192), 0);
+        assert_eq!(scale_anti_deadzone(1, 8192), 8192);
+        assert_eq!(scale_anti_deadzone(-1, 8192), -8192);
+        // Half way there is (about) half way between the minimum and full scale
+        assert_eq!(scale_anti_deadzone(16384, 8192), 20479);
+        assert_eq!(
+            scale_anti_deadzone(i16::max_value(), 8192),
+            i16::max_value()
+        );
+        assert_eq!(
+            scale_anti_deadzone(i16::min_value(), 8192),
+            -i16::max_value()
+        );
+    }
+
+    #[test]
+    fn convert_packet_matches_the_controller_maps() {
+        use super::{controller_map_seven_byte, controller_map_twenty_byte, convert_packet};
+        use super::{ControllerEmulatorPacketType, MappingOptions, TriggerMode};
+        use sdl2::controller::{Axis, Button};
+
+        let mut controller = FauxController::create_with_name(String::from("Sega Saturn Pad"));
+        controller.set_button(Button::A, true);
+        controller.set_button(Button::DPadUp, true);
+        controller.set_button(Button::LeftShoulder, true);
+        controller.set_axis(Axis::TriggerRight, i16::max_value());
+        controller.set_axis(Axis::LeftX, -12_000);
+
+        let options = MappingOptions::new(TriggerMode::Normal, true);
+        let seven = controller_map_seven_byte(&controller, &options);
+        let twenty = controller_map_twenty_byte(&controller, &options);
+
+        assert_eq!(
+            convert_packet(&twenty, &ControllerEmulatorPacketType::SevenByte),
+            Ok(seven.clone())
+        );
+        // Digital buttons come out just as the twenty-byte map builds them
+        assert_eq!(
+            convert_packet(&seven, &ControllerEmulatorPacketType::TwentyByte),
+            Ok(twenty.clone())
+        );
+        assert_eq!(
+            convert_packet(&twenty, &ControllerEmulatorPacketType::TwentyByte),
+            Ok(twenty)
+        );
+
+        assert!(convert_packet(&seven[..6], &ControllerEmulatorPacketType::TwentyByte).is_err());
+        assert!(convert_packet(&seven, &ControllerEmulatorPacketType::None).is_err());
+    }
+
+    #[test]
+    fn triggers_combine_onto_one_axis() {
+        use super::{combine_triggers, controller_map_twenty_byte};
+        use super::{CombinedTriggerAxis, MappingOptions, TriggerMode};
+        use sdl2::controller::Axis;
+
+        assert_eq!(combine_triggers(0, 0), 0);
+        assert_eq!(combine_triggers(i16::max_value(), 0), i16::max_value());
+        assert_eq!(combine_triggers(0, i16::max_value()), -i16::max_value());
+        assert_eq!(combine_triggers(i16::min_value(), 1), i16::min_value());
+
+        let mut controller =
+            FauxController::create_with_name(String::from("Logitech Driving Force"));
+        controller.set_axis(Axis::TriggerRight, i16::max_value());
+
+        // Right stick Y, then left stick Y
+        let (right_y, left_y) = (4, 6);
+
+        let options = MappingOptions {
+            combine_triggers: Some(CombinedTriggerAxis::LeftStickY),
+            ..MappingOptions::new(TriggerMode::Normal, false)
+        };
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[left_y], 0x00);
+        assert_eq!(packet[right_y], 0x80);
+
+        // The right-stick trigger mode combines them the same way
+        let packet = controller_map_twenty_byte(
+            &controller,
+            &MappingOptions::new(TriggerMode::RightStick, false),
+        );
+        assert_eq!(packet[right_y], 0x00);
+        assert_eq!(packet[left_y], 0x80);
+    }
+
+    #[test]
+    fn shift_layer_applies_only_while_held() {
+        use super::controller_map_twenty_byte;
+        use super::TriggerMode;
+        use super::{ButtonRoute, ButtonRoutes, Buttons1, Buttons2, MappingOptions, ShiftLayer};
+        use sdl2::controller::Button;
+        use std::str::FromStr;
+
+        // While Select is held, the bottom and top face buttons swap
+        let options = MappingOptions {
+            shift_layer: Some(ShiftLayer {
+                button: Button::Back,
+                button_routes: ButtonRoutes::from_routes(&[
+                    ButtonRoute::from_str("y=a").unwrap(),
+                    ButtonRoute::from_str("a=y").unwrap(),
+                ])
+                .unwrap(),
+            }),
+            ..MappingOptions::new(TriggerMode::Normal, false)
+        };
+
+        let mut controller = FauxController::create_with_name(String::from("Logitech WingMan"));
+        controller.set_button(Button::A, true);
+
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[1], !Buttons1::empty().bits());
+        assert_eq!(packet[2], !Buttons2::CROSS.bits());
+
+        // Select itself is never sent
+        controller.set_button(Button::Back, true);
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[1], !Buttons1::empty().bits());
+        assert_eq!(packet[2], !Buttons2::TRIANGLE.bits());
+
+        controller.set_button(Button::Back, false);
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[1], !Buttons1::empty().bits());
+        assert_eq!(packet[2], !Buttons2::CROSS.bits());
+
+        // Nor are any withheld buttons
+        let options = MappingOptions {
+            withheld_buttons: vec![Button::A],
+            ..options
+        };
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[2], !Buttons2::empty().bits());
+    }
+
+    #[test]
+    fn button_routes_combine_their_inputs() {
+        use super::controller_map_twenty_byte;
+        use super::TriggerMode;
+        use super::{ButtonRoute, ButtonRoutes, Buttons2, MappingOptions};
+        use sdl2::controller::Button;
+        use std::str::FromStr;
+
+        // Either shoulder fires R1
+        let options = MappingOptions {
+            button_routes: ButtonRoutes::from_routes(&[ButtonRoute::from_str(
+                "right-shoulder=right-shoulder,left-shoulder",
+            )
+            .unwrap()])
+            .unwrap(),
+            ..MappingOptions::new(TriggerMode::Normal, false)
+        };
+
+        let mut controller = FauxController::create_with_name(String::from("Logitech WingMan"));
+        for (left, right) in [(true, false), (false, true), (true, true)] {
+            controller.set_button(Button::LeftShoulder, left);
+            controller.set_button(Button::RightShoulder, right);
+            let packet = controller_map_twenty_byte(&controller, &options);
+            // The DualShock protocol considers 0 to mean pressed
+            assert_eq!(packet[2] & Buttons2::R1.bits(), 0);
+        }
+
+        controller.set_button(Button::LeftShoulder, false);
+        controller.set_button(Button::RightShoulder, false);
+        let packet = controller_map_twenty_byte(&controller, &options);
+        assert_eq!(packet[2], !Buttons2::empty().bits());
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_follows_axis_routes() {
+        use super::controller_map_twenty_byte;
+        use super::DUALSHOCK_MAGIC;
+        use super::{AxisRoute, AxisRoutes, Buttons1, Buttons2, MappingOptions, TriggerMode};
+        use sdl2::controller::Axis;
+        use std::str::FromStr;
+
+        let mut controller = FauxController::create_with_name(String::from("Logitech WingMan"));
+        controller.set_axis(Axis::TriggerLeft, i16::max_value());
+        controller.set_axis(Axis::RightX, -24_000);
+        controller.set_axis(Axis::RightY, 16_500);
+
+        // Put the triggers on the right stick
+        let options = MappingOptions {
+            axis_routes: AxisRoutes::from_routes(&[
+                AxisRoute::from_str("right-x=trigger-left").unwrap(),
+                AxisRoute::from_str("right-y=trigger-right").unwrap(),
+            ])
+            .unwrap(),
+            ..MappingOptions::new(TriggerMode::Normal, false)
+        };
+
+        assert_eq!(
+            controller_map_twenty_byte(&controller, &options),
+            vec![
+                DUALSHOCK_MAGIC,
+                !Buttons1::empty().bits(),
+                // The triggers themselves are unaffected
+                !Buttons2::L2.bits(),
+                // Analog sticks
+                0xFF,
+                0x80,
+                0x80,
+                0x80,
+                // Pressure values
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0xFF,
+                0x00,
+                // Mode footer
+                0x55,
+            ]
+        );
+    }
+
+    #[test]
+    fn communication_mode_describes_itself() {
+        use super::ControllerEmulatorPacketType;
+
+        assert_eq!(
+            ControllerEmulatorPacketType::TwentyByte.to_string(),
+            "twenty-byte protocol (Aaron Clovsky's firmware)"
+        );
+        assert_eq!(
+            ControllerEmulatorPacketType::SevenByte.to_string(),
+            "seven-byte protocol (Johnny Chung Lee's firmware)"
+        );
+        assert!(ControllerEmulatorPacketType::None
+            .to_string()
+            .contains("logging only"));
+    }
+
+    #[test]
+    fn packet_layout_matches_controller_map() {
+        use super::{controller_map_seven_byte, controller_map_twenty_byte};
+        use super::{MappingOptions, TriggerMode};
+        use super::{PACKET_LAYOUT, SEVEN_BYTE_PACKET_LENGTH, TWENTY_BYTE_PACKET_LENGTH};
+
+        let controller = FauxController::create_with_name(String::from("Sega Activator"));
+
+        assert_eq!(PACKET_LAYOUT.len(), TWENTY_BYTE_PACKET_LENGTH);
+        assert_eq!(
+            controller_map_twenty_byte(
+                &controller,
+                &MappingOptions::new(TriggerMode::Normal, true)
+            )
+            .len(),
+            TWENTY_BYTE_PACKET_LENGTH
+        );
+        assert_eq!(
+            controller_map_seven_byte(&controller, &MappingOptions::new(TriggerMode::Normal, true))
+                .len(),
+            SEVEN_BYTE_PACKET_LENGTH
+        );
+    }
+
+    #[test]
+    fn describe_protocol_lists_button_bits() {
+        use super::{describe_button_bits, describe_protocol};
+
+        assert_eq!(describe_button_bits(0), vec![]);
+        assert_eq!(describe_button_bits(1)[0], (7, String::from("LEFT")));
+        assert_eq!(describe_button_bits(2)[7], (0, String::from("L2")));
+
+        let description = describe_protocol();
+
+        assert!(description.contains("  Byte  1: Buttons 1 (0 = pressed)\n    Bit 7: LEFT\n"));
+        assert!(description.contains("    Bit 4: TRIANGLE\n"));
+        assert!(description.contains("  Byte 19: Mode footer"));
+    }
+
+    #[test]
+    fn trigger_range_rescales_and_clamps() {
+        use super::TriggerRange;
+        use std::str::FromStr;
+
+        let range = TriggerRange::from_str("1000, 21000").unwrap();
+        assert_eq!(
+            range,
+            TriggerRange {
+                min: 1000,
+                max: 21000
+            }
+        );
+
+        assert_eq!(range.rescale(0), 0);
+        assert_eq!(range.rescale(1000), 0);
+        assert_eq!(range.rescale(11000), i16::max_value() / 2);
+        assert_eq!(range.rescale(21000), i16::max_value());
+        assert_eq!(range.rescale(i16::max_value()), i16::max_value());
+
+        // The default range changes nothing
+        let range = TriggerRange::default();
+        assert_eq!(range.rescale(0), 0);
+        assert_eq!(range.rescale(12345), 12345);
+        assert_eq!(range.rescale(i16::max_value()), i16::max_value());
+
+        assert!(TriggerRange::from_str("500").is_err());
+        assert!(TriggerRange::from_str("500,500").is_err());
+        assert!(TriggerRange::from_str("-1,500").is_err());
+        assert!(TriggerRange::from_str("0,40000").is_err());
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_mirrors_sticks() {
+        use super::controller_map_twenty_byte;
+        use super::{MappingOptions, StickMirror, TriggerMode};
+        use sdl2::controller::Axis;
+
+        let mut controller = FauxController::create_with_name(String::from("Hori Fighting Stick"));
+        controller.set_axis(Axis::LeftX, i16::min_value());
+        controller.set_axis(Axis::LeftY, i16::max_value());
+        controller.set_axis(Axis::RightX, i16::max_value());
+
+        let mirrored = |mirror_stick| {
+            let options = MappingOptions {
+                mirror_stick,
+                ..MappingOptions::new(TriggerMode::Normal, false)
+            };
+            controller_map_twenty_byte(&controller, &options)[3..7].to_vec()
+        };
+
+        // Right stick X & Y, then left stick X & Y
+        assert_eq!(mirrored(None), vec![0xFF, 0x80, 0x00, 0xFF]);
+        assert_eq!(
+            mirrored(Some(StickMirror::LeftToRight)),
+            vec![0x00, 0xFF, 0x00, 0xFF]
+        );
+        assert_eq!(
+            mirrored(Some(StickMirror::RightToLeft)),
+            vec![0xFF, 0x80, 0xFF, 0x80]
+        );
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_follows_range_profile() {
+        use super::controller_map_twenty_byte;
+        use super::{MappingOptions, RangeProfile, TriggerMode};
+        use sdl2::controller::Axis;
+        use std::str::FromStr;
+
+        let mut controller = FauxController::create_with_name(String::from("NeGcon"));
+        controller.set_axis(Axis::RightX, i16::max_value());
+        controller.set_axis(Axis::RightY, i16::max_value() / 2);
+        controller.set_axis(Axis::LeftX, i16::min_value());
+
+        let sticks = |name| {
+            let options = MappingOptions {
+                range_profile: RangeProfile::builtin(name).unwrap(),
+                ..MappingOptions::new(TriggerMode::Normal, true)
+            };
+            controller_map_twenty_byte(&controller, &options)[3..7].to_vec()
+        };
+
+        // Right stick X & Y, then left stick X & Y
+        assert_eq!(sticks("dualshock2"), vec![0xFF, 0xC6, 0x00, 0x80]);
+        assert_eq!(sticks("linear"), vec![0xFF, 0xBF, 0x00, 0x80]);
+        assert_eq!(sticks("inset"), vec![0xF7, 0xC6, 0x08, 0x80]);
+
+        assert_eq!(
+            RangeProfile::default(),
+            RangeProfile::builtin("DualShock2").unwrap()
+        );
+        assert_eq!(
+            RangeProfile::from_str("5, 16, 239"),
+            Ok(RangeProfile {
+                normalise_percent: 5,
+                min: 16,
+                max: 239
+            })
+        );
+        assert!(RangeProfile::from_str("101,0,255").is_err());
+        assert!(RangeProfile::from_str("10,200,100").is_err());
+        assert!(RangeProfile::from_str("10,0").is_err());
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_normalises_sticks_separately() {
+        use super::controller_map_twenty_byte;
+        use super::{MappingOptions, TriggerMode};
+        use sdl2::controller::Axis;
+
+        let mut controller =
+            FauxController::create_with_name(String::from("PlayStation Analog Joystick"));
+        controller.set_axis(Axis::RightY, i16::max_value() / 2);
+        controller.set_axis(Axis::LeftY, i16::max_value() / 2);
+
+        let sticks = |normalise_left, normalise_right| {
+            let options = MappingOptions {
+                normalise_left,
+                normalise_right,
+                ..MappingOptions::new(TriggerMode::Normal, true)
+            };
+            let packet = controller_map_twenty_byte(&controller, &options);
+            (packet[6], packet[4])
+        };
+
+        // Left stick Y, then right stick Y
+        assert_eq!(sticks(None, None), (0xC6, 0xC6));
+        assert_eq!(sticks(Some(0), None), (0xBF, 0xC6));
+        assert_eq!(sticks(None, Some(0)), (0xC6, 0xBF));
+        assert_eq!(sticks(Some(20), Some(0)), (0xCC, 0xBF));
+    }
+
+    #[test]
+    fn clamp_stick_to_shape_differs_on_diagonals() {
+        use super::{clamp_stick_to_shape, StickShape};
+
+        let max = i32::from(i16::max_value());
+        let min = i32::from(i16::min_value());
+
+        // Along the axes, both shapes agree
+        for shape in [StickShape::Square, StickShape::Circle] {
+            assert_eq!(
+                clamp_stick_to_shape(max + 3000, 0, shape),
+                (i16::max_value(), 0)
+            );
+            assert_eq!(
+                clamp_stick_to_shape(0, max + 3000, shape),
+                (0, i16::max_value())
+            );
+            assert_eq!(clamp_stick_to_shape(1000, -2000, shape), (1000, -2000));
+        }
+
+        // Pushed out to the corner, they differ most
+        assert_eq!(
+            clamp_stick_to_shape(max, max, StickShape::Square),
+            (i16::max_value(), i16::max_value())
+        );
+        // 32767 / √2
+        assert_eq!(
+            clamp_stick_to_shape(max, max, StickShape::Circle),
+            (23169, 23169)
+        );
+        assert_eq!(
+            clamp_stick_to_shape(min, max + 5000, StickShape::Circle),
+            (-21473, 24749)
+        );
+    }
+
+    #[test]
+    fn snap_to_octant_snaps_within_the_tolerance() {
+        use super::snap_to_octant;
+
+        // Polar to stick coordinates, with the angle in degrees
+        let at = |angle: f64, distance: f64| {
+            let angle = angle.to_radians();
+            (
+                (distance * angle.cos()).round() as i16,
+                (distance * angle.sin()).round() as i16,
+            )
+        };
+        let snapped = |angle: f64, tolerance: f32| {
+            let (x, y) = at(angle, 20000.0);
+            snap_to_octant(x, y, tolerance)
+        };
+
+        // Just inside the tolerance, either side of a direction
+        assert_eq!(snapped(9.5, 10.0), (20000, 0));
+        assert_eq!(snapped(-9.5, 10.0), (20000, 0));
+        assert_eq!(snapped(99.5, 10.0), (0, 20000));
+        assert_eq!(snapped(180.0 - 9.5, 10.0), (-20000, 0));
+        assert_eq!(snapped(-180.0 + 9.5, 10.0), (-20000, 0));
+        assert_eq!(snapped(45.0 + 9.5, 10.0), at(45.0, 20000.0));
+        assert_eq!(snapped(-135.0 - 9.5, 10.0), at(-135.0, 20000.0));
+
+        // Just outside it, nothing changes
+        for angle in [10.5, -10.5, 45.0 + 10.5, 90.0 - 10.5, 180.0 - 10.5] {
+            assert_eq!(snapped(angle, 10.0), at(angle, 20000.0));
+        }
+
+        // Nothing's snapped with no tolerance, or at the centre
+        assert_eq!(snapped(1.0, 0.0), at(1.0, 20000.0));
+        assert_eq!(snap_to_octant(0, 0, 22.5), (0, 0));
+
+        // Snapping a corner onto an edge stays in range
+        assert_eq!(
+            snap_to_octant(i16::min_value(), 2000, 10.0),
+            (i16::min_value(), 0)
+        );
+        assert_eq!(
+            snap_to_octant(i16::max_value(), -2000, 10.0),
+            (i16::max_value(), 0)
+        );
+    }
+
+    #[test]
+    fn clamp_radial_keeps_diagonals_within_the_radius() {
+        use super::clamp_radial;
+
+        // Anything inside the circle is left alone
+        assert_eq!(clamp_radial(1000, -2000, 30000), (1000, -2000));
+        assert_eq!(clamp_radial(30000, 0, 30000), (30000, 0));
+        assert_eq!(clamp_radial(0, -32768, 30000), (0, -30000));
+
+        // Normalised diagonals, in every direction, end up on the circle
+        for (x, y) in [
+            (36043, 36043),
+            (-36043, 36043),
+            (36043, -36043),
+            (-36044, -36044),
+        ] {
+            let (clamped_x, clamped_y) = clamp_radial(x, y, 30000);
+            let distance = f64::from(clamped_x).hypot(f64::from(clamped_y));
+            assert!(
+                distance <= 30000.0,
+                "{:?} is {} from centre",
+                (x, y),
+                distance
+            );
+            assert!(distance > 29999.0);
+            assert_eq!(clamped_x.signum(), x.signum());
+            assert_eq!(clamped_y.signum(), y.signum());
+        }
+    }
+
+    #[test]
+    fn controller_map_twenty_byte_reads_the_touchpad() {
+        use super::controller_map_twenty_byte;
+        use super::{Buttons1, MappingOptions, TouchpadMode, TriggerMode};
+
+        let mut controller = FauxController::create_with_name(String::from("DualSense"));
+        // Towards the top right corner
+        controller.set_touchpad_finger(0, Some((0.9, 0.1)));
+
+        let map = |controller: &FauxController, touchpad_mode| {
+            let options = MappingOptions {
+                touchpad_mode,
+                ..MappingOptions::new(TriggerMode::Normal, false)
+            };
+            controller_map_twenty_byte(controller, &options)
+        };
+
+        // Off by default
+        let untouched = map(&controller, TouchpadMode::Off);
+        assert_eq!(untouched[1], 0xFF);
+        assert_eq!(untouched[3..5], [0x80, 0x80]);
+
+        let stick = map(&controller, TouchpadMode::RightStick);
+        assert_eq!(stick[1], 0xFF);
+        assert_eq!(stick[3..5], [0xE6, 0x19]);
+
+        let dpad = map(&controller, TouchpadMode::DPad);
+        assert_eq!(dpad[1], !(Buttons1::RIGHT | Buttons1::UP).bits());
+        assert_eq!(dpad[3..5], [0x80, 0x80]);
+        // Right & up pressure
+        assert_eq!(dpad[7], 0xFF);
+        assert_eq!(dpad[9], 0xFF);
+
+        // Lifting the finger lets go
+        controller.set_touchpad_finger(0, None);
+        assert_eq!(map(&controller, TouchpadMode::DPad)[1], 0xFF);
+        assert_eq!(
+            map(&controller, TouchpadMode::RightStick)[3..5],
+            [0x80, 0x80]
+        );
+    }
+
+    #[test]
+    fn button_polarity_inverts_each_button() {
+        use super::{ButtonPolarity, Buttons1, Buttons2, NEUTRAL_PACKET};
+
+        // Nothing is inverted by default
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        ButtonPolarity::default().apply(&mut packet);
+        assert_eq!(packet, NEUTRAL_PACKET.to_vec());
+
+        for bit in 0..8 {
+            let button = Buttons1::from_bits(1 << bit).unwrap();
+            let polarity = ButtonPolarity {
+                inverted1: button,
+                ..ButtonPolarity::default()
+            };
+
+            // Released, the inverted button's bit is sent as 0
+            let mut packet = NEUTRAL_PACKET.to_vec();
+            polarity.apply(&mut packet);
+            assert_eq!(packet[1], !0 ^ button.bits(), "{:?}", button);
+            assert_eq!(packet[2], NEUTRAL_PACKET[2]);
+
+            // Pressed, it's sent as 1, and other buttons are left alone
+            let mut packet = NEUTRAL_PACKET.to_vec();
+            packet[1] = !(button | Buttons1::START).bits();
+            polarity.apply(&mut packet);
+            let expected = if button == Buttons1::START {
+                !0
+            } else {
+                !Buttons1::START.bits()
+            };
+            assert_eq!(packet[1], expected, "{:?}", button);
+        }
+
+        for bit in 0..8 {
+            let button = Buttons2::from_bits(1 << bit).unwrap();
+            let polarity = ButtonPolarity {
+                inverted2: button,
+                ..ButtonPolarity::default()
+            };
+
+            let mut packet = NEUTRAL_PACKET.to_vec();
+            polarity.apply(&mut packet);
+            assert_eq!(packet[1], NEUTRAL_PACKET[1]);
+            assert_eq!(packet[2], !0 ^ button.bits(), "{:?}", button);
+
+            let mut packet = NEUTRAL_PACKET.to_vec();
+            packet[2] = !button.bits();
+            polarity.apply(&mut packet);
+            assert_eq!(packet[2], !0, "{:?}", button);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::Write;
+
+// Rumble Log
+// Records the rumble the device asks for each frame as CSV, one line per
+// response, for graphing a game's rumble pattern. Each line has the
+// seconds since the session started, then the small and large motors'
+// intensities from 0 to 255, as the device sent them (after
+// --rumble-max-duty, but before any rumble curve).
+
+// How often, in seconds, what's been recorded is written out
+const RUMBLE_LOG_FLUSH_INTERVAL: f64 = 1.0;
+
+pub struct RumbleLog {
+    output: Box<dyn Write>,
+    last_flush: Option<f64>,
+}
+
+impl RumbleLog {
+    pub fn new(mut output: Box<dyn Write>) -> std::io::Result<RumbleLog> {
+        writeln!(output, "seconds,small_motor,large_motor")?;
+
+        Ok(RumbleLog {
+            output,
+            last_flush: None,
+        })
+    }
+
+    pub fn record(&mut self, now: f64, small_motor: u8, large_motor: u8) -> std::io::Result<()> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("RumbleLog#record()");
+        writeln!(self.output, "{:.3},{},{}", now, small_motor, large_motor)?;
+
+        let flush_due = match self.last_flush {
+            Some(last_flush) => now - last_flush >= RUMBLE_LOG_FLUSH_INTERVAL,
+            None => true,
+        };
+        if flush_due {
+            self.last_flush = Some(now);
+            self.output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mockstream;
+
+    #[test]
+    fn rumble_log_writes_csv_periodically() {
+        use self::mockstream::SharedMockStream;
+        use super::RumbleLog;
+        use std::io::BufWriter;
+
+        let mut stream = SharedMockStream::new();
+        let mut log = RumbleLog::new(Box::new(BufWriter::new(stream.clone()))).unwrap();
+
+        // The first line's written straight away
+        log.record(0.0, 0x00, 0x40).unwrap();
+        assert_eq!(
+            String::from_utf8(stream.pop_bytes_written()).unwrap(),
+            "seconds,small_motor,large_motor\n0.000,0,64\n"
+        );
+
+        // Then the rest only every so often
+        log.record(0.5, 0xFF, 0x80).unwrap();
+        assert!(stream.pop_bytes_written().is_empty());
+        log.record(1.25, 0x00, 0x00).unwrap();
+        assert_eq!(
+            String::from_utf8(stream.pop_bytes_written()).unwrap(),
+            "0.500,255,128\n1.250,0,0\n"
+        );
+
+        log.record(1.5, 0xFF, 0xFF).unwrap();
+        log.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(stream.pop_bytes_written()).unwrap(),
+            "1.500,255,255\n"
+        );
+    }
+}
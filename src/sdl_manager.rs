@@ -28,6 +28,18 @@ pub trait GameController {
     fn name(&self) -> String;
     fn button(&self, button: sdl2::controller::Button) -> bool;
     fn axis(&self, axis: sdl2::controller::Axis) -> i16;
+    fn has_rumble(&self) -> bool;
+    // Whether the controller is still connected
+    fn attached(&self) -> bool;
+    // The controller's USB vendor & product ids, where SDL knows them
+    fn usb_id(&self) -> Option<(u16, u16)>;
+    // SDL's GUID for the controller, in lowercase hex. It's the same for
+    // every controller of the same model, but stays the same when one
+    // reconnects, unlike its instance id.
+    fn guid(&self) -> Option<String>;
+    // Where a finger is on the controller's touchpad, from (0, 0) at the
+    // top left to (1, 1) at the bottom right, if it's touching
+    fn touchpad_finger(&self, index: u8) -> Option<(f32, f32)>;
     fn set_rumble(
         &mut self,
         low_frequency_rumble: u16,
@@ -36,8 +48,100 @@ pub trait GameController {
     ) -> Result<(), String>;
 }
 
+// Our SDL bindings predate touchpad support, so when it's wanted we
+// declare the one function we need ourselves (it needs SDL 2.0.14)
+#[cfg(feature = "touchpad")]
+extern "C" {
+    fn SDL_GameControllerGetTouchpadFinger(
+        gamecontroller: *mut sdl2::sys::SDL_GameController,
+        touchpad: i32,
+        finger: i32,
+        state: *mut u8,
+        x: *mut f32,
+        y: *mut f32,
+        pressure: *mut f32,
+    ) -> i32;
+}
+
+// A raw joystick input, as the device reports it before SDL's mapping
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawInput {
+    Axis(u8),
+    Button(u8),
+    Hat(u8),
+}
+
+// A control SDL's mapping presents to us
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MappedControl {
+    Axis(sdl2::controller::Axis),
+    Button(sdl2::controller::Button),
+}
+
+const MAPPED_AXES: [sdl2::controller::Axis; 6] = [
+    sdl2::controller::Axis::LeftX,
+    sdl2::controller::Axis::LeftY,
+    sdl2::controller::Axis::RightX,
+    sdl2::controller::Axis::RightY,
+    sdl2::controller::Axis::TriggerLeft,
+    sdl2::controller::Axis::TriggerRight,
+];
+
+const MAPPED_BUTTONS: [sdl2::controller::Button; 15] = [
+    sdl2::controller::Button::A,
+    sdl2::controller::Button::B,
+    sdl2::controller::Button::X,
+    sdl2::controller::Button::Y,
+    sdl2::controller::Button::Back,
+    sdl2::controller::Button::Guide,
+    sdl2::controller::Button::Start,
+    sdl2::controller::Button::LeftStick,
+    sdl2::controller::Button::RightStick,
+    sdl2::controller::Button::LeftShoulder,
+    sdl2::controller::Button::RightShoulder,
+    sdl2::controller::Button::DPadUp,
+    sdl2::controller::Button::DPadDown,
+    sdl2::controller::Button::DPadLeft,
+    sdl2::controller::Button::DPadRight,
+];
+
+// Whether a binding from SDL's mapping reads from `input`
+fn binding_reads(bind: &sdl2::sys::SDL_GameControllerButtonBind, input: RawInput) -> bool {
+    use sdl2::sys::SDL_GameControllerBindType::*;
+
+    // SAFETY: each arm only reads the union field `bindType` says is in use
+    unsafe {
+        match (bind.bindType, input) {
+            (SDL_CONTROLLER_BINDTYPE_AXIS, RawInput::Axis(index)) => {
+                bind.value.axis == i32::from(index)
+            }
+            (SDL_CONTROLLER_BINDTYPE_BUTTON, RawInput::Button(index)) => {
+                bind.value.button == i32::from(index)
+            }
+            (SDL_CONTROLLER_BINDTYPE_HAT, RawInput::Hat(index)) => {
+                bind.value.hat.hat == i32::from(index)
+            }
+            _ => false,
+        }
+    }
+}
+
 pub struct ControllerManager {
     controller: sdl2::controller::GameController,
+    // Our SDL bindings can't ask whether a controller has rumble,
+    // so we assume it does until SDL tells us otherwise
+    has_rumble: bool,
+    usb_id: Option<(u16, u16)>,
+    guid: Option<String>,
+}
+
+// The GUID a mapping line starts with
+fn mapping_guid(mapping: &str) -> Option<String> {
+    mapping
+        .split(',')
+        .next()
+        .filter(|guid| !guid.is_empty())
+        .map(str::to_lowercase)
 }
 
 impl GameController for ControllerManager {
@@ -53,6 +157,54 @@ impl GameController for ControllerManager {
         self.controller.axis(axis)
     }
 
+    fn has_rumble(&self) -> bool {
+        self.has_rumble
+    }
+
+    fn attached(&self) -> bool {
+        self.controller.attached()
+    }
+
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        self.usb_id
+    }
+
+    fn guid(&self) -> Option<String> {
+        self.guid.clone()
+    }
+
+    #[cfg(feature = "touchpad")]
+    fn touchpad_finger(&self, index: u8) -> Option<(f32, f32)> {
+        let (mut state, mut x, mut y, mut pressure) = (0u8, 0.0f32, 0.0f32, 0.0f32);
+
+        // SAFETY: SDL hands back the controller we already have open (or
+        // null, which it checks for), and only writes to the locals above
+        let result = unsafe {
+            let raw = sdl2::sys::SDL_GameControllerFromInstanceID(
+                self.controller.instance_id() as sdl2::sys::SDL_JoystickID
+            );
+            SDL_GameControllerGetTouchpadFinger(
+                raw,
+                0,
+                i32::from(index),
+                &mut state,
+                &mut x,
+                &mut y,
+                &mut pressure,
+            )
+        };
+
+        match (result, state) {
+            (0, 1) => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(feature = "touchpad"))]
+    fn touchpad_finger(&self, _index: u8) -> Option<(f32, f32)> {
+        None
+    }
+
     fn set_rumble(
         &mut self,
         low_frequency_rumble: u16,
@@ -64,10 +216,136 @@ impl GameController for ControllerManager {
             .set_rumble(low_frequency_rumble, high_frequency_rumble, duration_ms)
         {
             Ok(_) => Ok(()),
-            Err(error) => match error {
-                sdl2::IntegerOrSdlError::SdlError(string) => Err(string),
-                _ => Err("SDL gave an integer error while setting rumble. WTF?".to_string()),
-            },
+            Err(error) => {
+                self.has_rumble = false;
+
+                match error {
+                    sdl2::IntegerOrSdlError::SdlError(string) => Err(string),
+                    _ => Err("SDL gave an integer error while setting rumble. WTF?".to_string()),
+                }
+            }
+        }
+    }
+}
+
+impl ControllerManager {
+    // Which of our controls SDL's mapping takes from a raw joystick input
+    pub fn controls_reading(&self, input: RawInput) -> Vec<MappedControl> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ControllerManager#controls_reading()");
+        // SAFETY: SDL hands back the controller we already have open, and
+        // looking up a binding on a null controller just gives none
+        let raw = unsafe {
+            sdl2::sys::SDL_GameControllerFromInstanceID(
+                self.controller.instance_id() as sdl2::sys::SDL_JoystickID
+            )
+        };
+
+        let axes = MAPPED_AXES.iter().filter(|axis| {
+            let bind = unsafe { sdl2::sys::SDL_GameControllerGetBindForAxis(raw, axis.to_ll()) };
+            binding_reads(&bind, input)
+        });
+        let buttons = MAPPED_BUTTONS.iter().filter(|button| {
+            let bind =
+                unsafe { sdl2::sys::SDL_GameControllerGetBindForButton(raw, button.to_ll()) };
+            binding_reads(&bind, input)
+        });
+
+        axes.map(|axis| MappedControl::Axis(*axis))
+            .chain(buttons.map(|button| MappedControl::Button(*button)))
+            .collect()
+    }
+}
+
+// Merged Controller
+// Presents two controllers as one, for sharing a single virtual pad
+
+pub struct MergedController<'a, A: GameController, B: GameController> {
+    first: &'a A,
+    second: &'a B,
+}
+
+impl<'a, A: GameController, B: GameController> MergedController<'a, A, B> {
+    pub fn new(first: &'a A, second: &'a B) -> MergedController<'a, A, B> {
+        MergedController { first, second }
+    }
+}
+
+impl<'a, A: GameController, B: GameController> GameController for MergedController<'a, A, B> {
+    fn name(&self) -> String {
+        format!("{} + {}", self.first.name(), self.second.name())
+    }
+
+    fn button(&self, button: sdl2::controller::Button) -> bool {
+        self.first.button(button) || self.second.button(button)
+    }
+
+    fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
+        let first = self.first.axis(axis);
+        let second = self.second.axis(axis);
+
+        // Whichever controller is pushed further from centre wins.
+        // Widen before taking the magnitude, as `i16::min_value()`
+        // has no positive counterpart.
+        if i32::from(second).abs() > i32::from(first).abs() {
+            second
+        } else {
+            first
+        }
+    }
+
+    fn has_rumble(&self) -> bool {
+        self.first.has_rumble() || self.second.has_rumble()
+    }
+
+    fn attached(&self) -> bool {
+        self.first.attached() && self.second.attached()
+    }
+
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        // A merged controller isn't any one piece of hardware
+        None
+    }
+
+    fn guid(&self) -> Option<String> {
+        None
+    }
+
+    fn touchpad_finger(&self, index: u8) -> Option<(f32, f32)> {
+        self.first
+            .touchpad_finger(index)
+            .or_else(|| self.second.touchpad_finger(index))
+    }
+
+    fn set_rumble(
+        &mut self,
+        _low_frequency_rumble: u16,
+        _high_frequency_rumble: u16,
+        _duration_ms: u32,
+    ) -> Result<(), String> {
+        // We only hold shared references to the underlying controllers,
+        // so rumble needs to be sent to each of them individually.
+        Err("rumble must be set on each merged controller individually".to_string())
+    }
+}
+
+// Adds `controller` to `controllers` under `id`, unless a connected
+// controller already has that id. SDL can give a reconnecting controller
+// an id we still hold a stale entry for (if its removal was missed),
+// so entries which are no longer attached are replaced.
+// Returns whether the controller was added.
+pub fn track_controller<T: GameController>(
+    controllers: &mut HashMap<u32, T>,
+    id: u32,
+    controller: T,
+) -> bool {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("track_controller()");
+    match controllers.get(&id) {
+        Some(existing) if existing.attached() => false,
+        _ => {
+            controllers.insert(id, controller);
+            true
         }
     }
 }
@@ -77,10 +355,35 @@ pub struct SDLManager {
     pub video_subsystem: Option<sdl2::VideoSubsystem>,
     pub game_controller_subsystem: sdl2::GameControllerSubsystem,
     pub active_controllers: HashMap<u32, ControllerManager>,
+    // The built-in mapping lines we loaded, by controller GUID
+    pub loaded_mappings: HashMap<String, String>,
+}
+
+// Keys mapping lines by the GUID they start with, keeping only those
+// for `platform` (or for every platform), as SDL does
+pub fn index_mappings<'a, I: Iterator<Item = &'a str>>(
+    mappings: I,
+    platform: &str,
+) -> HashMap<String, String> {
+    mappings
+        .filter(|mapping| {
+            match mapping
+                .split(',')
+                .find_map(|field| field.trim().strip_prefix("platform:"))
+            {
+                Some(mapping_platform) => mapping_platform == platform,
+                None => true,
+            }
+        })
+        .filter_map(|mapping| {
+            let (guid, _) = mapping.split_once(',')?;
+            Some((guid.to_lowercase(), mapping.to_string()))
+        })
+        .collect()
 }
 
 impl SDLManager {
-    pub fn init() -> Result<SDLManager, String> {
+    pub fn init(builtin_mappings: bool) -> Result<SDLManager, String> {
         #[cfg(feature = "flamegraph-profiling")]
         let _guard = flame::start_guard("SDLManager::init()");
         // Initialise SDL2, plus the video & game controller subsystems
@@ -118,28 +421,38 @@ impl SDLManager {
             video_subsystem,
             game_controller_subsystem,
             active_controllers,
+            loaded_mappings: HashMap::new(),
         };
 
-        #[cfg(feature = "flamegraph-profiling")]
-        flame::start("import controller mappings");
-        // Load pre-set controller mappings (note that SDL will still read
-        // others from the SDL_GAMECONTROLLERCONFIG environment variable)
-        let controller_mappings =
-            include_str!("../vendor/SDL_GameControllerDB/gamecontrollerdb.txt")
-                .lines()
-                .map(|line| line.trim())
-                .filter(|line| !line.is_empty() && !line.starts_with('#'));
-
-        // Load each mapping individually rather than using load_mappings,
-        // as it turns out doing them together can break without warning
-        // if the file's syntax is ever invalid
-        for mapping in controller_mappings {
-            if let Err(error) = sdl_manager.game_controller_subsystem.add_mapping(mapping) {
-                panic!("failed to load mapping: {}", error)
+        if builtin_mappings {
+            #[cfg(feature = "flamegraph-profiling")]
+            flame::start("import controller mappings");
+            // Load pre-set controller mappings (note that SDL will still read
+            // others from the SDL_GAMECONTROLLERCONFIG environment variable)
+            // (These are copied in by the build script, and may be empty
+            // if the SDL_GameControllerDB submodule wasn't checked out)
+            let controller_mappings =
+                include_str!(concat!(env!("OUT_DIR"), "/gamecontrollerdb.txt"))
+                    .lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+            // Load each mapping individually rather than using load_mappings,
+            // as it turns out doing them together can break without warning
+            // if the file's syntax is ever invalid
+            for mapping in controller_mappings.clone() {
+                if let Err(error) = sdl_manager.game_controller_subsystem.add_mapping(mapping) {
+                    panic!("failed to load mapping: {}", error)
+                }
             }
+
+            // Hold on to them, to say which one a controller ended up with
+            sdl_manager.loaded_mappings = index_mappings(controller_mappings, sdl2::get_platform());
+            #[cfg(feature = "flamegraph-profiling")]
+            flame::end("import controller mappings");
+        } else {
+            println!("Skipping built-in controller mappings");
         }
-        #[cfg(feature = "flamegraph-profiling")]
-        flame::end("import controller mappings");
 
         // Look into controllers that were already connected at start-up
         sdl_manager.add_available_controllers();
@@ -180,24 +493,43 @@ impl SDLManager {
         let controller = self.game_controller_subsystem.open(index)?;
         let controller_id = controller.instance_id();
 
-        let controller_manager = ControllerManager { controller };
+        // SDL reports 0 for ids it doesn't know
+        // SAFETY: SDL checks the index itself, and the call has no other inputs
+        let usb_id = match unsafe {
+            (
+                sdl2::sys::SDL_JoystickGetDeviceVendor(index as i32),
+                sdl2::sys::SDL_JoystickGetDeviceProduct(index as i32),
+            )
+        } {
+            (0, 0) => None,
+            ids => Some(ids),
+        };
+
+        let guid = mapping_guid(&controller.mapping());
+        let controller_manager = ControllerManager {
+            controller,
+            has_rumble: true,
+            usb_id,
+            guid,
+        };
 
-        self.active_controllers
-            .insert(controller_id, controller_manager);
+        track_controller(
+            &mut self.active_controllers,
+            controller_id,
+            controller_manager,
+        );
         Ok(controller_id)
     }
 
     pub fn add_controller(&mut self, index: u32) -> Result<u32, sdl2::IntegerOrSdlError> {
         #[cfg(feature = "flamegraph-profiling")]
         let _guard = flame::start_guard("SDLManager#add_controller()");
-        let controller = self.game_controller_subsystem.open(index)?;
-        let controller_id = controller.instance_id();
-
-        if self.active_controllers.contains_key(&controller_id) {
-            return Ok(controller_id);
+        if self.has_controller(index)? {
+            let controller = self.game_controller_subsystem.open(index)?;
+            return Ok(controller.instance_id());
         }
 
-        let result = self.insert_controller(index);
+        let controller_id = self.insert_controller(index)?;
 
         println!(
             "Added “{}” (#{})",
@@ -205,16 +537,41 @@ impl SDLManager {
             controller_id
         );
 
-        result
+        Ok(controller_id)
     }
 
+    // Whether we're tracking the controller at `index`; stale entries
+    // left by a controller which has since disconnected don't count
     pub fn has_controller(&self, index: u32) -> Result<bool, sdl2::IntegerOrSdlError> {
         #[cfg(feature = "flamegraph-profiling")]
         let _guard = flame::start_guard("SDLManager#has_controller()");
         let controller = self.game_controller_subsystem.open(index)?;
-        Ok(self
-            .active_controllers
-            .contains_key(&controller.instance_id()))
+        Ok(
+            match self.active_controllers.get(&controller.instance_id()) {
+                Some(existing) => existing.attached(),
+                None => false,
+            },
+        )
+    }
+
+    // Describes the mapping SDL chose for a controller,
+    // and whether it's one of the built-in ones
+    pub fn describe_mapping(&self, id: u32) -> Option<String> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("SDLManager#describe_mapping()");
+        let mapping = self.active_controllers.get(&id)?.controller.mapping();
+        let guid = mapping_guid(&mapping).unwrap_or_default();
+
+        let source = match self.loaded_mappings.get(&guid) {
+            Some(line) => format!("Built-in mapping: {}", line),
+            None => "Not from the built-in mappings (SDL's own, or SDL_GAMECONTROLLERCONFIG)"
+                .to_string(),
+        };
+
+        Some(format!(
+            "Controller #{} has GUID {}\n  SDL is using: {}\n  {}",
+            id, guid, mapping, source
+        ))
     }
 
     pub fn remove_controller(&mut self, id: u32) -> Option<ControllerManager> {
@@ -234,3 +591,56 @@ impl SDLManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn index_mappings_keys_by_guid_for_this_platform() {
+        use super::index_mappings;
+
+        let mappings = [
+            "030000004c050000c405000000010000,PS4 Controller,a:b1,b:b2,platform:Mac OS X,",
+            "030000004c050000c405000000010000,PS4 Controller,a:b0,b:b1,platform:Linux,",
+            "03000000D620000011A7000000000000,Switch Pro,a:b1,",
+            "nonsense",
+        ];
+
+        let indexed = index_mappings(mappings.iter().copied(), "Linux");
+
+        assert_eq!(indexed.len(), 2);
+        assert_eq!(
+            indexed["030000004c050000c405000000010000"],
+            mappings[1].to_string()
+        );
+        assert_eq!(
+            indexed["03000000d620000011a7000000000000"],
+            mappings[2].to_string()
+        );
+    }
+
+    #[test]
+    fn track_controller_survives_reconnects() {
+        use super::{track_controller, GameController};
+        use crate::faux_controller::FauxController;
+        use std::collections::HashMap;
+
+        let mut controllers: HashMap<u32, FauxController> = HashMap::new();
+        let controller = |name: &str| FauxController::create_with_name(name.to_string());
+
+        // Added, removed, then added again with the same id
+        assert!(track_controller(&mut controllers, 0, controller("First")));
+        assert!(controllers.remove(&0).is_some());
+        assert!(track_controller(&mut controllers, 0, controller("Second")));
+        assert_eq!(controllers[&0].name(), "Second");
+
+        // A repeated add event for a connected controller changes nothing
+        assert!(!track_controller(&mut controllers, 0, controller("Repeat")));
+        assert_eq!(controllers[&0].name(), "Second");
+
+        // Unplugged without us hearing about it, then replugged
+        controllers.get_mut(&0).unwrap().set_attached(false);
+        assert!(track_controller(&mut controllers, 0, controller("Third")));
+        assert_eq!(controllers[&0].name(), "Third");
+        assert_eq!(controllers.len(), 1);
+    }
+}
@@ -0,0 +1,361 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use omnishock::faux_controller::FauxController;
+use omnishock::sdl_manager::GameController;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+// Shared Memory Controller
+// A controller whose state another program on the same machine keeps in a
+// POSIX shared memory object, for the least latency between it and us.
+// The object holds SHM_LAYOUT_LENGTH bytes, in the machine's byte order:
+//
+//   0..4    the magic bytes "OSHM"
+//   4..8    u32 sequence number: made odd before the state changes, and
+//           even again once it's done, so we never read half an update
+//   8..12   u32 buttons held: bit n is SHM_BUTTONS[n], in SDL's order
+//   12..24  i16 axes: left-x, left-y, right-x, right-y, trigger-left and
+//           trigger-right, as SDL reports them
+//
+// Until the magic bytes are there, nothing's sent. After that, the most
+// recent state holds until the next one. The object mustn't be truncated
+// while it's mapped: reading past its new end raises SIGBUS.
+
+pub const SHM_MAGIC: &[u8; 4] = b"OSHM";
+pub const SHM_LAYOUT_LENGTH: usize = 24;
+
+const SEQUENCE_OFFSET: usize = 4;
+const BUTTONS_OFFSET: usize = 8;
+const AXES_OFFSET: usize = 12;
+
+// How many times to try for a whole update before waiting for the next frame
+const READ_ATTEMPTS: usize = 8;
+
+pub const SHM_BUTTONS: [sdl2::controller::Button; 15] = [
+    sdl2::controller::Button::A,
+    sdl2::controller::Button::B,
+    sdl2::controller::Button::X,
+    sdl2::controller::Button::Y,
+    sdl2::controller::Button::Back,
+    sdl2::controller::Button::Guide,
+    sdl2::controller::Button::Start,
+    sdl2::controller::Button::LeftStick,
+    sdl2::controller::Button::RightStick,
+    sdl2::controller::Button::LeftShoulder,
+    sdl2::controller::Button::RightShoulder,
+    sdl2::controller::Button::DPadUp,
+    sdl2::controller::Button::DPadDown,
+    sdl2::controller::Button::DPadLeft,
+    sdl2::controller::Button::DPadRight,
+];
+
+pub const SHM_AXES: [sdl2::controller::Axis; 6] = [
+    sdl2::controller::Axis::LeftX,
+    sdl2::controller::Axis::LeftY,
+    sdl2::controller::Axis::RightX,
+    sdl2::controller::Axis::RightY,
+    sdl2::controller::Axis::TriggerLeft,
+    sdl2::controller::Axis::TriggerRight,
+];
+
+// Reads the state from a copy of the object's bytes
+fn parse_state(name: &str, bytes: &[u8; SHM_LAYOUT_LENGTH]) -> Option<FauxController> {
+    if &bytes[..SHM_MAGIC.len()] != SHM_MAGIC {
+        return None;
+    }
+
+    let mut state = FauxController::create_with_name(name.to_string());
+    state.set_has_rumble(false);
+
+    let mut buttons = [0; 4];
+    buttons.copy_from_slice(&bytes[BUTTONS_OFFSET..AXES_OFFSET]);
+    let buttons = u32::from_ne_bytes(buttons);
+    for (bit, button) in SHM_BUTTONS.iter().enumerate() {
+        state.set_button(*button, buttons & (1 << bit) != 0);
+    }
+
+    for (index, axis) in SHM_AXES.iter().enumerate() {
+        let offset = AXES_OFFSET + index * 2;
+        state.set_axis(
+            *axis,
+            i16::from_ne_bytes([bytes[offset], bytes[offset + 1]]),
+        );
+    }
+
+    Some(state)
+}
+
+// The object, mapped into our memory for reading
+struct ShmMapping {
+    region: *const u8,
+}
+
+impl ShmMapping {
+    // The 32-bit word at `offset`, which the other program may be changing
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        // SAFETY: The mapping is page aligned and at least
+        // `SHM_LAYOUT_LENGTH` long, and every offset asked for is a multiple
+        // of four within it, so each word is in range and aligned
+        unsafe { &*(self.region.add(offset) as *const AtomicU32) }
+    }
+
+    // Copies out the object's bytes, so long as the writer wasn't part way
+    // through changing them
+    fn read(&self) -> Option<[u8; SHM_LAYOUT_LENGTH]> {
+        let sequence = self.word(SEQUENCE_OFFSET);
+
+        for _ in 0..READ_ATTEMPTS {
+            let before = sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            // A word at a time, as the writer may be changing any of them;
+            // a torn copy is caught by the sequence number
+            let mut bytes = [0; SHM_LAYOUT_LENGTH];
+            for (offset, chunk) in (0..SHM_LAYOUT_LENGTH)
+                .step_by(4)
+                .zip(bytes.chunks_exact_mut(4))
+            {
+                chunk.copy_from_slice(&self.word(offset).load(Ordering::Relaxed).to_ne_bytes());
+            }
+            fence(Ordering::Acquire);
+
+            if sequence.load(Ordering::Relaxed) == before {
+                return Some(bytes);
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for ShmMapping {
+    fn drop(&mut self) {
+        // SAFETY: `region` came from `mmap`, with this length, and nothing
+        // holds on to it past here
+        unsafe {
+            libc::munmap(self.region as *mut libc::c_void, SHM_LAYOUT_LENGTH);
+        }
+    }
+}
+
+pub struct ShmController {
+    name: String,
+    mapping: ShmMapping,
+    state: Option<FauxController>,
+}
+
+impl ShmController {
+    // Maps the shared memory object `name`, which the other program
+    // must already have made at least `SHM_LAYOUT_LENGTH` bytes long
+    pub fn open(name: &str) -> Result<ShmController, String> {
+        let name = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("/{}", name)
+        };
+        let c_name = CString::new(name.as_str())
+            .map_err(|_| format!("Unexpected shared memory name '{}'", name))?;
+
+        // SAFETY: `c_name` is a valid C string for the length of the call
+        let fd = unsafe { libc::shm_open(c_name.as_ptr(), libc::O_RDONLY, 0) };
+        if fd < 0 {
+            return Err(format!(
+                "Couldn't open shared memory '{}': {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+        // SAFETY: `fd` is ours alone, and closing it leaves the mapping be
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let length = file
+            .metadata()
+            .map_err(|error| format!("Couldn't read shared memory '{}': {}", name, error))?
+            .len();
+        if length < SHM_LAYOUT_LENGTH as u64 {
+            return Err(format!(
+                "Shared memory '{}' is {} bytes long, but needs at least {}",
+                name, length, SHM_LAYOUT_LENGTH
+            ));
+        }
+
+        // SAFETY: Mapping a file we've just checked is long enough
+        let region = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                SHM_LAYOUT_LENGTH,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if region == libc::MAP_FAILED {
+            return Err(format!(
+                "Couldn't map shared memory '{}': {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(ShmController {
+            name: format!("Shared memory {}", name),
+            mapping: ShmMapping {
+                region: region as *const u8,
+            },
+            state: None,
+        })
+    }
+
+    // Takes up the latest state written. Returns whether there's any state
+    // at all yet.
+    pub fn poll(&mut self) -> bool {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("ShmController#poll()");
+        if let Some(state) = self
+            .mapping
+            .read()
+            .and_then(|bytes| parse_state(&self.name, &bytes))
+        {
+            self.state = Some(state);
+        }
+
+        self.state.is_some()
+    }
+
+    fn state(&self) -> Option<&FauxController> {
+        self.state.as_ref()
+    }
+}
+
+impl GameController for ShmController {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn button(&self, button: sdl2::controller::Button) -> bool {
+        self.state()
+            .map(|state| state.button(button))
+            .unwrap_or(false)
+    }
+
+    fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
+        self.state().map(|state| state.axis(axis)).unwrap_or(0)
+    }
+
+    fn has_rumble(&self) -> bool {
+        false
+    }
+
+    fn attached(&self) -> bool {
+        true
+    }
+
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    fn guid(&self) -> Option<String> {
+        None
+    }
+
+    fn touchpad_finger(&self, _index: u8) -> Option<(f32, f32)> {
+        None
+    }
+
+    fn set_rumble(
+        &mut self,
+        _low_frequency_rumble: u16,
+        _high_frequency_rumble: u16,
+        _duration_ms: u32,
+    ) -> Result<(), String> {
+        Err("shared memory has no rumble".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SHM_LAYOUT_LENGTH;
+
+    fn layout(sequence: u32, buttons: u32, axes: [i16; 6]) -> Vec<u8> {
+        let mut bytes = super::SHM_MAGIC.to_vec();
+        bytes.extend_from_slice(&sequence.to_ne_bytes());
+        bytes.extend_from_slice(&buttons.to_ne_bytes());
+        for axis in axes.iter() {
+            bytes.extend_from_slice(&axis.to_ne_bytes());
+        }
+        assert_eq!(bytes.len(), SHM_LAYOUT_LENGTH);
+        bytes
+    }
+
+    // Shared memory objects live in /dev/shm on Linux, which
+    // lets us play the other program with plain file writes
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn shm_controller_follows_the_writer() {
+        use super::ShmController;
+        use omnishock::sdl_manager::GameController;
+        use sdl2::controller::{Axis, Button};
+
+        let name = format!("omnishock-test-{}", std::process::id());
+        let path = format!("/dev/shm/{}", name);
+
+        // Nothing's there yet
+        assert!(ShmController::open(&name).is_err());
+
+        // Or there's too little to hold the layout
+        std::fs::write(&path, [0; SHM_LAYOUT_LENGTH - 1]).unwrap();
+        assert!(ShmController::open(&name).is_err());
+
+        // Nor is there anything until the magic bytes are
+        std::fs::write(&path, [0; SHM_LAYOUT_LENGTH]).unwrap();
+        let mut controller = ShmController::open(&name).unwrap();
+        assert!(!controller.poll());
+
+        // A and D-Pad Up, with the left stick pushed left
+        std::fs::write(&path, layout(2, 1 | 1 << 11, [-32768, 0, 0, 0, 0, 32767])).unwrap();
+        assert!(controller.poll());
+        assert!(controller.button(Button::A));
+        assert!(controller.button(Button::DPadUp));
+        assert!(!controller.button(Button::B));
+        assert_eq!(controller.axis(Axis::LeftX), -32768);
+        assert_eq!(controller.axis(Axis::TriggerRight), 32767);
+
+        // Part way through an update, the last state holds
+        std::fs::write(&path, layout(3, 1 << 1, [0; 6])).unwrap();
+        assert!(controller.poll());
+        assert!(controller.button(Button::A));
+        assert!(!controller.button(Button::B));
+
+        std::fs::write(&path, layout(4, 1 << 1, [0; 6])).unwrap();
+        assert!(controller.poll());
+        assert!(!controller.button(Button::A));
+        assert!(controller.button(Button::B));
+        assert_eq!(controller.axis(Axis::LeftX), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
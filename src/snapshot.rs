@@ -0,0 +1,193 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::PACKET_BUTTONS;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Snapshot
+// Draws a packet as a picture of a DualShock, with the pressed buttons
+// lit up and each stick's dot where it's being pushed, saved as an SVG
+// for sharing exactly what was being sent when something went wrong.
+
+const PRESSED_FILL: &str = "#44cc44";
+const RELEASED_FILL: &str = "#dddddd";
+
+// How far, in pixels, a stick's dot moves from the centre at full tilt
+const STICK_TRAVEL: f64 = 16.0;
+
+enum Shape {
+    // x, y, width & height
+    Rect(u32, u32, u32, u32),
+    // Centre x & y, then radius
+    Circle(u32, u32, u32),
+}
+
+// Where each of `PACKET_BUTTONS` is drawn, apart from L3 & R3,
+// which light up their stick instead
+const BUTTON_SHAPES: [(&str, Shape); 14] = [
+    ("l2", Shape::Rect(40, 8, 50, 12)),
+    ("l1", Shape::Rect(40, 24, 50, 12)),
+    ("r2", Shape::Rect(230, 8, 50, 12)),
+    ("r1", Shape::Rect(230, 24, 50, 12)),
+    ("up", Shape::Rect(57, 58, 16, 16)),
+    ("down", Shape::Rect(57, 86, 16, 16)),
+    ("left", Shape::Rect(43, 72, 16, 16)),
+    ("right", Shape::Rect(71, 72, 16, 16)),
+    ("select", Shape::Rect(130, 75, 20, 10)),
+    ("start", Shape::Rect(170, 75, 20, 10)),
+    ("triangle", Shape::Circle(255, 66, 8)),
+    ("cross", Shape::Circle(255, 94, 8)),
+    ("square", Shape::Circle(241, 80, 8)),
+    ("circle", Shape::Circle(269, 80, 8)),
+];
+
+// Each stick's name, the button pressing it in lights up, where its
+// centre is drawn, and which packet bytes hold its X & Y
+const STICKS: [(&str, &str, u32, u32, usize, usize); 2] = [
+    ("left-stick", "l3", 115, 130, 5, 6),
+    ("right-stick", "r3", 205, 130, 3, 4),
+];
+
+fn is_pressed(packet: &[u8], button: &str) -> bool {
+    PACKET_BUTTONS
+        .iter()
+        .find(|(name, _, _, _)| *name == button)
+        // The DualShock protocol considers 0 to mean pressed
+        .map(|(_, byte, bits, _)| packet.get(*byte).is_some_and(|value| value & bits == 0))
+        .unwrap_or(false)
+}
+
+fn fill(pressed: bool) -> &'static str {
+    if pressed {
+        PRESSED_FILL
+    } else {
+        RELEASED_FILL
+    }
+}
+
+// Where a stick's dot goes along one axis, given its packet byte
+fn stick_offset(value: u8) -> f64 {
+    (f64::from(value) - 128.0) / 128.0 * STICK_TRAVEL
+}
+
+pub fn render_svg(packet: &[u8]) -> String {
+    let mut svg = vec![
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" "#,
+            r#"width="320" height="180" viewBox="0 0 320 180">"#
+        )
+        .to_string(),
+        concat!(
+            r#"<rect x="10" y="40" width="300" height="130" rx="30" "#,
+            r##"fill="#f4f4f4" stroke="#888888"/>"##
+        )
+        .to_string(),
+    ];
+
+    for (name, shape) in BUTTON_SHAPES.iter() {
+        let fill = fill(is_pressed(packet, name));
+        svg.push(match shape {
+            Shape::Rect(x, y, width, height) => format!(
+                r#"<rect id="{}" x="{}" y="{}" width="{}" height="{}" rx="3" fill="{}"/>"#,
+                name, x, y, width, height, fill
+            ),
+            Shape::Circle(x, y, radius) => format!(
+                r#"<circle id="{}" cx="{}" cy="{}" r="{}" fill="{}"/>"#,
+                name, x, y, radius, fill
+            ),
+        });
+    }
+
+    for (name, button, x, y, x_byte, y_byte) in STICKS.iter() {
+        svg.push(format!(
+            r##"<circle id="{}" cx="{}" cy="{}" r="22" fill="{}" stroke="#888888"/>"##,
+            name,
+            x,
+            y,
+            fill(is_pressed(packet, button))
+        ));
+
+        // Without stick bytes there's nothing to say where the dot goes
+        if let (Some(x_value), Some(y_value)) = (packet.get(*x_byte), packet.get(*y_byte)) {
+            svg.push(format!(
+                r##"<circle id="{}-position" cx="{:.1}" cy="{:.1}" r="6" fill="#333333"/>"##,
+                name,
+                f64::from(*x) + stick_offset(*x_value),
+                f64::from(*y) + stick_offset(*y_value)
+            ));
+        }
+    }
+
+    svg.push("</svg>".to_string());
+    svg.join("\n") + "\n"
+}
+
+// Writes `packet` as an SVG in the current directory, named for when it
+// was taken, returning the file's name
+pub fn save(packet: &[u8]) -> std::io::Result<String> {
+    #[cfg(feature = "flamegraph-profiling")]
+    let _guard = flame::start_guard("snapshot::save()");
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = format!(
+        "omnishock-snapshot-{}-{:03}.svg",
+        since_epoch.as_secs(),
+        since_epoch.subsec_millis()
+    );
+
+    std::fs::write(&path, render_svg(packet))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn render_svg_draws_the_packet() {
+        use super::render_svg;
+        use omnishock::protocol::{Buttons1, Buttons2, NEUTRAL_PACKET};
+
+        let svg = render_svg(&NEUTRAL_PACKET);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(r##"<circle id="cross" cx="255" cy="94" r="8" fill="#dddddd"/>"##));
+        assert!(svg.contains(r#"<circle id="left-stick-position" cx="115.0" cy="130.0""#));
+
+        let mut packet = NEUTRAL_PACKET[..7].to_vec();
+        packet[1] = !(Buttons1::UP | Buttons1::L3).bits();
+        packet[2] = !Buttons2::CROSS.bits();
+        packet[5] = 0xFF;
+        packet[4] = 0x00;
+
+        let svg = render_svg(&packet);
+        assert!(svg.contains(r##"<circle id="cross" cx="255" cy="94" r="8" fill="#44cc44"/>"##));
+        assert!(svg.contains(
+            r##"<rect id="up" x="57" y="58" width="16" height="16" rx="3" fill="#44cc44"/>"##
+        ));
+        assert!(
+            svg.contains(r##"<circle id="left-stick" cx="115" cy="130" r="22" fill="#44cc44""##)
+        );
+        assert!(
+            svg.contains(r##"<circle id="right-stick" cx="205" cy="130" r="22" fill="#dddddd""##)
+        );
+        assert!(svg.contains(r#"<circle id="left-stick-position" cx="130.9" cy="130.0""#));
+        assert!(svg.contains(r#"<circle id="right-stick-position" cx="205.0" cy="114.0""#));
+    }
+}
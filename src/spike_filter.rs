@@ -0,0 +1,210 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::sdl_manager::GameController;
+use std::collections::HashMap;
+
+// Spike Filter
+// Some flaky wireless controllers now and then report a single wild axis
+// value for a frame. This holds each axis at its last value whenever it
+// jumps further than a threshold in one frame, only taking up the new
+// value once it's been there for a few frames running.
+
+const AXES: [sdl2::controller::Axis; 6] = [
+    sdl2::controller::Axis::LeftX,
+    sdl2::controller::Axis::LeftY,
+    sdl2::controller::Axis::RightX,
+    sdl2::controller::Axis::RightY,
+    sdl2::controller::Axis::TriggerLeft,
+    sdl2::controller::Axis::TriggerRight,
+];
+
+// Whether going from `previous` to `current` in one frame is too far a jump
+pub fn is_spike(previous: i16, current: i16, threshold: u16) -> bool {
+    (i32::from(current) - i32::from(previous)).abs() > i32::from(threshold)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AxisHistory {
+    // The value we're passing on
+    accepted: i16,
+    // How many frames running the axis has been too far from it
+    spike_frames: u32,
+}
+
+pub struct SpikeFilter {
+    threshold: u16,
+    // How many frames a jump must last to be believed
+    sustain_frames: u32,
+    axes: HashMap<sdl2::controller::Axis, AxisHistory>,
+}
+
+impl SpikeFilter {
+    pub fn new(threshold: u16, sustain_frames: u32) -> SpikeFilter {
+        SpikeFilter {
+            threshold,
+            sustain_frames,
+            axes: HashMap::new(),
+        }
+    }
+
+    // Takes in this frame's axis values from `controller`
+    pub fn update<T: GameController>(&mut self, controller: &T) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("SpikeFilter#update()");
+        for axis in AXES.iter() {
+            let current = controller.axis(*axis);
+            let history = self.axes.entry(*axis).or_insert(AxisHistory {
+                accepted: current,
+                spike_frames: 0,
+            });
+
+            if !is_spike(history.accepted, current, self.threshold) {
+                history.accepted = current;
+                history.spike_frames = 0;
+                continue;
+            }
+
+            history.spike_frames += 1;
+            if history.spike_frames >= self.sustain_frames {
+                history.accepted = current;
+                history.spike_frames = 0;
+            }
+        }
+    }
+
+    // Forgets every axis' history, such as when the controller changes
+    pub fn reset(&mut self) {
+        self.axes.clear();
+    }
+
+    fn value(&self, axis: sdl2::controller::Axis) -> Option<i16> {
+        self.axes.get(&axis).map(|history| history.accepted)
+    }
+}
+
+// Presents a controller with its axes passed through a spike filter
+pub struct FilteredController<'a, T: GameController> {
+    controller: &'a T,
+    filter: &'a SpikeFilter,
+}
+
+impl<'a, T: GameController> FilteredController<'a, T> {
+    pub fn new(controller: &'a T, filter: &'a SpikeFilter) -> FilteredController<'a, T> {
+        FilteredController { controller, filter }
+    }
+}
+
+impl<'a, T: GameController> GameController for FilteredController<'a, T> {
+    fn name(&self) -> String {
+        self.controller.name()
+    }
+
+    fn button(&self, button: sdl2::controller::Button) -> bool {
+        self.controller.button(button)
+    }
+
+    fn axis(&self, axis: sdl2::controller::Axis) -> i16 {
+        self.filter
+            .value(axis)
+            .unwrap_or_else(|| self.controller.axis(axis))
+    }
+
+    fn has_rumble(&self) -> bool {
+        self.controller.has_rumble()
+    }
+
+    fn attached(&self) -> bool {
+        self.controller.attached()
+    }
+
+    fn usb_id(&self) -> Option<(u16, u16)> {
+        self.controller.usb_id()
+    }
+
+    fn guid(&self) -> Option<String> {
+        self.controller.guid()
+    }
+
+    fn touchpad_finger(&self, index: u8) -> Option<(f32, f32)> {
+        self.controller.touchpad_finger(index)
+    }
+
+    fn set_rumble(
+        &mut self,
+        _low_frequency_rumble: u16,
+        _high_frequency_rumble: u16,
+        _duration_ms: u32,
+    ) -> Result<(), String> {
+        // Like merged controllers, we only hold a shared reference
+        Err("rumble must be set on the filtered controller itself".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::faux_controller::FauxController;
+
+    #[test]
+    fn is_spike_compares_against_the_threshold() {
+        use super::is_spike;
+
+        assert!(!is_spike(0, 1000, 1000));
+        assert!(is_spike(0, 1001, 1000));
+        assert!(is_spike(0, -1001, 1000));
+        // The widest possible jump doesn't overflow
+        assert!(is_spike(
+            i16::min_value(),
+            i16::max_value(),
+            u16::max_value() - 1
+        ));
+    }
+
+    #[test]
+    fn spike_filter_holds_through_brief_spikes() {
+        use super::{FilteredController, SpikeFilter};
+        use crate::sdl_manager::GameController;
+        use sdl2::controller::Axis;
+
+        let mut controller = FauxController::create_with_name(String::from("Flaky Wireless Pad"));
+        let mut filter = SpikeFilter::new(8192, 3);
+
+        let mut frame = |filter: &mut SpikeFilter, value: i16| {
+            controller.set_axis(Axis::LeftX, value);
+            filter.update(&controller);
+            FilteredController::new(&controller, filter).axis(Axis::LeftX)
+        };
+
+        assert_eq!(frame(&mut filter, 1000), 1000);
+        assert_eq!(frame(&mut filter, 4000), 4000);
+        // A single wild frame is ignored
+        assert_eq!(frame(&mut filter, i16::max_value()), 4000);
+        assert_eq!(frame(&mut filter, 4100), 4100);
+
+        // But one that lasts is taken up
+        assert_eq!(frame(&mut filter, -30000), 4100);
+        assert_eq!(frame(&mut filter, -30000), 4100);
+        assert_eq!(frame(&mut filter, -30000), -30000);
+
+        // Nothing's held once the history's been forgotten
+        filter.reset();
+        assert_eq!(frame(&mut filter, 30000), 30000);
+    }
+}
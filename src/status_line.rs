@@ -0,0 +1,143 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::PACKET_BUTTONS;
+use std::io::{IsTerminal, Write};
+
+// Status Line
+// A one-line picture of each packet, for keeping an eye on things while
+// tuning. On a terminal it's redrawn in place every frame, with colour;
+// anywhere else a plain line is printed every so often instead.
+
+// How often, in seconds, to print the plain status line
+const PLAIN_STATUS_INTERVAL: f64 = 1.0;
+
+// How many characters wide each stick axis' bar is
+const STICK_BAR_WIDTH: usize = 9;
+
+const PRESSED: &str = "\x1b[1;32m";
+const RELEASED: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+pub struct StatusLine {
+    colour: bool,
+    last_printed: Option<f64>,
+}
+
+impl StatusLine {
+    pub fn new() -> StatusLine {
+        StatusLine {
+            colour: std::io::stdout().is_terminal(),
+            last_printed: None,
+        }
+    }
+
+    // Shows `packet`, if it's time to, at `now` seconds into the session
+    pub fn show(&mut self, packet: &[u8], now: f64) {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("StatusLine#show()");
+        if self.colour {
+            // Go back to the start of the line, and clear it
+            print!("\r\x1b[2K{}", render(packet, true));
+            let _ = std::io::stdout().flush();
+            return;
+        }
+
+        if let Some(last_printed) = self.last_printed {
+            if now - last_printed < PLAIN_STATUS_INTERVAL {
+                return;
+            }
+        }
+
+        self.last_printed = Some(now);
+        println!("{}", render(packet, false));
+    }
+}
+
+// Draws a stick axis' position as a bar, e.g. "[----|----]" when centred
+fn stick_bar(value: u8) -> String {
+    let position = usize::from(value) * (STICK_BAR_WIDTH - 1) / usize::from(u8::max_value());
+
+    let bar: String = (0..STICK_BAR_WIDTH)
+        .map(|index| if index == position { '|' } else { '-' })
+        .collect();
+
+    format!("[{}]", bar)
+}
+
+pub fn render(packet: &[u8], colour: bool) -> String {
+    let mut parts = Vec::new();
+
+    for (name, byte, bits, _) in PACKET_BUTTONS.iter() {
+        // The DualShock protocol considers 0 to mean pressed
+        let pressed = packet.get(*byte).is_some_and(|value| value & bits == 0);
+
+        if colour {
+            let style = if pressed { PRESSED } else { RELEASED };
+            parts.push(format!("{}{}{}", style, name, RESET));
+        } else if pressed {
+            parts.push(name.to_string());
+        }
+    }
+
+    if !colour && parts.is_empty() {
+        parts.push("(no buttons)".to_string());
+    }
+
+    // Right stick X & Y, then left stick X & Y
+    if let Some(sticks) = packet.get(3..7) {
+        parts.push(format!(
+            "L {} {} R {} {}",
+            stick_bar(sticks[2]),
+            stick_bar(sticks[3]),
+            stick_bar(sticks[0]),
+            stick_bar(sticks[1])
+        ));
+    }
+
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn render_shows_buttons_and_sticks() {
+        use super::render;
+        use omnishock::protocol::{Buttons2, NEUTRAL_PACKET};
+
+        let mut packet = NEUTRAL_PACKET.to_vec();
+        packet[2] = !(Buttons2::CROSS | Buttons2::L1).bits();
+        packet[3] = 0xFF;
+        packet[6] = 0x00;
+
+        assert_eq!(
+            render(&packet, false),
+            "cross l1 L [----|----] [|--------] R [--------|] [----|----]"
+        );
+        assert_eq!(
+            render(&NEUTRAL_PACKET, false),
+            "(no buttons) L [----|----] [----|----] R [----|----] [----|----]"
+        );
+
+        let coloured = render(&packet, true);
+        assert!(coloured.contains("\x1b[1;32mcross\x1b[0m"));
+        assert!(coloured.contains("\x1b[2mcircle\x1b[0m"));
+    }
+}
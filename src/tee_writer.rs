@@ -0,0 +1,121 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use hex_view::HexView;
+use std::io::{Read, Write};
+
+// Tee Writer
+// Wraps a stream so that everything written to it is also copied to
+// some other writers, for capturing exactly what went to the device.
+// Reads only ever come from the wrapped stream.
+
+pub struct TeeWriter<I> {
+    primary: I,
+    copies: Vec<Box<dyn Write>>,
+}
+
+impl<I> TeeWriter<I> {
+    pub fn new(primary: I, copies: Vec<Box<dyn Write>>) -> TeeWriter<I> {
+        TeeWriter { primary, copies }
+    }
+}
+
+impl<I: Read> Read for TeeWriter<I> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        self.primary.read(buffer)
+    }
+}
+
+impl<I: Write> Write for TeeWriter<I> {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        #[cfg(feature = "flamegraph-profiling")]
+        let _guard = flame::start_guard("TeeWriter#write()");
+        let written = self.primary.write(buffer)?;
+
+        // Only copy what the device actually took,
+        // so the copies see the same bytes it did
+        for copy in &mut self.copies {
+            copy.write_all(&buffer[..written])?;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.primary.flush()?;
+
+        for copy in &mut self.copies {
+            copy.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Prints each write to standard output as a line of hex
+pub struct HexStdout;
+
+impl Write for HexStdout {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        println!("{:x}", HexView::from(buffer));
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TeeWriter;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    // A writer whose contents can still be seen once it's been boxed up
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tee_writer_copies_writes_and_reads_from_the_primary() {
+        let copy = SharedBuffer::default();
+        let primary = std::io::Cursor::new(vec![0xFA, 0xF3]);
+        let mut tee = TeeWriter::new(primary, vec![Box::new(copy.clone())]);
+
+        let mut response = [0; 2];
+        tee.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0xFA, 0xF3]);
+
+        tee.write_all(&[0x5A, 0xFF, 0xFF]).unwrap();
+        assert_eq!(*copy.0.lock().unwrap(), vec![0x5A, 0xFF, 0xFF]);
+        assert_eq!(tee.primary.into_inner(), vec![0xFA, 0xF3, 0x5A, 0xFF, 0xFF]);
+    }
+}
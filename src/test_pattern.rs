@@ -0,0 +1,154 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use omnishock::faux_controller::FauxController;
+use sdl2::controller::{Axis, Button};
+
+// Test Pattern
+// A sequence of controller states which presses every DualShock
+// button in turn, then sweeps each stick axis through its range
+
+// Each controller button, alongside the DualShock button it presses
+const BUTTONS: [(Button, &str); 15] = [
+    (Button::DPadUp, "Up"),
+    (Button::DPadRight, "Right"),
+    (Button::DPadDown, "Down"),
+    (Button::DPadLeft, "Left"),
+    (Button::Y, "Triangle"),
+    (Button::B, "Circle"),
+    (Button::A, "Cross"),
+    (Button::X, "Square"),
+    (Button::LeftShoulder, "L1"),
+    (Button::RightShoulder, "R1"),
+    (Button::LeftStick, "L3"),
+    (Button::RightStick, "R3"),
+    (Button::Back, "Select"),
+    (Button::Start, "Start"),
+    (Button::Guide, "Analog mode"),
+];
+
+// The analog triggers, which are pressed fully
+const TRIGGERS: [(Axis, &str); 2] = [(Axis::TriggerLeft, "L2"), (Axis::TriggerRight, "R2")];
+
+// The stick axes, which are swept from one extreme to the other
+const STICK_AXES: [(Axis, &str); 4] = [
+    (Axis::LeftX, "left stick X"),
+    (Axis::LeftY, "left stick Y"),
+    (Axis::RightX, "right stick X"),
+    (Axis::RightY, "right stick Y"),
+];
+
+// Where along a sweep from minimum to maximum we are at `frame`
+fn sweep_value(frame: u32, frames: u32) -> i16 {
+    if frames <= 1 {
+        return i16::max_value();
+    }
+
+    let range = i32::from(i16::max_value()) - i32::from(i16::min_value());
+    let offset = i64::from(range) * i64::from(frame) / i64::from(frames - 1);
+
+    (i64::from(i16::min_value()) + offset) as i16
+}
+
+// Builds the whole pattern, one controller state per frame,
+// alongside a description of what that frame is testing
+pub fn test_pattern(frames_per_step: u32) -> Vec<(String, FauxController)> {
+    let mut frames = Vec::new();
+    let neutral = FauxController::create_with_name(String::from("Test Pattern"));
+
+    for (button, name) in BUTTONS.iter() {
+        let mut controller = neutral.clone();
+        controller.set_button(*button, true);
+
+        for _ in 0..frames_per_step {
+            frames.push((format!("Pressing {}", name), controller.clone()));
+        }
+    }
+
+    for (axis, name) in TRIGGERS.iter() {
+        let mut controller = neutral.clone();
+        controller.set_axis(*axis, i16::max_value());
+
+        for _ in 0..frames_per_step {
+            frames.push((format!("Pressing {}", name), controller.clone()));
+        }
+    }
+
+    for (axis, name) in STICK_AXES.iter() {
+        for frame in 0..frames_per_step {
+            let mut controller = neutral.clone();
+            controller.set_axis(*axis, sweep_value(frame, frames_per_step));
+
+            frames.push((format!("Sweeping {}", name), controller));
+        }
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use omnishock::sdl_manager::GameController;
+
+    #[test]
+    fn sweep_value_covers_the_range() {
+        use super::sweep_value;
+
+        assert_eq!(sweep_value(0, 30), i16::min_value());
+        assert_eq!(sweep_value(29, 30), i16::max_value());
+        assert_eq!(sweep_value(0, 1), i16::max_value());
+
+        let values: Vec<i16> = (0..30).map(|frame| sweep_value(frame, 30)).collect();
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_pattern_presses_each_button_in_turn() {
+        use super::{test_pattern, BUTTONS, STICK_AXES, TRIGGERS};
+        use sdl2::controller::Axis;
+
+        let frames = test_pattern(3);
+
+        assert_eq!(
+            frames.len(),
+            (BUTTONS.len() + TRIGGERS.len() + STICK_AXES.len()) * 3
+        );
+
+        // Every frame of a button's step presses that button, and only that button
+        for (index, (button, name)) in BUTTONS.iter().enumerate() {
+            for (label, controller) in &frames[index * 3..index * 3 + 3] {
+                assert_eq!(label, &format!("Pressing {}", name));
+
+                for (other, _) in BUTTONS.iter() {
+                    assert_eq!(controller.button(*other), other == button);
+                }
+            }
+        }
+
+        // The last step sweeps the right stick's Y axis
+        let (label, controller) = &frames[frames.len() - 3];
+        assert_eq!(label, "Sweeping right stick Y");
+        assert_eq!(controller.axis(Axis::RightY), i16::min_value());
+        assert_eq!(controller.axis(Axis::RightX), 0);
+
+        let (_, controller) = &frames[frames.len() - 1];
+        assert_eq!(controller.axis(Axis::RightY), i16::max_value());
+    }
+}
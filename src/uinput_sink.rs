@@ -0,0 +1,302 @@
+/*
+ * Omnishock: Something to do with game controllers!
+ * Copyright (C) 2017-2019 Jessica Stokes
+ *
+ * This file is part of Omnishock.
+ *
+ * Omnishock is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Omnishock is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Omnishock.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use omnishock::protocol::{
+    Buttons1, Buttons2, DUALSHOCK_MAGIC, TWENTY_BYTE_OK_HEADER, TWENTY_BYTE_PACKET_LENGTH,
+};
+
+// uinput Sink
+// Presents a virtual gamepad to Linux via uinput, and translates each
+// DualShock packet written to it back into input events for that gamepad.
+// It answers like Aaron Clovsky's firmware does (minus any rumble), so
+// detection picks the twenty-byte protocol and the pressure bytes survive.
+
+// From <linux/input-event-codes.h>
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_Z: u16 = 0x02;
+const ABS_RX: u16 = 0x03;
+const ABS_RY: u16 = 0x04;
+const ABS_RZ: u16 = 0x05;
+
+const BTN_SOUTH: u16 = 0x130;
+const BTN_EAST: u16 = 0x131;
+const BTN_NORTH: u16 = 0x133;
+const BTN_WEST: u16 = 0x134;
+const BTN_TL: u16 = 0x136;
+const BTN_TR: u16 = 0x137;
+const BTN_TL2: u16 = 0x138;
+const BTN_TR2: u16 = 0x139;
+const BTN_SELECT: u16 = 0x13a;
+const BTN_START: u16 = 0x13b;
+const BTN_MODE: u16 = 0x13c;
+const BTN_THUMBL: u16 = 0x13d;
+const BTN_THUMBR: u16 = 0x13e;
+const BTN_DPAD_UP: u16 = 0x220;
+const BTN_DPAD_DOWN: u16 = 0x221;
+const BTN_DPAD_LEFT: u16 = 0x222;
+const BTN_DPAD_RIGHT: u16 = 0x223;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+// From <linux/uinput.h>
+const UI_DEV_CREATE: u64 = 0x5501;
+const UI_DEV_DESTROY: u64 = 0x5502;
+const UI_SET_EVBIT: u64 = 0x4004_5564;
+const UI_SET_KEYBIT: u64 = 0x4004_5565;
+const UI_SET_ABSBIT: u64 = 0x4004_5567;
+
+const BUTTONS1_KEYS: [(Buttons1, u16); 8] = [
+    (Buttons1::LEFT, BTN_DPAD_LEFT),
+    (Buttons1::DOWN, BTN_DPAD_DOWN),
+    (Buttons1::RIGHT, BTN_DPAD_RIGHT),
+    (Buttons1::UP, BTN_DPAD_UP),
+    (Buttons1::START, BTN_START),
+    (Buttons1::R3, BTN_THUMBR),
+    (Buttons1::L3, BTN_THUMBL),
+    (Buttons1::SELECT, BTN_SELECT),
+];
+
+const BUTTONS2_KEYS: [(Buttons2, u16); 8] = [
+    (Buttons2::SQUARE, BTN_WEST),
+    (Buttons2::CROSS, BTN_SOUTH),
+    (Buttons2::CIRCLE, BTN_EAST),
+    (Buttons2::TRIANGLE, BTN_NORTH),
+    (Buttons2::R1, BTN_TR),
+    (Buttons2::L1, BTN_TL),
+    (Buttons2::R2, BTN_TR2),
+    (Buttons2::L2, BTN_TL2),
+];
+
+// Stick axes, in the order they appear in the packet, then L2 & R2 pressure
+const STICK_AXES: [u16; 4] = [ABS_RX, ABS_RY, ABS_X, ABS_Y];
+const TRIGGER_AXES: [u16; 2] = [ABS_Z, ABS_RZ];
+
+pub struct UInputSink {
+    device: File,
+    pending: VecDeque<u8>,
+}
+
+fn ioctl(device: &File, request: u64, value: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: Each request we make takes either no argument, or an int
+    if unsafe { libc::ioctl(device.as_raw_fd(), request as _, value) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+impl UInputSink {
+    pub fn create(name: &str) -> std::io::Result<UInputSink> {
+        let mut device = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")?;
+
+        ioctl(&device, UI_SET_EVBIT, EV_KEY.into())?;
+        let keys = BUTTONS1_KEYS.iter().map(|(_, key)| key);
+        for key in keys.chain(BUTTONS2_KEYS.iter().map(|(_, key)| key)) {
+            ioctl(&device, UI_SET_KEYBIT, (*key).into())?;
+        }
+        ioctl(&device, UI_SET_KEYBIT, BTN_MODE.into())?;
+
+        ioctl(&device, UI_SET_EVBIT, EV_ABS.into())?;
+        for axis in STICK_AXES.iter().chain(TRIGGER_AXES.iter()) {
+            ioctl(&device, UI_SET_ABSBIT, (*axis).into())?;
+        }
+
+        // SAFETY: `uinput_user_dev` is plain old data, for which zero is valid
+        let mut setup: libc::uinput_user_dev = unsafe { std::mem::zeroed() };
+        for (target, source) in setup.name.iter_mut().zip(name.bytes().take(79)) {
+            *target = source as libc::c_char;
+        }
+        setup.id.bustype = BUS_VIRTUAL;
+        setup.id.version = 1;
+        for axis in STICK_AXES.iter().chain(TRIGGER_AXES.iter()) {
+            setup.absmin[usize::from(*axis)] = 0x00;
+            setup.absmax[usize::from(*axis)] = 0xFF;
+        }
+
+        // SAFETY: We're only viewing the struct's bytes for the duration of the write
+        device.write_all(unsafe {
+            std::slice::from_raw_parts(
+                &setup as *const libc::uinput_user_dev as *const u8,
+                std::mem::size_of::<libc::uinput_user_dev>(),
+            )
+        })?;
+
+        ioctl(&device, UI_DEV_CREATE, 0)?;
+
+        Ok(UInputSink {
+            device,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn emit(&mut self, event_type: u16, code: u16, value: i32) -> std::io::Result<()> {
+        // SAFETY: `input_event` is plain old data, for which zero is valid,
+        //         and the kernel fills in the timestamp for us
+        let mut event: libc::input_event = unsafe { std::mem::zeroed() };
+        event.type_ = event_type;
+        event.code = code;
+        event.value = value;
+
+        // SAFETY: We're only viewing the struct's bytes for the duration of the write
+        self.device.write_all(unsafe {
+            std::slice::from_raw_parts(
+                &event as *const libc::input_event as *const u8,
+                std::mem::size_of::<libc::input_event>(),
+            )
+        })
+    }
+
+    fn emit_packet(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        for (event_type, code, value) in packet_events(packet) {
+            self.emit(event_type, code, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Translates a DualShock packet into (type, code, value) input events
+fn packet_events(packet: &[u8]) -> Vec<(u16, u16, i32)> {
+    // Don't try to make sense of anything that isn't a whole packet
+    if packet.len() < 7 || packet[0] != DUALSHOCK_MAGIC {
+        return Vec::new();
+    }
+
+    // The DualShock protocol considers 0 to mean pressed
+    let buttons1 = Buttons1::from_bits_truncate(!packet[1]);
+    let buttons2 = Buttons2::from_bits_truncate(!packet[2]);
+
+    let mut events = Vec::new();
+
+    for (flag, key) in BUTTONS1_KEYS.iter() {
+        events.push((EV_KEY, *key, buttons1.contains(*flag).into()));
+    }
+    for (flag, key) in BUTTONS2_KEYS.iter() {
+        events.push((EV_KEY, *key, buttons2.contains(*flag).into()));
+    }
+
+    for (axis, value) in STICK_AXES.iter().zip(packet[3..7].iter()) {
+        events.push((EV_ABS, *axis, (*value).into()));
+    }
+
+    if packet.len() >= TWENTY_BYTE_PACKET_LENGTH {
+        for (axis, value) in TRIGGER_AXES.iter().zip(packet[17..19].iter()) {
+            events.push((EV_ABS, *axis, (*value).into()));
+        }
+        events.push((EV_KEY, BTN_MODE, (packet[19] == 0xAA).into()));
+    }
+
+    events.push((EV_SYN, SYN_REPORT, 0));
+
+    events
+}
+
+impl Read for UInputSink {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        // Behave like a serial port with nothing left to say
+        if self.pending.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let mut count = 0;
+        while count < buffer.len() {
+            match self.pending.pop_front() {
+                Some(byte) => buffer[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for UInputSink {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.emit_packet(buffer)?;
+
+        // Answer the way Aaron Clovsky's firmware would, with no rumble
+        self.pending.clear();
+        self.pending
+            .extend(&[TWENTY_BYTE_OK_HEADER, 0x00, 0x00, 0x55]);
+
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for UInputSink {
+    fn drop(&mut self) {
+        // If this fails, the kernel will clean up when the file closes anyway
+        #[allow(unused_must_use)]
+        {
+            ioctl(&self.device, UI_DEV_DESTROY, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn packet_events_decode_twenty_byte_packets() {
+        use super::*;
+        use omnishock::protocol::NEUTRAL_PACKET;
+
+        let mut packet = NEUTRAL_PACKET;
+        packet[2] = !Buttons2::CROSS.bits();
+        packet[5] = 0x00;
+        packet[18] = 0xFF;
+        packet[19] = 0xAA;
+
+        let events = packet_events(&packet);
+
+        assert!(events.contains(&(EV_KEY, BTN_SOUTH, 1)));
+        assert!(events.contains(&(EV_KEY, BTN_EAST, 0)));
+        assert!(events.contains(&(EV_KEY, BTN_DPAD_UP, 0)));
+        assert!(events.contains(&(EV_ABS, ABS_X, 0x00)));
+        assert!(events.contains(&(EV_ABS, ABS_Y, 0x80)));
+        assert!(events.contains(&(EV_ABS, ABS_RZ, 0xFF)));
+        assert!(events.contains(&(EV_KEY, BTN_MODE, 1)));
+        assert_eq!(events.last(), Some(&(EV_SYN, SYN_REPORT, 0)));
+
+        assert!(packet_events(&[0x00; 7]).is_empty());
+    }
+}